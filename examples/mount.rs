@@ -0,0 +1,31 @@
+//! Mounts a zstd:chunked file read-only with FUSE
+//! Only the ranges of the file that are needed for what's read get decompressed
+
+use std::fs::File;
+
+use anyhow::{Context, Result};
+use clap::Parser;
+
+use zstd_chunked::{MetadataReferences, Stream, blob::BlobReader, fuse::LayerFs};
+
+#[derive(Parser)]
+struct Args {
+    /// A zstd:chunked file to mount
+    filename: String,
+
+    /// Where to mount it
+    mountpoint: String,
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+    let file = File::open(args.filename).context("Unable to open file")?;
+
+    let references = MetadataReferences::from_blob(&file)?
+        .context("This doesn't appear to be a zstd:chunked file")?;
+
+    let stream = Stream::from_blob(&file, &references)?;
+
+    let fs = LayerFs::new(stream, move |reference| file.content(reference))?;
+    fs.mount(args.mountpoint).context("Failed to mount")
+}
@@ -1,304 +1,422 @@
 //! Pull a zstd:chunked image using oci-client
 use std::{
-    fmt, fs,
-    ops::Range,
+    collections::HashMap,
     path::PathBuf,
-    sync::Mutex,
-    thread,
-    time::{Duration, Instant},
+    sync::{
+        Arc, Mutex, PoisonError,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::Duration,
 };
 
-use anyhow::{Context, Result, bail};
-use clap::Parser;
-use futures::{
-    channel::oneshot,
-    stream::{self, StreamExt, TryStreamExt},
-    try_join,
+use anyhow::{Context, Result};
+use clap::{Parser, ValueEnum};
+use indicatif::{DecimalBytes, MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use oci_client::{Client, Reference, client::ClientConfig};
+
+use zstd_chunked::{
+    Stream,
+    extract::{ExtractOptions, IdMap, IdRange},
+    negative_cache::NegativeCache,
+    pull::{Event, Progress, Puller},
+    quota::{DiskQuota, SpaceReservation},
+    replay::{Recorder, Replay},
+    store::{Backend, ChunkStore, Layout, MemoryStore},
+    verify::ChecksumPolicy,
 };
-use futures_timer::Delay;
-use indicatif::{ProgressBar, ProgressStyle};
-use oci_client::{
-    Client, Reference,
-    client::{BlobResponse, ClientConfig},
-    manifest::{OciDescriptor, OciManifest},
-    secrets::RegistryAuth,
-};
-
-use zstd_chunked::{ContentReference, MetadataReference, MetadataReferences, Stream};
 
-#[derive(Parser, Debug)]
-struct Args {
-    image: Reference,
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum Format {
+    /// Progress bars (and optionally events) for humans
+    Text,
+    /// One JSON object per event, followed by the final report, with no progress bars
+    JsonLines,
 }
 
-// The Chameleon keeps track of how well the download is going.  Each byte successfully downloaded
-// increases the karma by 1 and each network failure decreases it by 1.  The passage of time also
-// decreases karma, with exponential decay.  This means that as long as progress is steady,
-// even with really slow download speeds (think 10bytes/sec), we can tolerate a large number of
-// network errors, but once we stop making forward progress and exponential decay sets in, our
-// patience for errors decreases rapidly.  It also means that a single error at the start is
-// immediately fatal, which feels correct.
-struct Chameleon {
-    // 🌈🦎📊
-    karma: f64,
-    updated: Instant,
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum FreeSpace {
+    /// Fail (before downloading any content) if a layer won't fit in the free space
+    Check,
+    /// Preallocate the space needed by each layer before downloading its content, so that it
+    /// can't run out part way through
+    Reserve,
 }
 
-impl Chameleon {
-    fn get(&self, now: &Instant) -> f64 {
-        // first order exponential decay, time constant = 1s (ie: drops to 36.79% after 1 sec)
-        self.karma / now.duration_since(self.updated).as_secs_f64().exp()
-    }
-
-    fn update(&mut self, delta: impl Into<f64>) -> f64 {
-        let now = Instant::now();
-        self.karma = self.get(&now) + delta.into();
-        self.updated = now;
-        self.karma
-    }
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum Checksums {
+    /// Only checksums of the compressed metadata, as in the spec
+    Strict,
+    /// Also checksums of the decompressed metadata, as written by some producers
+    Lenient,
 }
 
-impl fmt::Debug for Chameleon {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(
-            f,
-            "Chameleon {{ value: {}, updated: {:?} }} -> {}",
-            self.karma,
-            self.updated,
-            self.get(&Instant::now())
-        )
-    }
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum Storage {
+    /// Keep content in the cache directory, for later pulls
+    Disk,
+    /// Keep content in memory, for one-off extraction
+    Memory,
 }
 
-impl Default for Chameleon {
-    fn default() -> Self {
-        Self {
-            karma: 0.,
-            updated: Instant::now(),
-        }
-    }
+#[derive(Parser, Debug)]
+struct Args {
+    /// The images to pull, all at once
+    #[arg(required = true)]
+    images: Vec<Reference>,
+
+    /// Where to keep the content of the layers
+    #[arg(long, value_enum, default_value_t = Storage::Disk)]
+    storage: Storage,
+
+    /// Lay out the cache directory as a composefs repository
+    #[arg(long, conflicts_with = "sharded")]
+    composefs: bool,
+
+    /// Spread the cache directory over subdirectories, by digest prefix
+    #[arg(long)]
+    sharded: bool,
+
+    /// The maximum number of concurrent range requests, shared between all layers
+    #[arg(long, default_value_t = 100)]
+    connections: usize,
+
+    /// Limit the download rate of all images together to this many bytes per second
+    #[arg(long)]
+    bandwidth: Option<u64>,
+
+    /// Send a second request for any range that hasn't completed after this many milliseconds,
+    /// and use whichever finishes first
+    #[arg(long)]
+    hedge_after: Option<u64>,
+
+    /// Which manifest and tarsplit checksums to accept
+    #[arg(long, value_enum, default_value_t = Checksums::Strict)]
+    checksums: Checksums,
+
+    /// Fetch this file before the others (can be given more than once)
+    #[arg(long)]
+    priority: Vec<String>,
+
+    /// Give up if any layer isn't ready within this many seconds
+    #[arg(long)]
+    layer_timeout: Option<u64>,
+
+    /// Add the files from an existing directory tree (like an extracted rootfs) to the cache first
+    #[arg(long)]
+    seed: Option<PathBuf>,
+
+    /// Remember layers that fail verification in this file, and refuse to partially pull layers
+    /// that have failed repeatedly
+    #[arg(long)]
+    negative_cache: Option<PathBuf>,
+
+    /// How many hours to remember verification failures for
+    #[arg(long, default_value_t = 24)]
+    negative_cache_ttl: u64,
+
+    /// Fail (before downloading any content) if the layers would add more than this many bytes to
+    /// the cache
+    #[arg(long)]
+    quota: Option<u64>,
+
+    /// Check the free space on the cache's filesystem
+    #[arg(long, value_enum)]
+    free_space: Option<FreeSpace>,
+
+    /// Unpack the image into this directory.  Each layer is applied (in order) as soon as its
+    /// content is in, while later layers are still downloading
+    #[arg(long)]
+    extract: Option<PathBuf>,
+
+    /// Map the owners of extracted files, as CONTAINER:HOST:SIZE (as for podman, and can be given
+    /// more than once)
+    #[arg(long, value_parser = parse_id_range, requires = "extract")]
+    uidmap: Vec<IdRange>,
+
+    /// Map the groups of extracted files, as for --uidmap
+    #[arg(long, value_parser = parse_id_range, requires = "extract")]
+    gidmap: Vec<IdRange>,
+
+    /// Record every response from the registry into a replay bundle in this directory
+    #[arg(long, conflicts_with = "replay")]
+    record: Option<PathBuf>,
+
+    /// Repeat a pull offline, with the responses from a replay bundle made by --record
+    #[arg(long)]
+    replay: Option<PathBuf>,
+
+    /// Print an event for each step of the pull
+    #[arg(long)]
+    events: bool,
+
+    /// The output format
+    #[arg(long, value_enum, default_value_t = Format::Text)]
+    format: Format,
 }
 
-struct PullOp {
-    client: Client,
-    cache: PathBuf,
-    image: Reference,
-    progress: ProgressBar,
-    karma: Mutex<Chameleon>, // could be RefCell but then PullOp isn't Send
+fn parse_id_range(arg: &str) -> Result<IdRange> {
+    let mut ids = arg.splitn(3, ':').map(str::parse);
+    let mut next = || -> Result<u32> { Ok(ids.next().context("Expected CONTAINER:HOST:SIZE")??) };
+    Ok(IdRange {
+        container: next()?,
+        host: next()?,
+        size: next()?,
+    })
 }
 
-async fn run_in_thread(f: impl FnOnce() -> Result<()> + Send + 'static) -> Result<()> {
-    let (tx, rx) = oneshot::channel();
-    thread::spawn(move || tx.send(f()));
-    rx.await.context("Thread panicked or sender dropped")?
+// Progress bars for the whole image and for each layer, and the events (if requested)
+struct Bars {
+    multi: MultiProgress,
+    total: ProgressBar,
+    // The bar of each layer, with the number of bytes found in the cache
+    layers: Mutex<HashMap<String, (ProgressBar, u64)>>,
+    layers_total: AtomicUsize,
+    layers_done: AtomicUsize,
+    format: Format,
+    events: bool,
 }
 
-impl PullOp {
-    async fn softfail(&self, err: impl Into<anyhow::Error>) -> Result<()> {
-        #[allow(clippy::unwrap_used)]
-        if self.karma.lock().unwrap().update(-1.) < 0. {
-            // Karma went negative: let the error bubble out.
-            Err(err.into())
+impl Bars {
+    fn new(args: &Args) -> Result<Self> {
+        let multi = if args.format == Format::JsonLines {
+            MultiProgress::with_draw_target(ProgressDrawTarget::hidden())
         } else {
-            // Give it a second...
-            Delay::new(Duration::from_secs(1)).await;
-            Ok(())
-        }
+            MultiProgress::new()
+        };
+        let total = multi.add(ProgressBar::new(0));
+        total.enable_steady_tick(Duration::from_millis(100));
+        total.set_style(ProgressStyle::with_template(
+            "[eta {eta}] {bar:40.cyan/blue} {decimal_bytes:>7}/{decimal_total_bytes:7} {decimal_bytes_per_sec} {msg}",
+        )?);
+        Ok(Self {
+            multi,
+            total,
+            layers: Mutex::default(),
+            layers_total: AtomicUsize::new(0),
+            layers_done: AtomicUsize::new(0),
+            format: args.format,
+            events: args.events,
+        })
     }
 
-    // To simplify progress tracking, if this function fails, the entire operation needs to be
-    // aborted, so it tries really hard not to fail... it will also never download any byte that it
-    // has already successfully received (ie: it will make the range request smaller before trying
-    // again).
-    async fn download_range(&self, desc: &OciDescriptor, range: &Range<u64>) -> Result<Vec<u8>> {
-        let (mut start, end) = (range.start, range.end);
-        let mut data = vec![];
-
-        'send_request: while start < end {
-            let resp = match self
-                .client
-                .pull_blob_stream_partial(&self.image, desc, start, Some(range.end - start))
-                .await
-            {
-                Ok(resp) => resp,
-                Err(err) => {
-                    self.softfail(err).await?;
-                    continue 'send_request;
-                }
-            };
-
-            // Maybe some servers would respond with a full request if we give the complete range
-            // but let's wait until someone actually encounters that before we try to handle it...
-            let BlobResponse::Partial(mut stream) = resp else {
-                bail!("Server has no range support");
-            };
-
-            while let Some(result) = stream.next().await {
-                match result {
-                    Ok(bytes) => {
-                        let n_bytes = bytes.len() as u64;
-
-                        #[allow(clippy::cast_precision_loss, clippy::unwrap_used)]
-                        self.karma.lock().unwrap().update(n_bytes as f64);
-                        data.extend_from_slice(&bytes);
-                        self.progress.inc(n_bytes);
-                        start += n_bytes;
-                    }
-                    Err(err) => {
-                        self.softfail(err).await?;
-                        continue 'send_request;
-                    }
-                }
-            }
+    fn with_layer(&self, layer: &str, f: impl FnOnce(&mut (ProgressBar, u64))) {
+        let mut layers = self.layers.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(entry) = layers.get_mut(layer) {
+            f(entry);
         }
-
-        Ok(data)
     }
+}
 
-    async fn check_and_save(path: PathBuf, decompress: bool, mut data: Vec<u8>) -> Result<()> {
-        run_in_thread(move || {
-            if decompress {
-                data = zstd::decode_all(&data[..])?;
-            }
-
-            // TODO: validate...
-            let digest = path.file_name();
-            let _ = digest;
-
-            // write it to the path
-            fs::write(&path, &data)?;
-            Ok(())
-        })
-        .await
+impl Progress for Bars {
+    fn pull_started(&self, layers: usize, size: u64) {
+        self.total.inc_length(size);
+        let total = self.layers_total.fetch_add(layers, Ordering::Relaxed) + layers;
+        let done = self.layers_done.load(Ordering::Relaxed);
+        self.total.set_message(format!("{done}/{total} layers"));
     }
 
-    async fn download_metadata(
-        &self,
-        layer: &OciDescriptor,
-        reference: &MetadataReference,
-    ) -> Result<Vec<u8>> {
-        if let Some(digest) = &reference.digest {
-            if let Ok(data) = fs::read(self.cache.join(digest)) {
-                // TODO: validate
-                self.progress
-                    .dec_length(reference.range.end - reference.range.start);
-                return Ok(data);
-            }
-        }
-
-        let result = self.download_range(layer, &reference.range).await?;
-
-        if let Some(digest) = &reference.digest {
-            // Caching metadata might not make sense for the "incremental updates" case (since it's
-            // definitely going to be different next time) but it definitely makes sense from the
-            // "bad network connection and my download got interrupted" case.
-            Self::check_and_save(self.cache.join(digest), false, result.clone()).await?;
+    fn layer_started(&self, layer: &str, size: u64) {
+        let bar = self
+            .multi
+            .insert_before(&self.total, ProgressBar::new(size));
+        if let Ok(style) = ProgressStyle::with_template(
+            "{prefix} {bar:40.green/white} {decimal_bytes:>7}/{decimal_total_bytes:7} {msg}",
+        ) {
+            bar.set_style(style);
         }
-
-        Ok(result)
+        bar.set_prefix(layer.chars().take(19).collect::<String>());
+        self.layers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(layer.to_owned(), (bar, 0));
     }
 
-    async fn ensure_content(
-        &self,
-        layer: &OciDescriptor,
-        reference: &ContentReference,
-    ) -> Result<()> {
-        let cache_path = self.cache.join(&reference.digest);
-        if fs::exists(&cache_path)? {
-            self.progress
-                .dec_length(reference.range.end - reference.range.start);
-        } else {
-            let result = self.download_range(layer, &reference.range).await?;
-            Self::check_and_save(cache_path, true, result).await?;
-        }
-
-        Ok(())
+    fn downloaded(&self, layer: &str, bytes: u64) {
+        self.total.inc(bytes);
+        self.with_layer(layer, |(bar, _)| bar.inc(bytes));
     }
 
-    async fn download_zstd_chunked_layer(&self, layer: &OciDescriptor) -> Result<Stream> {
-        let metadata = layer
-            .annotations
-            .as_ref()
-            .and_then(|annotations| MetadataReferences::from_oci(|key| annotations.get(key)))
-            .context("Not a zstd:chunked image?")?;
-
-        let (manifest, tarsplit) = try_join!(
-            self.download_metadata(layer, &metadata.manifest),
-            self.download_metadata(layer, &metadata.tarsplit)
-        )?;
-
-        let stream = Stream::new_from_frames(&manifest[..], &tarsplit[..])?;
-
-        // Remove the parts of the file that we know we won't need (tar headers, etc.)
-        // We get that by summing up the parts we do need and subtracting it from the total size.
-        let already_accounted = (manifest.len() + tarsplit.len()) as u64;
-        let needed: u64 = stream
-            .references()
-            .map(|r| r.range.end - r.range.start)
-            .sum();
-        let unneeded = TryInto::<u64>::try_into(layer.size)? - needed - already_accounted;
-        self.progress.dec_length(unneeded);
-
-        stream::iter(stream.references())
-            .map(Result::<_, anyhow::Error>::Ok)
-            .try_for_each_concurrent(100, |reference| async move {
-                self.ensure_content(layer, reference).await?;
-                Ok(())
-            })
-            .await?;
-
-        Ok(stream)
+    fn skipped(&self, layer: &str, bytes: u64) {
+        self.total.dec_length(bytes);
+        self.with_layer(layer, |(bar, _)| bar.dec_length(bytes));
     }
 
-    async fn pull(image: Reference, cache: PathBuf) -> Result<()> {
-        let client = Client::new(ClientConfig {
-            connect_timeout: Some(Duration::from_secs(1)),
-            read_timeout: Some(Duration::from_secs(1)),
-            ..Default::default()
+    fn cached(&self, layer: &str, bytes: u64) {
+        self.skipped(layer, bytes);
+        self.with_layer(layer, |(bar, cached)| {
+            *cached += bytes;
+            bar.set_message(format!("{} cached", DecimalBytes(*cached)));
         });
+    }
 
-        let (manifest, _) = client
-            .pull_manifest(&image, &RegistryAuth::Anonymous)
-            .await?;
-
-        let OciManifest::Image(manifest) = manifest else {
-            bail!("This is not an image manifest");
-        };
+    fn event(&self, event: &Event<'_>) {
+        if let Event::LayerComplete { layer } = event {
+            self.with_layer(layer, |(bar, _)| bar.finish());
+            let done = self.layers_done.fetch_add(1, Ordering::Relaxed) + 1;
+            let total = self.layers_total.load(Ordering::Relaxed);
+            self.total.set_message(format!("{done}/{total} layers"));
+        }
+        match self.format {
+            Format::JsonLines => {
+                if let Ok(json) = serde_json::to_string(event) {
+                    println!("{json}");
+                }
+            }
+            Format::Text if self.events => {
+                let _ = self.multi.println(event.to_string());
+            }
+            Format::Text => {}
+        }
+    }
+}
 
-        let total: i64 = manifest.layers.iter().map(|l| l.size).sum();
+fn extract_options(args: &Args) -> ExtractOptions {
+    let options = ExtractOptions::new().with_whiteouts(true);
+    if args.uidmap.is_empty() && args.gidmap.is_empty() {
+        return options;
+    }
+    let ids = args
+        .uidmap
+        .iter()
+        .fold(IdMap::new(), |ids, &r| ids.with_uids(r));
+    options.with_id_map(args.gidmap.iter().fold(ids, |ids, &r| ids.with_gids(r)))
+}
 
-        let progress = ProgressBar::new(total.try_into()?);
-        progress.enable_steady_tick(Duration::from_millis(100));
-        progress.set_style(ProgressStyle::with_template(
-            "[eta {eta}] {bar:40.cyan/blue} {decimal_bytes:>7}/{decimal_total_bytes:7} {decimal_bytes_per_sec} {msg}",
-        )?);
+// Sets up the puller from the command line, with `disk` as the cache directory (if any)
+fn puller(
+    args: &Args,
+    cache: Arc<dyn Backend>,
+    disk: Option<&ChunkStore>,
+    bars: Arc<Bars>,
+) -> Result<Puller> {
+    let client = Client::new(ClientConfig {
+        connect_timeout: Some(Duration::from_secs(1)),
+        read_timeout: Some(Duration::from_secs(1)),
+        ..Default::default()
+    });
+
+    // Metadata first, then the priority files, then everything else
+    let priority_files = args.priority.clone();
+    let mut puller = Puller::new(client, cache)
+        .with_connections(args.connections)
+        .with_checksum_policy(match args.checksums {
+            Checksums::Strict => ChecksumPolicy::Strict,
+            Checksums::Lenient => ChecksumPolicy::Lenient,
+        })
+        .with_priority(move |name| {
+            if priority_files
+                .iter()
+                .any(|path| path.trim_start_matches('/') == name)
+            {
+                1
+            } else {
+                2
+            }
+        })
+        .with_progress(bars);
 
-        let this = Self {
-            client,
-            cache,
-            image,
-            progress,
-            karma: Chameleon::default().into(),
+    if let Some(bandwidth) = args.bandwidth {
+        puller = puller.with_bandwidth_limit(bandwidth);
+    }
+    if let Some(hedge_after) = args.hedge_after {
+        puller = puller.with_hedge_after(Duration::from_millis(hedge_after));
+    }
+    if let Some(timeout) = args.layer_timeout {
+        puller = puller.with_layer_timeout(Duration::from_secs(timeout));
+    }
+    if let Some(path) = &args.negative_cache {
+        let ttl = Duration::from_secs(args.negative_cache_ttl * 3600);
+        puller = puller.with_negative_cache(NegativeCache::open(path, ttl)?);
+    }
+    if let Some(limit) = args.quota {
+        puller = puller.with_quota(DiskQuota::new(limit));
+    }
+    if let Some(free_space) = args.free_space {
+        let disk = disk.context("--free-space needs --storage disk")?;
+        puller = match free_space {
+            FreeSpace::Check => puller.with_free_space_check(disk.clone()),
+            FreeSpace::Reserve => {
+                puller.with_space_reservation(SpaceReservation::new(disk.root())?)
+            }
         };
-
-        for layer in &manifest.layers {
-            this.download_zstd_chunked_layer(layer).await?;
-        }
-
-        this.progress.finish();
-
-        Ok(())
     }
+    if let Some(dir) = &args.extract {
+        puller = puller.with_extraction(dir, extract_options(args));
+    }
+    if let Some(dir) = &args.record {
+        puller = puller.with_recorder(Recorder::create(dir)?);
+    }
+    if let Some(dir) = &args.replay {
+        puller = puller.with_replay(Replay::open(dir)?);
+    }
+    Ok(puller)
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let args = Args::parse();
 
-    let cache = PathBuf::from("tmp");
-    fs::create_dir_all(&cache)?;
+    let layout = if args.composefs {
+        Layout::Composefs
+    } else if args.sharded {
+        Layout::Sharded
+    } else {
+        Layout::Flat
+    };
+    let disk = match args.storage {
+        Storage::Disk => Some(ChunkStore::with_layout("tmp", layout)),
+        Storage::Memory => None,
+    };
+    let cache: Arc<dyn Backend> = match &disk {
+        Some(disk) => Arc::new(disk.clone()),
+        None => Arc::new(MemoryStore::new()),
+    };
+
+    if let Some(seed) = &args.seed {
+        let disk = disk.as_ref().context("--seed needs --storage disk")?;
+        let report = disk.ingest_tree(seed)?;
+        eprintln!(
+            "Added {} of {} files ({} bytes) from {}",
+            report.added,
+            report.files,
+            report.added_bytes,
+            seed.display()
+        );
+    }
+
+    let bars = Arc::new(Bars::new(&args)?);
+    let puller = puller(&args, cache, disk.as_ref(), Arc::clone(&bars))?;
+    let results = puller.pull_all(&args.images).await;
+    bars.total.finish();
+
+    let mut failed = None;
+    for (name, result) in args.images.iter().zip(results) {
+        let image = match result {
+            Ok(image) => image,
+            Err(err) => {
+                eprintln!("{name}: {err:#}");
+                failed.get_or_insert(err);
+                continue;
+            }
+        };
 
-    PullOp::pull(args.image, cache).await?;
+        // Remember which objects this image uses
+        if let Some(disk) = &disk {
+            disk.set_ref(
+                &image.manifest_digest,
+                image
+                    .streams
+                    .iter()
+                    .flat_map(Stream::references)
+                    .map(|reference| &*reference.digest),
+            )?;
+        }
+
+        match args.format {
+            Format::Text => println!("{name}:\n{}", image.report),
+            Format::JsonLines => println!("{}", serde_json::to_string(&image.report)?),
+        }
+    }
 
-    Ok(())
+    failed.map_or(Ok(()), Err)
 }
@@ -0,0 +1,279 @@
+//! Random access to zstd:chunked blobs, wherever they're stored.
+//!
+//! Reconstructing a layer only needs the ranges named by its references, so there's no need to
+//! read the whole blob into memory first.  [`BlobReader`] is implemented for files, memory maps
+//! (with the `mmap` feature) and byte slices, and [`MetadataReferences::from_blob()`],
+//! [`Stream::from_blob()`] and [`BlobReader::content()`] work with any of them.
+//!
+//! [`MetadataReferences::from_blob()`]: crate::MetadataReferences::from_blob()
+//! [`Stream::from_blob()`]: crate::Stream::from_blob()
+//!
+//! A blob that's entirely in memory (usually a memory map of a local file) doesn't need to be
+//! read at all: [`MappedBlob`] parses the footer in place and hands out slices of the blob,
+//! without copying, which makes inspecting whole directories of blobs cheap.
+//!
+//! ```
+//! # use zstd_chunked::blob::MappedBlob;
+//! # fn example(maps: &[&[u8]]) -> anyhow::Result<()> {
+//! for &map in maps {
+//!     if let Some(blob) = MappedBlob::new(map)? {
+//!         println!("manifest at {:?}", blob.references().manifest.range);
+//!     }
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use core::ops::Range;
+use std::io;
+
+use anyhow::Result;
+
+use crate::{
+    ContentReference, Error, FOOTER_SIZE, Footer, MetadataReference, MetadataReferences, Stream,
+};
+
+/// A source of ranges of a blob.
+pub trait BlobReader {
+    /// Reads the given range of the blob.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`io::ErrorKind::UnexpectedEof`] if the range extends past the end of the blob,
+    /// or if reading fails.
+    fn read_at(&self, range: &Range<u64>) -> io::Result<Vec<u8>>;
+
+    /// The size of the blob, in bytes.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the size can't be determined.
+    fn size(&self) -> io::Result<u64>;
+
+    /// Reads the end of the blob, as needed by
+    /// [`MetadataReferences::from_footer()`](crate::MetadataReferences::from_footer()).  This is
+    /// shorter than [`FOOTER_SIZE`] if the blob is.
+    ///
+    /// # Errors
+    ///
+    /// Fails if reading fails.
+    fn footer(&self) -> io::Result<Vec<u8>> {
+        let size = self.size()?;
+        self.read_at(&(size.saturating_sub(FOOTER_SIZE)..size))
+    }
+
+    /// Reads and decompresses the content for a reference.  This can be passed (as a closure) to
+    /// [`Stream::write_to()`](crate::Stream::write_to()) and friends.
+    ///
+    /// # Errors
+    ///
+    /// Fails if reading fails or as for [`ContentReference::decompress()`].
+    fn content(&self, reference: &ContentReference) -> Result<Vec<u8>> {
+        Ok(reference.decompress(&self.read_at(&reference.range)?)?)
+    }
+}
+
+fn out_of_range(range: &Range<u64>) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        format!("Range {range:?} is outside of the blob"),
+    )
+}
+
+impl BlobReader for [u8] {
+    fn read_at(&self, range: &Range<u64>) -> io::Result<Vec<u8>> {
+        let start = usize::try_from(range.start).map_err(|_| out_of_range(range))?;
+        let end = usize::try_from(range.end).map_err(|_| out_of_range(range))?;
+        self.get(start..end)
+            .map(<[u8]>::to_vec)
+            .ok_or_else(|| out_of_range(range))
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.len() as u64)
+    }
+}
+
+impl BlobReader for Vec<u8> {
+    fn read_at(&self, range: &Range<u64>) -> io::Result<Vec<u8>> {
+        self.as_slice().read_at(range)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        self.as_slice().size()
+    }
+}
+
+/// Reads at an offset (with `pread()` on Unix), so a file can be shared between threads.
+#[cfg(any(unix, windows))]
+impl BlobReader for std::fs::File {
+    fn read_at(&self, range: &Range<u64>) -> io::Result<Vec<u8>> {
+        // Check before allocating, as the range might come from untrusted metadata
+        if range.start > range.end || range.end > self.size()? {
+            return Err(out_of_range(range));
+        }
+        let len = usize::try_from(range.end - range.start).map_err(|_| out_of_range(range))?;
+        let mut data = vec![0; len];
+
+        #[cfg(unix)]
+        std::os::unix::fs::FileExt::read_exact_at(self, &mut data, range.start)?;
+
+        #[cfg(windows)]
+        {
+            let mut done = 0;
+            while done < data.len() {
+                let n = std::os::windows::fs::FileExt::seek_read(
+                    self,
+                    &mut data[done..],
+                    range.start + done as u64,
+                )?;
+                if n == 0 {
+                    return Err(out_of_range(range));
+                }
+                done += n;
+            }
+        }
+
+        Ok(data)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+}
+
+/// Memory maps are created with `unsafe` code, so that's left to the caller.
+#[cfg(feature = "mmap")]
+impl BlobReader for memmap2::Mmap {
+    fn read_at(&self, range: &Range<u64>) -> io::Result<Vec<u8>> {
+        (**self).read_at(range)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        (**self).size()
+    }
+}
+
+impl<T: BlobReader + ?Sized> BlobReader for &T {
+    fn read_at(&self, range: &Range<u64>) -> io::Result<Vec<u8>> {
+        (**self).read_at(range)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        (**self).size()
+    }
+}
+
+/// A complete zstd:chunked blob in memory, like a memory map of a local file, which is parsed
+/// and sliced without copying.
+///
+/// Memory maps are created with `unsafe` code, so that's left to the caller: map the file (with
+/// `memmap2`, for example) and pass the mapping here.
+#[derive(Debug, Clone, Copy)]
+pub struct MappedBlob<'a> {
+    data: &'a [u8],
+    footer: &'a Footer,
+}
+
+impl<'a> MappedBlob<'a> {
+    /// Parses the footer at the end of the blob in place.  Returns `None` if it doesn't appear to
+    /// be a zstd:chunked blob.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`Error::UnsupportedManifestType`] if the footer has an unknown manifest type,
+    /// or with [`Error::Io`] if the manifest or tarsplit aren't within the blob.
+    pub fn new(data: &'a [u8]) -> Result<Option<Self>, Error> {
+        let Some(references) = MetadataReferences::try_from_footer(data)? else {
+            return Ok(None);
+        };
+        let Some(footer) = Footer::from_suffix(data) else {
+            return Ok(None);
+        };
+        let blob = Self { data, footer };
+        blob.slice(&references.manifest.range)?;
+        blob.slice(&references.tarsplit.range)?;
+        Ok(Some(blob))
+    }
+
+    /// The whole blob.
+    #[must_use]
+    pub const fn data(&self) -> &'a [u8] {
+        self.data
+    }
+
+    /// The footer, as it appears at the end of the blob.
+    #[must_use]
+    pub const fn footer(&self) -> &'a Footer {
+        self.footer
+    }
+
+    /// The metadata references from the footer (without digests).
+    #[must_use]
+    pub fn references(&self) -> MetadataReferences {
+        // new() checked that the ranges don't overflow
+        MetadataReferences {
+            manifest: self
+                .footer
+                .manifest()
+                .unwrap_or_else(MetadataReference::absent),
+            tarsplit: self
+                .footer
+                .tarsplit()
+                .unwrap_or_else(MetadataReference::absent),
+        }
+    }
+
+    /// A range of the blob.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`io::ErrorKind::UnexpectedEof`] if the range extends past the end of the blob.
+    pub fn slice(&self, range: &Range<u64>) -> io::Result<&'a [u8]> {
+        let start = usize::try_from(range.start).map_err(|_| out_of_range(range))?;
+        let end = usize::try_from(range.end).map_err(|_| out_of_range(range))?;
+        self.data.get(start..end).ok_or_else(|| out_of_range(range))
+    }
+
+    /// The compressed manifest.
+    #[must_use]
+    pub fn manifest(&self) -> &'a [u8] {
+        self.slice(&self.references().manifest.range)
+            .unwrap_or_default()
+    }
+
+    /// The compressed tarsplit, which is empty for artifacts without one.
+    #[must_use]
+    pub fn tarsplit(&self) -> &'a [u8] {
+        self.slice(&self.references().tarsplit.range)
+            .unwrap_or_default()
+    }
+
+    /// The compressed data for a reference.
+    ///
+    /// # Errors
+    ///
+    /// Fails as for [`Self::slice()`].
+    pub fn data_for(&self, reference: &ContentReference) -> io::Result<&'a [u8]> {
+        self.slice(&reference.range)
+    }
+
+    /// Parses the stream, as for [`Stream::from_blob()`].
+    ///
+    /// # Errors
+    ///
+    /// Fails as for [`Stream::from_blob()`].
+    pub fn stream(&self) -> Result<Stream, Error> {
+        Stream::from_blob(self.data, &self.references())
+    }
+}
+
+impl BlobReader for MappedBlob<'_> {
+    fn read_at(&self, range: &Range<u64>) -> io::Result<Vec<u8>> {
+        self.slice(range).map(<[u8]>::to_vec)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.data.len() as u64)
+    }
+}
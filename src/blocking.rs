@@ -0,0 +1,80 @@
+//! Blocking wrappers for async APIs, for command-line tools and build scripts.
+//!
+//! Reconstruction itself is synchronous, but content is usually fetched with an async client
+//! (such as a registry client).  These functions drive such a resolver (or, with the `pull`
+//! feature, a whole `pull::Puller`) on a private, single-threaded tokio runtime, so that callers
+//! don't need to adopt async themselves.  They must not be called from within an async context:
+//! tokio doesn't allow one runtime to be started from inside another.
+
+use std::io::Write;
+
+use anyhow::Result;
+use tokio::runtime::{Builder, Runtime};
+
+use crate::{ContentReference, Error, Stream};
+#[cfg(feature = "pull")]
+use {
+    crate::pull::{PulledImage, Puller},
+    oci_client::Reference,
+};
+
+fn runtime() -> Result<Runtime, Error> {
+    Ok(Builder::new_current_thread().enable_all().build()?)
+}
+
+/// Pulls an image with [`Puller::pull()`], blocking until it's done.
+///
+/// # Errors
+///
+/// As for [`Puller::pull()`], or if the runtime can't be started.
+#[cfg(feature = "pull")]
+pub fn pull_blocking(puller: &Puller, image: &Reference) -> anyhow::Result<PulledImage> {
+    runtime()?.block_on(puller.pull(image))
+}
+
+/// Writes the reconstructed content of an already parsed layer, with an async resolver.
+///
+/// The content is checked as for [`Stream::write_to_strict()`].  Nothing is cached: use
+/// `pull::Puller` (with the `pull` feature) for that.
+///
+/// # Errors
+///
+/// As for [`Stream::write_to_strict()`], or with [`Error::Io`] if the runtime can't be started.
+pub fn write_stream_blocking<F>(
+    stream: &Stream,
+    write: &mut impl Write,
+    resolve_reference: impl Fn(&ContentReference) -> F,
+) -> Result<(), Error>
+where
+    F: Future<Output = Result<Vec<u8>>>,
+{
+    let runtime = runtime()?;
+    stream.write_to_strict(write, |reference| {
+        runtime.block_on(resolve_reference(reference))
+    })
+}
+
+/// Returns the content of the named file (as for [`Stream::file()`]), fetching only its own
+/// references with an async resolver.  Returns `None` if there's no such file.
+///
+/// # Errors
+///
+/// As for [`Stream::read_file()`], or with [`Error::Io`] if the runtime can't be started.
+pub fn extract_file_blocking<F>(
+    stream: &Stream,
+    name: &str,
+    resolve_reference: impl Fn(&ContentReference) -> F,
+) -> Result<Option<Vec<u8>>, Error>
+where
+    F: Future<Output = Result<Vec<u8>>>,
+{
+    let Some(file) = stream.file(name) else {
+        return Ok(None);
+    };
+    let runtime = runtime()?;
+    stream
+        .read_file(file, |reference| {
+            runtime.block_on(resolve_reference(reference))
+        })
+        .map(Some)
+}
@@ -0,0 +1,31 @@
+//! The CRC-64 that tar-split stores for the content of each file.
+//!
+//! This is Go's `crc64.ISO`, which uses the reflected polynomial `0xd800000000000000`, with the
+//! register inverted before and after.  tar-split stores the result big-endian.
+
+const CRC64_TABLE: [u64; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u64;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xd800_0000_0000_0000
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+// Continues a CRC with more data.  Start with 0: the inversion is taken care of for each call.
+pub fn update(crc: u64, data: &[u8]) -> u64 {
+    !data.iter().fold(!crc, |crc, &byte| {
+        CRC64_TABLE[usize::from(crc.to_le_bytes()[0] ^ byte)] ^ (crc >> 8)
+    })
+}
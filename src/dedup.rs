@@ -0,0 +1,152 @@
+//! Predicting how well a layer deduplicates against related layers.
+
+use std::{
+    cmp::Reverse,
+    collections::{HashMap, HashSet},
+};
+
+use crate::{ContentReference, Stream};
+
+/// Files smaller than this are never suggested for re-chunking: the per-chunk overhead would
+/// outweigh any savings.
+pub const RECHUNK_THRESHOLD: u64 = 1024 * 1024;
+
+/// The predicted savings from partial pulls of a layer, for a client which already has a set of
+/// related layers (for example, previous nightly builds of the same image).
+#[derive(Debug, Clone, Default)]
+pub struct DedupReport {
+    /// The total compressed size of the unique content in the layer.
+    pub total_size: u64,
+
+    /// The compressed size of the content which the client already has, and doesn't need to
+    /// download.
+    pub reused_size: u64,
+
+    /// The number of unique content references in the layer.
+    pub references: u64,
+
+    /// The number of unique content references that the client already has.
+    pub reused_references: u64,
+
+    /// Large files whose content doesn't appear in any of the related layers, with their
+    /// compressed sizes, largest first.  These have probably changed slightly since the related
+    /// layers were built: splitting them into (content-defined) chunks would allow the unchanged
+    /// parts to be reused, which is what `writer::rechunk()` does (with the `writer` feature).
+    pub rechunk_candidates: Vec<(String, u64)>,
+}
+
+impl DedupReport {
+    /// Analyzes `layer` against the layers that the client already has.
+    #[must_use]
+    pub fn new(layer: &Stream, have: &[&Stream]) -> Self {
+        let have: HashSet<&str> = have
+            .iter()
+            .flat_map(|stream| stream.references())
+            .map(|reference| &*reference.digest)
+            .collect();
+
+        let mut report = Self::default();
+        for reference in layer.unique_references() {
+            report.references += 1;
+            report.total_size += reference.compressed_size();
+            if have.contains(&*reference.digest) {
+                report.reused_references += 1;
+                report.reused_size += reference.compressed_size();
+            }
+        }
+
+        for file in layer.files.iter() {
+            let size: u64 = layer
+                .file_references(file)
+                .map(ContentReference::compressed_size)
+                .sum();
+            if size >= RECHUNK_THRESHOLD
+                && !layer
+                    .file_references(file)
+                    .any(|reference| have.contains(&*reference.digest))
+            {
+                report.rechunk_candidates.push((file.name.clone(), size));
+            }
+        }
+        report
+            .rechunk_candidates
+            .sort_by_key(|(_, size)| Reverse(*size));
+
+        report
+    }
+
+    /// The compressed size of the content that the client would need to download.
+    #[must_use]
+    pub const fn download_size(&self) -> u64 {
+        self.total_size - self.reused_size
+    }
+}
+
+/// The overlap in content between a set of images, for choosing base images and layering
+/// strategies.
+///
+/// All sizes are uncompressed, so that they don't depend on how each image happened to compress
+/// the shared content.
+#[derive(Debug, Clone, Default)]
+pub struct OverlapMatrix {
+    /// For each pair of images, the size of the content that they have in common.  The diagonal
+    /// contains the total size of the unique content of each image.
+    pub shared: Vec<Vec<u64>>,
+
+    /// The size of the content which appears in every image.
+    pub common_to_all: u64,
+
+    /// The size of all of the unique content across all images: this is what a client that pulls
+    /// all of the images would need to store.
+    pub union: u64,
+}
+
+impl OverlapMatrix {
+    /// Computes the overlap between the given images, each of which is given as the list of its
+    /// layers.
+    #[must_use]
+    pub fn new(images: &[&[&Stream]]) -> Self {
+        let contents: Vec<HashMap<&str, u64>> = images
+            .iter()
+            .map(|layers| {
+                layers
+                    .iter()
+                    .flat_map(|stream| stream.references())
+                    .map(|reference| (&*reference.digest, reference.size))
+                    .collect()
+            })
+            .collect();
+
+        let shared = contents
+            .iter()
+            .map(|a| {
+                contents
+                    .iter()
+                    .map(|b| {
+                        a.iter()
+                            .filter(|(digest, _)| b.contains_key(*digest))
+                            .map(|(_, size)| size)
+                            .sum()
+                    })
+                    .collect()
+            })
+            .collect();
+
+        let mut union = HashMap::new();
+        for content in &contents {
+            union.extend(content.iter().map(|(digest, size)| (*digest, *size)));
+        }
+
+        let common_to_all = union
+            .iter()
+            .filter(|(digest, _)| contents.iter().all(|content| content.contains_key(*digest)))
+            .map(|(_, size)| size)
+            .sum();
+
+        Self {
+            shared,
+            common_to_all,
+            union: union.values().sum(),
+        }
+    }
+}
@@ -0,0 +1,126 @@
+//! SHA-256 digest computation.
+//!
+//! The implementation is selected at build time via crate features: `openssl` takes precedence if
+//! it's enabled, then `ring`, and finally the pure-Rust `sha2` crate, which is the default.
+
+use core::fmt;
+use std::fmt::Write as _;
+
+use anyhow::{Result, ensure};
+
+#[cfg(not(any(feature = "sha2", feature = "ring", feature = "openssl")))]
+compile_error!("One of the sha2, ring or openssl features must be enabled");
+
+#[cfg(feature = "openssl")]
+type Inner = openssl::sha::Sha256;
+
+#[cfg(all(feature = "ring", not(feature = "openssl")))]
+type Inner = ring::digest::Context;
+
+#[cfg(all(feature = "sha2", not(any(feature = "ring", feature = "openssl"))))]
+type Inner = sha2::Sha256;
+
+/// The name of the backend that was selected at build time.
+pub const BACKEND: &str = if cfg!(feature = "openssl") {
+    "openssl"
+} else if cfg!(feature = "ring") {
+    "ring"
+} else {
+    "sha2"
+};
+
+/// An incremental SHA-256 hasher.
+#[derive(Clone)]
+pub struct Sha256(Inner);
+
+impl Sha256 {
+    /// Creates a new hasher.
+    #[must_use]
+    pub fn new() -> Self {
+        #[cfg(feature = "openssl")]
+        let inner = openssl::sha::Sha256::new();
+        #[cfg(all(feature = "ring", not(feature = "openssl")))]
+        let inner = ring::digest::Context::new(&ring::digest::SHA256);
+        #[cfg(all(feature = "sha2", not(any(feature = "ring", feature = "openssl"))))]
+        let inner = <sha2::Sha256 as sha2::Digest>::new();
+
+        Self(inner)
+    }
+
+    /// Adds data to the hasher.
+    pub fn update(&mut self, data: &[u8]) {
+        #[cfg(any(feature = "openssl", feature = "ring"))]
+        self.0.update(data);
+        #[cfg(all(feature = "sha2", not(any(feature = "ring", feature = "openssl"))))]
+        sha2::Digest::update(&mut self.0, data);
+    }
+
+    /// Returns the digest of all of the data that was added.
+    #[must_use]
+    pub fn finalize(self) -> [u8; 32] {
+        #[cfg(feature = "openssl")]
+        let digest = self.0.finish();
+        #[cfg(all(feature = "ring", not(feature = "openssl")))]
+        let digest = self.0.finish().as_ref().try_into().unwrap_or_default();
+        #[cfg(all(feature = "sha2", not(any(feature = "ring", feature = "openssl"))))]
+        let digest = sha2::Digest::finalize(self.0).into();
+
+        digest
+    }
+
+    /// Returns the digest of all of the data that was added, in the `sha256:0123...` form used by
+    /// the manifest.
+    #[must_use]
+    pub fn finalize_string(self) -> String {
+        format!("sha256:{}", to_hex(&self.finalize()))
+    }
+
+    /// Computes the digest of the given data.
+    #[must_use]
+    pub fn digest(data: &[u8]) -> [u8; 32] {
+        let mut hasher = Self::new();
+        hasher.update(data);
+        hasher.finalize()
+    }
+}
+
+impl Default for Sha256 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for Sha256 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Sha256")
+            .field("backend", &BACKEND)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Computes the digest of the given data, in the `sha256:0123...` form used by the manifest.
+#[must_use]
+pub fn sha256(data: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(data);
+    hasher.finalize_string()
+}
+
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut hex, byte| {
+        let _ = write!(hex, "{byte:02x}");
+        hex
+    })
+}
+
+// Digests often come from untrusted sources and sometimes get used as filenames, so check them.
+pub(crate) fn check_digest(digest: &str) -> Result<()> {
+    let hex = digest.strip_prefix("sha256:");
+    ensure!(
+        hex.is_some_and(
+            |hex| hex.len() == 64 && hex.bytes().all(|c| matches!(c, b'0'..=b'9' | b'a'..=b'f'))
+        ),
+        "Invalid digest {digest:?}"
+    );
+    Ok(())
+}
@@ -0,0 +1,121 @@
+//! The error type for parsing metadata and reconstructing layers.
+
+use std::{io, sync::Arc};
+
+use crate::{
+    BackwardsRange, Crc64Mismatch, MemoryBudgetExceeded, RegionOverlap, UnsupportedManifestType,
+    lint::InvalidAnnotations,
+};
+
+/// An underlying error, with the details of what went wrong.
+pub type BoxError = Box<dyn std::error::Error + Send + Sync + 'static>;
+
+/// The errors returned by [`crate::MetadataReferences`], [`crate::Stream`] and the references,
+/// so that callers can tell a corrupt layer from an unsupported one, or from a failure of their
+/// own resolver.
+///
+/// The other modules deal mostly with storage, networks and functions given by the caller, and
+/// return [`anyhow::Error`]: their failures are rarely more than passed on, and the few that
+/// callers act on have types of their own (like [`crate::store::CorruptObject`],
+/// [`crate::quota::QuotaExceeded`] or `pull::Fallback`), to be found with `downcast_ref()`.
+/// Functions resolving content return [`anyhow::Result`] too, so that they can fail in any way:
+/// their errors end up in [`Error::Resolver`].
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum Error {
+    /// The footer or the annotations use a manifest type that this crate doesn't understand.
+    #[error(transparent)]
+    UnsupportedManifestType(#[from] UnsupportedManifestType),
+
+    /// The annotations on the layer descriptor are malformed.
+    #[error(transparent)]
+    InvalidAnnotations(#[from] InvalidAnnotations),
+
+    /// The manifest couldn't be decompressed or parsed, or is inconsistent.
+    #[error("Invalid zstd:chunked manifest")]
+    Manifest(#[source] BoxError),
+
+    /// The tarsplit couldn't be decompressed or parsed, or doesn't match the manifest.
+    #[error("Invalid zstd:chunked tarsplit")]
+    Tarsplit(#[source] BoxError),
+
+    /// Parsing the metadata would exceed [`crate::ParseOptions::memory_budget`].
+    #[error(transparent)]
+    MemoryBudgetExceeded(#[from] MemoryBudgetExceeded),
+
+    /// An entry of the manifest has an `offset` after its `endOffset`.
+    #[error(transparent)]
+    BackwardsRange(#[from] BackwardsRange),
+
+    /// A content reference points into the metadata, or out of the blob.
+    #[error(transparent)]
+    RegionOverlap(#[from] RegionOverlap),
+
+    /// The stream contains a [`crate::Chunk::Unavailable`] chunk for the named file.
+    #[error("Content of {0} is unavailable")]
+    Unavailable(String),
+
+    /// The function resolving content references failed.
+    #[error("Unable to resolve content {digest}")]
+    Resolver {
+        /// The digest of the content that was being resolved.
+        digest: Arc<str>,
+        /// The error returned by the function.
+        #[source]
+        source: BoxError,
+    },
+
+    /// Compressed content isn't a valid zstd frame, is inconsistent with its reference, or
+    /// couldn't be decompressed.
+    #[error("Invalid compressed content for {digest}")]
+    InvalidContent {
+        /// The digest of the reference.
+        digest: Arc<str>,
+        /// What was wrong with the content.
+        #[source]
+        source: BoxError,
+    },
+
+    /// Resolved content didn't have the size or digest of its reference.
+    #[error("Resolved content for {digest} is invalid")]
+    Verification {
+        /// The digest of the reference.
+        digest: Arc<str>,
+        /// What was wrong with the content.
+        #[source]
+        source: BoxError,
+    },
+
+    /// The content of a file didn't match the CRC-64 recorded in the tarsplit.
+    #[error(transparent)]
+    Crc64Mismatch(#[from] Crc64Mismatch),
+
+    /// An entry would be written outside of the target directory by
+    /// [`crate::extract::extract_to_dir()`].
+    #[cfg(all(feature = "extract", unix))]
+    #[error(transparent)]
+    UnsafePath(#[from] crate::extract::UnsafePath),
+
+    /// The owner of an entry isn't covered by the [`crate::extract::IdMap`] given to
+    /// [`crate::extract::extract_to_dir()`].
+    #[cfg(all(feature = "extract", unix))]
+    #[error(transparent)]
+    UnmappedId(#[from] crate::extract::UnmappedId),
+
+    /// Writing the output (or decompressing inline data) failed.
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+impl Error {
+    // Wraps an internal error, keeping the kinds that callers may want to match on separate.
+    pub(crate) fn wrap(err: anyhow::Error, kind: impl FnOnce(BoxError) -> Self) -> Self {
+        match err.downcast::<MemoryBudgetExceeded>() {
+            Ok(err) => err.into(),
+            Err(err) => match err.downcast::<BackwardsRange>() {
+                Ok(err) => err.into(),
+                Err(err) => kind(err.into()),
+            },
+        }
+    }
+}
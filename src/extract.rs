@@ -0,0 +1,1153 @@
+//! Unpacking layers to directories.
+//!
+//! The reconstructed tar stream is read with the `tar` crate and unpacked as it's being
+//! reconstructed, so the layer is never held in memory or written out as a whole.
+//!
+//! Entries are checked before anything is written for them: absolute paths, paths with `..`
+//! components, and paths which symlinks already in the target directory would take outside of it
+//! (including through absolute symlinks, which are only meaningful inside of a container) are
+//! rejected with [`UnsafePath`].  The same goes for the targets of hardlinks.  Symlinks themselves
+//! can point anywhere, since they're only followed by the container.
+//!
+//! Everything is opened relative to a descriptor of the target directory, with
+//! `openat2(RESOLVE_BENEATH)` on Linux and one `O_NOFOLLOW` component at a time elsewhere, so
+//! there's no window between checking a path and writing to it in which a concurrent change to the
+//! target directory could redirect the write outside of it.
+//!
+//! By default, whiteout files (`.wh.*`) are unpacked as they are.  To unpack a whole image, extract
+//! its layers in order into the same directory with [`ExtractOptions::with_whiteouts()`]: each
+//! layer then replaces what the layers below it left there, as it would with overlayfs.
+//!
+//! For rootless use, [`ExtractOptions::with_id_map()`] shifts the owners of the files into the
+//! range of IDs of a user namespace (or gives everything to the current user), as podman does.
+//! Where that isn't enough, [`ExtractOptions::with_rootless_fallback()`] skips what needs
+//! privileges and records it in the `user.containers.override_stat` xattr instead, as
+//! containers/storage does for fuse-overlayfs.
+//!
+//! This module is only available on Unix.  On macOS, device nodes and FIFOs can't be created, so
+//! unpacking them fails unless the rootless fallback is enabled.  Windows isn't supported: layers
+//! are Linux filesystems, with names that Windows reserves, case-sensitive paths, symlinks that
+//! need privileges and metadata that it has no place for, so anything unpacked there would be a
+//! lossy copy that no runtime could use.  Tools that only need to look at the content can read
+//! the files from [`Stream`] and the metadata instead.
+
+use std::{
+    cell::Cell,
+    collections::{HashSet, VecDeque},
+    ffi::{OsStr, OsString},
+    fs::{self, File},
+    io::{self, ErrorKind, Read},
+    os::{
+        fd::{AsFd, OwnedFd},
+        unix::ffi::OsStrExt,
+    },
+    path::{Component, Path, PathBuf},
+};
+
+use anyhow::{Result, anyhow};
+use rustix::{
+    fs::{
+        AtFlags, Dir, FileType, Gid, Mode, OFlags, RawMode, Timespec, Timestamps, Uid, chmodat,
+        chownat, fchmod, fchown, futimens, linkat, mkdirat, openat, readlinkat, statat, symlinkat,
+        unlinkat, utimensat,
+    },
+    io::Errno,
+};
+
+use crate::{
+    ContentReference, Error, Stream, resolver,
+    telemetry::{Phase, PhaseSpan},
+};
+
+// The most symlinks followed while checking a path, as for Linux
+const MAX_SYMLINKS: usize = 40;
+
+/// Options for [`extract_to_dir()`].  The defaults apply modes (including setuid bits), the
+/// modification times of files and xattrs, but not ownership.
+#[derive(Debug, Clone)]
+pub struct ExtractOptions {
+    ownership: bool,
+    xattrs: bool,
+    existing: Existing,
+    ids: Option<IdMap>,
+    rootless: bool,
+}
+
+// What happens to the existing content of the target directory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Existing {
+    Fail,
+    Overwrite,
+    ApplyLayer,
+}
+
+impl Default for ExtractOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ExtractOptions {
+    /// Creates the default options.
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            ownership: false,
+            xattrs: true,
+            existing: Existing::Overwrite,
+            ids: None,
+            rootless: false,
+        }
+    }
+
+    /// Sets whether the owners of the files are applied, which usually requires running as root.
+    #[must_use]
+    pub const fn with_ownership(mut self, ownership: bool) -> Self {
+        self.ownership = ownership;
+        self
+    }
+
+    /// Sets whether extended attributes are applied.  Some (like `security.capability`) need
+    /// privileges, and some filesystems don't support them at all.
+    #[must_use]
+    pub const fn with_xattrs(mut self, xattrs: bool) -> Self {
+        self.xattrs = xattrs;
+        self
+    }
+
+    /// Sets whether existing files in the target directory are replaced.  Otherwise, unpacking
+    /// fails when it comes to a file that already exists.  This replaces any earlier
+    /// [`Self::with_whiteouts()`].
+    #[must_use]
+    pub const fn with_overwrite(mut self, overwrite: bool) -> Self {
+        self.existing = if overwrite {
+            Existing::Overwrite
+        } else {
+            Existing::Fail
+        };
+        self
+    }
+
+    /// Sets whether the layer is applied on top of what's already in the target directory.  A
+    /// whiteout (`.wh.name`) then deletes `name`, an opaque whiteout (`.wh..wh..opq`) deletes
+    /// everything in its directory that this layer didn't add, and an entry replaces whatever
+    /// was at its path (unless both are directories).  Whiteouts under symlinks are ignored.
+    /// Otherwise, existing files are overwritten, as for [`Self::with_overwrite()`].
+    #[must_use]
+    pub const fn with_whiteouts(mut self, whiteouts: bool) -> Self {
+        self.existing = if whiteouts {
+            Existing::ApplyLayer
+        } else {
+            Existing::Overwrite
+        };
+        self
+    }
+
+    /// Applies the owners of the files through an ID map, whether or not
+    /// [`Self::with_ownership()`] is set.  Setuid and setgid bits and file capabilities are kept.
+    #[must_use]
+    pub fn with_id_map(mut self, ids: IdMap) -> Self {
+        self.ids = Some(ids);
+        self
+    }
+
+    /// Sets whether owners and device nodes that can't be applied without privileges are
+    /// skipped instead of failing.  Device nodes are replaced with empty files, and the owner,
+    /// mode and type that files and directories should have are recorded in their
+    /// `user.containers.override_stat` xattr (where supported), which fuse-overlayfs presents
+    /// instead of the real ones.  Owners of symlinks and FIFOs are skipped without a record.
+    #[must_use]
+    pub const fn with_rootless_fallback(mut self, fallback: bool) -> Self {
+        self.rootless = fallback;
+        self
+    }
+}
+
+/// A range of IDs in an [`IdMap`]: `size` IDs from `container` onwards are mapped to as many IDs
+/// from `host` onwards, as in `/proc/<pid>/uid_map`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IdRange {
+    /// The first ID in the layer.
+    pub container: u32,
+    /// The ID that `container` is mapped to.
+    pub host: u32,
+    /// The number of IDs in the range.
+    pub size: u32,
+}
+
+/// How the owners recorded in a layer map to the owners of the unpacked files.
+///
+/// A new map leaves user and group IDs as they are until ranges are added for them, and then only
+/// maps the IDs in those ranges: unpacking an entry owned by any other ID fails with
+/// [`UnmappedId`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IdMap {
+    uids: Vec<IdRange>,
+    gids: Vec<IdRange>,
+    squash: Option<(u32, u32)>,
+}
+
+impl IdMap {
+    /// Creates a map which leaves all IDs as they are.
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            uids: vec![],
+            gids: vec![],
+            squash: None,
+        }
+    }
+
+    /// Creates a map which gives every file to the same user and group.
+    #[must_use]
+    pub const fn squash(uid: u32, gid: u32) -> Self {
+        Self {
+            uids: vec![],
+            gids: vec![],
+            squash: Some((uid, gid)),
+        }
+    }
+
+    /// Creates a map which gives every file to the current user and group, which works without
+    /// privileges.
+    #[must_use]
+    pub fn current_user() -> Self {
+        Self::squash(
+            rustix::process::getuid().as_raw(),
+            rustix::process::getgid().as_raw(),
+        )
+    }
+
+    /// Adds a range of user IDs.
+    #[must_use]
+    pub fn with_uids(mut self, range: IdRange) -> Self {
+        self.uids.push(range);
+        self
+    }
+
+    /// Adds a range of group IDs.
+    #[must_use]
+    pub fn with_gids(mut self, range: IdRange) -> Self {
+        self.gids.push(range);
+        self
+    }
+
+    /// Maps a user ID from the layer, returning `None` if it isn't in any of the ranges.
+    #[must_use]
+    pub fn uid(&self, uid: u32) -> Option<u32> {
+        self.squash
+            .map_or_else(|| map_id(&self.uids, uid), |(uid, _)| Some(uid))
+    }
+
+    /// Maps a group ID from the layer, returning `None` if it isn't in any of the ranges.
+    #[must_use]
+    pub fn gid(&self, gid: u32) -> Option<u32> {
+        self.squash
+            .map_or_else(|| map_id(&self.gids, gid), |(_, gid)| Some(gid))
+    }
+}
+
+fn map_id(ranges: &[IdRange], id: u32) -> Option<u32> {
+    if ranges.is_empty() {
+        return Some(id);
+    }
+    ranges.iter().find_map(|range| {
+        id.checked_sub(range.container)
+            .filter(|&offset| offset < range.size)
+            .and_then(|offset| range.host.checked_add(offset))
+    })
+}
+
+/// The error returned by [`extract_to_dir()`] for an entry whose owner isn't covered by the
+/// [`IdMap`].
+#[derive(Debug, Clone, thiserror::Error)]
+#[error(
+    "No mapping for {} {id} of {}",
+    if *.group { "gid" } else { "uid" },
+    .path.display()
+)]
+pub struct UnmappedId {
+    /// The path of the entry.
+    pub path: PathBuf,
+    /// The user or group ID recorded in the layer.
+    pub id: u64,
+    /// Whether `id` is a group ID.
+    pub group: bool,
+}
+
+/// Unpacks the content of a layer into a directory, which is created if needed.  Content is
+/// resolved as it's reached and checked against its digest, as for [`Stream::write_to_verified()`].
+///
+/// # Errors
+///
+/// As for [`Stream::write_to_verified()`], with [`Error::UnsafePath`] for entries which would be
+/// written outside of the directory, or [`Error::Io`] if unpacking fails.  Nothing is unpacked if
+/// the stream has [`crate::Chunk::Unavailable`] chunks.
+pub fn extract_to_dir(
+    stream: &Stream,
+    dir: impl AsRef<Path>,
+    options: &ExtractOptions,
+    resolve_reference: impl Fn(&ContentReference) -> Result<Vec<u8>>,
+) -> Result<(), Error> {
+    let span = PhaseSpan::start(Phase::Extract, &[]);
+    if let Some((name, _)) = stream.unavailable().next() {
+        return span.finish(Err(Error::Unavailable(name.to_owned())));
+    }
+    span.add_bytes(stream.size());
+
+    // The tar crate only passes on the message of the reader's errors, so keep the error itself
+    let resolve = resolver(resolve_reference, true);
+    let failure = Cell::new(None);
+    let reader = stream.reader(|reference| {
+        resolve(reference).map_err(|err| {
+            let message = anyhow!("{err}");
+            failure.set(Some(err));
+            message
+        })
+    });
+
+    let mut archive = tar::Archive::new(reader);
+    let result = Target::open(dir.as_ref())
+        .map_err(Error::from)
+        .and_then(|target| unpack(&mut archive, target, options));
+    span.finish(failure.take().map_or(result, Err))
+}
+
+/// Why [`UnsafePath`] was returned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnsafeReason {
+    /// The path has a `..` component.
+    ParentDir,
+    /// The path is absolute.
+    Absolute,
+    /// A symlink already in the target directory would take the path outside of it (or there
+    /// are too many symlinks to follow).
+    SymlinkEscape,
+}
+
+impl UnsafeReason {
+    const fn description(self) -> &'static str {
+        match self {
+            Self::ParentDir => "it contains `..`",
+            Self::Absolute => "it's absolute",
+            Self::SymlinkEscape => "symlinks would take it outside of the target directory",
+        }
+    }
+}
+
+/// The error returned by [`extract_to_dir()`] for an entry that would be written outside of the
+/// target directory, or a hardlink to something outside of it.  Nothing is written for it.
+#[derive(Debug, Clone, thiserror::Error)]
+#[error("Refusing to unpack {}: {}", .path.display(), .reason.description())]
+pub struct UnsafePath {
+    /// The path of the entry, or the target of the hardlink.
+    pub path: PathBuf,
+    /// What's wrong with it.
+    pub reason: UnsafeReason,
+}
+
+// Unpacks the entries of a layer, optionally on top of the existing content of the directory
+fn unpack(
+    archive: &mut tar::Archive<impl Read>,
+    mut target: Target,
+    options: &ExtractOptions,
+) -> Result<(), Error> {
+    let apply_layer = options.existing == Existing::ApplyLayer;
+    let mut directories = vec![];
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let Some(path) = check(&entry.path()?)? else {
+            continue;
+        };
+        if entry.header().entry_type().is_hard_link()
+            && let Some(link) = entry.link_name()?
+        {
+            check(&link)?;
+        }
+
+        let whiteout = path
+            .file_name()
+            .and_then(|name| name.to_str()?.strip_prefix(".wh."));
+        if apply_layer && let Some(name) = whiteout {
+            let parent = path.parent().unwrap_or_else(|| Path::new(""));
+            if name == ".wh..opq" {
+                target.clear_opaque(parent)?;
+            } else {
+                target.remove(&parent.join(name))?;
+            }
+            continue;
+        }
+
+        let is_dir = entry.header().entry_type() == tar::EntryType::Directory;
+        if apply_layer {
+            target.replace(&path, is_dir)?;
+        }
+        if is_dir {
+            target.create_dir(&path)?;
+            directories.push((path, entry));
+        } else {
+            target.unpack_entry(&mut entry, &path, options)?;
+        }
+    }
+
+    // As for tar::Archive::unpack(), the attributes of directories are applied last (deepest
+    // first), so that their permissions can't get in the way of unpacking their content
+    directories.sort_by(|(a, _), (b, _)| b.cmp(a));
+    for (path, mut directory) in directories {
+        let attributes = Attributes::of(&mut directory, &path, options)?;
+        match target.open_parent(&path, false)? {
+            (Resolved::Dir(parent), name) => {
+                let dir = openat(&parent, name, DIR_FLAGS | OFlags::NOFOLLOW, Mode::empty())
+                    .map_err(io::Error::from)?;
+                attributes.apply(&dir, false)?;
+            }
+            // Removed again by a whiteout in the same layer
+            (Resolved::Missing, _) => {}
+            (Resolved::Escape, _) => Err(unsafe_path(&path, UnsafeReason::SymlinkEscape))?,
+        }
+    }
+    Ok(())
+}
+
+fn unsafe_path(path: &Path, reason: UnsafeReason) -> UnsafePath {
+    UnsafePath {
+        path: path.to_owned(),
+        reason,
+    }
+}
+
+// Checks the path of an entry (or a hardlink target), returning it relative to the target
+// directory, or None for the target directory itself.  Symlinks are checked as the path is
+// resolved, by Target::open_dir().
+fn check(path: &Path) -> Result<Option<PathBuf>, Error> {
+    let mut relative = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => relative.push(name),
+            Component::CurDir => {}
+            Component::ParentDir => Err(unsafe_path(path, UnsafeReason::ParentDir))?,
+            Component::RootDir | Component::Prefix(_) => {
+                Err(unsafe_path(path, UnsafeReason::Absolute))?;
+            }
+        }
+    }
+    Ok((!relative.as_os_str().is_empty()).then_some(relative))
+}
+
+// Directories are opened for reading, so that their attributes can be set through the descriptor
+const DIR_FLAGS: OFlags = OFlags::RDONLY
+    .union(OFlags::DIRECTORY)
+    .union(OFlags::CLOEXEC);
+
+// The outcome of resolving a directory inside of the target directory
+enum Resolved {
+    Dir(OwnedFd),
+    // Something along the way doesn't exist, or isn't a directory
+    Missing,
+    // Symlinks would take the path outside of the target directory (or there are too many)
+    Escape,
+}
+
+// The target directory, and the paths that the layer has added to it so far (along with their
+// parent directories).  Everything is resolved relative to the descriptor of the directory, one
+// directory at a time, so a concurrent change to the tree can't redirect a write outside of it.
+struct Target {
+    root: OwnedFd,
+    added: HashSet<PathBuf>,
+    // Cleared once openat2() turns out to be unavailable, to go straight to the walk
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    openat2: Cell<bool>,
+}
+
+impl Target {
+    fn open(dir: &Path) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        Ok(Self {
+            root: rustix::fs::open(dir, DIR_FLAGS, Mode::empty())?,
+            added: HashSet::new(),
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            openat2: Cell::new(true),
+        })
+    }
+
+    // Opens the directory at a path relative to the target directory, following symlinks as long
+    // as they stay inside of it, and creating missing directories if asked to
+    fn open_dir(&self, path: &Path, create: bool) -> io::Result<Resolved> {
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        if self.openat2.get() {
+            use rustix::fs::{ResolveFlags, openat2};
+
+            let path = if path.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                path
+            };
+            let resolve = ResolveFlags::BENEATH | ResolveFlags::NO_MAGICLINKS;
+            match openat2(&self.root, path, DIR_FLAGS, Mode::empty(), resolve) {
+                Ok(dir) => return Ok(Resolved::Dir(dir)),
+                Err(Errno::XDEV | Errno::LOOP) => return Ok(Resolved::Escape),
+                Err(Errno::NOENT | Errno::NOTDIR) if !create => return Ok(Resolved::Missing),
+                // The walk creates missing directories, and works without openat2() (on older
+                // kernels, or in sandboxes which don't allow it)
+                Err(Errno::NOENT | Errno::NOTDIR) => {}
+                Err(Errno::NOSYS | Errno::PERM) => self.openat2.set(false),
+                Err(err) => return Err(err.into()),
+            }
+        }
+        self.walk(path, create)
+    }
+
+    // Resolves a path one component at a time, reading each symlink and checking where it goes
+    // before following it, as the kernel would
+    fn walk(&self, path: &Path, create: bool) -> io::Result<Resolved> {
+        // The target directory is always at the bottom
+        let mut dirs = vec![openat(&self.root, ".", DIR_FLAGS, Mode::empty())?];
+        let mut remaining: VecDeque<OsString> = path.iter().map(ToOwned::to_owned).collect();
+        let mut symlinks = 0;
+        while let Some(component) = remaining.pop_front() {
+            let name = match Path::new(&component).components().next() {
+                Some(Component::Normal(name)) => name,
+                Some(Component::ParentDir) if dirs.len() > 1 => {
+                    dirs.pop();
+                    continue;
+                }
+                Some(Component::ParentDir | Component::RootDir | Component::Prefix(_)) => {
+                    return Ok(Resolved::Escape);
+                }
+                Some(Component::CurDir) | None => continue,
+            };
+            let Some(current) = dirs.last() else {
+                return Ok(Resolved::Escape);
+            };
+            match readlinkat(current, name, vec![]) {
+                Ok(link) => {
+                    symlinks += 1;
+                    let link = Path::new(OsStr::from_bytes(link.as_bytes()));
+                    if symlinks > MAX_SYMLINKS || link.has_root() {
+                        return Ok(Resolved::Escape);
+                    }
+                    for component in link.iter().rev() {
+                        remaining.push_front(component.to_owned());
+                    }
+                    continue;
+                }
+                // Not a symlink
+                Err(Errno::INVAL) => {}
+                Err(Errno::NOENT) if create => {
+                    match mkdirat(current, name, Mode::from_raw_mode(0o755)) {
+                        Ok(()) | Err(Errno::EXIST) => {}
+                        Err(err) => return Err(err.into()),
+                    }
+                }
+                Err(Errno::NOENT | Errno::NOTDIR) => return Ok(Resolved::Missing),
+                Err(err) => return Err(err.into()),
+            }
+            // If a symlink appeared since it was checked, this fails instead of following it
+            match openat(current, name, DIR_FLAGS | OFlags::NOFOLLOW, Mode::empty()) {
+                Ok(dir) => dirs.push(dir),
+                Err(Errno::NOENT | Errno::NOTDIR) if !create => return Ok(Resolved::Missing),
+                Err(err) => return Err(err.into()),
+            }
+        }
+        Ok(dirs.pop().map_or(Resolved::Escape, Resolved::Dir))
+    }
+
+    // Opens the parent directory of a path which check() returned, along with the final name
+    fn open_parent<'a>(&self, path: &'a Path, create: bool) -> io::Result<(Resolved, &'a OsStr)> {
+        let name = path.file_name().unwrap_or_default();
+        let parent = path.parent().unwrap_or_else(|| Path::new(""));
+        Ok((self.open_dir(parent, create)?, name))
+    }
+
+    // Opens the parent directory of an entry, creating it if needed
+    fn entry_parent<'a>(&self, path: &'a Path) -> Result<(OwnedFd, &'a OsStr), Error> {
+        match self.open_parent(path, true)? {
+            (Resolved::Dir(parent), name) => Ok((parent, name)),
+            (Resolved::Missing, _) => Err(io::Error::from(ErrorKind::NotFound))?,
+            (Resolved::Escape, _) => Err(unsafe_path(path, UnsafeReason::SymlinkEscape))?,
+        }
+    }
+
+    // Creates the directory for an entry, unless there's one already
+    fn create_dir(&self, path: &Path) -> Result<(), Error> {
+        let (parent, name) = self.entry_parent(path)?;
+        match mkdirat(&parent, name, Mode::from_raw_mode(0o755)) {
+            Err(Errno::EXIST) if !is_dir_at(&parent, name)? => {
+                Err(io::Error::from(ErrorKind::AlreadyExists))?
+            }
+            Ok(()) | Err(Errno::EXIST) => Ok(()),
+            Err(err) => Err(io::Error::from(err))?,
+        }
+    }
+
+    // Unpacks anything but a directory
+    fn unpack_entry(
+        &self,
+        entry: &mut tar::Entry<impl Read>,
+        path: &Path,
+        options: &ExtractOptions,
+    ) -> Result<(), Error> {
+        let (parent, name) = self.entry_parent(path)?;
+        let kind = entry.header().entry_type();
+        let attributes = Attributes::of(entry, path, options)?;
+        if options.existing != Existing::Fail && !is_dir_at(&parent, name)? {
+            match unlinkat(&parent, name, AtFlags::empty()) {
+                Ok(()) | Err(Errno::NOENT) => {}
+                Err(err) => Err(io::Error::from(err))?,
+            }
+        }
+
+        match kind {
+            tar::EntryType::Symlink => {
+                let link = entry.link_name()?.unwrap_or_default();
+                symlinkat(&*link, &parent, name).map_err(io::Error::from)?;
+                attributes.apply_at(&parent, name, false)?;
+            }
+            tar::EntryType::Link => {
+                let link = entry.link_name()?.unwrap_or_default();
+                let link =
+                    check(&link)?.ok_or_else(|| unsafe_path(&link, UnsafeReason::Absolute))?;
+                let (link_parent, link_name) = self.entry_parent(&link)?;
+                linkat(&link_parent, link_name, &parent, name, AtFlags::empty())
+                    .map_err(io::Error::from)?;
+            }
+            tar::EntryType::Char | tar::EntryType::Block | tar::EntryType::Fifo => {
+                match make_node(entry, &parent, name, attributes.mode) {
+                    Ok(()) => attributes.apply_at(&parent, name, true)?,
+                    Err(err)
+                        if options.rootless
+                            && matches!(
+                                err.kind(),
+                                ErrorKind::PermissionDenied | ErrorKind::Unsupported
+                            ) =>
+                    {
+                        attributes.apply(&create_file(&parent, name)?, true)?;
+                    }
+                    Err(err) => Err(err)?,
+                }
+            }
+            // As for tar, anything else is a regular file
+            _ => {
+                let mut file = create_file(&parent, name)?;
+                io::copy(entry, &mut file)?;
+                attributes.apply(&file, false)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Removes whatever is at a path, as for a whiteout
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        if let (Resolved::Dir(parent), name) = self.open_parent(path, false)? {
+            remove_at(&parent, name)?;
+        }
+        Ok(())
+    }
+
+    // Makes way for an entry of this layer, replacing what's there unless both are directories
+    fn replace(&mut self, path: &Path, is_dir: bool) -> io::Result<()> {
+        for ancestor in path.ancestors() {
+            if !self.added.insert(ancestor.to_owned()) {
+                break;
+            }
+        }
+        if let (Resolved::Dir(parent), name) = self.open_parent(path, false)?
+            && !(is_dir && is_dir_at(&parent, name)?)
+        {
+            remove_at(&parent, name)?;
+        }
+        Ok(())
+    }
+
+    // Removes everything in a directory that wasn't added by this layer, as for an opaque whiteout
+    fn clear_opaque(&self, path: &Path) -> io::Result<()> {
+        let dir = if path.as_os_str().is_empty() {
+            openat(&self.root, ".", DIR_FLAGS, Mode::empty())?
+        } else {
+            let (Resolved::Dir(parent), name) = self.open_parent(path, false)? else {
+                return Ok(());
+            };
+            match openat(&parent, name, DIR_FLAGS | OFlags::NOFOLLOW, Mode::empty()) {
+                Ok(dir) => dir,
+                // Missing, or not a directory (including a symlink to one)
+                Err(Errno::NOENT | Errno::NOTDIR | Errno::LOOP) => return Ok(()),
+                Err(err) => return Err(err.into()),
+            }
+        };
+        self.clear_opaque_at(&dir, path)
+    }
+
+    fn clear_opaque_at(&self, dir: &OwnedFd, path: &Path) -> io::Result<()> {
+        for name in children(dir)? {
+            let child = path.join(&name);
+            if !self.added.contains(&child) {
+                remove_at(dir, &name)?;
+            } else if let Ok(subdir) =
+                openat(dir, &name, DIR_FLAGS | OFlags::NOFOLLOW, Mode::empty())
+            {
+                self.clear_opaque_at(&subdir, &child)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Creates a regular file for an entry
+fn create_file(dir: &OwnedFd, name: &OsStr) -> io::Result<File> {
+    let flags = OFlags::WRONLY | OFlags::CREATE | OFlags::EXCL | OFlags::NOFOLLOW | OFlags::CLOEXEC;
+    Ok(openat(dir, name, flags, Mode::from_raw_mode(0o600))?.into())
+}
+
+// The metadata of an entry, as it's applied to what was unpacked for it
+struct Attributes {
+    owner: Option<(u32, u32)>,
+    mode: u32,
+    mtime: u64,
+    xattrs: Vec<(OsString, Vec<u8>)>,
+    // The value for user.containers.override_stat, if privileges turn out to be missing
+    rootless: Option<String>,
+}
+
+impl Attributes {
+    fn of(
+        entry: &mut tar::Entry<impl Read>,
+        path: &Path,
+        options: &ExtractOptions,
+    ) -> Result<Self, Error> {
+        let header = entry.header();
+        let (mut uid, mut gid) = (header.uid()?, header.gid()?);
+        let (mode, mtime) = (header.mode()?, header.mtime()?);
+        let kind = match header.entry_type() {
+            tar::EntryType::Directory => "dir".to_owned(),
+            tar::EntryType::Symlink => "symlink".to_owned(),
+            tar::EntryType::Fifo => "pipe".to_owned(),
+            kind @ (tar::EntryType::Char | tar::EntryType::Block) => format!(
+                "{}-{}-{}",
+                if kind == tar::EntryType::Char {
+                    "char"
+                } else {
+                    "block"
+                },
+                header.device_major()?.unwrap_or_default(),
+                header.device_minor()?.unwrap_or_default()
+            ),
+            _ => "file".to_owned(),
+        };
+
+        // Like Go (which writes most layers), take the IDs which don't fit in the header from PAX
+        let mut xattrs = vec![];
+        if let Some(extensions) = entry.pax_extensions()? {
+            for extension in extensions {
+                let extension = extension?;
+                let value = || extension.value().ok().and_then(|value| value.parse().ok());
+                match extension.key_bytes() {
+                    b"uid" => uid = value().unwrap_or(uid),
+                    b"gid" => gid = value().unwrap_or(gid),
+                    key => {
+                        if let Some(name) = key.strip_prefix(b"SCHILY.xattr.")
+                            && options.xattrs
+                        {
+                            let name = OsStr::from_bytes(name).to_owned();
+                            xattrs.push((name, extension.value_bytes().to_vec()));
+                        }
+                    }
+                }
+            }
+        }
+
+        let owner = match &options.ids {
+            Some(ids) => {
+                let unmapped = |id, group| UnmappedId {
+                    path: path.to_owned(),
+                    id,
+                    group,
+                };
+                let owner = u32::try_from(uid)
+                    .ok()
+                    .and_then(|uid| ids.uid(uid))
+                    .ok_or_else(|| unmapped(uid, false))?;
+                let group = u32::try_from(gid)
+                    .ok()
+                    .and_then(|gid| ids.gid(gid))
+                    .ok_or_else(|| unmapped(gid, true))?;
+                Some((owner, group))
+            }
+            None if options.ownership => Some((
+                u32::try_from(uid).map_err(io::Error::other)?,
+                u32::try_from(gid).map_err(io::Error::other)?,
+            )),
+            None => None,
+        };
+        // The IDs are recorded as they are in the layer, since that's what the container sees
+        let rootless = options
+            .rootless
+            .then(|| format!("{uid}:{gid}:0{:o}:{kind}", mode & 0o7777));
+        Ok(Self {
+            owner,
+            mode,
+            mtime,
+            xattrs,
+            rootless,
+        })
+    }
+
+    fn timestamps(&self) -> Timestamps {
+        let time = Timespec {
+            tv_sec: self.mtime.try_into().unwrap_or(i64::MAX),
+            tv_nsec: 0,
+        };
+        Timestamps {
+            last_access: time,
+            last_modification: time,
+        }
+    }
+
+    // Applies everything to an open file or directory (or the placeholder for a device node).
+    // The owner goes first, since changing it clears setuid and setgid bits and file capabilities.
+    fn apply(&self, file: impl AsFd, placeholder: bool) -> io::Result<()> {
+        let mut record = placeholder;
+        if let Some((uid, gid)) = self.owner {
+            match fchown(&file, Some(Uid::from_raw(uid)), Some(Gid::from_raw(gid))) {
+                // EINVAL is for IDs that aren't mapped in the user namespace
+                Err(Errno::PERM | Errno::INVAL) if self.rootless.is_some() => record = true,
+                result => result?,
+            }
+        }
+        fchmod(&file, mode(self.mode))?;
+        #[cfg(any(target_os = "linux", target_os = "android", target_vendor = "apple"))]
+        {
+            use rustix::fs::{XattrFlags, fsetxattr};
+
+            for (name, value) in &self.xattrs {
+                fsetxattr(&file, name, value, XattrFlags::empty())?;
+            }
+            if record && let Some(stat) = &self.rootless {
+                let name = "user.containers.override_stat";
+                match fsetxattr(&file, name, stat.as_bytes(), XattrFlags::empty()) {
+                    // Nowhere to record it, so it's only skipped
+                    Ok(()) | Err(Errno::NOTSUP | Errno::PERM) => {}
+                    Err(err) => return Err(err.into()),
+                }
+            }
+        }
+        #[cfg(not(any(target_os = "linux", target_os = "android", target_vendor = "apple")))]
+        let _ = record;
+        futimens(&file, &self.timestamps())?;
+        Ok(())
+    }
+
+    // Applies the owner, mode and modification time to something that can't be opened, like a
+    // symlink or a device node, without following symlinks
+    fn apply_at(&self, dir: &OwnedFd, name: &OsStr, with_mode: bool) -> io::Result<()> {
+        if let Some((uid, gid)) = self.owner {
+            let (uid, gid) = (Uid::from_raw(uid), Gid::from_raw(gid));
+            match chownat(dir, name, Some(uid), Some(gid), AtFlags::SYMLINK_NOFOLLOW) {
+                // user.* xattrs can't be set on symlinks and FIFOs, so there's no record of this
+                Err(Errno::PERM | Errno::INVAL) if self.rootless.is_some() => {}
+                result => result?,
+            }
+        }
+        if with_mode {
+            match chmodat(dir, name, mode(self.mode), AtFlags::SYMLINK_NOFOLLOW) {
+                // Older kernels can't do this without following symlinks, so make do with the
+                // mode that the node was created with
+                Ok(()) | Err(Errno::NOTSUP | Errno::NOSYS) => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+        utimensat(dir, name, &self.timestamps(), AtFlags::SYMLINK_NOFOLLOW)?;
+        Ok(())
+    }
+}
+
+fn mode(mode: u32) -> Mode {
+    Mode::from_raw_mode(RawMode::try_from(mode & 0o7777).unwrap_or_default())
+}
+
+// Creates a device node or a FIFO
+fn make_node(
+    entry: &tar::Entry<impl Read>,
+    dir: &OwnedFd,
+    name: &OsStr,
+    mode_bits: u32,
+) -> io::Result<()> {
+    #[cfg(not(target_vendor = "apple"))]
+    {
+        let header = entry.header();
+        let kind = match header.entry_type() {
+            tar::EntryType::Char => FileType::CharacterDevice,
+            tar::EntryType::Block => FileType::BlockDevice,
+            _ => return Ok(rustix::fs::mkfifoat(dir, name, mode(mode_bits))?),
+        };
+        let device = rustix::fs::makedev(
+            header.device_major()?.unwrap_or_default(),
+            header.device_minor()?.unwrap_or_default(),
+        );
+        Ok(rustix::fs::mknodat(
+            dir,
+            name,
+            kind,
+            mode(mode_bits),
+            device,
+        )?)
+    }
+    #[cfg(target_vendor = "apple")]
+    {
+        let _ = (entry, dir, name, mode_bits);
+        Err(io::Error::new(
+            ErrorKind::Unsupported,
+            "Device nodes and FIFOs can't be unpacked on this platform",
+        ))
+    }
+}
+
+// Checks if there's a directory (not a symlink to one) in a directory
+fn is_dir_at(dir: &OwnedFd, name: &OsStr) -> io::Result<bool> {
+    match statat(dir, name, AtFlags::SYMLINK_NOFOLLOW) {
+        Ok(stat) => Ok(FileType::from_raw_mode(stat.st_mode) == FileType::Directory),
+        Err(Errno::NOENT) => Ok(false),
+        Err(err) => Err(err.into()),
+    }
+}
+
+// The names in a directory, other than `.` and `..`
+fn children(dir: &OwnedFd) -> io::Result<Vec<OsString>> {
+    let mut names = vec![];
+    for entry in Dir::read_from(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_bytes();
+        if name != b"." && name != b".." {
+            names.push(OsStr::from_bytes(name).to_owned());
+        }
+    }
+    Ok(names)
+}
+
+// Removes whatever is at `name` in a directory, with everything in it, without following symlinks
+fn remove_at(dir: &OwnedFd, name: &OsStr) -> io::Result<()> {
+    let result = if is_dir_at(dir, name)? {
+        let subdir = openat(dir, name, DIR_FLAGS | OFlags::NOFOLLOW, Mode::empty())?;
+        for child in children(&subdir)? {
+            remove_at(&subdir, &child)?;
+        }
+        unlinkat(dir, name, AtFlags::REMOVEDIR)
+    } else {
+        unlinkat(dir, name, AtFlags::empty())
+    };
+    match result {
+        Ok(()) | Err(Errno::NOENT) => Ok(()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use base64::{Engine, prelude::BASE64_STANDARD};
+
+    use super::*;
+    use crate::testing::TempDir;
+
+    // A directory for a test, with the target directory and a sibling which must stay untouched
+    struct Scratch(TempDir);
+
+    impl Scratch {
+        fn new() -> Result<Self> {
+            let dir = TempDir::new()?;
+            fs::create_dir(dir.path().join("outside"))?;
+            fs::write(dir.path().join("outside/secret"), "secret")?;
+            Ok(Self(dir))
+        }
+
+        fn base(&self) -> &Path {
+            self.0.path()
+        }
+
+        fn root(&self) -> PathBuf {
+            self.base().join("root")
+        }
+
+        fn outside(&self) -> Result<Vec<OsString>> {
+            let mut names = fs::read_dir(self.base().join("outside"))?
+                .map(|entry| Ok(entry?.file_name()))
+                .collect::<Result<Vec<_>>>()?;
+            names.sort();
+            Ok(names)
+        }
+    }
+
+    // A tar stream with the given entries, written byte for byte (tar::Builder refuses `..`)
+    fn tar(entries: &[(&[u8], tar::EntryType, &[u8])]) -> Vec<u8> {
+        let mut data = vec![];
+        for &(name, kind, link_or_content) in entries {
+            let mut header = tar::Header::new_old();
+            header.as_old_mut().name[..name.len()].copy_from_slice(name);
+            header.set_entry_type(kind);
+            header.set_mode(if kind.is_dir() { 0o755 } else { 0o644 });
+            header.set_uid(0);
+            header.set_gid(0);
+            header.set_mtime(0);
+            let content = if kind.is_symlink() || kind.is_hard_link() {
+                header.as_old_mut().linkname[..link_or_content.len()]
+                    .copy_from_slice(link_or_content);
+                &[][..]
+            } else {
+                link_or_content
+            };
+            header.set_size(content.len() as u64);
+            header.set_cksum();
+            data.extend_from_slice(header.as_bytes());
+            data.extend_from_slice(content);
+            data.resize(data.len().next_multiple_of(512), 0);
+        }
+        data.resize(data.len() + 1024, 0);
+        data
+    }
+
+    // Extracts a tar stream, as a layer whose tarsplit holds all of it inline
+    fn extract(tar: &[u8], dir: &Path) -> Result<(), Error> {
+        let manifest = zstd::encode_all(&br#"{"version":1,"entries":[]}"#[..], 0)?;
+        let segment = format!(
+            r#"{{"type":2,"payload":"{}"}}"#,
+            BASE64_STANDARD.encode(tar)
+        );
+        let tarsplit = zstd::encode_all(segment.as_bytes(), 0)?;
+        let stream = Stream::new_from_frames(&manifest, &tarsplit)?;
+        extract_to_dir(&stream, dir, &ExtractOptions::new(), |_| {
+            Err(anyhow!("No content to resolve"))
+        })
+    }
+
+    // Extracts a tar stream, resolving paths one component at a time as without openat2()
+    fn extract_walking(tar: &[u8], dir: &Path) -> Result<(), Error> {
+        let target = Target::open(dir)?;
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        target.openat2.set(false);
+        unpack(&mut tar::Archive::new(tar), target, &ExtractOptions::new())
+    }
+
+    fn unsafe_reason(result: Result<(), Error>) -> Option<UnsafeReason> {
+        match result {
+            Err(Error::UnsafePath(err)) => Some(err.reason),
+            _ => None,
+        }
+    }
+
+    // Layers which try to write (or link to) something outside of the target directory
+    fn malicious(base: &Path) -> Vec<(Vec<u8>, UnsafeReason)> {
+        use tar::EntryType::{Directory, Link, Regular, Symlink};
+
+        let outside = base.join("outside");
+        let absolute = outside.join("evil");
+        let secret = outside.join("secret");
+        vec![
+            (
+                tar(&[(b"../outside/evil", Regular, b"evil")]),
+                UnsafeReason::ParentDir,
+            ),
+            (
+                tar(&[(b"dir/../../outside/evil", Regular, b"evil")]),
+                UnsafeReason::ParentDir,
+            ),
+            (
+                tar(&[(absolute.as_os_str().as_bytes(), Regular, b"evil")]),
+                UnsafeReason::Absolute,
+            ),
+            // Through a symlink in an intermediate directory
+            (
+                tar(&[
+                    (b"dir", Directory, b""),
+                    (b"dir/up", Symlink, b"../.."),
+                    (b"dir/up/outside/evil", Regular, b"evil"),
+                ]),
+                UnsafeReason::SymlinkEscape,
+            ),
+            (
+                tar(&[
+                    (b"abs", Symlink, outside.as_os_str().as_bytes()),
+                    (b"abs/evil", Regular, b"evil"),
+                ]),
+                UnsafeReason::SymlinkEscape,
+            ),
+            (
+                tar(&[
+                    (b"dir", Directory, b""),
+                    (b"dir/up", Symlink, b".."),
+                    (b"dir/up/up", Symlink, b".."),
+                    (b"dir/up/up/outside/evil", Regular, b"evil"),
+                ]),
+                UnsafeReason::SymlinkEscape,
+            ),
+            // Hardlinks to files outside of the target directory
+            (
+                tar(&[(b"evil", Link, b"../outside/secret")]),
+                UnsafeReason::ParentDir,
+            ),
+            (
+                tar(&[(b"evil", Link, secret.as_os_str().as_bytes())]),
+                UnsafeReason::Absolute,
+            ),
+            (
+                tar(&[
+                    (b"up", Symlink, b".."),
+                    (b"evil", Link, b"up/outside/secret"),
+                ]),
+                UnsafeReason::SymlinkEscape,
+            ),
+        ]
+    }
+
+    fn check_malicious(extract: fn(&[u8], &Path) -> Result<(), Error>) -> Result<()> {
+        for case in 0.. {
+            let scratch = Scratch::new()?;
+            let Some((tar, reason)) = malicious(scratch.base()).into_iter().nth(case) else {
+                break;
+            };
+            let result = extract(&tar, &scratch.root());
+            assert_eq!(unsafe_reason(result), Some(reason), "case {case}");
+            assert_eq!(scratch.outside()?, ["secret"], "case {case}");
+            assert!(!scratch.root().join("evil").exists(), "case {case}");
+        }
+        Ok(())
+    }
+
+    fn check_benign(extract: fn(&[u8], &Path) -> Result<(), Error>) -> Result<()> {
+        use tar::EntryType::{Directory, Link, Regular, Symlink};
+
+        // Symlinks which stay inside of the target directory are followed
+        let scratch = Scratch::new()?;
+        let tar = tar(&[
+            (b"./dir/", Directory, b""),
+            (b"dir/up", Symlink, b".."),
+            (b"dir/up/dir/up/file", Regular, b"content"),
+            (b"link", Link, b"dir/up/file"),
+        ]);
+        extract(&tar, &scratch.root())?;
+        assert_eq!(fs::read(scratch.root().join("file"))?, b"content");
+        assert_eq!(fs::read(scratch.root().join("link"))?, b"content");
+        assert_eq!(scratch.outside()?, ["secret"]);
+        Ok(())
+    }
+
+    #[test]
+    fn paths_are_made_relative() -> Result<()> {
+        assert_eq!(check(Path::new("./a/./b/"))?, Some(PathBuf::from("a/b")));
+        assert_eq!(check(Path::new("."))?, None);
+        assert_eq!(check(Path::new(""))?, None);
+        let reason = |path| unsafe_reason(check(Path::new(path)).map(drop));
+        assert_eq!(reason("a/.."), Some(UnsafeReason::ParentDir));
+        assert_eq!(reason("/a"), Some(UnsafeReason::Absolute));
+        Ok(())
+    }
+
+    #[test]
+    fn malicious_entries_are_rejected() -> Result<()> {
+        check_malicious(extract)?;
+        check_benign(extract)
+    }
+
+    #[test]
+    fn malicious_entries_are_rejected_without_openat2() -> Result<()> {
+        check_malicious(extract_walking)?;
+        check_benign(extract_walking)
+    }
+}
@@ -1,20 +1,47 @@
+use std::{
+    collections::BTreeMap,
+    io::{self, Write},
+};
+
 use anyhow::Result;
 use base64::Engine;
 use base64::engine::general_purpose::STANDARD as b64;
 use serde::{
     Deserialize,
-    de::{self, Deserializer},
+    de::{self, DeserializeOwned, Deserializer},
 };
 use zerocopy::{
     FromBytes, Immutable, IntoBytes, KnownLayout, Unaligned,
     little_endian::{U32, U64},
 };
 
+use crate::MetadataReference;
+#[cfg(doc)]
+use crate::{FOOTER_SIZE, MetadataReferences};
+
+// Parses JSON using the configured backend.  simd-json needs to modify the buffer in-place.
+#[cfg_attr(not(feature = "simd-json"), allow(clippy::needless_pass_by_ref_mut))]
+pub fn from_json<T: DeserializeOwned>(data: &mut [u8]) -> Result<T> {
+    #[cfg(feature = "simd-json")]
+    return Ok(simd_json::serde::from_slice(data)?);
+    #[cfg(not(feature = "simd-json"))]
+    return Ok(serde_json::from_slice(data)?);
+}
+
 // "tarsplit" file format
+pub const TARSPLIT_FILE_TYPE: u8 = 1;
+pub const TARSPLIT_SEGMENT_TYPE: u8 = 2;
+
 #[derive(Debug, Deserialize)]
 pub struct TarSplitEntry {
+    #[serde(rename = "type")]
+    pub kind: u8,
     #[serde(default)]
     pub name: Option<String>,
+    // tar-split uses this instead of "name" for names that aren't valid UTF-8
+    #[serde(default)]
+    #[serde(deserialize_with = "deserialize_option_base64")]
+    pub name_raw: Option<Box<[u8]>>,
     #[serde(default)]
     pub size: Option<u64>,
     #[serde(default)]
@@ -38,21 +65,99 @@ where
     )
 }
 
-// "manifest" file format
-#[derive(Debug, Deserialize)]
+fn deserialize_base64_map<'de, D>(deserializer: D) -> Result<BTreeMap<String, Vec<u8>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Option::<BTreeMap<String, String>>::deserialize(deserializer)?
+        .unwrap_or_default()
+        .into_iter()
+        .map(|(key, value)| Ok((key, b64.decode(value).map_err(de::Error::custom)?)))
+        .collect()
+}
+
+/// The zstd:chunked (CRFS) manifest, which describes every entry in the layer.
+#[derive(Debug, Clone, Deserialize)]
 pub struct Manifest {
+    /// The version of the format, which is always 1.
     pub version: u32,
+
+    /// The entries, in the order of the tar stream.
     pub entries: Vec<ManifestEntry>,
 }
 
-#[derive(Debug, Deserialize)]
+/// An entry in the [`Manifest`]: the metadata of a file, or a further chunk of a large file.
+///
+/// Fields which don't apply to an entry (or which were left out because they're zero) are
+/// `None` or empty.  This is enough to create the filesystem tree without parsing the tar headers.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct ManifestEntry {
+    /// The type of the entry: `"reg"`, `"dir"`, `"symlink"`, `"hardlink"`, `"char"`, `"block"`,
+    /// `"fifo"`, or `"chunk"` for the second and later chunks of a regular file.
+    #[serde(rename = "type", default)]
+    pub kind: String,
+
+    /// The path of the file, relative to the root of the layer.
     pub name: String,
+
+    /// The target of a symlink, or the path of the file that a hard link refers to.
+    pub link_name: Option<String>,
+
+    /// The permission bits, including the setuid, setgid and sticky bits.
+    pub mode: Option<u32>,
+
+    /// The size of a regular file.
     pub size: Option<u64>,
+
+    /// The owner.
+    pub uid: Option<u32>,
+
+    /// The group.
+    pub gid: Option<u32>,
+
+    /// The modification time, in RFC 3339 format.
+    #[serde(rename = "modtime")]
+    pub mtime: Option<String>,
+
+    /// The access time, in RFC 3339 format.
+    #[serde(rename = "accesstime")]
+    pub atime: Option<String>,
+
+    /// The change time, in RFC 3339 format.
+    #[serde(rename = "changetime")]
+    pub ctime: Option<String>,
+
+    /// The major number of a device node.
+    pub dev_major: Option<u64>,
+
+    /// The minor number of a device node.
+    pub dev_minor: Option<u64>,
+
+    /// The extended attributes, by name.
+    #[serde(default, deserialize_with = "deserialize_base64_map")]
+    pub xattrs: BTreeMap<String, Vec<u8>>,
+
+    /// The digest of the whole content of a regular file.
     pub digest: Option<String>,
+
+    /// The offset of the compressed content (of this chunk) in the layer blob.
     pub offset: Option<u64>,
-    #[serde(rename = "endOffset")]
+
+    /// The end of the compressed content (of this chunk) in the layer blob.
     pub end_offset: Option<u64>,
+
+    /// The uncompressed size of this chunk.
+    pub chunk_size: Option<u64>,
+
+    /// The offset of this chunk in the content of the file.
+    pub chunk_offset: Option<u64>,
+
+    /// The digest of the uncompressed content of this chunk.
+    pub chunk_digest: Option<String>,
+
+    /// The kind of chunk: `"zeros"` for chunks with no data in the blob.
+    pub chunk_type: Option<String>,
 }
 
 // Footer
@@ -64,6 +169,8 @@ pub struct FooterReference {
     pub length_uncompressed: U64,
 }
 
+/// The footer at the end of a zstd:chunked blob: a zstd skippable frame holding the positions of
+/// the manifest and the tarsplit.  It's always [`FOOTER_SIZE`] bytes long.
 #[repr(C)]
 #[derive(Debug, Copy, Clone, IntoBytes, FromBytes, Unaligned, KnownLayout, Immutable)]
 pub struct Footer {
@@ -77,23 +184,87 @@ pub struct Footer {
     pub(crate) zstd_chunked_magic: [u8; 8],
 }
 
-const ZSTD_SKIPPABLE_MAGIC: [u8; 4] = [0x50, 0x2a, 0x4d, 0x18];
+pub const ZSTD_SKIPPABLE_MAGIC: [u8; 4] = [0x50, 0x2a, 0x4d, 0x18];
 const ZSTD_CHUNKED_FOOTER_SIZE: u32 = 64;
-const ZSTD_CHUNKED_MANIFEST_TYPE: u64 = 1;
-const ZSTD_CHUNKED_MAGIC: [u8; 8] = *b"GNUlInUx";
+pub const ZSTD_CHUNKED_MANIFEST_TYPE: u64 = 1;
+pub const ZSTD_CHUNKED_MAGIC: [u8; 8] = *b"GNUlInUx";
+
+impl FooterReference {
+    fn new(reference: &MetadataReference) -> Self {
+        Self {
+            offset: reference.range.start.into(),
+            length_compressed: (reference.range.end.saturating_sub(reference.range.start)).into(),
+            length_uncompressed: reference.uncompressed_size.into(),
+        }
+    }
+}
 
 impl Footer {
+    /// Creates a footer pointing at the given manifest and tarsplit.  Their digests aren't part of
+    /// the footer: they only go in the annotations (see [`MetadataReferences::to_oci()`]).
+    #[must_use]
+    pub fn new(manifest: &MetadataReference, tarsplit: &MetadataReference) -> Self {
+        Self {
+            skippable_magic: ZSTD_SKIPPABLE_MAGIC,
+            skippable_size: ZSTD_CHUNKED_FOOTER_SIZE.into(),
+            manifest: FooterReference::new(manifest),
+            manifest_type: ZSTD_CHUNKED_MANIFEST_TYPE.into(),
+            tarsplit: FooterReference::new(tarsplit),
+            zstd_chunked_magic: ZSTD_CHUNKED_MAGIC,
+        }
+    }
+
     fn valid(&self) -> bool {
         self.skippable_magic == ZSTD_SKIPPABLE_MAGIC
             && self.skippable_size == ZSTD_CHUNKED_FOOTER_SIZE
-            && self.manifest_type == ZSTD_CHUNKED_MANIFEST_TYPE
             && self.zstd_chunked_magic == ZSTD_CHUNKED_MAGIC
     }
 
     /// Tries to extract a zstd:chunked footer from the passed slice.  The slice can be the entire
-    /// file or some portion of the end of it, but should be at least 72 bytes in length.
+    /// file or some portion of the end of it, but should be at least 72 bytes in length.  The
+    /// manifest type isn't checked: see [`Self::supported()`].
+    #[must_use]
     pub fn from_suffix(data: &[u8]) -> Option<&Self> {
         let (_rest, footer) = Self::ref_from_suffix(data).ok()?;
         if footer.valid() { Some(footer) } else { None }
     }
+
+    /// The position of the manifest, without a digest, or `None` if its range overflows.
+    #[must_use]
+    pub const fn manifest(&self) -> Option<MetadataReference> {
+        MetadataReference::from_footer(&self.manifest)
+    }
+
+    /// The position of the tarsplit, without a digest, or `None` if its range overflows.
+    #[must_use]
+    pub const fn tarsplit(&self) -> Option<MetadataReference> {
+        MetadataReference::from_footer(&self.tarsplit)
+    }
+
+    /// The type of the manifest, which is 1 for the zstd:chunked manifests that this crate reads.
+    #[must_use]
+    pub const fn manifest_type(&self) -> u64 {
+        self.manifest_type.get()
+    }
+
+    /// Checks whether this crate understands the manifest type.
+    #[must_use]
+    pub const fn supported(&self) -> bool {
+        self.manifest_type() == ZSTD_CHUNKED_MANIFEST_TYPE
+    }
+
+    /// Returns the serialized footer, as it appears at the end of the blob.
+    #[must_use]
+    pub fn to_bytes(&self) -> [u8; size_of::<Self>()] {
+        zerocopy::transmute!(*self)
+    }
+
+    /// Writes the serialized footer.
+    ///
+    /// # Errors
+    ///
+    /// Fails if writing fails.
+    pub fn write_to(&self, write: &mut impl Write) -> io::Result<()> {
+        write.write_all(self.as_bytes())
+    }
 }
@@ -0,0 +1,233 @@
+//! Parsing of zstd frame headers, as described in [RFC 8878 section
+//! 3.1.1.1](https://datatracker.ietf.org/doc/html/rfc8878#name-frame-header).
+
+use anyhow::{Context, Result, bail, ensure};
+
+pub(crate) const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// Information from the header of a zstd frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameHeader {
+    /// The size of the data after decompression, if the frame declares it.
+    pub content_size: Option<u64>,
+
+    /// The minimum buffer size that the decoder needs to decompress the frame.  This is equal to
+    /// the content size for single-segment frames.
+    pub window_size: Option<u64>,
+
+    /// The ID of the dictionary required to decompress the frame, if any.
+    pub dictionary_id: Option<u32>,
+
+    /// If the frame ends with a checksum of the decompressed data.
+    pub has_checksum: bool,
+
+    /// The length of the header (including the magic number), in bytes.
+    pub header_size: usize,
+}
+
+fn read_le(data: &[u8]) -> u64 {
+    data.iter()
+        .rev()
+        .fold(0, |acc, &byte| (acc << 8) | u64::from(byte))
+}
+
+impl FrameHeader {
+    /// The maximum length of a frame header.  Reading this many bytes from the start of a frame is
+    /// always enough to parse its header.
+    pub const MAX_SIZE: usize = 18;
+
+    /// Parses the header at the start of the given data, which must begin with a zstd frame.  Only
+    /// the header itself (at most [`FrameHeader::MAX_SIZE`] bytes) needs to be present.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the data doesn't start with a (non-skippable) zstd frame header or if it's too
+    /// short to contain the complete header.
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        let Some((magic, rest)) = data.split_first_chunk::<4>() else {
+            bail!("Too short to contain a zstd frame");
+        };
+        ensure!(*magic == ZSTD_MAGIC, "Not a zstd frame");
+
+        let (&descriptor, rest) = rest.split_first().context("Truncated zstd frame header")?;
+        ensure!(
+            descriptor & 0x08 == 0,
+            "Reserved bit set in zstd frame header"
+        );
+
+        let single_segment = descriptor & 0x20 != 0;
+        let has_checksum = descriptor & 0x04 != 0;
+        let dictionary_id_size = [0, 1, 2, 4][usize::from(descriptor & 0x03)];
+        let content_size_size = match descriptor >> 6 {
+            0 if single_segment => 1,
+            0 => 0,
+            1 => 2,
+            2 => 4,
+            _ => 8,
+        };
+        let window_descriptor_size = usize::from(!single_segment);
+
+        let fields_size = window_descriptor_size + dictionary_id_size + content_size_size;
+        let fields = rest
+            .get(..fields_size)
+            .context("Truncated zstd frame header")?;
+        let (window_descriptor, fields) = fields.split_at(window_descriptor_size);
+        let (dictionary_id, content_size) = fields.split_at(dictionary_id_size);
+
+        let content_size = match content_size_size {
+            0 => None,
+            2 => Some(read_le(content_size) + 256),
+            _ => Some(read_le(content_size)),
+        };
+
+        let window_size = match window_descriptor {
+            [descriptor] => {
+                let log = 10 + u32::from(descriptor >> 3);
+                let base = 1u64 << log;
+                Some(base + (base / 8) * u64::from(descriptor & 0x07))
+            }
+            _ => content_size,
+        };
+
+        Ok(Self {
+            content_size,
+            window_size,
+            // "a value of 0 has the same meaning as no Dictionary_ID"
+            dictionary_id: u32::try_from(read_le(dictionary_id))
+                .ok()
+                .filter(|&id| id != 0),
+            has_checksum,
+            header_size: 5 + fields_size,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(descriptor: u8, fields: &[u8]) -> Vec<u8> {
+        [&ZSTD_MAGIC[..], &[descriptor], fields].concat()
+    }
+
+    #[test]
+    fn single_segment_headers() -> Result<()> {
+        // A one-byte content size, which is also the window size
+        let parsed = FrameHeader::parse(&header(0x20, &[5]))?;
+        assert_eq!(
+            parsed,
+            FrameHeader {
+                content_size: Some(5),
+                window_size: Some(5),
+                dictionary_id: None,
+                has_checksum: false,
+                header_size: 6,
+            }
+        );
+
+        // Two-byte content sizes are offset by 256
+        let parsed = FrameHeader::parse(&header(0x64, &[0x00, 0x01]))?;
+        assert_eq!(parsed.content_size, Some(512));
+        assert_eq!(parsed.window_size, Some(512));
+        assert!(parsed.has_checksum);
+        assert_eq!(parsed.header_size, 7);
+
+        let parsed = FrameHeader::parse(&header(0xa0, &[0x78, 0x56, 0x34, 0x12]))?;
+        assert_eq!(parsed.content_size, Some(0x1234_5678));
+        assert_eq!(parsed.header_size, 9);
+        Ok(())
+    }
+
+    #[test]
+    fn window_descriptor_headers() -> Result<()> {
+        // An exponent of 11 (2 MiB) and no content size
+        let parsed = FrameHeader::parse(&header(0x00, &[0x58]))?;
+        assert_eq!(parsed.content_size, None);
+        assert_eq!(parsed.window_size, Some(2 << 20));
+        assert_eq!(parsed.header_size, 6);
+
+        // A mantissa of 3 adds 3/8 of the base
+        let parsed = FrameHeader::parse(&header(0x00, &[0x5b]))?;
+        assert_eq!(parsed.window_size, Some((2 << 20) + 3 * (256 << 10)));
+
+        // The smallest window, followed by a two-byte content size
+        let parsed = FrameHeader::parse(&header(0x40, &[0x00, 0x10, 0x00]))?;
+        assert_eq!(parsed.window_size, Some(1 << 10));
+        assert_eq!(parsed.content_size, Some(256 + 0x10));
+        Ok(())
+    }
+
+    #[test]
+    fn dictionary_ids_of_each_size() -> Result<()> {
+        let cases: [(u8, &[u8], Option<u32>); 5] = [
+            (0x21, &[0x07], Some(0x07)),
+            (0x22, &[0x34, 0x12], Some(0x1234)),
+            (0x23, &[0x78, 0x56, 0x34, 0x12], Some(0x1234_5678)),
+            (0x23, &[0xff, 0xff, 0xff, 0xff], Some(u32::MAX)),
+            // Zero means no dictionary
+            (0x21, &[0x00], None),
+        ];
+        for (descriptor, dictionary_id, expected) in cases {
+            let parsed = FrameHeader::parse(&header(descriptor, &[dictionary_id, &[9]].concat()))?;
+            assert_eq!(parsed.dictionary_id, expected, "{dictionary_id:?}");
+            assert_eq!(parsed.content_size, Some(9), "{dictionary_id:?}");
+            assert_eq!(
+                parsed.header_size,
+                6 + dictionary_id.len(),
+                "{dictionary_id:?}"
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn largest_headers_are_max_size() -> Result<()> {
+        // Window descriptor, four-byte dictionary ID and eight-byte content size
+        let data = header(0xc3, &[0x50, 1, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(data.len(), FrameHeader::MAX_SIZE);
+
+        let parsed = FrameHeader::parse(&data)?;
+        assert_eq!(parsed.header_size, FrameHeader::MAX_SIZE);
+        assert_eq!(parsed.dictionary_id, Some(1));
+        assert_eq!(parsed.content_size, Some(2));
+        assert_eq!(parsed.window_size, Some(1 << 20));
+        Ok(())
+    }
+
+    #[test]
+    fn truncated_headers_are_rejected() -> Result<()> {
+        let data = header(0xc3, &[0x50, 1, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0]);
+        for len in 0..data.len() {
+            assert!(FrameHeader::parse(&data[..len]).is_err(), "{len} bytes");
+        }
+
+        // Anything after the header is ignored
+        let mut data = data;
+        data.extend_from_slice(b"block data");
+        assert_eq!(
+            FrameHeader::parse(&data)?.header_size,
+            FrameHeader::MAX_SIZE
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn other_data_is_rejected() {
+        assert!(FrameHeader::parse(b"\x28\xb5\x2f\xfe\x20\x05").is_err());
+        // The reserved bit
+        assert!(FrameHeader::parse(&header(0x28, &[5])).is_err());
+        // A skippable frame
+        assert!(FrameHeader::parse(b"\x50\x2a\x4d\x18\x00\x00\x00\x00").is_err());
+    }
+
+    #[test]
+    fn headers_written_by_zstd_are_parsed() -> Result<()> {
+        let data = vec![b'x'; 1000];
+        let frame = zstd::bulk::Compressor::new(3)?.compress(&data)?;
+        let parsed = FrameHeader::parse(&frame)?;
+        assert_eq!(parsed.content_size, Some(1000));
+        assert_eq!(parsed.dictionary_id, None);
+        assert!(parsed.header_size <= FrameHeader::MAX_SIZE);
+        Ok(())
+    }
+}
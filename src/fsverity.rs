@@ -0,0 +1,67 @@
+// Userspace computation of fs-verity digests, as used by composefs to name its objects.
+//
+// This is the "version 1" format with SHA-256, a 4096 byte block size and no salt, which is what
+// composefs uses.  See https://docs.kernel.org/filesystems/fsverity.html for the details.
+
+use zerocopy::{
+    Immutable, IntoBytes, KnownLayout,
+    little_endian::{U32, U64},
+};
+
+use crate::digest::Sha256;
+
+const BLOCK_SIZE: usize = 4096;
+const LOG_BLOCK_SIZE: u8 = 12;
+const HASH_ALGORITHM_SHA256: u8 = 1;
+
+#[repr(C)]
+#[derive(IntoBytes, KnownLayout, Immutable)]
+struct Descriptor {
+    version: u8,
+    hash_algorithm: u8,
+    log_blocksize: u8,
+    salt_size: u8,
+    sig_size: U32,
+    data_size: U64,
+    root_hash: [u8; 64],
+    salt: [u8; 32],
+    reserved: [u8; 144],
+}
+
+fn hash_block(block: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(block);
+    hasher.update(&[0; BLOCK_SIZE][block.len()..]);
+    hasher.finalize()
+}
+
+fn root_hash(data: &[u8]) -> [u8; 32] {
+    if data.is_empty() {
+        return [0; 32];
+    }
+
+    // Keep hashing until we get down to a single hash: that's the root.
+    let mut level: Vec<u8> = data.chunks(BLOCK_SIZE).flat_map(hash_block).collect();
+    while level.len() > 32 {
+        level = level.chunks(BLOCK_SIZE).flat_map(hash_block).collect();
+    }
+    level.try_into().unwrap_or_default()
+}
+
+/// Computes the fs-verity digest of the given data, in the same way that the kernel would if
+/// fs-verity were enabled on a file containing it.
+pub fn digest(data: &[u8]) -> [u8; 32] {
+    let mut descriptor = Descriptor {
+        version: 1,
+        hash_algorithm: HASH_ALGORITHM_SHA256,
+        log_blocksize: LOG_BLOCK_SIZE,
+        salt_size: 0,
+        sig_size: 0.into(),
+        data_size: (data.len() as u64).into(),
+        root_hash: [0; 64],
+        salt: [0; 32],
+        reserved: [0; 144],
+    };
+    descriptor.root_hash[..32].copy_from_slice(&root_hash(data));
+    Sha256::digest(descriptor.as_bytes())
+}
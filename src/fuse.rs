@@ -0,0 +1,684 @@
+//! A read-only FUSE filesystem over a zstd:chunked layer, for lazy pulling.
+//!
+//! Only the metadata needs to be fetched up front: the tree is indexed from the tar headers in
+//! the tarsplit, and the content of each file is resolved when (and only as far as) it's read.
+//! This allows starting a container without waiting for all of its layers to be pulled.
+//!
+//! Content is verified against its digest the first time it's read (with a [`LazyVerifier`]),
+//! and reads of content that doesn't match fail with `EIO`.
+
+use core::{fmt, time::Duration};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    ffi::{OsStr, OsString},
+    io::{self, Read, Seek, SeekFrom},
+    os::unix::ffi::OsStrExt,
+    path::{Component, Path},
+    sync::{Arc, Mutex, PoisonError},
+    time::UNIX_EPOCH,
+};
+
+use anyhow::{Context, Result, bail};
+use fuser::{
+    BackgroundSession, Config, Errno, FileAttr, FileHandle, FileType, Filesystem, Generation,
+    INodeNo, LockOwner, MountOption, OpenFlags, ReplyAttr, ReplyData, ReplyDirectory, ReplyEntry,
+    Request,
+};
+use tar::EntryType;
+
+use crate::{ContentReference, Stream, verify::LazyVerifier};
+
+// The layer never changes, so the kernel can cache everything for as long as it likes
+const TTL: Duration = Duration::from_hours(1);
+
+// The number of resolved chunks kept around, since the kernel reads large files in pieces
+const RECENT_CHUNKS: usize = 8;
+
+type RecentChunks = VecDeque<(Arc<str>, Arc<[u8]>)>;
+
+#[derive(Debug)]
+enum Data {
+    None,
+    // The offset of the content in the reconstructed stream
+    File(u64),
+    Symlink(OsString),
+}
+
+#[derive(Debug)]
+struct Inode {
+    parent: u64,
+    kind: FileType,
+    perm: u16,
+    uid: u32,
+    gid: u32,
+    mtime: u64,
+    rdev: u32,
+    nlink: u32,
+    size: u64,
+    data: Data,
+    children: BTreeMap<OsString, u64>,
+}
+
+impl Inode {
+    const fn directory(parent: u64) -> Self {
+        Self {
+            parent,
+            kind: FileType::Directory,
+            perm: 0o755,
+            uid: 0,
+            gid: 0,
+            mtime: 0,
+            rdev: 0,
+            nlink: 2,
+            size: 0,
+            data: Data::None,
+            children: BTreeMap::new(),
+        }
+    }
+}
+
+/// A read-only FUSE filesystem showing the content of a layer.
+///
+/// Regular files are read through the `resolve_reference()` function (as for
+/// [`Stream::write_to()`]), which is called only for the chunks covering the offsets being read,
+/// and the content is verified before it's used.  A few recently resolved chunks are kept in
+/// memory, but the function should do its own caching (for example, in a
+/// [`crate::store::ChunkStore`]) to avoid fetching content more than once.
+pub struct LayerFs<R> {
+    stream: Stream,
+    verifier: LazyVerifier<R>,
+    inodes: Vec<Inode>,
+    recent: Mutex<RecentChunks>,
+}
+
+impl<R> fmt::Debug for LayerFs<R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LayerFs")
+            .field("inodes", &self.inodes.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl<R> LayerFs<R>
+where
+    R: Fn(&ContentReference) -> Result<Vec<u8>> + Send + Sync + 'static,
+{
+    /// Creates the filesystem, indexing the tree from the tar headers in the stream.  This
+    /// doesn't resolve any content.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the headers can't be parsed, or if they describe something that can't be shown
+    /// as a tree (such as hard links to missing files).
+    pub fn new(stream: Stream, resolve_reference: R) -> Result<Self> {
+        let mut fs = Self {
+            stream,
+            verifier: LazyVerifier::new(resolve_reference),
+            inodes: vec![Inode::directory(1)],
+            recent: Mutex::default(),
+        };
+        fs.index()?;
+        Ok(fs)
+    }
+
+    /// Mounts the filesystem at `mountpoint` and serves requests until it's unmounted.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the filesystem can't be mounted.
+    pub fn mount(self, mountpoint: impl AsRef<Path>) -> io::Result<()> {
+        fuser::mount(self, mountpoint, &Self::config())
+    }
+
+    /// Mounts the filesystem at `mountpoint` and serves requests from a background thread.  The
+    /// filesystem is unmounted when the returned session is dropped.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the filesystem can't be mounted.
+    pub fn spawn_mount(self, mountpoint: impl AsRef<Path>) -> io::Result<BackgroundSession> {
+        fuser::spawn_mount(self, mountpoint, &Self::config())
+    }
+
+    fn config() -> Config {
+        let mut config = Config::default();
+        config.mount_options = vec![
+            MountOption::FSName("zstd-chunked".into()),
+            MountOption::Subtype("zstd-chunked".into()),
+            MountOption::RO,
+            MountOption::DefaultPermissions,
+        ];
+        config
+    }
+
+    fn index(&mut self) -> Result<()> {
+        let mut inodes = vec![Inode::directory(1)];
+        let mut archive = tar::Archive::new(self.stream.reader(|r| self.resolve(r)));
+        for entry in archive.entries_with_seek()? {
+            let entry = entry?;
+            let header = entry.header();
+            let path = entry.path()?;
+            let Some((parent, name)) = parent_and_name(&mut inodes, &path)? else {
+                // The root directory itself
+                if header.entry_type() == EntryType::Directory
+                    && let Some(root) = inodes.first_mut()
+                {
+                    set_metadata(root, header)?;
+                }
+                continue;
+            };
+
+            let (kind, data) = match header.entry_type() {
+                EntryType::Regular | EntryType::Continuous => {
+                    (FileType::RegularFile, Data::File(entry.raw_file_position()))
+                }
+                EntryType::Directory => (FileType::Directory, Data::None),
+                EntryType::Symlink => {
+                    let target = entry.link_name()?.context("Symlink without a target")?;
+                    (FileType::Symlink, Data::Symlink(target.into_owned().into()))
+                }
+                EntryType::Char => (FileType::CharDevice, Data::None),
+                EntryType::Block => (FileType::BlockDevice, Data::None),
+                EntryType::Fifo => (FileType::NamedPipe, Data::None),
+                EntryType::Link => {
+                    let target = entry.link_name()?.context("Hard link without a target")?;
+                    let ino = lookup_path(&inodes, &target)
+                        .with_context(|| format!("Hard link to missing {}", target.display()))?;
+                    if let Some(inode) = inode_mut(&mut inodes, ino) {
+                        inode.nlink += 1;
+                    }
+                    insert_child(&mut inodes, parent, name, ino);
+                    continue;
+                }
+                _ => continue,
+            };
+
+            // A later entry for an existing directory updates it, rather than emptying it
+            let existing = inode(&inodes, parent).and_then(|inode| inode.children.get(&name));
+            if let Some(&ino) = existing
+                && kind == FileType::Directory
+                && let Some(inode) = inode_mut(&mut inodes, ino)
+                && inode.kind == FileType::Directory
+            {
+                set_metadata(inode, header)?;
+                continue;
+            }
+
+            let mut inode = Inode::directory(parent);
+            inode.kind = kind;
+            inode.nlink = if kind == FileType::Directory { 2 } else { 1 };
+            inode.size = match &data {
+                Data::File(_) => entry.size(),
+                Data::Symlink(target) => target.len() as u64,
+                Data::None => 0,
+            };
+            inode.data = data;
+            set_metadata(&mut inode, header)?;
+            inodes.push(inode);
+            let ino = inodes.len() as u64;
+            insert_child(&mut inodes, parent, name, ino);
+        }
+        drop(archive);
+
+        // Each subdirectory links back to its parent with ".."
+        for ino in 2..=inodes.len() as u64 {
+            let Some(&Inode {
+                kind: FileType::Directory,
+                parent,
+                ..
+            }) = inode(&inodes, ino)
+            else {
+                continue;
+            };
+            if let Some(parent) = inode_mut(&mut inodes, parent) {
+                parent.nlink += 1;
+            }
+        }
+
+        self.inodes = inodes;
+        Ok(())
+    }
+
+    fn resolve(&self, reference: &ContentReference) -> Result<Vec<u8>> {
+        let mut recent = self.recent.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some((_, data)) = recent
+            .iter()
+            .find(|(digest, _)| *digest == reference.digest)
+        {
+            return Ok(data.to_vec());
+        }
+        // Don't hold the lock while the content is being fetched
+        drop(recent);
+
+        let data = self.verifier.read(reference)?;
+        recent = self.recent.lock().unwrap_or_else(PoisonError::into_inner);
+        recent.push_front((Arc::clone(&reference.digest), data.as_slice().into()));
+        recent.truncate(RECENT_CHUNKS);
+        drop(recent);
+        Ok(data)
+    }
+
+    fn read_file(&self, inode: &Inode, offset: u64, size: u32) -> io::Result<Vec<u8>> {
+        let Data::File(start) = inode.data else {
+            return Err(io::ErrorKind::InvalidInput.into());
+        };
+        let end = inode.size.min(offset.saturating_add(size.into()));
+        let mut data = vec![0; usize::try_from(end.saturating_sub(offset)).unwrap_or_default()];
+        let mut reader = self.stream.reader(|r| self.resolve(r));
+        reader.seek(SeekFrom::Start(start + offset))?;
+        reader.read_exact(&mut data)?;
+        Ok(data)
+    }
+
+    fn attr(&self, ino: u64) -> Option<FileAttr> {
+        let inode = inode(&self.inodes, ino)?;
+        let mtime = UNIX_EPOCH + Duration::from_secs(inode.mtime);
+        Some(FileAttr {
+            ino: INodeNo(ino),
+            size: inode.size,
+            blocks: inode.size.div_ceil(512),
+            atime: mtime,
+            mtime,
+            ctime: mtime,
+            crtime: mtime,
+            kind: inode.kind,
+            perm: inode.perm,
+            nlink: inode.nlink,
+            uid: inode.uid,
+            gid: inode.gid,
+            rdev: inode.rdev,
+            blksize: 4096,
+            flags: 0,
+        })
+    }
+}
+
+// The error to report for a failed read.  Content that fails verification is an I/O error as
+// far as readers are concerned, as is anything else that goes wrong while resolving it.
+fn errno(err: &io::Error) -> Errno {
+    if err.kind() == io::ErrorKind::InvalidInput {
+        Errno::EISDIR
+    } else {
+        Errno::EIO
+    }
+}
+
+impl<R> Filesystem for LayerFs<R>
+where
+    R: Fn(&ContentReference) -> Result<Vec<u8>> + Send + Sync + 'static,
+{
+    fn lookup(&self, _req: &Request, parent: INodeNo, name: &OsStr, reply: ReplyEntry) {
+        match inode(&self.inodes, parent.into())
+            .and_then(|inode| inode.children.get(name))
+            .and_then(|&ino| self.attr(ino))
+        {
+            Some(attr) => reply.entry(&TTL, &attr, Generation(0)),
+            None => reply.error(Errno::ENOENT),
+        }
+    }
+
+    fn getattr(&self, _req: &Request, ino: INodeNo, _fh: Option<FileHandle>, reply: ReplyAttr) {
+        match self.attr(ino.into()) {
+            Some(attr) => reply.attr(&TTL, &attr),
+            None => reply.error(Errno::ENOENT),
+        }
+    }
+
+    fn readlink(&self, _req: &Request, ino: INodeNo, reply: ReplyData) {
+        match inode(&self.inodes, ino.into()).map(|inode| &inode.data) {
+            Some(Data::Symlink(target)) => reply.data(target.as_bytes()),
+            Some(_) => reply.error(Errno::EINVAL),
+            None => reply.error(Errno::ENOENT),
+        }
+    }
+
+    fn read(
+        &self,
+        _req: &Request,
+        ino: INodeNo,
+        _fh: FileHandle,
+        offset: u64,
+        size: u32,
+        _flags: OpenFlags,
+        _lock_owner: Option<LockOwner>,
+        reply: ReplyData,
+    ) {
+        let Some(inode) = inode(&self.inodes, ino.into()) else {
+            return reply.error(Errno::ENOENT);
+        };
+        match self.read_file(inode, offset, size) {
+            Ok(data) => reply.data(&data),
+            Err(err) => reply.error(errno(&err)),
+        }
+    }
+
+    fn readdir(
+        &self,
+        _req: &Request,
+        ino: INodeNo,
+        _fh: FileHandle,
+        offset: u64,
+        mut reply: ReplyDirectory,
+    ) {
+        let Some(inode) = inode(&self.inodes, ino.into()) else {
+            return reply.error(Errno::ENOENT);
+        };
+        if inode.kind != FileType::Directory {
+            return reply.error(Errno::ENOTDIR);
+        }
+
+        let dots = [(ino.into(), "."), (inode.parent, "..")]
+            .into_iter()
+            .map(|(ino, name)| (ino, OsStr::new(name)));
+        let children = inode.children.iter().map(|(name, &ino)| (ino, &**name));
+        let entries = dots.chain(children).enumerate();
+        for (index, (ino, name)) in entries.skip(usize::try_from(offset).unwrap_or(usize::MAX)) {
+            let kind = self
+                .inodes
+                .get(index_of(ino))
+                .map_or(FileType::RegularFile, |i| i.kind);
+            // The offset is that of the next entry
+            if reply.add(INodeNo(ino), index as u64 + 1, kind, name) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+}
+
+fn index_of(ino: u64) -> usize {
+    usize::try_from(ino.saturating_sub(1)).unwrap_or(usize::MAX)
+}
+
+fn inode(inodes: &[Inode], ino: u64) -> Option<&Inode> {
+    inodes.get(index_of(ino))
+}
+
+fn inode_mut(inodes: &mut [Inode], ino: u64) -> Option<&mut Inode> {
+    inodes.get_mut(index_of(ino))
+}
+
+fn insert_child(inodes: &mut [Inode], parent: u64, name: OsString, ino: u64) {
+    if let Some(parent) = inode_mut(inodes, parent) {
+        parent.children.insert(name, ino);
+    }
+}
+
+// The names of the components of a path in the archive, which are all relative to the root
+fn components(path: &Path) -> Result<Vec<&OsStr>> {
+    let mut names = vec![];
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => names.push(name),
+            Component::CurDir | Component::RootDir => {}
+            Component::ParentDir | Component::Prefix(_) => {
+                bail!("Unsupported path {} in layer", path.display())
+            }
+        }
+    }
+    Ok(names)
+}
+
+fn lookup_path(inodes: &[Inode], path: &Path) -> Option<u64> {
+    components(path).ok()?.into_iter().try_fold(1, |ino, name| {
+        inode(inodes, ino)?.children.get(name).copied()
+    })
+}
+
+// Finds (or creates) the directory containing a path, returning None for the root itself
+fn parent_and_name(inodes: &mut Vec<Inode>, path: &Path) -> Result<Option<(u64, OsString)>> {
+    let mut names = components(path)?;
+    let Some(name) = names.pop() else {
+        return Ok(None);
+    };
+
+    let mut parent = 1;
+    for dir in names {
+        let existing = inode(inodes, parent).and_then(|inode| inode.children.get(dir));
+        parent = match existing.copied() {
+            Some(ino) if inode(inodes, ino).is_some_and(|i| i.kind == FileType::Directory) => ino,
+            Some(_) => bail!("Parent of {} is not a directory", path.display()),
+            None => {
+                // Directories don't need entries of their own in the archive
+                inodes.push(Inode::directory(parent));
+                let ino = inodes.len() as u64;
+                insert_child(inodes, parent, dir.to_owned(), ino);
+                ino
+            }
+        };
+    }
+    Ok(Some((parent, name.to_owned())))
+}
+
+fn set_metadata(inode: &mut Inode, header: &tar::Header) -> Result<()> {
+    inode.perm = u16::try_from(header.mode()? & 0o7777)?;
+    inode.uid = u32::try_from(header.uid()?)?;
+    inode.gid = u32::try_from(header.gid()?)?;
+    inode.mtime = header.mtime()?;
+    if matches!(header.entry_type(), EntryType::Char | EntryType::Block)
+        && let (Some(major), Some(minor)) = (header.device_major()?, header.device_minor()?)
+    {
+        // The kernel's encoding of the device number, as for makedev()
+        inode.rdev = ((major & 0xfff) << 8) | (minor & 0xff) | ((minor & 0xfff00) << 12);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::{HashMap, HashSet},
+        mem,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    use base64::{Engine, prelude::BASE64_STANDARD};
+    use serde_json::{Value, json};
+
+    use super::*;
+    use crate::{digest, verify::VerificationFailed};
+
+    // An entry of a test layer: its name, type, link target (or content) and device numbers
+    type TestEntry<'a> = (&'a str, EntryType, &'a [u8], (u32, u32));
+
+    type TestFs = LayerFs<Box<dyn Fn(&ContentReference) -> Result<Vec<u8>> + Send + Sync>>;
+
+    // The content of the files, by digest, and how many times it was resolved
+    #[derive(Default)]
+    struct Content {
+        files: HashMap<String, Vec<u8>>,
+        corrupt: HashSet<String>,
+        resolved: AtomicUsize,
+    }
+
+    // Moves the pending tar headers and padding into a segment of the tarsplit
+    fn segment(inline: &mut Vec<u8>, tarsplit: &mut Vec<Value>) {
+        let payload = BASE64_STANDARD.encode(mem::take(inline));
+        tarsplit.push(json!({"type": 2, "payload": payload}));
+    }
+
+    // Builds a layer with the content of the files outside of the tarsplit, as in a real layer
+    fn layer(entries: &[TestEntry<'_>]) -> Result<(Stream, Content)> {
+        let mut content = Content::default();
+        let (mut manifest, mut tarsplit, mut inline) = (vec![], vec![], vec![]);
+        for (offset, &(name, kind, link_or_content, (major, minor))) in entries.iter().enumerate() {
+            let mut header = tar::Header::new_gnu();
+            header.set_path(name)?;
+            header.set_entry_type(kind);
+            header.set_mode(if kind.is_dir() { 0o750 } else { 0o640 });
+            header.set_uid(1000);
+            header.set_gid(1000);
+            header.set_mtime(offset as u64);
+            header.set_device_major(major)?;
+            header.set_device_minor(minor)?;
+            let data = if kind.is_symlink() || kind.is_hard_link() {
+                header.set_link_name(OsStr::from_bytes(link_or_content))?;
+                &[]
+            } else {
+                link_or_content
+            };
+            header.set_size(data.len() as u64);
+            header.set_cksum();
+            inline.extend_from_slice(header.as_bytes());
+            if data.is_empty() {
+                continue;
+            }
+
+            segment(&mut inline, &mut tarsplit);
+            tarsplit.push(json!({"type": 1, "name": name, "size": data.len()}));
+            // The offsets only need to be distinct, since the content is resolved by digest
+            let digest = digest::sha256(data);
+            manifest.push(json!({
+                "type": "reg",
+                "name": name,
+                "size": data.len(),
+                "digest": digest,
+                "offset": offset,
+                "endOffset": offset + 1,
+            }));
+            content.files.insert(digest, data.to_vec());
+            inline.resize(data.len().next_multiple_of(512) - data.len(), 0);
+        }
+        inline.resize(inline.len() + 1024, 0);
+        segment(&mut inline, &mut tarsplit);
+
+        let manifest = json!({"version": 1, "entries": manifest}).to_string();
+        let tarsplit = tarsplit
+            .iter()
+            .map(Value::to_string)
+            .collect::<Vec<_>>()
+            .join("\n");
+        let stream = Stream::new_from_frames(
+            &zstd::encode_all(manifest.as_bytes(), 0)?,
+            &zstd::encode_all(tarsplit.as_bytes(), 0)?,
+        )?;
+        Ok((stream, content))
+    }
+
+    fn mount(entries: &[TestEntry<'_>], corrupt: &[&[u8]]) -> Result<(TestFs, Arc<Content>)> {
+        let (stream, mut content) = layer(entries)?;
+        content.corrupt = corrupt.iter().map(|data| digest::sha256(data)).collect();
+        let content = Arc::new(content);
+        let shared = Arc::clone(&content);
+        let fs = LayerFs::new(
+            stream,
+            Box::new(move |reference: &ContentReference| {
+                shared.resolved.fetch_add(1, Ordering::Relaxed);
+                let mut data = shared
+                    .files
+                    .get(&*reference.digest)
+                    .cloned()
+                    .context("Unknown content")?;
+                if shared.corrupt.contains(&*reference.digest) {
+                    data.reverse();
+                }
+                Ok(data)
+            }) as Box<_>,
+        )?;
+        Ok((fs, content))
+    }
+
+    fn lookup<'a, R>(fs: &'a LayerFs<R>, path: &str) -> Option<&'a Inode> {
+        inode(&fs.inodes, lookup_path(&fs.inodes, Path::new(path))?)
+    }
+
+    #[test]
+    fn tree_is_indexed_from_the_headers() -> Result<()> {
+        use EntryType::{Block, Char, Directory, Link, Regular, Symlink};
+
+        let (fs, content) = mount(
+            &[
+                ("./", Directory, b"", (0, 0)),
+                ("dir/", Directory, b"", (0, 0)),
+                ("dir/file", Regular, b"content", (0, 0)),
+                ("implicit/sub/file", Regular, b"", (0, 0)),
+                ("hardlink", Link, b"dir/file", (0, 0)),
+                ("symlink", Symlink, b"dir/file", (0, 0)),
+                ("char", Char, b"", (0x123, 0x4_5678)),
+                ("block", Block, b"", (8, 1)),
+                // Updates the directory, keeping its content
+                ("dir", Directory, b"", (0, 0)),
+            ],
+            &[],
+        )?;
+        assert_eq!(content.resolved.load(Ordering::Relaxed), 0);
+
+        let root = lookup(&fs, "").context("No root")?;
+        assert_eq!((root.perm, root.uid, root.nlink), (0o750, 1000, 4));
+        let dir = lookup(&fs, "dir").context("No dir")?;
+        assert_eq!((dir.perm, dir.mtime, dir.nlink), (0o750, 8, 2));
+        assert!(dir.children.contains_key(OsStr::new("file")));
+
+        // Parents without entries of their own
+        let implicit = lookup(&fs, "implicit").context("No implicit parent")?;
+        assert_eq!(
+            (implicit.kind, implicit.perm, implicit.nlink),
+            (FileType::Directory, 0o755, 3)
+        );
+        let file = lookup(&fs, "implicit/sub/file").context("No implicit/sub/file")?;
+        assert_eq!((file.kind, file.size), (FileType::RegularFile, 0));
+
+        assert_eq!(
+            lookup_path(&fs.inodes, Path::new("hardlink")),
+            lookup_path(&fs.inodes, Path::new("dir/file"))
+        );
+        let file = lookup(&fs, "dir/file").context("No dir/file")?;
+        assert_eq!((file.nlink, file.size), (2, 7));
+
+        let symlink = lookup(&fs, "symlink").context("No symlink")?;
+        assert!(matches!(&symlink.data, Data::Symlink(target) if target == "dir/file"));
+        assert_eq!(symlink.size, 8);
+
+        // As the kernel's makedev() would encode them
+        let char = lookup(&fs, "char").context("No char")?;
+        assert_eq!((char.kind, char.rdev), (FileType::CharDevice, 0x4561_2378));
+        let block = lookup(&fs, "block").context("No block")?;
+        assert_eq!((block.kind, block.rdev), (FileType::BlockDevice, 0x801));
+        Ok(())
+    }
+
+    #[test]
+    fn hardlinks_to_missing_files_are_rejected() {
+        let result = mount(&[("hardlink", EntryType::Link, b"missing", (0, 0))], &[]);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn files_are_read_lazily_and_verified() -> Result<()> {
+        let (fs, content) = mount(
+            &[
+                ("dir/", EntryType::Directory, b"", (0, 0)),
+                ("good", EntryType::Regular, b"good content", (0, 0)),
+                ("bad", EntryType::Regular, b"bad content", (0, 0)),
+            ],
+            &[b"bad content"],
+        )?;
+        let good = lookup(&fs, "good").context("No good")?;
+        assert_eq!(fs.read_file(good, 0, 4096)?, b"good content");
+        assert_eq!(fs.read_file(good, 5, 3)?, b"con");
+        assert_eq!(fs.read_file(good, 100, 10)?, b"");
+        // Only resolved once, and then kept
+        assert_eq!(content.resolved.load(Ordering::Relaxed), 1);
+
+        let dir = lookup(&fs, "dir").context("No dir")?;
+        let err = fs.read_file(dir, 0, 10).err().context("Read a directory")?;
+        assert_eq!(errno(&err), Errno::EISDIR);
+
+        // Content that doesn't match its digest can't be read, even in part
+        let bad = lookup(&fs, "bad").context("No bad")?;
+        let failed = VerificationFailed {
+            digest: digest::sha256(b"bad content").into(),
+        }
+        .to_string();
+        for (offset, size) in [(0, 4096), (4, 3)] {
+            let err = fs.read_file(bad, offset, size).err().context("Read bad")?;
+            assert_eq!(errno(&err), Errno::EIO);
+            let mut source = err.get_ref().map(|err| err as &dyn std::error::Error);
+            while let Some(err) = source.filter(|err| err.to_string() != failed) {
+                source = err.source();
+            }
+            assert!(source.is_some(), "{err:?}");
+        }
+        Ok(())
+    }
+}
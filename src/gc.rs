@@ -0,0 +1,219 @@
+//! Working out what deleting images from a store would free, and what it would break.
+//!
+//! Chunks are shared between images, so deleting all of the objects used by one image can break
+//! others.  A [`UsageIndex`] records which images use each object, and answers the reverse
+//! questions: who uses this chunk, and what does this image share with the rest?
+//!
+//! ```
+//! # use zstd_chunked::{Stream, gc::UsageIndex, store::Backend};
+//! # fn example(old: &Stream, new: &Stream, store: &dyn Backend) -> anyhow::Result<()> {
+//! let mut index = UsageIndex::new();
+//! index.add_image("old", &[old]);
+//! index.add_image("new", &[new]);
+//! let removal = index.removal(&["old"], store)?;
+//! println!("{} bytes freed", removal.freed_size);
+//! for (image, size) in &removal.shared {
+//!     println!("{size} bytes shared with {image}");
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+};
+
+use anyhow::Result;
+
+use crate::{
+    Stream,
+    store::{Backend, ChunkStore},
+};
+
+#[derive(Debug, Clone, Default)]
+struct Object {
+    size: u64,
+    users: Vec<usize>,
+}
+
+/// For each object, the images which use it.
+#[derive(Debug, Clone, Default)]
+pub struct UsageIndex {
+    images: Vec<String>,
+    objects: HashMap<String, Object>,
+}
+
+impl UsageIndex {
+    /// Creates an empty index.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Builds an index from the refs of a store (see [`ChunkStore::set_ref()`]), with one image
+    /// per ref.  Objects are sized by their files in the store, or 0 if they're missing.
+    ///
+    /// # Errors
+    ///
+    /// Fails if any of the refs can't be read.
+    pub fn from_store(store: &ChunkStore) -> Result<Self> {
+        let mut index = Self::new();
+        for name in store.refs()? {
+            let Some(digests) = store.get_ref(&name)? else {
+                continue; // removed since listing
+            };
+            let mut objects = vec![];
+            for digest in digests {
+                let size = fs::metadata(store.path(&digest)?).map_or(0, |meta| meta.len());
+                objects.push((digest, size));
+            }
+            index.add_objects(&name, objects);
+        }
+        Ok(index)
+    }
+
+    /// Records the content used by an image, given as the list of its layers.  Objects are sized
+    /// by their uncompressed content.  Adding more content under an existing name extends it.
+    pub fn add_image(&mut self, name: &str, layers: &[&Stream]) {
+        self.add_objects(
+            name,
+            layers
+                .iter()
+                .flat_map(|stream| stream.references())
+                .map(|reference| (reference.digest.to_string(), reference.size)),
+        );
+    }
+
+    /// Records objects (digests and sizes) as used by the named image.
+    pub fn add_objects(&mut self, name: &str, objects: impl IntoIterator<Item = (String, u64)>) {
+        let image = self.image_index(name).unwrap_or_else(|| {
+            self.images.push(name.to_owned());
+            self.images.len() - 1
+        });
+        for (digest, size) in objects {
+            let object = self.objects.entry(digest).or_default();
+            object.size = object.size.max(size);
+            if !object.users.contains(&image) {
+                object.users.push(image);
+            }
+        }
+    }
+
+    /// The names of the images in the index, in the order that they were added.
+    pub fn images(&self) -> impl Iterator<Item = &str> {
+        self.images.iter().map(String::as_str)
+    }
+
+    /// The number of distinct objects in the index.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.objects.len()
+    }
+
+    /// Checks if the index has no objects.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.objects.is_empty()
+    }
+
+    /// The images which use the object with the given digest (none, if it isn't in the index).
+    pub fn users(&self, digest: &str) -> impl Iterator<Item = &str> {
+        self.objects
+            .get(digest)
+            .into_iter()
+            .flat_map(|object| &object.users)
+            .map(|&image| self.images[image].as_str())
+    }
+
+    /// For each other image, the size of the objects it shares with the named image.  Images that
+    /// share nothing are left out.
+    #[must_use]
+    pub fn shared_with(&self, name: &str) -> BTreeMap<String, u64> {
+        let mut shared = BTreeMap::new();
+        let Some(image) = self.image_index(name) else {
+            return shared;
+        };
+        for object in self.objects.values() {
+            if object.users.contains(&image) {
+                for &other in object.users.iter().filter(|&&other| other != image) {
+                    *shared.entry(self.images[other].clone()).or_default() += object.size;
+                }
+            }
+        }
+        shared
+    }
+
+    /// Works out what deleting the named images would do to the store: which objects could be
+    /// removed, and which are still needed by the remaining images.  Only objects present in the
+    /// store are counted.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the store fails.
+    pub fn removal(&self, names: &[&str], store: &dyn Backend) -> Result<Removal> {
+        let removed: Vec<usize> = names
+            .iter()
+            .filter_map(|name| self.image_index(name))
+            .collect();
+
+        let mut removal = Removal::default();
+        for (digest, object) in &self.objects {
+            if !object.users.iter().any(|image| removed.contains(image)) {
+                continue;
+            }
+            if !store.contains(digest)? {
+                removal.missing += 1;
+                continue;
+            }
+            let mut others = object
+                .users
+                .iter()
+                .filter(|image| !removed.contains(image))
+                .peekable();
+            if others.peek().is_none() {
+                removal.freed.push(digest.clone());
+                removal.freed_size += object.size;
+            } else {
+                removal.retained += 1;
+                removal.retained_size += object.size;
+                for &image in others {
+                    *removal
+                        .shared
+                        .entry(self.images[image].clone())
+                        .or_default() += object.size;
+                }
+            }
+        }
+        removal.freed.sort_unstable();
+        Ok(removal)
+    }
+
+    fn image_index(&self, name: &str) -> Option<usize> {
+        self.images.iter().position(|image| image == name)
+    }
+}
+
+/// The effect of deleting some images from a store, from [`UsageIndex::removal()`].
+#[derive(Debug, Clone, Default)]
+pub struct Removal {
+    /// The objects used only by the deleted images, which can safely be removed, sorted.
+    pub freed: Vec<String>,
+
+    /// The total size of the freed objects.
+    pub freed_size: u64,
+
+    /// The number of objects used by the deleted images which must be kept, because other images
+    /// still use them.  Removing them anyway would break those images.
+    pub retained: u64,
+
+    /// The total size of the retained objects.
+    pub retained_size: u64,
+
+    /// For each remaining image that shares objects with the deleted ones, the size of those
+    /// objects: what it would lose if the deleted images' objects were removed regardless.
+    pub shared: BTreeMap<String, u64>,
+
+    /// The number of objects used by the deleted images which aren't in the store at all.
+    pub missing: u64,
+}
@@ -0,0 +1,160 @@
+//! Deciding when to give up on a flaky transport.
+//!
+//! A [`HealthTracker`] keeps score ("karma") of how well a transfer is going.  Each byte received
+//! adds 1 and each failure takes 1 away, and the score decays exponentially over time.  This means
+//! that as long as progress is steady, even with really slow download speeds (think 10bytes/sec),
+//! a large number of errors can be tolerated, but once forward progress stops and the decay sets
+//! in, patience for errors decreases rapidly.  A failure that takes the score below zero is fatal,
+//! so (without any initial credit) a single error at the start is too, which feels correct.
+//!
+//! Pullers use this to decide whether to retry, and so can `http::HttpBlob` (with the `http`
+//! feature).  Other transports can use it the same way: report progress and failures, and retry
+//! (after [`RetryPolicy::retry_delay()`]) for as long as [`HealthTracker::failure()`] says so.
+
+use core::time::Duration;
+use std::{
+    sync::{Mutex, PoisonError},
+    time::Instant,
+};
+
+/// The settings for a [`HealthTracker`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+    decay: Duration,
+    initial_credit: f64,
+    max_retries: Option<u32>,
+    retry_delay: Duration,
+}
+
+impl RetryPolicy {
+    /// Creates the default policy: karma decays with a time constant of a second, there's no
+    /// initial credit or limit on the number of retries, and retries wait a second.
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            decay: Duration::from_secs(1),
+            initial_credit: 0.,
+            max_retries: None,
+            retry_delay: Duration::from_secs(1),
+        }
+    }
+
+    /// Sets the time constant of the decay: karma drops to 37% of its value after this long.
+    #[must_use]
+    pub const fn with_decay(mut self, decay: Duration) -> Self {
+        self.decay = decay;
+        self
+    }
+
+    /// Sets the karma to start with, which is the number of failures that are tolerated before
+    /// any progress has been made.
+    #[must_use]
+    pub const fn with_initial_credit(mut self, credit: f64) -> Self {
+        self.initial_credit = credit;
+        self
+    }
+
+    /// Gives up after this many failures in total, however well the transfer is going otherwise.
+    #[must_use]
+    pub const fn with_max_retries(mut self, retries: u32) -> Self {
+        self.max_retries = Some(retries);
+        self
+    }
+
+    /// Sets how long to wait before retrying after a failure.
+    #[must_use]
+    pub const fn with_retry_delay(mut self, delay: Duration) -> Self {
+        self.retry_delay = delay;
+        self
+    }
+
+    /// How long to wait before retrying after a failure.
+    #[must_use]
+    pub const fn retry_delay(&self) -> Duration {
+        self.retry_delay
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// The Chameleon keeps track of how well the download is going
+#[derive(Debug)]
+struct Chameleon {
+    // 🌈🦎📊
+    karma: f64,
+    updated: Instant,
+    failures: u32,
+}
+
+/// Keeps score of how well a transfer (or a group of them) is going, to decide whether failures
+/// are worth retrying.  See the [module documentation](self).
+///
+/// Trackers can be shared between threads and tasks, for transfers that should give up together.
+#[derive(Debug)]
+pub struct HealthTracker {
+    policy: RetryPolicy,
+    state: Mutex<Chameleon>,
+}
+
+impl HealthTracker {
+    /// Creates a tracker with the given policy, starting now.
+    #[must_use]
+    pub fn new(policy: RetryPolicy) -> Self {
+        Self {
+            policy,
+            state: Mutex::new(Chameleon {
+                karma: policy.initial_credit,
+                updated: Instant::now(),
+                failures: 0,
+            }),
+        }
+    }
+
+    /// The policy of the tracker.
+    #[must_use]
+    pub const fn policy(&self) -> &RetryPolicy {
+        &self.policy
+    }
+
+    // Applies the decay since the last update, and then the change
+    fn update(&self, delta: f64, failure: bool) -> (f64, u32) {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        let now = Instant::now();
+        let elapsed = now.duration_since(state.updated).as_secs_f64();
+        let decay = self.policy.decay.as_secs_f64().max(f64::MIN_POSITIVE);
+        // First order exponential decay
+        state.karma = state.karma / (elapsed / decay).exp() + delta;
+        state.updated = now;
+        state.failures += u32::from(failure);
+        (state.karma, state.failures)
+    }
+
+    /// The current karma.
+    #[must_use]
+    pub fn karma(&self) -> f64 {
+        self.update(0., false).0
+    }
+
+    /// The number of failures recorded so far.
+    #[must_use]
+    pub fn failures(&self) -> u32 {
+        self.update(0., false).1
+    }
+
+    /// Records that some bytes were received.
+    #[allow(clippy::cast_precision_loss)]
+    pub fn progress(&self, bytes: u64) {
+        self.update(bytes as f64, false);
+    }
+
+    /// Records a failure.  Returns true if it's worth retrying, or false if it's time to give up.
+    #[must_use]
+    pub fn failure(&self) -> bool {
+        let (karma, failures) = self.update(-1., true);
+        karma >= 0. && self.policy.max_retries.is_none_or(|max| failures <= max)
+    }
+}
@@ -0,0 +1,431 @@
+//! Reading blobs over HTTP with range requests.
+//!
+//! [`HttpBlob`] is a [`BlobReader`] for a blob at a URL, like a layer in a registry (given a token
+//! for it) or a file on a plain web server.  Each read is a `Range` request, which is retried (and
+//! resumed from where it stopped) after network errors, server errors and responses that are cut
+//! short.  Servers that ignore the range and send the whole blob work too, just slowly.  By
+//! default, failed requests are retried a fixed number of times, but
+//! [`HttpBlob::with_retry_policy()`] retries for as long as the reads keep making progress instead.
+//!
+//! [`HttpBlob::read_references()`] fetches the data for many references at once.  Nearby ranges
+//! are merged by a [`RangePlanner`], and servers that support `multipart/byteranges` responses can
+//! be asked for several ranges per request with [`HttpBlob::with_max_ranges()`].
+//!
+//! This uses the blocking `reqwest` client, which can't be used from within an async runtime:
+//! call it from `spawn_blocking()` or a thread of its own.
+
+use core::{ops::Range, time::Duration};
+use std::{
+    collections::HashMap,
+    io::{self, Read},
+    sync::OnceLock,
+    thread,
+};
+
+use reqwest::{
+    StatusCode,
+    blocking::{Client, RequestBuilder, Response},
+    header::{CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, HeaderMap, RANGE},
+};
+
+use crate::{
+    ContentReference,
+    blob::BlobReader,
+    health::{HealthTracker, RetryPolicy},
+    range::{self, RangeBuffer, RangePlanner},
+};
+
+/// A blob at a URL, read with HTTP range requests.
+#[derive(Debug)]
+pub struct HttpBlob {
+    client: Client,
+    url: reqwest::Url,
+    headers: HeaderMap,
+    retries: u32,
+    retry_delay: Duration,
+    retry_policy: Option<RetryPolicy>,
+    max_ranges: usize,
+    size: OnceLock<u64>,
+}
+
+// The outcome of one attempt: failures that are worth retrying are kept apart from the rest
+enum Attempt<T> {
+    Done(T),
+    Retry(io::Error),
+}
+
+// The ranges that a server sent in response to a request for several, with their data
+type Parts = Vec<(Range<u64>, Vec<u8>)>;
+
+fn other(err: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> io::Error {
+    io::Error::other(err)
+}
+
+// Checks the status of a response, sorting errors into those that may go away by themselves and
+// those that won't
+fn check_status(response: Response, range: &Range<u64>) -> io::Result<Attempt<Response>> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(Attempt::Done(response));
+    }
+    let err = other(format!("HTTP {status} from {}", response.url()));
+    match status {
+        StatusCode::RANGE_NOT_SATISFIABLE => Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!("Range {range:?} is outside of the blob"),
+        )),
+        StatusCode::REQUEST_TIMEOUT | StatusCode::TOO_MANY_REQUESTS => Ok(Attempt::Retry(err)),
+        _ if status.is_server_error() => Ok(Attempt::Retry(err)),
+        _ => Err(err),
+    }
+}
+
+// Sends a request, treating failures to connect (or to get a response at all) as retryable
+fn send(request: RequestBuilder, range: &Range<u64>) -> io::Result<Attempt<Response>> {
+    match request.send() {
+        Ok(response) => check_status(response, range),
+        Err(err) if err.is_builder() => Err(other(err)),
+        Err(err) => Ok(Attempt::Retry(other(err))),
+    }
+}
+
+impl HttpBlob {
+    /// Creates a reader for the blob at the given URL, with a default client.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the URL can't be parsed.
+    pub fn new(url: &str) -> io::Result<Self> {
+        Ok(Self {
+            client: Client::new(),
+            url: reqwest::Url::parse(url).map_err(other)?,
+            headers: HeaderMap::new(),
+            retries: 3,
+            retry_delay: Duration::from_millis(500),
+            retry_policy: None,
+            max_ranges: 1,
+            size: OnceLock::new(),
+        })
+    }
+
+    /// Sets the client to use, for custom timeouts, proxies or TLS settings.
+    #[must_use]
+    pub fn with_client(mut self, client: Client) -> Self {
+        self.client = client;
+        self
+    }
+
+    /// Sets headers to send with every request, like `Authorization` for a registry.
+    #[must_use]
+    pub fn with_headers(mut self, headers: HeaderMap) -> Self {
+        self.headers = headers;
+        self
+    }
+
+    /// Sets the number of times that a failed request is retried, which is 3 by default.
+    #[must_use]
+    pub const fn with_retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// Sets the delay before the first retry, which doubles for each one after that.  The default
+    /// is half a second.
+    #[must_use]
+    pub const fn with_retry_delay(mut self, delay: Duration) -> Self {
+        self.retry_delay = delay;
+        self
+    }
+
+    /// Decides whether to retry failed requests with a [`HealthTracker`] following `policy`, for
+    /// each read, instead of a fixed number of retries.  This replaces the settings of
+    /// [`Self::with_retries()`] and [`Self::with_retry_delay()`].
+    #[must_use]
+    pub const fn with_retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.retry_policy = Some(policy);
+        self
+    }
+
+    /// Sets the number of ranges to ask for in a single request, in [`Self::read_ranges()`].  This
+    /// is 1 by default, since many servers (and some CDNs) don't support multiple ranges.
+    ///
+    /// Servers that don't support them send the whole blob instead, which works but is slow.
+    #[must_use]
+    pub const fn with_max_ranges(mut self, max_ranges: usize) -> Self {
+        self.max_ranges = max_ranges;
+        self
+    }
+
+    /// Sets the size of the blob, if it's known (from the layer descriptor, for example), which
+    /// saves a request.
+    #[must_use]
+    pub fn with_size(self, size: u64) -> Self {
+        let _ = self.size.set(size);
+        self
+    }
+
+    /// The URL of the blob.
+    #[must_use]
+    pub const fn url(&self) -> &reqwest::Url {
+        &self.url
+    }
+
+    // A tracker for one read, if there's a retry policy
+    fn health(&self) -> Option<HealthTracker> {
+        self.retry_policy.map(HealthTracker::new)
+    }
+
+    // Runs an attempt until it succeeds, fails for good, or runs out of retries (or patience)
+    fn retry<T>(
+        &self,
+        health: Option<&HealthTracker>,
+        mut attempt: impl FnMut() -> io::Result<Attempt<T>>,
+    ) -> io::Result<T> {
+        let mut delay = self.retry_delay;
+        let mut retries = self.retries;
+        loop {
+            match (attempt()?, health) {
+                (Attempt::Done(value), _) => return Ok(value),
+                (Attempt::Retry(err), Some(health)) => {
+                    if !health.failure() {
+                        return Err(err);
+                    }
+                    thread::sleep(health.policy().retry_delay());
+                }
+                (Attempt::Retry(err), None) if retries == 0 => return Err(err),
+                (Attempt::Retry(_), None) => {
+                    retries -= 1;
+                    thread::sleep(delay);
+                    delay = delay.saturating_mul(2);
+                }
+            }
+        }
+    }
+
+    // Makes one request for the rest of the range, adding what arrives to the buffer
+    fn fetch(
+        &self,
+        buffer: &mut RangeBuffer,
+        health: Option<&HealthTracker>,
+    ) -> io::Result<Attempt<()>> {
+        let range = buffer.range();
+        let start = buffer.position();
+        let request = self
+            .client
+            .get(self.url.clone())
+            .headers(self.headers.clone())
+            .header(RANGE, format!("bytes={start}-{}", range.end - 1));
+        let mut response = match send(request, &range)? {
+            Attempt::Done(response) => response,
+            Attempt::Retry(err) => return Ok(Attempt::Retry(err)),
+        };
+
+        // A server that ignores the range sends the whole blob, so skip to where we are
+        if response.status() == StatusCode::PARTIAL_CONTENT {
+            let first = response
+                .headers()
+                .get(CONTENT_RANGE)
+                .and_then(|value| value.to_str().ok())
+                .and_then(range::parse_content_range)
+                .map(|range| range.start);
+            if first.is_some_and(|first| first != start) {
+                return Err(other(format!(
+                    "Server sent the wrong range for bytes {start}- of {}",
+                    self.url
+                )));
+            }
+        } else if let Err(err) = io::copy(&mut (&mut response).take(start), &mut io::sink()) {
+            return Ok(Attempt::Retry(err));
+        }
+
+        let mut chunk = vec![0; 64 * 1024];
+        while !buffer.is_complete() {
+            match response.read(&mut chunk) {
+                Ok(0) => {
+                    return Ok(Attempt::Retry(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        format!("Response from {} was cut short", self.url),
+                    )));
+                }
+                Ok(n) => {
+                    let useful = buffer.push(&chunk[..n]);
+                    if let Some(health) = health {
+                        health.progress(useful);
+                    }
+                }
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Ok(Attempt::Retry(err)),
+            }
+        }
+        Ok(Attempt::Done(()))
+    }
+
+    // Makes one request for several ranges, returning the ranges and data that the server sent
+    fn fetch_ranges(&self, ranges: &[Range<u64>]) -> io::Result<Attempt<Parts>> {
+        let start = ranges.iter().map(|range| range.start).min().unwrap_or(0);
+        let end = ranges.iter().map(|range| range.end).max().unwrap_or(0);
+        let request = self
+            .client
+            .get(self.url.clone())
+            .headers(self.headers.clone())
+            .header(RANGE, range::range_header(ranges));
+        let response = match send(request, &(start..end))? {
+            Attempt::Done(response) => response,
+            Attempt::Retry(err) => return Ok(Attempt::Retry(err)),
+        };
+
+        let status = response.status();
+        let header = |name| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_owned)
+        };
+        let content_type = header(CONTENT_TYPE).unwrap_or_default();
+        let content_range = header(CONTENT_RANGE);
+
+        // A server that ignores the ranges sends the whole blob: we only need the start of it
+        let limit = if status == StatusCode::PARTIAL_CONTENT {
+            u64::MAX
+        } else {
+            end
+        };
+        let mut body = vec![];
+        if let Err(err) = response.take(limit).read_to_end(&mut body) {
+            return Ok(Attempt::Retry(err));
+        }
+
+        if status != StatusCode::PARTIAL_CONTENT {
+            return Ok(Attempt::Done(vec![(0..body.len() as u64, body)]));
+        }
+        if let Some(range) = content_range
+            .as_deref()
+            .and_then(range::parse_content_range)
+        {
+            // A single range, probably covering all of the ones that were asked for
+            return Ok(Attempt::Done(vec![(
+                range.start..range.start + body.len() as u64,
+                body,
+            )]));
+        }
+        let parts = range::split_multipart(&content_type, &body)
+            .map_err(|err| other(format!("Bad multipart response from {}: {err}", self.url)))?;
+        Ok(Attempt::Done(
+            parts
+                .into_iter()
+                .map(|(range, data)| (range, data.to_vec()))
+                .collect(),
+        ))
+    }
+
+    /// Reads several ranges of the blob, asking for up to [`Self::with_max_ranges()`] of them in
+    /// each request.  Returns the data for each range, in order.
+    ///
+    /// Ranges that the server leaves out of its responses are read on their own.
+    ///
+    /// # Errors
+    ///
+    /// Fails as for [`BlobReader::read_at()`], or if the server sends a malformed multipart
+    /// response.
+    pub fn read_ranges(&self, ranges: &[Range<u64>]) -> io::Result<Vec<Vec<u8>>> {
+        let mut results: Vec<Option<Vec<u8>>> = ranges
+            .iter()
+            .map(|range| (range.start >= range.end).then(Vec::new))
+            .collect();
+        let wanted: Vec<usize> = (0..ranges.len())
+            .filter(|&i| results[i].is_none())
+            .collect();
+
+        for batch in wanted.chunks(self.max_ranges.max(1)) {
+            if batch.len() < 2 {
+                continue;
+            }
+            let batch_ranges: Vec<_> = batch.iter().map(|&i| ranges[i].clone()).collect();
+            let parts = self.retry(self.health().as_ref(), || self.fetch_ranges(&batch_ranges))?;
+            for &i in batch {
+                let range = &ranges[i];
+                results[i] = parts.iter().find_map(|(part, data)| {
+                    let offset = usize::try_from(range.start.checked_sub(part.start)?).ok()?;
+                    let size = usize::try_from(range.end - range.start).ok()?;
+                    data.get(offset..offset.checked_add(size)?)
+                        .map(<[u8]>::to_vec)
+                });
+            }
+        }
+
+        results
+            .into_iter()
+            .zip(ranges)
+            .map(|(data, range)| data.map_or_else(|| self.read_at(range), Ok))
+            .collect()
+    }
+
+    /// Reads the data for the given references, which is returned in the same order.  The
+    /// planner decides which ranges are merged into a single range before they're requested.
+    ///
+    /// # Errors
+    ///
+    /// Fails as for [`Self::read_ranges()`].
+    pub fn read_references(
+        &self,
+        planner: &RangePlanner,
+        references: &[ContentReference],
+    ) -> io::Result<Vec<Vec<u8>>> {
+        let plan = planner.plan(references);
+        let ranges: Vec<_> = plan.iter().map(|request| request.range.clone()).collect();
+        let responses = self.read_ranges(&ranges)?;
+
+        let mut data = HashMap::new();
+        for (request, response) in plan.iter().zip(&responses) {
+            for (reference, slice) in request.split(response).map_err(other)? {
+                data.insert(reference.range.clone(), slice);
+            }
+        }
+        references
+            .iter()
+            .map(|reference| {
+                data.get(&reference.range)
+                    .map(|slice| slice.to_vec())
+                    .ok_or_else(|| other(format!("No data for {}", reference.digest)))
+            })
+            .collect()
+    }
+}
+
+impl BlobReader for HttpBlob {
+    fn read_at(&self, range: &Range<u64>) -> io::Result<Vec<u8>> {
+        if range.start >= range.end {
+            return Ok(vec![]);
+        }
+        // Each attempt continues from where the last one stopped
+        let mut buffer = RangeBuffer::new(range.clone());
+        let health = self.health();
+        self.retry(health.as_ref(), || self.fetch(&mut buffer, health.as_ref()))?;
+        Ok(buffer.into_data())
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        if let Some(&size) = self.size.get() {
+            return Ok(size);
+        }
+        let size = self.retry(self.health().as_ref(), || {
+            let request = self
+                .client
+                .head(self.url.clone())
+                .headers(self.headers.clone());
+            let response = match send(request, &(0..0))? {
+                Attempt::Done(response) => response,
+                Attempt::Retry(err) => return Ok(Attempt::Retry(err)),
+            };
+            let size = response
+                .headers()
+                .get(CONTENT_LENGTH)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.parse().ok())
+                .ok_or_else(|| other(format!("No Content-Length for {}", self.url)))?;
+            Ok(Attempt::Done(size))
+        })?;
+        Ok(*self.size.get_or_init(|| size))
+    }
+}
@@ -0,0 +1,64 @@
+//! A table of small, extremely common file contents which can be provided without fetching.
+
+use std::{collections::HashMap, sync::Arc};
+
+use crate::{ContentReference, digest};
+
+/// A table of content, keyed by digest, which is always available locally.
+///
+/// This is meant for tiny files that appear in a huge number of images (empty files, common
+/// license texts, `__init__.py` boilerplate, ...) where even a lookup in a chunk store is more
+/// expensive than keeping a copy in memory.
+#[derive(Debug, Clone, Default)]
+pub struct KnownContent {
+    table: HashMap<Arc<str>, Arc<[u8]>>,
+}
+
+impl KnownContent {
+    /// Creates an empty table.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a table containing the empty file.
+    #[must_use]
+    pub fn with_defaults() -> Self {
+        let mut table = Self::new();
+        table.add(&[]);
+        table
+    }
+
+    /// Adds some content to the table, returning its digest.
+    pub fn add(&mut self, data: &[u8]) -> Arc<str> {
+        let digest: Arc<str> = digest::sha256(data).into();
+        self.table.insert(Arc::clone(&digest), data.into());
+        digest
+    }
+
+    /// Returns the content with the given digest, if it's in the table.
+    #[must_use]
+    pub fn get(&self, digest: &str) -> Option<&Arc<[u8]>> {
+        self.table.get(digest)
+    }
+
+    /// Returns the content for the given reference, if it's in the table.  This can be used as
+    /// the first step of a resolver for [`crate::Stream::write_to()`].
+    #[must_use]
+    pub fn resolve(&self, reference: &ContentReference) -> Option<&Arc<[u8]>> {
+        self.get(&reference.digest)
+            .filter(|data| data.len() as u64 == reference.size)
+    }
+
+    /// The number of entries in the table.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.table.len()
+    }
+
+    /// Checks if the table is empty.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.table.is_empty()
+    }
+}
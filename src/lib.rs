@@ -1,12 +1,71 @@
-//! A library to help read zstd:chunked files
+//! A library to help read and write zstd:chunked files
+pub mod blob;
+#[cfg(feature = "tokio")]
+pub mod blocking;
+mod crc64;
+pub mod dedup;
+pub mod digest;
+mod error;
+#[cfg(all(feature = "extract", unix))]
+pub mod extract;
 mod format;
+pub mod frame;
+#[cfg(unix)]
+mod fsverity;
+#[cfg(all(feature = "fuse", unix))]
+pub mod fuse;
+pub mod gc;
+pub mod health;
+#[cfg(feature = "http")]
+pub mod http;
+pub mod known;
+pub mod lint;
+pub mod negative_cache;
+mod parallel;
+pub mod plan;
+pub mod prefetch;
+#[cfg(feature = "pull")]
+pub mod pull;
+pub mod query;
+pub mod quota;
+pub mod range;
+pub mod reader;
+pub mod redact;
+pub mod replay;
+#[cfg(any(unix, windows))]
+pub mod scan;
+pub mod stats;
+pub mod store;
+pub mod telemetry;
+#[cfg(all(test, unix, any(feature = "extract", feature = "writer")))]
+mod testing;
+pub mod toc_cache;
+pub mod verify;
+#[cfg(feature = "pull")]
+pub mod watch;
+#[cfg(all(feature = "writer", unix))]
+pub mod writer;
 
 use core::ops::Range;
-use std::{collections::HashMap, io::Write};
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashMap, HashSet},
+    io::{Read, Write},
+    slice,
+    sync::{Arc, Mutex, OnceLock, PoisonError},
+};
 
-use anyhow::{Context, Result, ensure};
+use anyhow::{Context, Result, bail, ensure};
 
-use self::format::{Footer, FooterReference, Manifest, TarSplitEntry};
+use self::digest::check_digest;
+pub use self::error::{BoxError, Error};
+pub use self::format::{Footer, Manifest, ManifestEntry};
+use self::format::{
+    FooterReference, TARSPLIT_FILE_TYPE, TARSPLIT_SEGMENT_TYPE, TarSplitEntry, ZSTD_CHUNKED_MAGIC,
+    ZSTD_CHUNKED_MANIFEST_TYPE, from_json,
+};
+use self::frame::FrameHeader;
+use self::telemetry::{Phase, PhaseSpan};
 
 /// A reference to a compressed range in a zstd:chunked file, along with size and checksum
 /// information about the uncompressed data at that range.
@@ -15,29 +74,728 @@ pub struct ContentReference {
     /// The range itself, in bytes, in the compressed file.
     pub range: Range<u64>,
 
-    /// The digest of the data at the range, after decompression.
-    pub digest: String,
+    /// The digest of the data at the range, after decompression.  Identical digests within a
+    /// [`Stream`] share the same allocation.
+    pub digest: Arc<str>,
 
     /// The size of the compressed data at the range, after decompression.
     pub size: u64,
 }
 
+impl ContentReference {
+    /// The size of the compressed data at the range.  Parsed references never have backwards
+    /// ranges (see [`BackwardsRange`]), but for one built by hand, this is 0.
+    #[must_use]
+    pub const fn compressed_size(&self) -> u64 {
+        self.range.end.saturating_sub(self.range.start)
+    }
+
+    /// The ratio of the uncompressed size to the compressed size.  Higher is better.
+    #[must_use]
+    #[allow(clippy::cast_precision_loss)]
+    pub fn compression_ratio(&self) -> f64 {
+        self.size as f64 / self.compressed_size() as f64
+    }
+
+    /// Parses the header of the zstd frame at the start of the range.  You need to provide at
+    /// least the first [`FrameHeader::MAX_SIZE`] bytes of the range (or the whole range, if it's
+    /// shorter).  If the range contains more than one frame, this is the header of the first one.
+    ///
+    /// This can be used to check the declared content size against the size in the manifest, or to
+    /// find out if a dictionary is required, without downloading and decompressing the data.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`Error::InvalidContent`] if the data doesn't start with a valid zstd frame
+    /// header.
+    pub fn frame_header(&self, prefix: &[u8]) -> Result<FrameHeader, Error> {
+        FrameHeader::parse(prefix).map_err(|err| self.invalid(err))
+    }
+
+    fn invalid(&self, err: impl Into<BoxError>) -> Error {
+        Error::InvalidContent {
+            digest: Arc::clone(&self.digest),
+            source: err.into(),
+        }
+    }
+
+    /// The part of the range that needs to be provided to [`Self::frame_header()`] and
+    /// [`Self::check_frame_header()`].
+    #[must_use]
+    pub fn frame_header_range(&self) -> Range<u64> {
+        let len = self.compressed_size().min(FrameHeader::MAX_SIZE as u64);
+        self.range.start..(self.range.start + len)
+    }
+
+    /// Parses the header of the zstd frame at the start of the range and checks that it's
+    /// consistent with the reference: the header must fit in the range, the declared content size
+    /// (if present) must not exceed the size of the reference, and no dictionary can be required.
+    /// This is much cheaper than [`Self::verify()`] but obviously also much weaker.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`Error::InvalidContent`] if the frame header is invalid or inconsistent with
+    /// the reference.
+    pub fn check_frame_header(&self, prefix: &[u8]) -> Result<FrameHeader, Error> {
+        let header = self.frame_header(prefix)?;
+        if header.header_size as u64 > self.compressed_size() {
+            return Err(self.invalid("zstd frame header is larger than the range"));
+        }
+        // If the file is chunked then the range contains multiple frames and the first one only
+        // covers part of the content, so we can't insist on equality.
+        if let Some(content_size) = header.content_size
+            && content_size > self.size
+        {
+            return Err(self.invalid(format!(
+                "zstd frame declares a content size of {content_size} but the reference is only {} bytes",
+                self.size
+            )));
+        }
+        if let Some(id) = header.dictionary_id {
+            return Err(self.invalid(format!("zstd frame requires dictionary {id}")));
+        }
+        Ok(header)
+    }
+
+    /// Decompresses the (complete) data at the range.  The output is limited to the size of the
+    /// reference, which protects against decompression bombs.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`Error::InvalidContent`] if the data isn't valid zstd, if it requires a
+    /// dictionary, or if it decompresses to more than the expected size.
+    pub fn decompress(&self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        let header = self.frame_header(data)?;
+        if let Some(id) = header.dictionary_id {
+            return Err(self.invalid(format!("zstd frame requires dictionary {id}")));
+        }
+        self.decompress_with_dictionary(data, |_| Ok(vec![]))
+    }
+
+    /// Like [`Self::decompress()`], but supporting frames which require a dictionary.  If the
+    /// frame header declares a dictionary ID, `resolve_dictionary()` is called to look it up (from
+    /// a skippable frame, a side blob, a local table, ...).
+    ///
+    /// # Errors
+    ///
+    /// As for [`Self::decompress()`], or with [`Error::Resolver`] if `resolve_dictionary()`
+    /// fails.
+    pub fn decompress_with_dictionary(
+        &self,
+        data: &[u8],
+        resolve_dictionary: impl FnOnce(u32) -> Result<Vec<u8>>,
+    ) -> Result<Vec<u8>, Error> {
+        let capacity = usize::try_from(self.size).map_err(|err| self.invalid(err))?;
+        let header = self.frame_header(data)?;
+        let result = match header.dictionary_id {
+            Some(id) => {
+                let dictionary = resolve_dictionary(id).map_err(|err| Error::Resolver {
+                    digest: Arc::clone(&self.digest),
+                    source: err.into(),
+                })?;
+                zstd::bulk::Decompressor::with_dictionary(&dictionary)
+                    .and_then(|mut decompressor| decompressor.decompress(data, capacity))
+                    .with_context(|| format!("Unable to decompress with dictionary {id}"))
+                    .map_err(|err| self.invalid(err))?
+            }
+            None => zstd::bulk::decompress(data, capacity).map_err(|err| self.invalid(err))?,
+        };
+        Ok(result)
+    }
+
+    /// Checks that the given data (after decompression) has the size and digest expected by this
+    /// reference.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`Error::Verification`] if the size or the digest of the data doesn't match.
+    pub fn verify(&self, data: &[u8]) -> Result<(), Error> {
+        let mismatch = |message: String| Error::Verification {
+            digest: Arc::clone(&self.digest),
+            source: message.into(),
+        };
+        if data.len() as u64 != self.size {
+            return Err(mismatch(format!(
+                "Size mismatch for {}: expected {} bytes but got {}",
+                self.digest,
+                self.size,
+                data.len()
+            )));
+        }
+        let actual = digest::sha256(data);
+        if *actual != *self.digest {
+            return Err(mismatch(format!(
+                "Digest mismatch: expected {} but got {actual}",
+                self.digest
+            )));
+        }
+        Ok(())
+    }
+
+    /// Checks if the referenced content consists entirely of zero bytes, in which case it can be
+    /// synthesized locally instead of being downloaded.  This is common for disk images and
+    /// preallocated files.
+    ///
+    /// Content that didn't compress extremely well is rejected straight away, as is content whose
+    /// compressed size is too small for its size to be true, or which is larger than 1 GiB.
+    /// Otherwise, this compares the digest against the digest of `size` zeros, which is computed
+    /// once for each size and then remembered.  Computing it takes time proportional to the size,
+    /// so async callers should check from a blocking thread.
+    #[must_use]
+    pub fn is_zeros(&self) -> bool {
+        self.could_be_zeros() && *zeros_digest(self.size) == *self.digest
+    }
+
+    // Zeros compress to a few bytes for each block of up to 128 KiB, so content which compressed
+    // much worse than that isn't worth hashing zeros for.  Every block has a 3-byte header, so
+    // content which compressed much better than that can't be the size that it claims, and the
+    // size is capped, since it comes from the (untrusted) manifest and the zeros get hashed and
+    // allocated.
+    pub(crate) const fn could_be_zeros(&self) -> bool {
+        const MAX_ZEROS: u64 = 1 << 30;
+        const BLOCK_SIZE: u64 = 128 << 10;
+        let compressed_size = self.compressed_size();
+        self.size <= MAX_ZEROS
+            && compressed_size >= 3 * self.size.div_ceil(BLOCK_SIZE)
+            && compressed_size <= 64 + self.size / 256
+    }
+}
+
+// The digest of `size` zeros.  Holes in sparse files and disk images tend to come in a few
+// sizes, so the digests are remembered (up to a limit).
+fn zeros_digest(size: u64) -> Arc<str> {
+    static ZEROS: [u8; 65536] = [0; 65536];
+    static DIGESTS: OnceLock<Mutex<HashMap<u64, Arc<str>>>> = OnceLock::new();
+    const MAX_DIGESTS: usize = 1024;
+
+    let digests = DIGESTS.get_or_init(Mutex::default);
+    if let Some(digest) = digests
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .get(&size)
+    {
+        return Arc::clone(digest);
+    }
+
+    let mut hasher = digest::Sha256::new();
+    let mut remaining = size;
+    while remaining > 0 {
+        let n = remaining.min(ZEROS.len() as u64);
+        #[allow(clippy::cast_possible_truncation)] // n <= ZEROS.len()
+        hasher.update(&ZEROS[..n as usize]);
+        remaining -= n;
+    }
+    let digest: Arc<str> = hasher.finalize_string().into();
+
+    let mut digests = digests.lock().unwrap_or_else(PoisonError::into_inner);
+    if digests.len() >= MAX_DIGESTS {
+        digests.clear();
+    }
+    digests.insert(size, Arc::clone(&digest));
+    digest
+}
+
 /// A chunk of data in a zstd:chunked stream.  Either contains inline data or a reference to a
 /// compressed range (and checksum and size information about the data at that range).
+///
+/// More kinds of chunks may be added in the future.
 #[derive(Debug, Clone)]
+#[non_exhaustive]
 pub enum Chunk {
     /// The literal data appears directly.
     Inline(Box<[u8]>),
     /// The data appears at the referenced range, which may need to be fetched and decompressed.
     External(ContentReference),
+    /// Inline data which was compressed in memory to save space.  This only appears when parsing
+    /// with [`ParseOptions::compress_inline`] set.
+    InlineCompressed {
+        /// The zstd-compressed data.
+        data: Box<[u8]>,
+        /// The size of the data after decompression.
+        size: usize,
+    },
+    /// The content of the named file couldn't be found in the manifest.  This only appears when
+    /// parsing with [`ParseOptions::tolerant`] set.  The stream can't be reconstructed.
+    Unavailable {
+        /// The name of the file, from the tarsplit.
+        name: String,
+        /// The size of the file, from the tarsplit.
+        size: u64,
+    },
+}
+
+/// Options controlling how [`Stream::new_from_frames_with_options()`] parses the metadata.
+#[derive(Debug, Clone, Default)]
+pub struct ParseOptions {
+    /// Instead of failing, record files whose content can't be found in the manifest (or whose
+    /// size doesn't match) as [`Chunk::Unavailable`].  This is useful for inspecting partially
+    /// broken layers.
+    pub tolerant: bool,
+
+    /// Fail with [`MemoryBudgetExceeded`] if the decompressed manifest, the decompressed tarsplit,
+    /// or the resulting [`Stream`] (as measured by [`Stream::heap_size()`]) would be larger than
+    /// this number of bytes.  This protects against untrusted layers with huge (or maliciously
+    /// compressed) metadata.
+    pub memory_budget: Option<usize>,
+
+    /// Keep the inline data (tar headers and padding) zstd-compressed in memory, as
+    /// [`Chunk::InlineCompressed`], merging adjacent segments first.  Layers with huge numbers of
+    /// files can otherwise hold tens of megabytes of mostly-zero inline data.
+    pub compress_inline: bool,
+}
+
+/// The error returned when parsing metadata would exceed [`ParseOptions::memory_budget`].
+#[derive(Debug, Clone, Copy, thiserror::Error)]
+#[error("zstd:chunked metadata exceeds the memory budget of {budget} bytes")]
+pub struct MemoryBudgetExceeded {
+    /// The budget that was exceeded.
+    pub budget: usize,
+}
+
+/// The error returned when an entry of the manifest has an `offset` after its `endOffset`.
+#[derive(Debug, Clone, thiserror::Error)]
+#[error("Manifest entry {name} has a backwards range {range:?}")]
+pub struct BackwardsRange {
+    /// The name of the entry.
+    pub name: String,
+    /// The range of the entry.
+    pub range: Range<u64>,
+}
+
+// Decompresses a metadata frame, failing if the result would be bigger than the budget
+fn decompress_metadata(data: &[u8], budget: Option<usize>) -> Result<Vec<u8>> {
+    let Some(budget) = budget else {
+        return Ok(zstd::decode_all(data)?);
+    };
+    let mut result = vec![];
+    zstd::Decoder::new(data)?
+        .take(budget as u64 + 1)
+        .read_to_end(&mut result)?;
+    if result.len() > budget {
+        Err(MemoryBudgetExceeded { budget })?;
+    }
+    Ok(result)
+}
+
+// The approximate amount of heap memory used by a chunk, including its entry in the chunk list.
+// Digests are shared, so they're accounted for separately.
+const fn chunk_heap_size(chunk: &Chunk) -> usize {
+    size_of::<Chunk>()
+        + match chunk {
+            Chunk::Inline(data) | Chunk::InlineCompressed { data, .. } => data.len(),
+            Chunk::External(..) => 0,
+            Chunk::Unavailable { name, .. } => name.len(),
+        }
+}
+
+// The strings and xattrs of a manifest entry, plus the entry itself
+fn entry_heap_size(entry: &ManifestEntry) -> usize {
+    let strings = [
+        Some(&entry.kind),
+        Some(&entry.name),
+        entry.link_name.as_ref(),
+        entry.mtime.as_ref(),
+        entry.atime.as_ref(),
+        entry.ctime.as_ref(),
+        entry.digest.as_ref(),
+        entry.chunk_digest.as_ref(),
+        entry.chunk_type.as_ref(),
+    ];
+    let xattrs: usize = entry.xattrs.iter().map(|(k, v)| k.len() + v.len()).sum();
+    size_of::<ManifestEntry>() + strings.iter().flatten().map(|s| s.len()).sum::<usize>() + xattrs
+}
+
+// Adjacent inline segments are merged up to this size before being compressed.
+const INLINE_GROUP_SIZE: usize = 65536;
+
+// Collects the chunks while parsing, enforcing the memory budget and (optionally) merging and
+// compressing inline data.
+struct ChunkList {
+    chunks: Vec<Chunk>,
+    files: Vec<FileChunks>,
+    entries: Vec<ManifestEntry>,
+    used: usize,
+    budget: Option<usize>,
+    pending_inline: Option<Vec<u8>>,
+}
+
+impl ChunkList {
+    fn append(&mut self, chunk: Chunk) -> Result<()> {
+        self.used += chunk_heap_size(&chunk);
+        if let Some(budget) = self.budget
+            && self.used > budget
+        {
+            Err(MemoryBudgetExceeded { budget })?;
+        }
+        self.chunks.push(chunk);
+        Ok(())
+    }
+
+    fn flush_inline(&mut self) -> Result<()> {
+        if let Some(pending) = &mut self.pending_inline
+            && !pending.is_empty()
+        {
+            let chunk = Chunk::InlineCompressed {
+                data: zstd::bulk::compress(pending, 0)?.into(),
+                size: pending.len(),
+            };
+            pending.clear();
+            self.append(chunk)?;
+        }
+        Ok(())
+    }
+
+    fn push(&mut self, chunk: Chunk) -> Result<()> {
+        match (chunk, &mut self.pending_inline) {
+            (Chunk::Inline(data), Some(pending)) => {
+                pending.extend_from_slice(&data);
+                if pending.len() >= INLINE_GROUP_SIZE {
+                    self.flush_inline()?;
+                }
+                Ok(())
+            }
+            (chunk, _) => {
+                self.flush_inline()?;
+                self.append(chunk)
+            }
+        }
+    }
+
+    fn push_file(
+        &mut self,
+        name: String,
+        crc64: Option<u64>,
+        chunks: impl IntoIterator<Item = Chunk>,
+    ) -> Result<()> {
+        self.flush_inline()?;
+        let index = self.chunks.len();
+        self.used += size_of::<FileChunks>() + name.len();
+        for chunk in chunks {
+            self.append(chunk)?;
+        }
+        self.files.push(FileChunks {
+            name,
+            crc64,
+            chunks: index..self.chunks.len(),
+        });
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<Stream> {
+        self.flush_inline()?;
+        Ok(Stream {
+            chunks: self.chunks.into(),
+            files: self.files.into(),
+            entries: self.entries.into(),
+            index: Arc::default(),
+        })
+    }
+}
+
+// The content references from the manifest, by filename.  Only entries with the digest, size,
+// offset and end_offset filled in (ie: regular files with content) are included.  Large files may
+// have been split into chunks: a "reg" entry for the first chunk, followed by "chunk" entries for
+// the rest.  Chunks of zeros are included like any other (see ContentReference::is_zeros()).
+struct ManifestReferences {
+    whole: HashMap<String, ContentReference>,
+    chunked: HashMap<String, Vec<ContentReference>>,
+}
+
+impl ManifestReferences {
+    fn new(manifest: &Manifest, intern: &mut impl FnMut(String) -> Arc<str>) -> Self {
+        let mut result = Self {
+            whole: HashMap::new(),
+            chunked: HashMap::new(),
+        };
+        for entry in &manifest.entries {
+            let Some((digest, size, range)) = entry_content(entry) else {
+                continue;
+            };
+            let reference = ContentReference {
+                range,
+                digest: intern(digest.to_owned()),
+                size,
+            };
+
+            if entry.kind == "chunk" {
+                let first = result.whole.remove(&entry.name);
+                result
+                    .chunked
+                    .entry(entry.name.clone())
+                    .or_insert_with(|| first.into_iter().collect())
+                    .push(reference);
+            } else {
+                result.whole.insert(entry.name.clone(), reference);
+            }
+        }
+        result
+    }
+
+    fn get(&self, name: &str) -> Option<&[ContentReference]> {
+        self.whole
+            .get(name)
+            .map(slice::from_ref)
+            .or_else(|| self.chunked.get(name).map(Vec::as_slice))
+    }
+}
+
+// The digest, size and range of the content (or the chunk of it) that a manifest entry refers to
+pub(crate) fn entry_content(entry: &ManifestEntry) -> Option<(&str, u64, Range<u64>)> {
+    let (Some(offset), Some(end_offset)) = (entry.offset, entry.end_offset) else {
+        return None;
+    };
+    let (digest, size) = match (&entry.chunk_digest, entry.chunk_size) {
+        // Unchunked files might still carry chunk information for their single chunk
+        (Some(digest), Some(size)) if Some(size) != entry.size || entry.kind == "chunk" => {
+            (digest, size)
+        }
+        _ => (entry.digest.as_ref()?, entry.size?),
+    };
+    Some((digest, size, offset..end_offset))
+}
+
+// An Arc<str> allocation has the string plus two reference counts
+const fn digest_heap_size(digest: &str) -> usize {
+    digest.len() + 2 * size_of::<usize>()
+}
+
+fn parse_manifest(data: &[u8], budget: Option<usize>) -> Result<Manifest> {
+    let manifest: Manifest = from_json(&mut decompress_metadata(data, budget)?)?;
+    ensure!(
+        manifest.version == 1,
+        "Incorrect zstd:chunked CRFS manifest version"
+    );
+    for entry in &manifest.entries {
+        if let (Some(offset), Some(end_offset)) = (entry.offset, entry.end_offset)
+            && offset > end_offset
+        {
+            Err(BackwardsRange {
+                name: entry.name.clone(),
+                range: offset..end_offset,
+            })?;
+        }
+    }
+    Ok(manifest)
+}
+
+impl Manifest {
+    /// Parses the compressed manifest frame, as referenced by [`MetadataReferences::manifest`].
+    /// Only [`ParseOptions::memory_budget`] is used from the options.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`Error::Manifest`] if the frame can't be decompressed or parsed, or with
+    /// [`Error::MemoryBudgetExceeded`] or [`Error::BackwardsRange`].
+    pub fn from_frame(data: &[u8], options: &ParseOptions) -> Result<Self, Error> {
+        parse_manifest(data, options.memory_budget).map_err(|err| Error::wrap(err, Error::Manifest))
+    }
+}
+
+// Splits the tarsplit into its non-empty lines, which end with "\n" or (as for str::lines())
+// "\r\n"
+fn lines(data: &mut [u8]) -> impl Iterator<Item = &mut [u8]> {
+    data.split_mut(|&c| c == b'\n').filter_map(|line| {
+        let len = line.len() - usize::from(line.ends_with(b"\r"));
+        let line = &mut line[..len];
+        (!line.is_empty()).then_some(line)
+    })
+}
+
+// Iterates over the chunks in the tarsplit.  For inline chunks, store the inline data.  For
+// external chunks, look them up in the manifest entries and store what we find.
+fn parse_tarsplit(
+    data: &[u8],
+    manifest_entries: &ManifestReferences,
+    mut chunks: ChunkList,
+    options: &ParseOptions,
+) -> Result<Stream> {
+    let mut tarsplit = decompress_metadata(data, options.memory_budget)?;
+
+    for line in lines(&mut tarsplit) {
+        let entry: TarSplitEntry = from_json(line)?;
+
+        let chunk = match entry {
+            // File entries carry the full name of the file, even if the tar stream spelled it
+            // using GNU longname or PAX headers: those are part of the preceding segment.
+            // Entries without a size (directories, symlinks, empty files, ...) have no data.
+            TarSplitEntry {
+                kind: TARSPLIT_FILE_TYPE,
+                name,
+                name_raw,
+                size: Some(size),
+                payload,
+            } => {
+                let name = match (name, name_raw) {
+                    (Some(name), _) => name,
+                    // The manifest is JSON, so the name was lossily converted there too
+                    (None, Some(raw)) => String::from_utf8_lossy(&raw).into_owned(),
+                    (None, None) => bail!("File entry in zstd:chunked tarsplit has no name"),
+                };
+                // The payload of a file entry is the big-endian CRC-64 of its content
+                let crc64 = payload
+                    .and_then(|payload| <[u8; 8]>::try_from(&*payload).ok())
+                    .map(u64::from_be_bytes);
+                match manifest_entries.get(&name) {
+                    Some(references) if references.iter().map(|r| r.size).sum::<u64>() == size => {
+                        let references = references.iter().cloned().map(Chunk::External);
+                        chunks.push_file(name, crc64, references)?;
+                    }
+                    _ if options.tolerant => {
+                        let chunk = Chunk::Unavailable {
+                            name: name.clone(),
+                            size,
+                        };
+                        chunks.push_file(name, crc64, [chunk])?;
+                    }
+                    Some(_) => bail!("Size mismatch for {name} in zstd:chunked tarsplit"),
+                    None => {
+                        bail!("Filename {name} in zstd:chunked tarsplit missing from manifest")
+                    }
+                }
+                continue;
+            }
+            TarSplitEntry {
+                kind: TARSPLIT_SEGMENT_TYPE,
+                payload: Some(payload),
+                ..
+            } => Chunk::Inline(payload),
+            TarSplitEntry {
+                kind: TARSPLIT_FILE_TYPE | TARSPLIT_SEGMENT_TYPE,
+                ..
+            } => continue,
+            TarSplitEntry { kind, .. } => {
+                bail!("Unknown zstd:chunked tarsplit entry type {kind}")
+            }
+        };
+
+        chunks.push(chunk)?;
+    }
+
+    chunks.finish()
+}
+
+// Parses the manifest and gets ready to collect the chunks of the stream
+fn prepare_chunks(
+    manifest: &[u8],
+    options: &ParseOptions,
+) -> Result<(ManifestReferences, ChunkList), Error> {
+    let budget = options.memory_budget;
+    let manifest =
+        parse_manifest(manifest, budget).map_err(|err| Error::wrap(err, Error::Manifest))?;
+
+    // Layers often contain the same content many times over (hardlinks, copies), so make
+    // sure we only store each digest once.
+    let mut digests = HashSet::<Arc<str>>::new();
+    let mut intern = |digest: String| {
+        let interned: Arc<str> = digests
+            .get(digest.as_str())
+            .cloned()
+            .unwrap_or_else(|| digest.into());
+        digests.insert(Arc::clone(&interned));
+        interned
+    };
+
+    let manifest_entries = ManifestReferences::new(&manifest, &mut intern);
+
+    // The further chunks of large files are already described by the references
+    let mut entries = manifest.entries;
+    entries.retain(|entry| entry.kind != "chunk");
+    entries.shrink_to_fit();
+
+    let chunks = ChunkList {
+        chunks: vec![],
+        files: vec![],
+        used: digests
+            .iter()
+            .map(|digest| digest_heap_size(digest))
+            .sum::<usize>()
+            + entries.iter().map(entry_heap_size).sum::<usize>(),
+        entries,
+        budget,
+        pending_inline: options.compress_inline.then(Vec::new),
+    };
+    Ok((manifest_entries, chunks))
+}
+
+// Without a tarsplit, the stream is just the regular files from the manifest, in order.
+fn collect_files(
+    manifest_entries: &ManifestReferences,
+    mut chunks: ChunkList,
+    options: &ParseOptions,
+) -> Result<Stream> {
+    let files: Vec<_> = chunks
+        .entries
+        .iter()
+        .filter(|entry| entry.kind == "reg")
+        .map(|entry| (entry.name.clone(), entry.size.unwrap_or(0)))
+        .collect();
+
+    for (name, size) in files {
+        match manifest_entries.get(&name) {
+            Some(references) => {
+                let references = references.iter().cloned().map(Chunk::External);
+                chunks.push_file(name, None, references)?;
+            }
+            // Empty files have no content to refer to
+            None if size == 0 => {}
+            None if options.tolerant => {
+                let chunk = Chunk::Unavailable {
+                    name: name.clone(),
+                    size,
+                };
+                chunks.push_file(name, None, [chunk])?;
+            }
+            None => bail!("File {name} in zstd:chunked manifest has no content reference"),
+        }
+    }
+
+    chunks.finish()
+}
+
+/// The chunks that make up the content of a single file in the stream.
+#[derive(Debug, Clone)]
+pub struct FileChunks {
+    /// The name of the file, from the tarsplit.
+    pub name: String,
+
+    /// The CRC-64 (ISO) of the content of the file, from the tarsplit, if it was present.
+    pub crc64: Option<u64>,
+
+    /// The indexes of the content chunks in [`Stream::chunks`].  Only the file content is
+    /// included: the tar headers (and padding) are in the surrounding inline chunks.
+    pub chunks: Range<usize>,
 }
 
 /// Represents the layout of a zstd:chunked file.  You can reconstruct the original file contents
 /// by iterating over the chunks.
-#[derive(Debug)]
+///
+/// The parsed metadata is immutable and reference-counted, so cloning a stream is cheap: a daemon
+/// can parse a layer once and hand clones to any number of threads serving reads from it.
+#[derive(Debug, Clone)]
 pub struct Stream {
     /// The chunks in the file.
-    pub chunks: Vec<Chunk>,
+    pub chunks: Arc<[Chunk]>,
+
+    /// The files in the stream which have content, in tarsplit order.  This allows progress and
+    /// errors to be attributed to files instead of anonymous ranges.
+    pub files: Arc<[FileChunks]>,
+
+    entries: Arc<[ManifestEntry]>,
+
+    // Shared between clones, so that the index only gets built once
+    index: Arc<OnceLock<PathIndex>>,
+}
+
+// Normalized paths to the indexes of their entry and file, built on the first lookup
+type PathIndex = HashMap<Box<str>, (usize, Option<usize>)>;
+
+// Paths in the manifest may or may not start with "./" or "/", and directories end with "/"
+pub(crate) fn normalize_path(path: &str) -> &str {
+    let path = path.trim_start_matches('/');
+    path.strip_prefix("./")
+        .unwrap_or(path)
+        .trim_start_matches('/')
+        .trim_end_matches('/')
 }
 
 impl Stream {
@@ -47,63 +805,68 @@ impl Stream {
     /// # Errors
     ///
     /// This function can fail if any of the metadata isn't in the expected format (zstd-compressed
-    /// JSON) or if there are missing mandatory fields or internal inconsistencies.  In all cases,
-    /// it indicates a corrupt zstd:chunked file (or a bug in the library).
-    pub fn new_from_frames(manifest: &[u8], tarsplit: &[u8]) -> Result<Self> {
-        let manifest = zstd::decode_all(manifest)?;
-        let manifest: Manifest = serde_json::from_slice(&manifest)?;
-
-        ensure!(
-            manifest.version == 1,
-            "Incorrect zstd:chunked CRFS manifest version"
-        );
-
-        // Read the manifest entries into a table by filename, taking only the ones that have the
-        // digest, size, offset and end_offset information filled in (ie: regular files).  Don't
-        // handle chunks.
-        let manifest_entries: HashMap<String, ContentReference> = manifest
-            .entries
-            .into_iter()
-            .filter_map(|entry| {
-                Some((
-                    entry.name,
-                    ContentReference {
-                        digest: entry.digest?,
-                        size: entry.size?,
-                        range: entry.offset?..entry.end_offset?,
-                    },
-                ))
-            })
-            .collect();
+    /// JSON) or if there are missing mandatory fields or internal inconsistencies, with
+    /// [`Error::Manifest`] or [`Error::Tarsplit`] depending on where the problem was found, or
+    /// with [`Error::BackwardsRange`].  In all cases, it indicates a corrupt zstd:chunked file (or
+    /// a bug in the library).
+    pub fn new_from_frames(manifest: &[u8], tarsplit: &[u8]) -> Result<Self, Error> {
+        Self::new_from_frames_with_options(manifest, tarsplit, &ParseOptions::default())
+    }
 
-        // Iterate over the chunks in the tarsplit.  For inline chunks, store the inline data.  For
-        // external chunks, look them up in the manifest_entries and store what we find.
-        let tarsplit = String::from_utf8(zstd::decode_all(tarsplit)?)?;
-        let mut chunks = vec![];
-
-        for line in tarsplit.lines() {
-            let entry: TarSplitEntry = serde_json::from_str(line)?;
-
-            match entry {
-                TarSplitEntry {
-                    name: Some(name),
-                    size: Some(size),
-                    ..  // ignored: crc64
-                } => {
-                    let reference = manifest_entries.get(&name)
-                        .with_context(|| format!("Filename {name} in zstd:chunked tarsplit missing from manifest"))?;
-                    ensure!(size == reference.size, "size mismatch");
-                    chunks.push(Chunk::External(reference.clone()));
-                }
-                TarSplitEntry {
-                    payload: Some(payload),
-                    ..
-                } => chunks.push(Chunk::Inline(payload)),
-                _ => {}
-            }
+    /// Reads and parses the metadata from a blob, after checking the frames against their digests
+    /// (if any) with [`MetadataReferences::verify()`].
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`Error::Io`] if reading fails, or as for [`MetadataReferences::verify()`] and
+    /// [`Self::new_from_frames()`] (or [`Self::new_from_manifest()`], if there's no tarsplit).
+    pub fn from_blob(
+        blob: &(impl blob::BlobReader + ?Sized),
+        references: &MetadataReferences,
+    ) -> Result<Self, Error> {
+        let manifest = blob.read_at(&references.manifest.range)?;
+        let tarsplit = blob.read_at(&references.tarsplit.range)?;
+        references.verify(&manifest, &tarsplit)?;
+        if references.has_tarsplit() {
+            Self::new_from_frames(&manifest, &tarsplit)
+        } else {
+            Self::new_from_manifest(&manifest, &ParseOptions::default())
         }
+    }
+
+    /// Like [`Self::new_from_frames()`] but with control over the details of parsing.
+    ///
+    /// # Errors
+    ///
+    /// As for [`Self::new_from_frames()`], except for the errors suppressed by the options, and
+    /// with [`Error::MemoryBudgetExceeded`] if the budget is exceeded.
+    pub fn new_from_frames_with_options(
+        manifest: &[u8],
+        tarsplit: &[u8],
+        options: &ParseOptions,
+    ) -> Result<Self, Error> {
+        let (manifest_entries, chunks) = prepare_chunks(manifest, options)?;
+        parse_tarsplit(tarsplit, &manifest_entries, chunks, options)
+            .map_err(|err| Error::wrap(err, Error::Tarsplit))
+    }
 
-        Ok(Self { chunks })
+    /// Creates the metadata structure from the manifest alone, for artifacts which use the
+    /// zstd:chunked framing but aren't tar layers, and so have no tarsplit.
+    ///
+    /// Without a tarsplit there are no tar headers to put between the files, so the stream is the
+    /// content of the regular files in the manifest, back to back, in manifest order.  Each file
+    /// can still be looked up and fetched on its own.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`Error::Manifest`] if the manifest isn't in the expected format or if a file
+    /// has no content reference (unless [`ParseOptions::tolerant`] is set), with
+    /// [`Error::BackwardsRange`], or with [`Error::MemoryBudgetExceeded`] if the budget is
+    /// exceeded.
+    pub fn new_from_manifest(manifest: &[u8], options: &ParseOptions) -> Result<Self, Error> {
+        let (manifest_entries, chunks) = prepare_chunks(manifest, options)?;
+        collect_files(&manifest_entries, chunks, options)
+            .map_err(|err| Error::wrap(err, Error::Manifest))
     }
 
     /// Iterates over all of the references that need to be satisfied for this stream to be
@@ -118,32 +881,465 @@ impl Stream {
         })
     }
 
+    /// Like [`Self::references()`], but with each digest only once (where it first appears), so
+    /// that content used by several files isn't fetched or verified more than once.
+    pub fn unique_references(&self) -> impl Iterator<Item = &ContentReference> {
+        let mut seen = HashSet::new();
+        self.references()
+            .filter(move |reference| seen.insert(&reference.digest))
+    }
+
+    /// Groups the references by digest, in order, for finding every use of some content.
+    #[must_use]
+    pub fn references_by_digest(&self) -> HashMap<&str, Vec<&ContentReference>> {
+        let mut references: HashMap<&str, Vec<_>> = HashMap::new();
+        for reference in self.references() {
+            references
+                .entry(&reference.digest)
+                .or_default()
+                .push(reference);
+        }
+        references
+    }
+
+    /// Iterates over the references whose content isn't available locally, according to `have`
+    /// (which is given each digest), for incremental pulls.  Each digest only appears once.
+    pub fn missing_references(
+        &self,
+        have: impl Fn(&str) -> bool,
+    ) -> impl Iterator<Item = &ContentReference> {
+        self.unique_references()
+            .filter(move |reference| !have(&reference.digest))
+    }
+
+    /// Iterates over the references needed to reconstruct the content of the given file.
+    pub fn file_references(&self, file: &FileChunks) -> impl Iterator<Item = &ContentReference> {
+        self.chunks
+            .get(file.chunks.clone())
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|chunk| {
+                if let Chunk::External(reference) = chunk {
+                    Some(reference)
+                } else {
+                    None
+                }
+            })
+    }
+
+    /// Plans the download of all of the content of the stream, from the blob with the given
+    /// digest (if it's known), for progress reporting or for handing over to other tools.
+    ///
+    /// Each piece of content appears once, in order of offset, with the chunks that use it.
+    #[must_use]
+    pub fn fetch_plan(&self, blob: Option<&str>) -> plan::FetchPlan {
+        plan::FetchPlan::for_stream(blob, self)
+    }
+
+    /// Iterates over the entries of the manifest, in order.  The `"chunk"` entries for the
+    /// further chunks of large files aren't included: see [`Self::lookup()`] for the references.
+    pub fn entries(&self) -> impl Iterator<Item = &ManifestEntry> {
+        self.entries.iter()
+    }
+
+    /// Looks up the manifest entry for a path, along with the references needed to reconstruct
+    /// its content (which are empty for anything but regular files with content).  Leading `./`
+    /// or `/` and trailing `/` are ignored, and the last entry wins for paths which appear more
+    /// than once.
+    #[must_use]
+    pub fn lookup(
+        &self,
+        path: &str,
+    ) -> Option<(&ManifestEntry, impl Iterator<Item = &ContentReference>)> {
+        let (entry, file) = self.lookup_indexes(path)?;
+        let references = file
+            .and_then(|file| self.files.get(file))
+            .into_iter()
+            .flat_map(|file| self.file_references(file));
+        Some((self.entries.get(entry)?, references))
+    }
+
+    // The indexes of the entry for a path, and of its file in `self.files` if it has one
+    pub(crate) fn lookup_indexes(&self, path: &str) -> Option<(usize, Option<usize>)> {
+        let index = self.index.get_or_init(|| {
+            let mut index: PathIndex = self
+                .entries
+                .iter()
+                .enumerate()
+                .map(|(i, entry)| (normalize_path(&entry.name).into(), (i, None)))
+                .collect();
+            for (i, file) in self.files.iter().enumerate() {
+                if let Some((_, file_index)) = index.get_mut(normalize_path(&file.name)) {
+                    *file_index = Some(i);
+                }
+            }
+            index
+        });
+        index.get(normalize_path(path)).copied()
+    }
+
+    /// Looks up a file by the name recorded for it in the tarsplit.
+    #[must_use]
+    pub fn file(&self, name: &str) -> Option<&FileChunks> {
+        self.files.iter().find(|file| file.name == name)
+    }
+
+    /// Returns the content of a single file, resolving only its own chunks.  The content is
+    /// checked as for [`Self::write_to_strict()`].
+    ///
+    /// # Errors
+    ///
+    /// As for [`Self::write_to_strict()`].
+    pub fn read_file(
+        &self,
+        file: &FileChunks,
+        resolve_reference: impl Fn(&ContentReference) -> Result<Vec<u8>>,
+    ) -> Result<Vec<u8>, Error> {
+        let resolve = resolver(resolve_reference, true);
+        let mut content = vec![];
+        for chunk in self.chunks.get(file.chunks.clone()).unwrap_or_default() {
+            content.extend_from_slice(&chunk_data(chunk, &resolve)?);
+        }
+        if let Some(expected) = file.crc64 {
+            let actual = crc64::update(0, &content);
+            if actual != expected {
+                Err(Crc64Mismatch {
+                    name: file.name.clone(),
+                    expected,
+                    actual,
+                })?;
+            }
+        }
+        Ok(content)
+    }
+
+    /// Returns the size of the reconstructed content (ie: the uncompressed tar stream), in bytes.
+    #[must_use]
+    pub fn size(&self) -> u64 {
+        self.chunks.iter().map(reader::chunk_size).sum()
+    }
+
+    /// Returns the approximate amount of heap memory used by the stream, in bytes.
+    #[must_use]
+    pub fn heap_size(&self) -> usize {
+        let mut digests = HashSet::new();
+        let digests_size: usize = self
+            .references()
+            .filter(|reference| digests.insert(Arc::as_ptr(&reference.digest)))
+            .map(|reference| digest_heap_size(&reference.digest))
+            .sum();
+        let chunks_size: usize = self.chunks.iter().map(chunk_heap_size).sum();
+        let files_size: usize = self.files.iter().map(|file| file.name.len()).sum::<usize>()
+            + self.files.len() * size_of::<FileChunks>();
+        let entries_size: usize = self.entries.iter().map(entry_heap_size).sum();
+
+        digests_size + chunks_size + files_size + entries_size
+    }
+
+    /// Iterates over the names and sizes of the files that were recorded as
+    /// [`Chunk::Unavailable`].  This is always empty unless [`ParseOptions::tolerant`] was used.
+    pub fn unavailable(&self) -> impl Iterator<Item = (&str, u64)> {
+        self.chunks.iter().filter_map(|chunk| {
+            if let Chunk::Unavailable { name, size } = chunk {
+                Some((name.as_str(), *size))
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Checks that none of the references point into the metadata (manifest, tarsplit or footer)
+    /// at the end of the file, or past the end of the file, and that none of them are empty.  Such
+    /// files are malformed, and would otherwise fail much later, in confusing ways (typically
+    /// during decompression).  If the size of the file isn't known then the footer and end-of-file
+    /// checks are skipped.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`Error::RegionOverlap`], describing the first bad reference.
+    pub fn check_references(
+        &self,
+        metadata: &MetadataReferences,
+        file_size: Option<u64>,
+    ) -> Result<(), Error> {
+        let footer = file_size.map(|size| size.saturating_sub(size_of::<Footer>() as u64)..size);
+
+        for reference in self.references() {
+            let overlaps = |range: &Range<u64>| {
+                reference.range.start < range.end && range.start < reference.range.end
+            };
+            let region = if overlaps(&metadata.manifest.range) {
+                Region::Manifest
+            } else if overlaps(&metadata.tarsplit.range) {
+                Region::Tarsplit
+            } else if footer.as_ref().is_some_and(overlaps) {
+                Region::Footer
+            } else if file_size.is_some_and(|size| reference.range.end > size)
+                // Also backwards: even an empty frame has a header
+                || reference.range.is_empty()
+            {
+                Region::OutOfBounds
+            } else {
+                continue;
+            };
+
+            Err(RegionOverlap {
+                digest: reference.digest.clone(),
+                range: reference.range.clone(),
+                region,
+            })?;
+        }
+
+        Ok(())
+    }
+
     /// Writes the content of the stream to the given writer.  The `resolve_reference()` function
     /// should return the *decompressed* data corresponding to the reference.
     ///
     /// # Errors
     ///
     /// This function can fail only in response to external errors: a failure of the
-    /// `resolve_reference()` function or a failure to write to the writer.
+    /// `resolve_reference()` function ([`Error::Resolver`]) or a failure to write to the writer
+    /// ([`Error::Io`]).  It also fails with [`Error::Unavailable`] if the stream contains
+    /// [`Chunk::Unavailable`] chunks.
     pub fn write_to(
         &self,
         write: &mut impl Write,
         resolve_reference: impl Fn(&ContentReference) -> Result<Vec<u8>>,
-    ) -> Result<()> {
-        for chunk in &self.chunks {
-            match chunk {
-                Chunk::Inline(data) => {
-                    write.write_all(data)?;
+    ) -> Result<(), Error> {
+        self.write_chunks(write, resolver(resolve_reference, false), false)
+    }
+
+    /// Like [`Self::write_to()`], but checks the data returned by `resolve_reference()` against
+    /// the size and digest of the reference before writing it, so a corrupted cache or a
+    /// misbehaving source can't produce an incorrect result.
+    ///
+    /// # Errors
+    ///
+    /// As for [`Self::write_to()`], or with [`Error::Verification`] if any of the content fails
+    /// [`ContentReference::verify()`].  Nothing more is written after a mismatch, but the output
+    /// will have been partially written.
+    pub fn write_to_verified(
+        &self,
+        write: &mut impl Write,
+        resolve_reference: impl Fn(&ContentReference) -> Result<Vec<u8>>,
+    ) -> Result<(), Error> {
+        let span = PhaseSpan::start(Phase::Verify, &[]);
+        span.add_bytes(self.size());
+        span.finish(self.write_chunks(write, resolver(resolve_reference, true), false))
+    }
+
+    /// Like [`Self::write_to_verified()`], but additionally checks the content of each file
+    /// against the CRC-64 recorded for it in the tarsplit (where present).  A mismatch there
+    /// means that the manifest and the tarsplit disagree about the content of the file.
+    ///
+    /// # Errors
+    ///
+    /// As for [`Self::write_to_verified()`], or with [`Error::Crc64Mismatch`] for the first file
+    /// whose content doesn't match its CRC-64.
+    pub fn write_to_strict(
+        &self,
+        write: &mut impl Write,
+        resolve_reference: impl Fn(&ContentReference) -> Result<Vec<u8>>,
+    ) -> Result<(), Error> {
+        let span = PhaseSpan::start(Phase::Verify, &[]);
+        span.add_bytes(self.size());
+        span.finish(self.write_chunks(write, resolver(resolve_reference, true), true))
+    }
+
+    /// Like [`Self::write_to()`], but for async writers and resolvers, so that content can be
+    /// fetched over the network without blocking.  The future returned by `resolve_reference()`
+    /// can't borrow the reference: clone what it needs (which is cheap).
+    ///
+    /// References are resolved one at a time, in order.  To fetch in parallel, make sure that all
+    /// of the content is available (for example in a [`store::ChunkStore`]) first.
+    ///
+    /// # Errors
+    ///
+    /// As for [`Self::write_to()`].
+    #[cfg(feature = "tokio")]
+    pub async fn write_to_async<F>(
+        &self,
+        write: &mut (impl tokio::io::AsyncWrite + Unpin),
+        resolve_reference: impl Fn(&ContentReference) -> F,
+    ) -> Result<(), Error>
+    where
+        F: Future<Output = Result<Vec<u8>>>,
+    {
+        use tokio::io::AsyncWriteExt;
+
+        for chunk in self.chunks.iter() {
+            let data = match (chunk, inline_data(chunk)?) {
+                (Chunk::External(reference), None) => {
+                    Cow::Owned(resolve_reference(reference).await.map_err(|err| {
+                        Error::Resolver {
+                            digest: Arc::clone(&reference.digest),
+                            source: err.into(),
+                        }
+                    })?)
                 }
-                Chunk::External(r#ref) => {
-                    write.write_all(&resolve_reference(r#ref)?)?;
+                (_, data) => data.unwrap_or_default(),
+            };
+            write.write_all(&data).await?;
+        }
+        Ok(())
+    }
+
+    /// Returns a reader over the content of the stream, which supports seeking and only resolves
+    /// the chunks that are needed for the offsets being read.  The `resolve_reference()`
+    /// function is as for [`Self::write_to()`].
+    pub fn reader<R>(&self, resolve_reference: R) -> reader::StreamReader<'_, R>
+    where
+        R: Fn(&ContentReference) -> Result<Vec<u8>>,
+    {
+        reader::StreamReader::new(self, resolve_reference)
+    }
+
+    /// Turns the stream into a [`tokio::io::AsyncRead`] over its content, resolving each chunk
+    /// only when the reader reaches it.  This allows the reconstructed tar stream to be consumed
+    /// (for example, by an async tar reader) without holding the whole layer in memory.  The
+    /// resolver is as for [`Self::write_to_async()`].
+    #[cfg(feature = "tokio")]
+    pub const fn into_async_read<R, F>(
+        self,
+        resolve_reference: R,
+    ) -> reader::AsyncStreamReader<R, F>
+    where
+        R: Fn(&ContentReference) -> F,
+        F: Future<Output = Result<Vec<u8>>>,
+    {
+        reader::AsyncStreamReader::new(self, resolve_reference)
+    }
+
+    fn write_chunks(
+        &self,
+        write: &mut impl Write,
+        resolve: impl Fn(&ContentReference) -> Result<Vec<u8>, Error>,
+        check_crc64: bool,
+    ) -> Result<(), Error> {
+        let chunks = |range: Range<usize>| self.chunks.get(range).unwrap_or_default();
+
+        let mut position = 0;
+        for file in self.files.iter() {
+            for chunk in chunks(position..file.chunks.start) {
+                write.write_all(&chunk_data(chunk, &resolve)?)?;
+            }
+            let mut crc = 0;
+            for chunk in chunks(file.chunks.clone()) {
+                let data = chunk_data(chunk, &resolve)?;
+                if check_crc64 {
+                    crc = crc64::update(crc, &data);
                 }
+                write.write_all(&data)?;
+            }
+            if check_crc64
+                && let Some(expected) = file.crc64
+                && crc != expected
+            {
+                Err(Crc64Mismatch {
+                    name: file.name.clone(),
+                    expected,
+                    actual: crc,
+                })?;
             }
+            position = file.chunks.end;
+        }
+        for chunk in chunks(position..self.chunks.len()) {
+            write.write_all(&chunk_data(chunk, &resolve)?)?;
         }
         Ok(())
     }
 }
 
+// Wraps a resolver function to report its failures as Error::Resolver, optionally verifying the
+// content that it returns.
+pub(crate) fn resolver(
+    resolve_reference: impl Fn(&ContentReference) -> Result<Vec<u8>>,
+    verify: bool,
+) -> impl Fn(&ContentReference) -> Result<Vec<u8>, Error> {
+    move |reference| {
+        let data = resolve_reference(reference).map_err(|err| Error::Resolver {
+            digest: Arc::clone(&reference.digest),
+            source: err.into(),
+        })?;
+        if verify {
+            reference.verify(&data)?;
+        }
+        Ok(data)
+    }
+}
+
+// Returns the data of a chunk, or None for external references, which need to be resolved
+pub(crate) fn inline_data(chunk: &Chunk) -> Result<Option<Cow<'_, [u8]>>, Error> {
+    Ok(match chunk {
+        Chunk::Inline(data) => Some(Cow::Borrowed(data)),
+        Chunk::InlineCompressed { data, size } => Some(zstd::bulk::decompress(data, *size)?.into()),
+        Chunk::External(..) => None,
+        Chunk::Unavailable { name, .. } => Err(Error::Unavailable(name.clone()))?,
+    })
+}
+
+// Returns the data of a chunk, resolving external references with the given function
+fn chunk_data(
+    chunk: &Chunk,
+    resolve_reference: impl Fn(&ContentReference) -> Result<Vec<u8>, Error>,
+) -> Result<Cow<'_, [u8]>, Error> {
+    match (chunk, inline_data(chunk)?) {
+        (Chunk::External(reference), None) => Ok(resolve_reference(reference)?.into()),
+        (_, data) => Ok(data.unwrap_or_default()),
+    }
+}
+
+/// The error returned by [`Stream::write_to_strict()`] when the content of a file doesn't match
+/// the CRC-64 recorded in the tarsplit.
+#[derive(Debug, Clone, thiserror::Error)]
+#[error("CRC-64 mismatch for {name}: expected {expected:016x} but got {actual:016x}")]
+pub struct Crc64Mismatch {
+    /// The name of the file.
+    pub name: String,
+    /// The CRC-64 from the tarsplit.
+    pub expected: u64,
+    /// The CRC-64 of the content that was written.
+    pub actual: u64,
+}
+
+/// A part of a zstd:chunked file which a content reference must not point into.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Region {
+    /// The compressed manifest.
+    Manifest,
+    /// The compressed tarsplit.
+    Tarsplit,
+    /// The footer at the end of the file.
+    Footer,
+    /// Past the end of the file (or an empty or backwards range).
+    OutOfBounds,
+}
+
+impl Region {
+    const fn description(self) -> &'static str {
+        match self {
+            Self::Manifest => "into the manifest",
+            Self::Tarsplit => "into the tarsplit",
+            Self::Footer => "into the footer",
+            Self::OutOfBounds => "out of bounds",
+        }
+    }
+}
+
+/// The error returned by [`Stream::check_references()`] when a reference points somewhere it
+/// shouldn't.
+#[derive(Debug, Clone, thiserror::Error)]
+#[error("Range {range:?} for {digest} points {}", .region.description())]
+pub struct RegionOverlap {
+    /// The digest of the bad reference.
+    pub digest: Arc<str>,
+    /// The range of the bad reference.
+    pub range: Range<u64>,
+    /// The region that the reference points into.
+    pub region: Region,
+}
+
 /// A reference to file metadata, either the manifest or the tarsplit
 #[derive(Debug)]
 pub struct MetadataReference {
@@ -151,7 +1347,8 @@ pub struct MetadataReference {
     pub range: Range<u64>,
 
     /// The digest of the data at the range, *before* decompression.  This will be missing if we
-    /// read from the file footer or if the OCI annotations didn't provide it.
+    /// read from the file footer or if the OCI annotations didn't provide it.  See
+    /// [`Self::verify()`].
     pub digest: Option<String>,
 
     /// The size of the compressed data at the range, after decompression.
@@ -159,15 +1356,65 @@ pub struct MetadataReference {
 }
 
 impl MetadataReference {
-    const fn from_footer(value: &FooterReference) -> Self {
+    // The tarsplit of an artifact that doesn't have one, as it appears in the footer
+    pub(crate) const fn absent() -> Self {
+        Self {
+            range: 0..0,
+            digest: None,
+            uncompressed_size: 0,
+        }
+    }
+
+    // None if the range overflows, since the footer can come from anywhere
+    const fn from_footer(value: &FooterReference) -> Option<Self> {
         let start = value.offset.get();
-        let end = start + value.length_compressed.get();
+        let Some(end) = start.checked_add(value.length_compressed.get()) else {
+            return None;
+        };
 
-        Self {
+        Some(Self {
             range: start..end,
             digest: None,
             uncompressed_size: value.length_uncompressed.get(),
+        })
+    }
+
+    /// Checks the (compressed) data at the range against the digest, and returns what the digest
+    /// turned out to cover, or `None` if there is no digest to check.  Producers have disagreed
+    /// about that, so the `policy` says which interpretations are acceptable.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the digest doesn't match under any of them.
+    pub fn verify(
+        &self,
+        data: &[u8],
+        policy: verify::ChecksumPolicy,
+    ) -> Result<Option<verify::ChecksumCoverage>, verify::ChecksumMismatch> {
+        let Some(expected) = &self.digest else {
+            return Ok(None);
+        };
+        let compressed = digest::sha256(data);
+        if compressed == *expected {
+            return Ok(Some(verify::ChecksumCoverage::Compressed));
+        }
+
+        let uncompressed = match policy {
+            verify::ChecksumPolicy::Strict => None,
+            verify::ChecksumPolicy::Lenient => {
+                decompress_metadata(data, usize::try_from(self.uncompressed_size).ok())
+                    .ok()
+                    .map(|data| digest::sha256(&data))
+            }
+        };
+        if uncompressed.as_ref() == Some(expected) {
+            return Ok(Some(verify::ChecksumCoverage::Uncompressed));
         }
+        Err(verify::ChecksumMismatch {
+            expected: expected.clone(),
+            compressed,
+            uncompressed,
+        })
     }
 }
 
@@ -177,53 +1424,467 @@ impl MetadataReference {
 pub struct MetadataReferences {
     /// The location of the manifest data
     pub manifest: MetadataReference,
-    /// The location of the tarsplit data
+    /// The location of the tarsplit data.  This is an empty range (without a digest) for
+    /// artifacts that don't have one: see [`Self::has_tarsplit()`].
     pub tarsplit: MetadataReference,
 }
 
-fn to_vec_u64(value: &str) -> Option<Vec<u64>> {
+/// The OCI annotation holding the digest of the compressed manifest (the "TOC digest").
+pub const MANIFEST_CHECKSUM_ANNOTATION: &str =
+    "io.github.containers.zstd-chunked.manifest-checksum";
+
+/// The OCI annotation holding the position of the manifest, as
+/// `offset:length:uncompressed_length:type`.
+pub const MANIFEST_POSITION_ANNOTATION: &str =
+    "io.github.containers.zstd-chunked.manifest-position";
+
+/// The OCI annotation holding the digest of the compressed tarsplit.
+pub const TARSPLIT_CHECKSUM_ANNOTATION: &str =
+    "io.github.containers.zstd-chunked.tarsplit-checksum";
+
+/// The OCI annotation holding the position of the tarsplit, as
+/// `offset:length:uncompressed_length`.
+pub const TARSPLIT_POSITION_ANNOTATION: &str =
+    "io.github.containers.zstd-chunked.tarsplit-position";
+
+/// The media types used for zstd-compressed layers.  Docker schema2 has no zstd media type of its
+/// own, but registries and proxies that convert OCI manifests to Docker ones use the second.
+pub const ZSTD_LAYER_MEDIA_TYPES: [&str; 2] = [
+    "application/vnd.oci.image.layer.v1.tar+zstd",
+    "application/vnd.docker.image.rootfs.diff.tar.zstd",
+];
+
+/// The number of bytes at the end of a zstd:chunked blob which are needed to parse the footer.
+pub const FOOTER_SIZE: u64 = size_of::<Footer>() as u64;
+
+/// Checks if a layer descriptor with the given media type might contain a zstd:chunked blob.
+/// zstd:chunked blobs are ordinary zstd streams, so the media type alone can't say for sure.
+#[must_use]
+pub fn is_zstd_media_type(media_type: &str) -> bool {
+    ZSTD_LAYER_MEDIA_TYPES.contains(&media_type)
+}
+
+/// Checks if a descriptor with the given media type might be an artifact (like a model or a data
+/// set) using the zstd:chunked framing: a `+zstd` type which isn't a tar layer.
+///
+/// Such artifacts may have no tarsplit, and are parsed with [`Stream::new_from_manifest()`].
+#[must_use]
+pub fn is_zstd_artifact_media_type(media_type: &str) -> bool {
+    media_type.ends_with("+zstd")
+        && !media_type.ends_with(".tar+zstd")
+        && !is_zstd_media_type(media_type)
+}
+
+/// The error returned for zstd:chunked metadata using a manifest type other than 1, the only one
+/// that's currently defined.  This is most likely a newer version of the format.
+#[derive(Debug, Clone, Copy, thiserror::Error)]
+#[error("Unsupported zstd:chunked manifest type {manifest_type}")]
+pub struct UnsupportedManifestType {
+    /// The manifest type from the annotation or footer.
+    pub manifest_type: u64,
+}
+
+pub(crate) fn to_vec_u64(value: &str) -> Option<Vec<u64>> {
     value.split(':').map(|s| s.parse().ok()).collect()
 }
 
+/// One version of a blob that was updated by appending to it, as found by
+/// [`MetadataReferences::footer_chain()`].
+#[derive(Debug)]
+pub struct FooterVersion {
+    /// The size of the blob as of this version, which is the offset just past its footer.
+    pub size: u64,
+
+    /// The metadata references from the footer.
+    pub references: MetadataReferences,
+}
+
 impl MetadataReferences {
+    /// Finds the footers of a blob that was updated by appending new frames and a new footer
+    /// (for example, by a log-structured layer update), newest first.
+    ///
+    /// The first version is the footer at the end of the blob, as for [`Self::from_footer()`],
+    /// and the chain is empty if there isn't one.  Anything appended after an older footer
+    /// includes the new metadata, so each older footer is searched for before the metadata of
+    /// the version after it, and is only accepted if its own metadata comes before it.  Footers
+    /// with unsupported manifest types aren't recognised.  `blob` must be the complete blob,
+    /// since the footers contain absolute offsets.
+    #[must_use]
+    pub fn footer_chain(blob: &[u8]) -> Vec<FooterVersion> {
+        let magic_len = ZSTD_CHUNKED_MAGIC.len();
+        let mut chain: Vec<FooterVersion> = vec![];
+        let mut candidate = Some(blob.len());
+        while let Some(end) = candidate {
+            let footer_start = (end as u64).saturating_sub(FOOTER_SIZE);
+            match blob.get(..end).and_then(Self::from_footer) {
+                Some(references)
+                    if references.manifest.range.end <= footer_start
+                        && references.tarsplit.range.end <= footer_start =>
+                {
+                    let metadata_start = references
+                        .manifest
+                        .range
+                        .start
+                        .min(references.tarsplit.range.start);
+                    chain.push(FooterVersion {
+                        size: end as u64,
+                        references,
+                    });
+                    candidate = usize::try_from(metadata_start).ok();
+                }
+                // The newest footer has to be at the very end
+                _ if chain.is_empty() => break,
+                // Otherwise, that was just the magic appearing in some data: look further back
+                _ => candidate = Some(end - 1),
+            }
+
+            // The next candidate is the last occurrence of the magic ending before the limit
+            candidate = candidate.and_then(|limit| {
+                blob.get(..limit)?
+                    .windows(magic_len)
+                    .rposition(|window| window == ZSTD_CHUNKED_MAGIC)
+                    .map(|position| position + magic_len)
+            });
+        }
+        chain
+    }
+
     /// Read the metadata references from the file footer.  The provided data can be any suffix of
     /// the file, but it must be at least 72 bytes in length (to contain the footer).  Returns None
-    /// if this doesn't appear to be a zstd:chunked file.
+    /// if this doesn't appear to be a zstd:chunked file (including footers with ranges that
+    /// overflow).
     #[must_use]
     pub fn from_footer(suffix: &[u8]) -> Option<Self> {
-        let footer = Footer::from_suffix(suffix)?;
-        Some(Self {
-            manifest: MetadataReference::from_footer(&footer.manifest),
-            tarsplit: MetadataReference::from_footer(&footer.tarsplit),
-        })
+        Self::try_from_footer(suffix).ok().flatten()
+    }
+
+    /// Reads the metadata references from the footer at the end of a blob.  Returns `None` if it
+    /// doesn't appear to be a zstd:chunked file.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`Error::Io`] if reading fails, or as for [`Self::try_from_footer()`].
+    pub fn from_blob(blob: &(impl blob::BlobReader + ?Sized)) -> Result<Option<Self>, Error> {
+        Self::try_from_footer(&blob.footer()?)
+    }
+
+    /// Like [`Self::from_footer()`], but distinguishes footers that use a manifest type other than
+    /// the one this crate understands (probably a newer version of the format) from blobs that
+    /// aren't zstd:chunked at all.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`Error::UnsupportedManifestType`] if the footer has an unknown manifest type.
+    pub fn try_from_footer(suffix: &[u8]) -> Result<Option<Self>, Error> {
+        let Some(footer) = Footer::from_suffix(suffix) else {
+            return Ok(None);
+        };
+        if !footer.supported() {
+            Err(UnsupportedManifestType {
+                manifest_type: footer.manifest_type(),
+            })?;
+        }
+        let (Some(manifest), Some(tarsplit)) = (footer.manifest(), footer.tarsplit()) else {
+            return Ok(None);
+        };
+        Ok(Some(Self { manifest, tarsplit }))
     }
 
     /// Parses the metadata references from OCI layer descriptor annotations.  You should provide a
     /// 'get' closure that returns the requested annotation, or None if it doesn't exist. Returns
-    /// None if this doesn't appear to be a zstd:chunked layer descriptor.
+    /// None if this doesn't appear to be a zstd:chunked layer descriptor, or if the annotations are
+    /// malformed: use [`Self::try_from_oci()`] to find out what's wrong with them.
     pub fn from_oci<'a, S: AsRef<str> + 'a>(get: impl Fn(&str) -> Option<&'a S>) -> Option<Self> {
-        let manifest_digest = get("io.github.containers.zstd-chunked.manifest-checksum");
-        let manifest_position = get("io.github.containers.zstd-chunked.manifest-position")?;
-        let tarsplit_digest = get("io.github.containers.zstd-chunked.tarsplit-checksum");
-        let tarsplit_position = get("io.github.containers.zstd-chunked.tarsplit-position")?;
+        Self::parse_oci(get, false)
+    }
 
-        Some(Self {
-            manifest: match to_vec_u64(manifest_position.as_ref())?.as_slice() {
-                &[start, length, uncompressed_size, 1] => MetadataReference {
-                    range: start..(start + length),
-                    digest: manifest_digest.map(|s| s.as_ref().to_owned()),
+    /// Like [`Self::from_oci()`], but for artifacts (see [`is_zstd_artifact_media_type()`]), where
+    /// the tarsplit annotations are optional.  If they're both missing, then so is the tarsplit.
+    pub fn from_oci_artifact<'a, S: AsRef<str> + 'a>(
+        get: impl Fn(&str) -> Option<&'a S>,
+    ) -> Option<Self> {
+        Self::parse_oci(get, true)
+    }
+
+    fn parse_oci<'a, S: AsRef<str> + 'a>(
+        get: impl Fn(&str) -> Option<&'a S>,
+        artifact: bool,
+    ) -> Option<Self> {
+        let digest = |key| {
+            get(key)
+                .map(|value| check_digest(value.as_ref()).map(|()| value.as_ref().to_owned()))
+                .transpose()
+                .ok()
+        };
+        let manifest_digest = digest(MANIFEST_CHECKSUM_ANNOTATION)?;
+        let manifest_position = get(MANIFEST_POSITION_ANNOTATION)?;
+        let tarsplit = match (
+            get(TARSPLIT_CHECKSUM_ANNOTATION),
+            get(TARSPLIT_POSITION_ANNOTATION),
+        ) {
+            (None, None) if artifact => MetadataReference::absent(),
+            (_, position) => match to_vec_u64(position?.as_ref())?.as_slice() {
+                &[start, length, uncompressed_size] => MetadataReference {
+                    range: start..start.checked_add(length)?,
+                    digest: digest(TARSPLIT_CHECKSUM_ANNOTATION)?,
                     uncompressed_size,
                 },
                 _ => None?,
             },
-            tarsplit: match to_vec_u64(tarsplit_position.as_ref())?.as_slice() {
-                &[start, length, uncompressed_size] => MetadataReference {
-                    range: start..(start + length),
-                    digest: tarsplit_digest.map(|s| s.as_ref().to_owned()),
-                    uncompressed_size,
-                },
+        };
+
+        Some(Self {
+            manifest: match to_vec_u64(manifest_position.as_ref())?.as_slice() {
+                &[start, length, uncompressed_size, ZSTD_CHUNKED_MANIFEST_TYPE] => {
+                    MetadataReference {
+                        range: start..start.checked_add(length)?,
+                        digest: manifest_digest,
+                        uncompressed_size,
+                    }
+                }
                 _ => None?,
             },
+            tarsplit,
         })
     }
+
+    /// Checks whether there is a tarsplit.  Only artifacts can be without one, and their streams
+    /// come from [`Stream::new_from_manifest()`] instead of [`Stream::new_from_frames()`].
+    #[must_use]
+    pub const fn has_tarsplit(&self) -> bool {
+        self.tarsplit.range.start < self.tarsplit.range.end
+    }
+
+    /// Returns the OCI layer descriptor annotations describing these references: the inverse of
+    /// [`Self::from_oci()`].  The checksum annotations are left out if the digests are missing, and
+    /// the tarsplit annotations if there's no tarsplit (as for [`Self::from_oci_artifact()`]).
+    #[must_use]
+    pub fn to_oci(&self) -> BTreeMap<String, String> {
+        let (manifest, tarsplit) = (&self.manifest, &self.tarsplit);
+        let mut annotations = BTreeMap::from([(
+            MANIFEST_POSITION_ANNOTATION.to_owned(),
+            format!(
+                "{}:{}:{}:{ZSTD_CHUNKED_MANIFEST_TYPE}",
+                manifest.range.start,
+                manifest.range.end.saturating_sub(manifest.range.start),
+                manifest.uncompressed_size
+            ),
+        )]);
+        if self.has_tarsplit() {
+            annotations.insert(
+                TARSPLIT_POSITION_ANNOTATION.to_owned(),
+                format!(
+                    "{}:{}:{}",
+                    tarsplit.range.start,
+                    tarsplit.range.end.saturating_sub(tarsplit.range.start),
+                    tarsplit.uncompressed_size
+                ),
+            );
+        }
+        for (key, digest) in [
+            (MANIFEST_CHECKSUM_ANNOTATION, &manifest.digest),
+            (TARSPLIT_CHECKSUM_ANNOTATION, &tarsplit.digest),
+        ] {
+            if let Some(digest) = digest {
+                annotations.insert(key.to_owned(), digest.clone());
+            }
+        }
+        annotations
+    }
+
+    /// Checks the references against the size of the blob (as given by its descriptor) and,
+    /// if the end of the blob is available, against its footer.
+    ///
+    /// References from annotations which disagree with the blob otherwise only show up later, as
+    /// baffling decompression errors.  As for [`Self::from_footer()`], only the last 72 bytes of
+    /// the blob are needed for `footer`, but passing more is fine.
+    #[must_use]
+    pub fn consistency_report(
+        &self,
+        blob_size: u64,
+        footer: Option<&[u8]>,
+    ) -> lint::ConsistencyReport {
+        lint::consistency_report(self, blob_size, footer)
+    }
+
+    /// Checks the compressed manifest and tarsplit frames against their digests, before they're
+    /// passed to [`Stream::new_from_frames()`].  Missing digests (as from the footer) are skipped.
+    ///
+    /// Only the documented interpretation of the digests is accepted: use
+    /// [`MetadataReference::verify()`] to choose a [`verify::ChecksumPolicy`].
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`Error::Manifest`] or [`Error::Tarsplit`], with a [`verify::ChecksumMismatch`]
+    /// as the source, if a frame doesn't match its digest.
+    pub fn verify(&self, manifest: &[u8], tarsplit: &[u8]) -> Result<(), Error> {
+        self.manifest
+            .verify(manifest, verify::ChecksumPolicy::Strict)
+            .map_err(|err| Error::Manifest(err.into()))?;
+        self.tarsplit
+            .verify(tarsplit, verify::ChecksumPolicy::Strict)
+            .map_err(|err| Error::Tarsplit(err.into()))?;
+        Ok(())
+    }
+
+    /// Like [`Self::from_oci()`], but validates the annotations first, for descriptors that come
+    /// from untrusted registries.  If the size of the blob is known, the positions are also
+    /// checked against it.  Returns `Ok(None)` if this isn't a zstd:chunked layer descriptor.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`Error::UnsupportedManifestType`] if the manifest position has an unknown
+    /// manifest type, or with [`Error::InvalidAnnotations`] if any of the annotations are
+    /// malformed.
+    pub fn try_from_oci<'a, S: AsRef<str> + 'a>(
+        get: impl Fn(&str) -> Option<&'a S>,
+        blob_size: Option<u64>,
+    ) -> Result<Option<Self>, Error> {
+        if let Some(position) = get(MANIFEST_POSITION_ANNOTATION)
+            && let Some(&[_, _, _, manifest_type]) = to_vec_u64(position.as_ref()).as_deref()
+            && manifest_type != ZSTD_CHUNKED_MANIFEST_TYPE
+        {
+            Err(UnsupportedManifestType { manifest_type })?;
+        }
+        let errors: Vec<_> = lint::lint_annotations(&get, blob_size, None)
+            .into_iter()
+            .filter(|diagnostic| diagnostic.severity == lint::Severity::Error)
+            .collect();
+        if !errors.is_empty() {
+            Err(lint::InvalidAnnotations { errors })?;
+        }
+        Ok(Self::from_oci(get))
+    }
+
+    /// Parses the metadata references from the annotations if they're present, or else from the
+    /// footer.  This is for descriptors that lost their annotations on the way, which is common
+    /// with Docker schema2 manifests converted by proxies.
+    ///
+    /// The `get` closure is as for [`Self::from_oci()`], and should return None for everything if
+    /// there are no annotations at all.  `suffix` is as for [`Self::from_footer()`], and can be
+    /// None if the end of the blob hasn't been fetched (yet).  When falling back to the footer,
+    /// any checksum annotations that did survive are still used.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`Error::UnsupportedManifestType`] if the footer has an unknown manifest type,
+    /// or with [`Error::InvalidAnnotations`] if a surviving checksum annotation is malformed.
+    pub fn from_oci_or_footer<'a, S: AsRef<str> + 'a>(
+        get: impl Fn(&str) -> Option<&'a S>,
+        suffix: Option<&[u8]>,
+    ) -> Result<Option<Self>, Error> {
+        if let Some(references) = Self::from_oci(&get) {
+            return Ok(Some(references));
+        }
+        let Some(mut references) = suffix.map(Self::try_from_footer).transpose()?.flatten() else {
+            return Ok(None);
+        };
+        let mut errors = vec![];
+        let mut digest = |annotation| {
+            let value = get(annotation)?.as_ref();
+            if let Err(err) = check_digest(value) {
+                errors.push(lint::Diagnostic {
+                    severity: lint::Severity::Error,
+                    annotation,
+                    message: err.to_string(),
+                });
+            }
+            Some(value.to_owned())
+        };
+        references.manifest.digest = digest(MANIFEST_CHECKSUM_ANNOTATION);
+        references.tarsplit.digest = digest(TARSPLIT_CHECKSUM_ANNOTATION);
+        if !errors.is_empty() {
+            Err(lint::InvalidAnnotations { errors })?;
+        }
+        Ok(Some(references))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tarsplit_lines_may_end_with_crlf() -> Result<()> {
+        let manifest = zstd::encode_all(&br#"{"version":1,"entries":[]}"#[..], 0)?;
+        let tarsplit = zstd::encode_all(
+            &b"{\"type\":2,\"payload\":\"aGVsbG8g\"}\r\n\r\n\
+              {\"type\":2,\"payload\":\"d29ybGQ=\"}\r\n"[..],
+            0,
+        )?;
+        let stream = Stream::new_from_frames(&manifest, &tarsplit)?;
+
+        let mut output = vec![];
+        stream.write_to(&mut output, |_| bail!("No external content"))?;
+        assert_eq!(output, b"hello world");
+        Ok(())
+    }
+
+    #[test]
+    fn zeros_are_recognised_within_limits() -> Result<()> {
+        let zeros = vec![0; 1 << 20];
+        let compressed = zstd::encode_all(&zeros[..], 3)?;
+        let reference = ContentReference {
+            range: 0..compressed.len() as u64,
+            digest: digest::sha256(&zeros).into(),
+            size: zeros.len() as u64,
+        };
+        assert!(reference.is_zeros());
+
+        // A huge size in a tiny range would take forever to hash
+        let forged = ContentReference {
+            range: 0..10,
+            size: 1 << 60,
+            ..reference
+        };
+        assert!(!forged.is_zeros());
+        Ok(())
+    }
+
+    #[test]
+    fn footer_ranges_that_overflow_are_ignored() {
+        let reference = |range| MetadataReference {
+            range,
+            digest: None,
+            uncompressed_size: 10,
+        };
+        let mut footer = Footer::new(&reference(0..10), &reference(10..20)).to_bytes();
+        // The offset of the manifest
+        footer[8..16].copy_from_slice(&u64::MAX.to_le_bytes());
+
+        assert!(MetadataReferences::from_footer(&footer).is_none());
+        assert!(MetadataReferences::footer_chain(&footer).is_empty());
+        assert!(blob::MappedBlob::new(&footer).is_ok_and(|blob| blob.is_none()));
+    }
+
+    #[test]
+    fn surviving_checksums_are_checked_on_the_footer_fallback() -> Result<()> {
+        let reference = |range| MetadataReference {
+            range,
+            digest: None,
+            uncompressed_size: 10,
+        };
+        let footer = Footer::new(&reference(0..10), &reference(10..20)).to_bytes();
+        let digest = format!("sha256:{}", "0".repeat(64));
+
+        let annotations = HashMap::from([(MANIFEST_CHECKSUM_ANNOTATION, digest.clone())]);
+        let references =
+            MetadataReferences::from_oci_or_footer(|name| annotations.get(name), Some(&footer))?
+                .context("Footer not found")?;
+        assert_eq!(references.manifest.digest.as_ref(), Some(&digest));
+        assert_eq!(references.tarsplit.digest, None);
+
+        let annotations = HashMap::from([
+            (MANIFEST_CHECKSUM_ANNOTATION, digest),
+            (TARSPLIT_CHECKSUM_ANNOTATION, "sha256:nothex".to_owned()),
+        ]);
+        let result =
+            MetadataReferences::from_oci_or_footer(|name| annotations.get(name), Some(&footer));
+        let Err(Error::InvalidAnnotations(err)) = result else {
+            bail!("Expected InvalidAnnotations but got {result:?}");
+        };
+        assert_eq!(err.errors.len(), 1);
+        assert_eq!(err.errors[0].annotation, TARSPLIT_CHECKSUM_ANNOTATION);
+        Ok(())
+    }
 }
@@ -0,0 +1,415 @@
+//! Checking the zstd:chunked annotations of OCI layer descriptors, for example in a registry
+//! policy webhook.
+
+use core::{
+    fmt::{self, Write},
+    ops::Range,
+};
+
+use crate::{
+    FOOTER_SIZE, MANIFEST_CHECKSUM_ANNOTATION, MANIFEST_POSITION_ANNOTATION, MetadataReference,
+    MetadataReferences, TARSPLIT_CHECKSUM_ANNOTATION, TARSPLIT_POSITION_ANNOTATION,
+    UnsupportedManifestType, digest::check_digest, format::ZSTD_CHUNKED_MANIFEST_TYPE, to_vec_u64,
+};
+
+/// How serious a problem with the annotations is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Severity {
+    /// The layer will still work, but clients lose something (usually the ability to verify part
+    /// of the metadata).
+    Warning,
+
+    /// Clients will fail to pull the layer, or pull it incorrectly.
+    Error,
+}
+
+/// A problem found with one of the annotations.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    /// How serious the problem is.
+    pub severity: Severity,
+
+    /// The annotation with the problem.
+    pub annotation: &'static str,
+
+    /// A human-readable description of the problem.
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        };
+        write!(f, "{severity}: {}: {}", self.annotation, self.message)
+    }
+}
+
+/// The error returned by [`MetadataReferences::try_from_oci()`] for malformed annotations.
+#[derive(Debug, Clone, thiserror::Error)]
+#[error("Invalid zstd:chunked annotations{}", summary(.errors))]
+pub struct InvalidAnnotations {
+    /// The problems that were found, all with [`Severity::Error`].
+    pub errors: Vec<Diagnostic>,
+}
+
+// The annotation and message of each error, for the message of InvalidAnnotations
+fn summary(errors: &[Diagnostic]) -> String {
+    errors.iter().fold(String::new(), |mut summary, error| {
+        let _ = write!(summary, "; {}: {}", error.annotation, error.message);
+        summary
+    })
+}
+
+#[derive(Debug, Default)]
+struct Diagnostics(Vec<Diagnostic>);
+
+impl Diagnostics {
+    fn push(&mut self, severity: Severity, annotation: &'static str, message: impl Into<String>) {
+        self.0.push(Diagnostic {
+            severity,
+            annotation,
+            message: message.into(),
+        });
+    }
+
+    fn error(&mut self, annotation: &'static str, message: impl Into<String>) {
+        self.push(Severity::Error, annotation, message);
+    }
+
+    fn warning(&mut self, annotation: &'static str, message: impl Into<String>) {
+        self.push(Severity::Warning, annotation, message);
+    }
+
+    fn checksum(&mut self, annotation: &'static str, value: Option<&str>) {
+        match value {
+            None => self.warning(annotation, "Missing, so the content can't be verified"),
+            Some(value) => {
+                if let Err(err) = check_digest(value) {
+                    self.error(annotation, err.to_string());
+                }
+            }
+        }
+    }
+
+    fn position(
+        &mut self,
+        annotation: &'static str,
+        value: Option<&str>,
+        blob_size: Option<u64>,
+        fields: usize,
+    ) -> Option<(Range<u64>, u64)> {
+        let Some(value) = value else {
+            self.error(annotation, "Missing");
+            return None;
+        };
+        let Some(numbers) = to_vec_u64(value).filter(|numbers| numbers.len() == fields) else {
+            self.error(
+                annotation,
+                format!("Expected {fields} colon-separated integers but got {value:?}"),
+            );
+            return None;
+        };
+        if fields == 4 && numbers[3] != ZSTD_CHUNKED_MANIFEST_TYPE {
+            self.error(
+                annotation,
+                UnsupportedManifestType {
+                    manifest_type: numbers[3],
+                }
+                .to_string(),
+            );
+        }
+        let (start, length, uncompressed_size) = (numbers[0], numbers[1], numbers[2]);
+        if length == 0 {
+            self.error(annotation, "Length is zero");
+        }
+        let Some(end) = start.checked_add(length) else {
+            self.error(annotation, "Offset plus length overflows");
+            return None;
+        };
+        if let Some(blob_size) = blob_size
+            && end > blob_size
+        {
+            self.error(
+                annotation,
+                format!("Range {start}..{end} is past the end of the {blob_size} byte blob"),
+            );
+        }
+        Some((start..end, uncompressed_size))
+    }
+}
+
+/// Checks the zstd:chunked annotations of a layer descriptor.
+///
+/// You should provide a `get` closure that returns the requested annotation, or None if it
+/// doesn't exist, as for [`MetadataReferences::from_oci()`].  If the size of the blob is known,
+/// the positions are checked against it.  If the end of the blob is available, the positions are
+/// also checked against the footer: only the last 72 bytes are needed, but passing more is fine.
+///
+/// Returns an empty list for descriptors that don't have any zstd:chunked annotations at all,
+/// since those are simply not zstd:chunked layers.
+pub fn lint_annotations<'a, S: AsRef<str> + 'a>(
+    get: impl Fn(&str) -> Option<&'a S>,
+    blob_size: Option<u64>,
+    footer: Option<&[u8]>,
+) -> Vec<Diagnostic> {
+    let get = |name| get(name).map(AsRef::as_ref);
+    let manifest_checksum = get(MANIFEST_CHECKSUM_ANNOTATION);
+    let manifest_position = get(MANIFEST_POSITION_ANNOTATION);
+    let tarsplit_checksum = get(TARSPLIT_CHECKSUM_ANNOTATION);
+    let tarsplit_position = get(TARSPLIT_POSITION_ANNOTATION);
+
+    let mut diagnostics = Diagnostics::default();
+    if manifest_checksum.is_none()
+        && manifest_position.is_none()
+        && tarsplit_checksum.is_none()
+        && tarsplit_position.is_none()
+    {
+        return diagnostics.0;
+    }
+
+    diagnostics.checksum(MANIFEST_CHECKSUM_ANNOTATION, manifest_checksum);
+    diagnostics.checksum(TARSPLIT_CHECKSUM_ANNOTATION, tarsplit_checksum);
+    let manifest = diagnostics.position(
+        MANIFEST_POSITION_ANNOTATION,
+        manifest_position,
+        blob_size,
+        4,
+    );
+    let tarsplit = diagnostics.position(
+        TARSPLIT_POSITION_ANNOTATION,
+        tarsplit_position,
+        blob_size,
+        3,
+    );
+
+    if let (Some((manifest, _)), Some((tarsplit, _))) = (&manifest, &tarsplit)
+        && manifest.start < tarsplit.end
+        && tarsplit.start < manifest.end
+    {
+        diagnostics.error(
+            TARSPLIT_POSITION_ANNOTATION,
+            format!("Range {tarsplit:?} overlaps the manifest at {manifest:?}"),
+        );
+    }
+
+    if let Some(footer) = footer {
+        match MetadataReferences::try_from_footer(footer) {
+            Err(err) => diagnostics.error(MANIFEST_POSITION_ANNOTATION, format!("Footer: {err}")),
+            Ok(None) => diagnostics.error(
+                MANIFEST_POSITION_ANNOTATION,
+                "The blob doesn't end with a zstd:chunked footer",
+            ),
+            Ok(Some(references)) => {
+                for (annotation, annotated, actual) in [
+                    (
+                        MANIFEST_POSITION_ANNOTATION,
+                        &manifest,
+                        &references.manifest,
+                    ),
+                    (
+                        TARSPLIT_POSITION_ANNOTATION,
+                        &tarsplit,
+                        &references.tarsplit,
+                    ),
+                ] {
+                    if let Some((range, uncompressed_size)) = annotated
+                        && (*range != actual.range
+                            || *uncompressed_size != actual.uncompressed_size)
+                    {
+                        diagnostics.error(
+                            annotation,
+                            format!(
+                                "Range {range:?} ({uncompressed_size} bytes uncompressed) doesn't \
+                                 match the footer: {:?} ({} bytes uncompressed)",
+                                actual.range, actual.uncompressed_size
+                            ),
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    diagnostics.0
+}
+
+/// Which piece of metadata an [`Inconsistency`] is about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetadataKind {
+    /// The manifest.
+    Manifest,
+    /// The tarsplit.
+    Tarsplit,
+}
+
+impl fmt::Display for MetadataKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Manifest => "manifest",
+            Self::Tarsplit => "tarsplit",
+        })
+    }
+}
+
+/// A disagreement found by [`MetadataReferences::consistency_report()`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Inconsistency {
+    /// The range doesn't fit in the blob before the footer, which starts at `limit`.
+    OutOfBounds {
+        /// The metadata with the range.
+        kind: MetadataKind,
+        /// The range of the metadata.
+        range: Range<u64>,
+        /// The offset of the footer in the blob.
+        limit: u64,
+    },
+
+    /// The manifest and the tarsplit overlap.
+    Overlap {
+        /// The range of the manifest.
+        manifest: Range<u64>,
+        /// The range of the tarsplit.
+        tarsplit: Range<u64>,
+    },
+
+    /// The blob doesn't end with a zstd:chunked footer.
+    MissingFooter,
+
+    /// The footer couldn't be parsed.
+    InvalidFooter(String),
+
+    /// The footer has a different range or uncompressed size for the metadata.
+    FooterMismatch {
+        /// The metadata which doesn't match.
+        kind: MetadataKind,
+        /// The range of the metadata in the references.
+        range: Range<u64>,
+        /// The uncompressed size of the metadata in the references.
+        uncompressed_size: u64,
+        /// The range of the metadata in the footer.
+        footer_range: Range<u64>,
+        /// The uncompressed size of the metadata in the footer.
+        footer_uncompressed_size: u64,
+    },
+}
+
+impl fmt::Display for Inconsistency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::OutOfBounds { kind, range, limit } => write!(
+                f,
+                "The {kind} at {range:?} doesn't end before the footer at offset {limit}"
+            ),
+            Self::Overlap { manifest, tarsplit } => write!(
+                f,
+                "The manifest at {manifest:?} overlaps the tarsplit at {tarsplit:?}"
+            ),
+            Self::MissingFooter => write!(f, "The blob doesn't end with a zstd:chunked footer"),
+            Self::InvalidFooter(err) => write!(f, "Invalid footer: {err}"),
+            Self::FooterMismatch {
+                kind,
+                range,
+                uncompressed_size,
+                footer_range,
+                footer_uncompressed_size,
+            } => write!(
+                f,
+                "The {kind} is at {range:?} ({uncompressed_size} bytes uncompressed), but the \
+                 footer has it at {footer_range:?} ({footer_uncompressed_size} bytes uncompressed)"
+            ),
+        }
+    }
+}
+
+/// The result of [`MetadataReferences::consistency_report()`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConsistencyReport {
+    /// The size of the blob that the references were checked against.
+    pub blob_size: u64,
+
+    /// The disagreements that were found, if any.
+    pub inconsistencies: Vec<Inconsistency>,
+}
+
+impl ConsistencyReport {
+    /// Checks that no disagreements were found.
+    #[must_use]
+    pub const fn is_consistent(&self) -> bool {
+        self.inconsistencies.is_empty()
+    }
+}
+
+impl fmt::Display for ConsistencyReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_consistent() {
+            return write!(f, "Consistent with the {} byte blob", self.blob_size);
+        }
+        write!(f, "Inconsistent with the {} byte blob", self.blob_size)?;
+        for inconsistency in &self.inconsistencies {
+            write!(f, "; {inconsistency}")?;
+        }
+        Ok(())
+    }
+}
+
+pub(crate) fn consistency_report(
+    references: &MetadataReferences,
+    blob_size: u64,
+    footer: Option<&[u8]>,
+) -> ConsistencyReport {
+    let metadata = [
+        (MetadataKind::Manifest, &references.manifest),
+        (MetadataKind::Tarsplit, &references.tarsplit),
+    ];
+    let mut inconsistencies = vec![];
+
+    let limit = blob_size.saturating_sub(FOOTER_SIZE);
+    for (kind, reference) in metadata {
+        if reference.range.end > limit {
+            inconsistencies.push(Inconsistency::OutOfBounds {
+                kind,
+                range: reference.range.clone(),
+                limit,
+            });
+        }
+    }
+
+    let (manifest, tarsplit) = (&references.manifest.range, &references.tarsplit.range);
+    if manifest.start < tarsplit.end && tarsplit.start < manifest.end {
+        inconsistencies.push(Inconsistency::Overlap {
+            manifest: manifest.clone(),
+            tarsplit: tarsplit.clone(),
+        });
+    }
+
+    match footer.map(MetadataReferences::try_from_footer) {
+        None => {}
+        Some(Err(err)) => inconsistencies.push(Inconsistency::InvalidFooter(err.to_string())),
+        Some(Ok(None)) => inconsistencies.push(Inconsistency::MissingFooter),
+        Some(Ok(Some(footer))) => {
+            let footer = [&footer.manifest, &footer.tarsplit];
+            for ((kind, reference), footer) in metadata.into_iter().zip(footer) {
+                if !same_position(reference, footer) {
+                    inconsistencies.push(Inconsistency::FooterMismatch {
+                        kind,
+                        range: reference.range.clone(),
+                        uncompressed_size: reference.uncompressed_size,
+                        footer_range: footer.range.clone(),
+                        footer_uncompressed_size: footer.uncompressed_size,
+                    });
+                }
+            }
+        }
+    }
+
+    ConsistencyReport {
+        blob_size,
+        inconsistencies,
+    }
+}
+
+fn same_position(a: &MetadataReference, b: &MetadataReference) -> bool {
+    a.range == b.range && a.uncompressed_size == b.uncompressed_size
+}
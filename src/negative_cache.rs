@@ -0,0 +1,220 @@
+//! A persistent record of layers that failed verification during partial pulls.
+//!
+//! If a layer is corrupt (or its metadata lies about its content), every attempt to pull it
+//! partially will fail in the same way, often only after most of the work has been done.  A
+//! [`NegativeCache`] remembers such failures across runs, so that a puller can give up on partial
+//! pulls of that layer right away and fall back to fetching the whole blob.
+//!
+//! Failures are recorded per blob, optionally along with the digest of the content that failed,
+//! and are forgotten after a configurable time-to-live.  A blob is only considered bad once it has
+//! failed [`NegativeCache::with_threshold()`] times within that window, so that a single network
+//! glitch doesn't disable partial pulls.
+
+use std::{
+    collections::HashMap,
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    process,
+    sync::{Mutex, MutexGuard, PoisonError},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result, ensure};
+use serde::{Deserialize, Serialize};
+
+use crate::digest::check_digest;
+
+const NEGATIVE_CACHE_VERSION: u32 = 1;
+
+/// A recorded failure, as returned by [`NegativeCache::failures()`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Failure {
+    /// The digest of the layer blob.
+    pub blob: String,
+
+    /// The digest of the content that failed verification, if the failure was for a specific
+    /// piece of content rather than the blob as a whole.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub digest: Option<String>,
+
+    /// The number of times this failure has been recorded since it was last forgotten.
+    pub count: u32,
+
+    /// When the failure was last recorded, in seconds since the Unix epoch.
+    pub last_seen: u64,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct CacheFile {
+    version: u32,
+    failures: Vec<Failure>,
+}
+
+type Key = (String, Option<String>);
+
+/// A persistent, thread-safe record of blobs (and content within them) that failed verification.
+///
+/// The whole file is rewritten whenever a failure is recorded.  Concurrent processes sharing the
+/// same file won't corrupt it, but may lose each other's updates.
+#[derive(Debug)]
+pub struct NegativeCache {
+    path: PathBuf,
+    ttl: Duration,
+    threshold: u32,
+    failures: Mutex<HashMap<Key, Failure>>,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+impl NegativeCache {
+    /// Opens the cache stored in the given file, creating it when the first failure is recorded.
+    /// Failures are forgotten `ttl` after they were last seen.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the file exists but can't be read or parsed.
+    pub fn open(path: impl Into<PathBuf>, ttl: Duration) -> Result<Self> {
+        let path = path.into();
+        let file: CacheFile = match fs::read(&path) {
+            Ok(data) => serde_json::from_slice(&data)
+                .with_context(|| format!("Unable to parse negative cache {}", path.display()))?,
+            Err(err) if err.kind() == ErrorKind::NotFound => CacheFile::default(),
+            Err(err) => Err(err)?,
+        };
+        ensure!(
+            file.failures.is_empty() || file.version == NEGATIVE_CACHE_VERSION,
+            "Unsupported negative cache version {}",
+            file.version
+        );
+
+        let cache = Self {
+            path,
+            ttl,
+            threshold: 2,
+            failures: Mutex::new(
+                file.failures
+                    .into_iter()
+                    .map(|failure| ((failure.blob.clone(), failure.digest.clone()), failure))
+                    .collect(),
+            ),
+        };
+        cache.lock().retain(|_, failure| !cache.expired(failure));
+        Ok(cache)
+    }
+
+    /// Sets the number of failures after which a blob is considered bad.  The default is 2.
+    #[must_use]
+    pub const fn with_threshold(mut self, threshold: u32) -> Self {
+        self.threshold = threshold;
+        self
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<Key, Failure>> {
+        // The map is always in a consistent state, so we can ignore poisoning
+        self.failures.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn expired(&self, failure: &Failure) -> bool {
+        now().saturating_sub(failure.last_seen) >= self.ttl.as_secs()
+    }
+
+    fn save(&self, failures: &HashMap<Key, Failure>) -> Result<()> {
+        let mut file = CacheFile {
+            version: NEGATIVE_CACHE_VERSION,
+            failures: failures.values().cloned().collect(),
+        };
+        file.failures
+            .sort_by(|a, b| (&a.blob, &a.digest).cmp(&(&b.blob, &b.digest)));
+
+        if let Some(dir) = self.path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        // Write to a temporary file first, so that readers never see a partial file
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(format!(".{}.tmp", process::id()));
+        let tmp = Path::new(&tmp);
+        fs::write(tmp, serde_json::to_vec_pretty(&file)?)?;
+        if let Err(err) = fs::rename(tmp, &self.path) {
+            let _ = fs::remove_file(tmp);
+            Err(err)?;
+        }
+        Ok(())
+    }
+
+    /// Records a failure of the given blob, optionally naming the content that failed, and saves
+    /// the cache.  Returns `true` if the blob is now considered bad.
+    ///
+    /// # Errors
+    ///
+    /// Fails if a digest is malformed or if the cache couldn't be saved.
+    pub fn record(&self, blob: &str, digest: Option<&str>) -> Result<bool> {
+        check_digest(blob)?;
+        digest.map(check_digest).transpose()?;
+
+        let mut failures = self.lock();
+        failures.retain(|_, failure| !self.expired(failure));
+        let failure = failures
+            .entry((blob.to_owned(), digest.map(str::to_owned)))
+            .or_insert_with(|| Failure {
+                blob: blob.to_owned(),
+                digest: digest.map(str::to_owned),
+                count: 0,
+                last_seen: 0,
+            });
+        failure.count = failure.count.saturating_add(1);
+        failure.last_seen = now();
+        self.save(&failures)?;
+        drop(failures);
+
+        Ok(self.is_bad(blob))
+    }
+
+    /// Checks if the given blob has failed often enough (within the time-to-live) that partial
+    /// pulls of it shouldn't be attempted.  Failures of different content within the same blob
+    /// are added together.
+    #[must_use]
+    pub fn is_bad(&self, blob: &str) -> bool {
+        let total = self
+            .lock()
+            .values()
+            .filter(|failure| failure.blob == blob && !self.expired(failure))
+            .map(|failure| failure.count)
+            .fold(0, u32::saturating_add);
+        total >= self.threshold
+    }
+
+    /// Returns all of the unexpired failures recorded for the given blob.
+    #[must_use]
+    pub fn failures(&self, blob: &str) -> Vec<Failure> {
+        let mut failures: Vec<_> = self
+            .lock()
+            .values()
+            .filter(|failure| failure.blob == blob && !self.expired(failure))
+            .cloned()
+            .collect();
+        failures.sort_by(|a, b| a.digest.cmp(&b.digest));
+        failures
+    }
+
+    /// Forgets the failures of the given blob (for example, after it was pulled successfully) and
+    /// saves the cache.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the cache couldn't be saved.
+    pub fn forget(&self, blob: &str) -> Result<()> {
+        let mut failures = self.lock();
+        let before = failures.len();
+        failures.retain(|(failed, _), _| failed != blob);
+        if failures.len() != before {
+            self.save(&failures)?;
+        }
+        drop(failures);
+        Ok(())
+    }
+}
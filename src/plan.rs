@@ -0,0 +1,450 @@
+//! Fetch plans, for handing the downloading of content over to external tools.
+//!
+//! A [`FetchPlan`] lists the ranges of a blob that need to be downloaded, and can be exported as
+//! JSON for download managers, fleet prefetchers and other tools with their own transport:
+//!
+//! ```json
+//! {
+//!   "version": 1,
+//!   "blob": "sha256:0123...",
+//!   "items": [
+//!     {
+//!       "range": "bytes=1024-2047",
+//!       "offset": 1024,
+//!       "length": 1024,
+//!       "digest": "sha256:4567...",
+//!       "size": 4096,
+//!       "path": "4567....zst",
+//!       "chunks": [3, 17]
+//!     }
+//!   ]
+//! }
+//! ```
+//!
+//! `blob` is the digest of the layer blob (ie: the last part of its registry URL), if it's known.
+//! For each item, `range` is the value for an HTTP `Range` header requesting the compressed data
+//! (`offset` and `length` give the same information in a more convenient form), and `path` is
+//! where the downloaded (still compressed) data should be saved, relative to an output directory
+//! chosen by the user.  `digest` and `size` describe the content after decompression.  `chunks`
+//! (only in plans made by [`Stream::fetch_plan()`]) lists the chunks of the stream which use the
+//! content.
+//!
+//! [`FetchPlan::batches()`] groups nearby items into fewer requests, for tools that can split up
+//! the responses again.
+//!
+//! Once the tool is done, [`FetchPlan::import_dir()`] checks the downloaded files against the plan
+//! and adds them to a store (any [`Backend`], like a [`ChunkStore`]), and [`FetchPlan::assemble()`]
+//! reconstructs the layer.
+//!
+//! A [`PartialPlan`] works out what's needed for just a few files out of a layer (like
+//! `/etc/os-release`), which can then be turned into a [`FetchPlan`].
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    io::{ErrorKind, Write},
+    path::Path,
+    sync::Arc,
+};
+
+use anyhow::{Context, Result, bail, ensure};
+use serde::{Deserialize, Serialize};
+
+#[cfg(doc)]
+use crate::store::ChunkStore;
+use crate::{
+    Chunk, ContentReference, Stream,
+    digest::check_digest,
+    range::{CoalescedRange, RangePlanner},
+    store::Backend,
+};
+
+/// The version of the JSON format written by [`FetchPlan::to_json()`].
+pub const FETCH_PLAN_VERSION: u32 = 1;
+
+/// A single range to be downloaded.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FetchItem {
+    /// The HTTP `Range` header value for the compressed data, like `bytes=1024-2047`.
+    pub range: String,
+
+    /// The offset of the compressed data in the blob.
+    pub offset: u64,
+
+    /// The length of the compressed data.
+    pub length: u64,
+
+    /// The digest of the data after decompression.
+    pub digest: String,
+
+    /// The size of the data after decompression.
+    pub size: u64,
+
+    /// Where to save the downloaded data, relative to the output directory.
+    pub path: String,
+
+    /// The indexes of the chunks in [`Stream::chunks`] which use the content, if the plan was
+    /// made by [`Stream::fetch_plan()`].
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub chunks: Vec<usize>,
+}
+
+impl FetchItem {
+    /// Creates the item for downloading the given reference.
+    #[must_use]
+    pub fn new(reference: &ContentReference) -> Self {
+        let hex = reference
+            .digest
+            .strip_prefix("sha256:")
+            .unwrap_or(&reference.digest);
+        Self {
+            range: format!(
+                "bytes={}-{}",
+                reference.range.start,
+                reference.range.end.saturating_sub(1)
+            ),
+            offset: reference.range.start,
+            length: reference.compressed_size(),
+            digest: reference.digest.to_string(),
+            size: reference.size,
+            path: format!("{hex}.zst"),
+            chunks: vec![],
+        }
+    }
+
+    /// The reference that this item was created from.  [`FetchPlan::from_json()`] rejects items
+    /// whose range doesn't fit in a `u64`, but for one built by hand, the range is cut short.
+    #[must_use]
+    pub fn reference(&self) -> ContentReference {
+        ContentReference {
+            range: self.offset..self.offset.saturating_add(self.length),
+            digest: Arc::from(self.digest.as_str()),
+            size: self.size,
+        }
+    }
+
+    /// Checks downloaded (compressed) data against the item, returning the decompressed content.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the data has the wrong length, can't be decompressed, or doesn't match the digest.
+    pub fn check(&self, data: &[u8]) -> Result<Vec<u8>> {
+        ensure!(
+            data.len() as u64 == self.length,
+            "Expected {} bytes for {} but got {}",
+            self.length,
+            self.path,
+            data.len()
+        );
+        let reference = self.reference();
+        let content = reference.decompress(data)?;
+        reference.verify(&content)?;
+        Ok(content)
+    }
+}
+
+/// The result of [`FetchPlan::import()`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ImportReport {
+    /// The number of items that were checked and added to the store.
+    pub imported: u64,
+
+    /// The number of items whose content was already in the store.
+    pub already_present: u64,
+}
+
+/// A list of ranges to download, with each piece of content appearing only once.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FetchPlan {
+    /// The version of the format: see [`FETCH_PLAN_VERSION`].
+    pub version: u32,
+
+    /// The digest of the blob that the ranges refer to, if it's known.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub blob: Option<String>,
+
+    /// The ranges to download, in the order that they were given (or in order of their offsets,
+    /// for [`Stream::fetch_plan()`]).
+    pub items: Vec<FetchItem>,
+}
+
+impl FetchPlan {
+    /// Creates a plan for downloading the given references (for example, those returned by
+    /// [`Stream::missing_references()`]) from the blob with the given digest.  Duplicate digests
+    /// are only included once.
+    pub fn new<'a>(
+        blob: Option<&str>,
+        references: impl IntoIterator<Item = &'a ContentReference>,
+    ) -> Self {
+        let mut seen = HashSet::new();
+        Self {
+            version: FETCH_PLAN_VERSION,
+            blob: blob.map(str::to_owned),
+            items: references
+                .into_iter()
+                .filter(|reference| seen.insert(&reference.digest))
+                .map(FetchItem::new)
+                .collect(),
+        }
+    }
+
+    // The plan for all of the content of a stream, in order of offset, as for
+    // `Stream::fetch_plan()`
+    pub(crate) fn for_stream(blob: Option<&str>, stream: &Stream) -> Self {
+        let mut items: Vec<FetchItem> = vec![];
+        let mut by_digest = HashMap::new();
+        for (index, chunk) in stream.chunks.iter().enumerate() {
+            if let Chunk::External(reference) = chunk {
+                let item = *by_digest.entry(&reference.digest).or_insert_with(|| {
+                    items.push(FetchItem::new(reference));
+                    items.len() - 1
+                });
+                if let Some(item) = items.get_mut(item) {
+                    item.chunks.push(index);
+                }
+            }
+        }
+        items.sort_by_key(|item| item.offset);
+        Self {
+            version: FETCH_PLAN_VERSION,
+            blob: blob.map(str::to_owned),
+            items,
+        }
+    }
+
+    /// The total number of (compressed) bytes to download.
+    #[must_use]
+    pub fn download_size(&self) -> u64 {
+        self.items.iter().map(|item| item.length).sum()
+    }
+
+    /// The total size of the content after decompression.
+    #[must_use]
+    pub fn content_size(&self) -> u64 {
+        self.items.iter().map(|item| item.size).sum()
+    }
+
+    /// Groups the items into requests, as planned by the given [`RangePlanner`].  The parts of
+    /// each request can be matched up with the items by their digests.
+    #[must_use]
+    pub fn batches(&self, planner: &RangePlanner) -> Vec<CoalescedRange> {
+        let references: Vec<_> = self.items.iter().map(FetchItem::reference).collect();
+        planner.plan(&references)
+    }
+
+    /// Imports downloaded items into a store, given as `(path, data)` pairs where `path` is the
+    /// [`FetchItem::path`] of the item and `data` is what was downloaded.  Each item is checked
+    /// before it's added.  Items whose content is already in the store can be left out.
+    ///
+    /// # Errors
+    ///
+    /// Fails if `fetched` yields an error, if a path isn't part of the plan, if an item fails
+    /// [`FetchItem::check()`], if any item is still missing at the end, or if the store fails.
+    pub fn import(
+        &self,
+        fetched: impl IntoIterator<Item = Result<(String, Vec<u8>)>>,
+        store: &(impl Backend + ?Sized),
+    ) -> Result<ImportReport> {
+        let by_path: HashMap<&str, &FetchItem> = self
+            .items
+            .iter()
+            .map(|item| (item.path.as_str(), item))
+            .collect();
+
+        let mut report = ImportReport::default();
+        for result in fetched {
+            let (path, data) = result?;
+            let item = by_path
+                .get(path.as_str())
+                .with_context(|| format!("{path:?} isn't part of the fetch plan"))?;
+            if store.contains(&item.digest)? {
+                report.already_present += 1;
+                continue;
+            }
+            let content = item
+                .check(&data)
+                .with_context(|| format!("Downloaded {path:?} is invalid"))?;
+            store.insert(&item.digest, &content)?;
+            report.imported += 1;
+        }
+
+        let mut missing = 0;
+        let mut example = None;
+        for item in &self.items {
+            if !store.contains(&item.digest)? {
+                missing += 1;
+                example.get_or_insert(&item.path);
+            }
+        }
+        if let Some(example) = example {
+            bail!("{missing} items haven't been downloaded, including {example:?}");
+        }
+        Ok(report)
+    }
+
+    /// Like [`Self::import()`], reading each item from its path in `dir`.  Files that don't exist
+    /// are skipped, which is fine if their content is already in the store.
+    ///
+    /// # Errors
+    ///
+    /// As for [`Self::import()`], or if a file can't be read.
+    pub fn import_dir(
+        &self,
+        dir: impl AsRef<Path>,
+        store: &(impl Backend + ?Sized),
+    ) -> Result<ImportReport> {
+        let dir = dir.as_ref();
+        self.import(
+            self.items
+                .iter()
+                .filter_map(|item| match fs::read(dir.join(&item.path)) {
+                    Ok(data) => Some(Ok((item.path.clone(), data))),
+                    Err(err) if err.kind() == ErrorKind::NotFound => None,
+                    Err(err) => Some(Err(err.into())),
+                }),
+            store,
+        )
+    }
+
+    /// Reconstructs the layer after the plan's items have been imported into `store`.  Content
+    /// that wasn't part of the plan (because it was already available) must be in the store too.
+    ///
+    /// # Errors
+    ///
+    /// Fails if any content is missing from the store or doesn't match its digest, or if writing
+    /// fails.
+    pub fn assemble(
+        &self,
+        stream: &Stream,
+        store: &(impl Backend + ?Sized),
+        output: &mut impl Write,
+    ) -> Result<()> {
+        stream.write_to_verified(output, |reference| {
+            store
+                .get(&reference.digest)?
+                .with_context(|| format!("Content {} is missing", reference.digest))
+        })?;
+        Ok(())
+    }
+
+    /// Exports the plan as (pretty-printed) JSON.
+    ///
+    /// # Errors
+    ///
+    /// Fails only if serialization fails, which shouldn't happen.
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    /// Reads a plan from JSON, as written by [`Self::to_json()`].  The digests and paths are
+    /// checked, since the plan may have been modified by other tools.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the JSON is malformed, the version is unsupported, or an item has a range past the
+    /// end of a `u64`, a malformed digest or a path that would escape the output directory.
+    pub fn from_json(json: &str) -> Result<Self> {
+        let plan: Self = serde_json::from_str(json)?;
+        ensure!(
+            plan.version == FETCH_PLAN_VERSION,
+            "Unsupported fetch plan version {}",
+            plan.version
+        );
+        for item in &plan.items {
+            check_digest(&item.digest)?;
+            ensure!(
+                item.offset.checked_add(item.length).is_some(),
+                "Invalid range of {} bytes at {} in fetch plan",
+                item.length,
+                item.offset
+            );
+            ensure!(
+                !item.path.is_empty()
+                    && !item.path.starts_with('.')
+                    && !item.path.contains(['/', '\\', '\0']),
+                "Invalid path {:?} in fetch plan",
+                item.path
+            );
+        }
+        Ok(plan)
+    }
+}
+
+/// The chunks needed to materialize a subset of the files in a [`Stream`], without fetching the
+/// content of the rest of the layer.
+#[derive(Debug, Clone, Default)]
+pub struct PartialPlan {
+    /// The indexes of the files in [`Stream::files`] whose content is needed, in the order that
+    /// they were asked for.  Hardlinks are followed to the file holding the content.
+    pub files: Vec<usize>,
+
+    /// The indexes of the chunks in [`Stream::chunks`] holding the content of those files, in
+    /// order.  Inline chunks are included, even though there's nothing to fetch for them.
+    pub chunks: Vec<usize>,
+
+    /// The references that need to be resolved, with each digest appearing only once.
+    pub references: Vec<ContentReference>,
+
+    /// The paths that weren't found in the manifest.
+    pub missing: Vec<String>,
+}
+
+impl PartialPlan {
+    /// Works out what's needed to materialize the given paths, which are looked up as for
+    /// [`Stream::lookup()`].  Paths which aren't regular files (or hardlinks to them) are found,
+    /// but don't need any content.  Files asked for more than once are only included once.
+    pub fn new<'a>(stream: &Stream, paths: impl IntoIterator<Item = &'a str>) -> Self {
+        let mut plan = Self::default();
+        let mut seen_files = HashSet::new();
+        let mut seen_digests = HashSet::new();
+        for path in paths {
+            let Some((entry, mut file)) = stream.lookup_indexes(path) else {
+                plan.missing.push(path.to_owned());
+                continue;
+            };
+            if let Some(entry) = stream.entries.get(entry)
+                && entry.kind == "hardlink"
+                && let Some(target) = &entry.link_name
+            {
+                file = stream.lookup_indexes(target).and_then(|(_, file)| file);
+            }
+
+            let Some((index, file)) = file.and_then(|i| Some((i, stream.files.get(i)?))) else {
+                continue;
+            };
+            if !seen_files.insert(index) {
+                continue;
+            }
+            plan.files.push(index);
+            for index in file.chunks.clone() {
+                let Some(chunk) = stream.chunks.get(index) else {
+                    continue;
+                };
+                plan.chunks.push(index);
+                if let Chunk::External(reference) = chunk
+                    && seen_digests.insert(Arc::clone(&reference.digest))
+                {
+                    plan.references.push(reference.clone());
+                }
+            }
+        }
+        plan
+    }
+
+    /// The total size of the content of the files, in bytes.
+    #[must_use]
+    pub fn size(&self, stream: &Stream) -> u64 {
+        self.chunks
+            .iter()
+            .filter_map(|&index| stream.chunks.get(index))
+            .map(crate::reader::chunk_size)
+            .sum()
+    }
+
+    /// Creates a [`FetchPlan`] for downloading the references from the blob with the given
+    /// digest.
+    #[must_use]
+    pub fn fetch_plan(&self, blob: Option<&str>) -> FetchPlan {
+        FetchPlan::new(blob, &self.references)
+    }
+}
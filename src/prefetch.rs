@@ -0,0 +1,375 @@
+//! Working out which files a container needs to start, so they can be fetched first.
+//!
+//! Starting from the entrypoint, [`prefetch_set()`] reads each executable out of the layer and
+//! follows its ELF program interpreter (or `#!` line) and the shared libraries it needs, looking
+//! them up much like the dynamic linker would.  Only the content of those files is resolved.  The
+//! result is a heuristic: libraries loaded with `dlopen()` (or found with `/etc/ld.so.conf`) aren't
+//! found unless their directories are added to [`PrefetchOptions::search_dirs`].
+
+use std::collections::{HashSet, VecDeque};
+
+use anyhow::Result;
+
+use crate::{ContentReference, Error, Stream, normalize_path, plan::PartialPlan};
+
+// The most symlinks followed while resolving a single path, as for Linux
+const MAX_SYMLINKS: usize = 40;
+
+/// Options for [`prefetch_set()`].
+#[derive(Debug, Clone)]
+pub struct PrefetchOptions {
+    /// The directories searched for shared libraries after the `DT_RPATH` or `DT_RUNPATH` of the
+    /// object that needs them.  The defaults cover the usual and multiarch directories.
+    pub search_dirs: Vec<String>,
+
+    /// The directories searched for the command of a `#!/usr/bin/env command` line.
+    pub path: Vec<String>,
+
+    /// The most files that are analyzed, which protects against huge or malicious layers.
+    pub max_files: usize,
+}
+
+impl Default for PrefetchOptions {
+    fn default() -> Self {
+        let strings = |dirs: &[&str]| dirs.iter().map(|&dir| dir.to_owned()).collect();
+        Self {
+            search_dirs: strings(&[
+                "/lib64",
+                "/usr/lib64",
+                "/lib",
+                "/usr/lib",
+                "/lib/x86_64-linux-gnu",
+                "/usr/lib/x86_64-linux-gnu",
+                "/lib/aarch64-linux-gnu",
+                "/usr/lib/aarch64-linux-gnu",
+                "/usr/local/lib",
+            ]),
+            path: strings(&[
+                "/usr/local/sbin",
+                "/usr/local/bin",
+                "/usr/sbin",
+                "/usr/bin",
+                "/sbin",
+                "/bin",
+            ]),
+            max_files: 256,
+        }
+    }
+}
+
+/// The files needed to start an entrypoint, as found by [`prefetch_set()`].
+#[derive(Debug, Clone, Default)]
+pub struct PrefetchSet {
+    /// The files, with symlinks resolved, in the order that they're needed: the entrypoint, its
+    /// interpreter, and then the libraries (breadth first).
+    pub paths: Vec<String>,
+
+    /// The interpreters and libraries that couldn't be found in the layer (which may be fine, if
+    /// they're in a lower layer).
+    pub missing: Vec<String>,
+}
+
+impl PrefetchSet {
+    /// Works out the content needed for the files, in priority order.
+    #[must_use]
+    pub fn plan(&self, stream: &Stream) -> PartialPlan {
+        PartialPlan::new(stream, self.paths.iter().map(String::as_str))
+    }
+}
+
+/// Finds the files needed to start the given entrypoint (an absolute path), reading the content of
+/// only those files.
+///
+/// Returns an empty set, with the entrypoint as missing, if it isn't in the layer.  Files which
+/// aren't ELF objects or scripts are included, but not analyzed further.
+///
+/// # Errors
+///
+/// As for [`Stream::read_file()`].
+pub fn prefetch_set(
+    stream: &Stream,
+    entrypoint: &str,
+    options: &PrefetchOptions,
+    resolve_reference: impl Fn(&ContentReference) -> Result<Vec<u8>>,
+) -> Result<PrefetchSet, Error> {
+    let mut set = PrefetchSet::default();
+    let mut seen = HashSet::new();
+    let mut queue = VecDeque::from([entrypoint.to_owned()]);
+    while let Some(wanted) = queue.pop_front() {
+        if set.paths.len() >= options.max_files {
+            break;
+        }
+        let Some(path) = resolve_path(stream, &wanted) else {
+            set.missing.push(wanted);
+            continue;
+        };
+        if !seen.insert(path.clone()) {
+            continue;
+        }
+        let Some(file) = stream
+            .lookup_indexes(&path)
+            .and_then(|(_, file)| stream.files.get(file?))
+        else {
+            continue;
+        };
+        let data = stream.read_file(file, &resolve_reference)?;
+        set.paths.push(path.clone());
+
+        if let Some(interpreter) = script_interpreter(&data, stream, options) {
+            queue.push_back(interpreter);
+        } else if let Some(elf) = Elf::parse(&data) {
+            queue.extend(elf.interpreter());
+            let origin = path.rsplit_once('/').map_or("", |(dir, _)| dir);
+            for library in elf.needed() {
+                queue.push_back(find_library(stream, &library, &elf, origin, options));
+            }
+        }
+    }
+    Ok(set)
+}
+
+// Resolves symlinks (including those in the directories along the way) and hardlinks, returning
+// the normalized name of the entry that the path refers to
+fn resolve_path(stream: &Stream, path: &str) -> Option<String> {
+    let mut resolved: Vec<String> = vec![];
+    let mut remaining: VecDeque<String> = path.split('/').map(str::to_owned).collect();
+    let mut symlinks = 0;
+    while let Some(component) = remaining.pop_front() {
+        match component.as_str() {
+            "" | "." => continue,
+            ".." => {
+                resolved.pop();
+                continue;
+            }
+            _ => resolved.push(component),
+        }
+        let (entry, _) = stream.lookup(&resolved.join("/"))?;
+        let Some(target) = entry.link_name.as_deref() else {
+            continue;
+        };
+        match entry.kind.as_str() {
+            "symlink" => {
+                symlinks += 1;
+                if symlinks > MAX_SYMLINKS {
+                    return None;
+                }
+                resolved.pop();
+                if target.starts_with('/') {
+                    resolved.clear();
+                }
+            }
+            // Hardlink targets are relative to the root of the layer
+            "hardlink" => resolved.clear(),
+            _ => continue,
+        }
+        for component in target.split('/').rev() {
+            remaining.push_front(component.to_owned());
+        }
+    }
+    Some(normalize_path(&resolved.join("/")).to_owned())
+}
+
+// Finds a library much like the dynamic linker would, returning the name to report as missing if
+// it can't be found
+fn find_library(
+    stream: &Stream,
+    library: &str,
+    elf: &Elf<'_>,
+    origin: &str,
+    options: &PrefetchOptions,
+) -> String {
+    if library.contains('/') {
+        return library.to_owned();
+    }
+    // DT_RPATH is only used if there's no DT_RUNPATH
+    let rpath = elf
+        .dynamic_string(DT_RUNPATH)
+        .or_else(|| elf.dynamic_string(DT_RPATH))
+        .unwrap_or_default();
+    let origin = format!("/{origin}");
+    rpath
+        .split(':')
+        .filter(|dir| !dir.is_empty())
+        .map(|dir| {
+            dir.replace("${ORIGIN}", &origin)
+                .replace("$ORIGIN", &origin)
+        })
+        .chain(options.search_dirs.iter().cloned())
+        .map(|dir| format!("{dir}/{library}"))
+        .find(|candidate| resolve_path(stream, candidate).is_some())
+        .unwrap_or_else(|| library.to_owned())
+}
+
+// The interpreter named by a "#!" line, looking up the command for "#!/usr/bin/env command"
+fn script_interpreter(data: &[u8], stream: &Stream, options: &PrefetchOptions) -> Option<String> {
+    let line = data.strip_prefix(b"#!")?.split(|&b| b == b'\n').next()?;
+    let line = str::from_utf8(line).ok()?;
+    let mut words = line.split_whitespace();
+    let interpreter = words.next()?;
+    if interpreter.rsplit('/').next() == Some("env")
+        && let Some(command) = words.find(|word| !word.starts_with('-'))
+    {
+        // Both are needed, but env itself is tiny: prefer the command if it's there
+        return Some(
+            options
+                .path
+                .iter()
+                .map(|dir| format!("{dir}/{command}"))
+                .find(|candidate| resolve_path(stream, candidate).is_some())
+                .unwrap_or_else(|| interpreter.to_owned()),
+        );
+    }
+    Some(interpreter.to_owned())
+}
+
+const PT_LOAD: u32 = 1;
+const PT_DYNAMIC: u32 = 2;
+const PT_INTERP: u32 = 3;
+const DT_NULL: u64 = 0;
+const DT_NEEDED: u64 = 1;
+const DT_STRTAB: u64 = 5;
+const DT_RPATH: u64 = 15;
+const DT_RUNPATH: u64 = 29;
+
+// A program header, with the fields that are needed here
+struct Segment {
+    kind: u32,
+    offset: u64,
+    vaddr: u64,
+    filesz: u64,
+}
+
+// Just enough of an ELF parser to find the interpreter and the needed libraries.  Malformed
+// objects are handled by finding nothing.
+struct Elf<'a> {
+    data: &'a [u8],
+    is_64: bool,
+    little_endian: bool,
+    segments: Vec<Segment>,
+    dynamic: Vec<(u64, u64)>,
+}
+
+impl<'a> Elf<'a> {
+    fn parse(data: &'a [u8]) -> Option<Self> {
+        if data.get(..4)? != b"\x7fELF" {
+            return None;
+        }
+        let mut elf = Self {
+            data,
+            is_64: *data.get(4)? == 2,
+            little_endian: *data.get(5)? == 1,
+            segments: vec![],
+            dynamic: vec![],
+        };
+
+        let (phoff, phentsize, phnum) = if elf.is_64 {
+            (elf.u64(0x20)?, elf.u16(0x36)?, elf.u16(0x38)?)
+        } else {
+            (elf.u32(0x1c)?.into(), elf.u16(0x2a)?, elf.u16(0x2c)?)
+        };
+        for i in 0..u64::from(phnum) {
+            let at = phoff.checked_add(i * u64::from(phentsize))?;
+            elf.segments.push(if elf.is_64 {
+                Segment {
+                    kind: elf.u32(at)?,
+                    offset: elf.u64(at + 8)?,
+                    vaddr: elf.u64(at + 16)?,
+                    filesz: elf.u64(at + 32)?,
+                }
+            } else {
+                Segment {
+                    kind: elf.u32(at)?,
+                    offset: elf.u32(at + 4)?.into(),
+                    vaddr: elf.u32(at + 8)?.into(),
+                    filesz: elf.u32(at + 16)?.into(),
+                }
+            });
+        }
+
+        if let Some(dynamic) = elf.segments.iter().find(|s| s.kind == PT_DYNAMIC) {
+            let size = if elf.is_64 { 16 } else { 8 };
+            let mut dynamic_entries = vec![];
+            for at in (dynamic.offset..dynamic.offset.saturating_add(dynamic.filesz)).step_by(size)
+            {
+                let entry = if elf.is_64 {
+                    (elf.u64(at)?, elf.u64(at + 8)?)
+                } else {
+                    (elf.u32(at)?.into(), elf.u32(at + 4)?.into())
+                };
+                if entry.0 == DT_NULL {
+                    break;
+                }
+                dynamic_entries.push(entry);
+            }
+            elf.dynamic = dynamic_entries;
+        }
+        Some(elf)
+    }
+
+    fn bytes<const N: usize>(&self, at: u64) -> Option<[u8; N]> {
+        let at = usize::try_from(at).ok()?;
+        self.data.get(at..at.checked_add(N)?)?.try_into().ok()
+    }
+
+    fn u16(&self, at: u64) -> Option<u16> {
+        let bytes = self.bytes(at)?;
+        Some(if self.little_endian {
+            u16::from_le_bytes(bytes)
+        } else {
+            u16::from_be_bytes(bytes)
+        })
+    }
+
+    fn u32(&self, at: u64) -> Option<u32> {
+        let bytes = self.bytes(at)?;
+        Some(if self.little_endian {
+            u32::from_le_bytes(bytes)
+        } else {
+            u32::from_be_bytes(bytes)
+        })
+    }
+
+    fn u64(&self, at: u64) -> Option<u64> {
+        let bytes = self.bytes(at)?;
+        Some(if self.little_endian {
+            u64::from_le_bytes(bytes)
+        } else {
+            u64::from_be_bytes(bytes)
+        })
+    }
+
+    // A nul-terminated string at a file offset
+    fn string(&self, at: u64) -> Option<String> {
+        let data = self.data.get(usize::try_from(at).ok()?..)?;
+        let end = data.iter().position(|&b| b == 0)?;
+        Some(String::from_utf8_lossy(data.get(..end)?).into_owned())
+    }
+
+    fn interpreter(&self) -> Option<String> {
+        let segment = self.segments.iter().find(|s| s.kind == PT_INTERP)?;
+        self.string(segment.offset)
+    }
+
+    // A string from the dynamic string table, which is given by its address in memory
+    fn dynamic_string_at(&self, offset: u64) -> Option<String> {
+        let (_, strtab) = self.dynamic.iter().find(|(tag, _)| *tag == DT_STRTAB)?;
+        let address = strtab.checked_add(offset)?;
+        let segment = self.segments.iter().find(|s| {
+            s.kind == PT_LOAD && (s.vaddr..s.vaddr.saturating_add(s.filesz)).contains(&address)
+        })?;
+        self.string(address - segment.vaddr + segment.offset)
+    }
+
+    fn dynamic_string(&self, tag: u64) -> Option<String> {
+        let (_, offset) = self.dynamic.iter().find(|(t, _)| *t == tag)?;
+        self.dynamic_string_at(*offset)
+    }
+
+    fn needed(&self) -> Vec<String> {
+        self.dynamic
+            .iter()
+            .filter(|(tag, _)| *tag == DT_NEEDED)
+            .filter_map(|(_, offset)| self.dynamic_string_at(*offset))
+            .collect()
+    }
+}
@@ -0,0 +1,1654 @@
+//! Pulling zstd:chunked images from registries, fetching only the content that isn't available
+//! locally.
+//!
+//! A [`Puller`] fetches the manifest of an image and then, for all layers at once, the metadata
+//! (using the annotations, or the footer if they got lost) and the content of each file, with HTTP
+//! range requests.  Content goes into a [`Backend`], where it's found by later pulls, and is
+//! verified on the way.  Content that's already in the store, that's in the [`KnownContent`]
+//! table, or that's all zeros, isn't fetched at all.
+//!
+//! Network failures are retried for as long as the pull keeps making progress (as judged by a
+//! [`HealthTracker`]), and each retry only asks for the bytes that are still missing.
+//!
+//! Several images can be pulled at once, with [`Puller::pull_all()`] or with concurrent calls to
+//! [`Puller::pull()`].  They share the puller's connections (which go to whichever pull has the
+//! fewest), its bandwidth limit and its disk limits, and content that appears in several images is
+//! only fetched once.  Progress is reported through the [`Progress`]
+//! trait, and a [`PullReport`] sums up what happened, for comparing efficiency across images.
+//!
+//! Artifacts using the zstd:chunked framing (see [`is_zstd_artifact_media_type()`]) can be pulled
+//! too, with or without a tarsplit, but they can't be unpacked.
+//!
+//! When a layer can't be pulled partially (it isn't zstd:chunked, the registry has no range
+//! support, ...) the error carries a [`Fallback`] giving the [`FallbackReason`], so that callers
+//! can fetch the layer in full instead and count how often (and why) that happens.
+
+use core::{fmt, ops::Range, time::Duration};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::{
+        Arc, Mutex, OnceLock, PoisonError,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Instant,
+};
+
+use anyhow::{Context, Result, bail, ensure};
+use futures::{
+    future::{Either, join_all, select, try_join_all},
+    stream::{self, StreamExt, TryStreamExt},
+    try_join,
+};
+use oci_client::{
+    Client, Reference,
+    client::BlobResponse,
+    manifest::{
+        IMAGE_MANIFEST_LIST_MEDIA_TYPE, IMAGE_MANIFEST_MEDIA_TYPE, OCI_IMAGE_INDEX_MEDIA_TYPE,
+        OCI_IMAGE_MEDIA_TYPE, OciDescriptor, OciManifest,
+    },
+    secrets::RegistryAuth,
+};
+use serde::Serialize;
+use tokio::sync::{Notify, OnceCell};
+
+use crate::{
+    ContentReference, FOOTER_SIZE, MetadataReference, MetadataReferences, ParseOptions, Stream,
+    digest,
+    health::{HealthTracker, RetryPolicy},
+    is_zstd_artifact_media_type, is_zstd_media_type,
+    known::KnownContent,
+    negative_cache::NegativeCache,
+    quota::{DiskQuota, QuotaReservation},
+    range::RangeBuffer,
+    replay::{Recorder, Replay},
+    store::{Backend, CorruptObject},
+    telemetry::{IMAGE_NAME, LAYER_DIGEST, Phase, SpanParent},
+    verify::{ChecksumCoverage, ChecksumPolicy},
+};
+#[cfg(unix)]
+use crate::{
+    quota::{SpaceReservation, check_free_space},
+    store::ChunkStore,
+};
+#[cfg(all(feature = "extract", unix))]
+use {
+    crate::extract::{ExtractOptions, extract_to_dir},
+    std::path::PathBuf,
+    tokio::sync::watch,
+};
+
+/// Receives the progress of a pull, for progress bars and logs.  All methods do nothing by
+/// default.
+///
+/// The byte counts add up to the compressed size of each layer: every byte is either downloaded
+/// or skipped (including the bytes found in the store).
+pub trait Progress: Send + Sync {
+    /// The pull of an image is starting, with the number of layers and their total (compressed)
+    /// size.  This is called for each image, when several are pulled at once.
+    fn pull_started(&self, layers: usize, size: u64) {
+        let _ = (layers, size);
+    }
+
+    /// A layer is starting, with its (compressed) size.
+    fn layer_started(&self, layer: &str, size: u64) {
+        let _ = (layer, size);
+    }
+
+    /// Some bytes of a layer were downloaded.
+    fn downloaded(&self, layer: &str, bytes: u64) {
+        let _ = (layer, bytes);
+    }
+
+    /// Some bytes of a layer don't need to be downloaded, because they aren't needed (like the
+    /// tar headers) or because the content is available locally.
+    fn skipped(&self, layer: &str, bytes: u64) {
+        let _ = (layer, bytes);
+    }
+
+    /// Some bytes of a layer were found in the store.  By default, this is the same as
+    /// [`Self::skipped()`].
+    fn cached(&self, layer: &str, bytes: u64) {
+        self.skipped(layer, bytes);
+    }
+
+    /// Something happened.
+    fn event(&self, event: &Event<'_>) {
+        let _ = event;
+    }
+}
+
+// The default, for pulls that nobody is watching
+struct NoProgress;
+
+impl Progress for NoProgress {}
+
+/// Something that happened during a pull.
+#[derive(Debug, Serialize)]
+#[serde(tag = "event")]
+pub enum Event<'a> {
+    /// The pull of a layer has started.
+    LayerStarted {
+        /// The digest of the layer.
+        layer: &'a str,
+    },
+    /// The metadata of a layer is in.
+    MetadataFetched {
+        /// The digest of the layer.
+        layer: &'a str,
+        /// The compressed size of the manifest.
+        manifest_size: usize,
+        /// The compressed size of the tarsplit.
+        tarsplit_size: usize,
+    },
+    /// Some content (or metadata) was downloaded.
+    ChunkFetched {
+        /// The digest of the content.
+        digest: &'a str,
+        /// The number of bytes that were downloaded.
+        bytes: u64,
+    },
+    /// Some content (or metadata) matched its digest.
+    ChunkVerified {
+        /// The digest of the content.
+        digest: &'a str,
+    },
+    /// All of the content of a layer is in the store.
+    LayerComplete {
+        /// The digest of the layer.
+        layer: &'a str,
+    },
+    /// A layer was unpacked.
+    LayerExtracted {
+        /// The digest of the layer.
+        layer: &'a str,
+    },
+}
+
+impl fmt::Display for Event<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::LayerStarted { layer } => write!(f, "{layer}: started"),
+            Self::MetadataFetched {
+                layer,
+                manifest_size,
+                tarsplit_size,
+            } => write!(
+                f,
+                "{layer}: metadata fetched ({manifest_size} + {tarsplit_size} bytes)"
+            ),
+            Self::ChunkFetched { digest, bytes } => write!(f, "{digest}: fetched {bytes} bytes"),
+            Self::ChunkVerified { digest } => write!(f, "{digest}: verified"),
+            Self::LayerComplete { layer } => write!(f, "{layer}: complete"),
+            Self::LayerExtracted { layer } => write!(f, "{layer}: extracted"),
+        }
+    }
+}
+
+/// What happened while pulling a single layer.
+#[derive(Debug, Clone, Serialize)]
+pub struct LayerReport {
+    /// The digest of the layer.
+    pub digest: String,
+    /// The number of bytes that were downloaded, including any that were thrown away.
+    pub downloaded: u64,
+    /// The number of (compressed) bytes whose content was found in the store.
+    pub cached: u64,
+    /// The number of failed requests that were retried.
+    pub retries: u64,
+    /// The number of hedged requests that were sent.
+    pub hedges: u64,
+    /// The time it took to get the metadata.
+    pub metadata_time: Duration,
+    /// The time it took to get the content, after the metadata.
+    pub content_time: Duration,
+    /// The time it took to unpack the layer, if it was unpacked.
+    pub extract_time: Option<Duration>,
+}
+
+impl fmt::Display for LayerReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} bytes downloaded, {} bytes from cache, {} retries, {} hedged requests, metadata {:?}, content {:?}",
+            self.digest,
+            self.downloaded,
+            self.cached,
+            self.retries,
+            self.hedges,
+            self.metadata_time,
+            self.content_time
+        )?;
+        if let Some(extract_time) = self.extract_time {
+            write!(f, ", extract {extract_time:?}")?;
+        }
+        Ok(())
+    }
+}
+
+/// What happened while pulling an image, for comparing efficiency across images.
+#[derive(Debug, Clone, Serialize)]
+pub struct PullReport {
+    /// The reports for each layer, in order.
+    pub layers: Vec<LayerReport>,
+    /// The time the whole pull took.
+    pub total_time: Duration,
+}
+
+impl fmt::Display for PullReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for layer in &self.layers {
+            writeln!(f, "{layer}")?;
+        }
+        let downloaded: u64 = self.layers.iter().map(|l| l.downloaded).sum();
+        let cached: u64 = self.layers.iter().map(|l| l.cached).sum();
+        let retries: u64 = self.layers.iter().map(|l| l.retries).sum();
+        write!(
+            f,
+            "total: {downloaded} bytes downloaded, {cached} bytes from cache, {retries} retries, {:?}",
+            self.total_time
+        )
+    }
+}
+
+/// The result of [`Puller::pull()`].
+#[derive(Debug)]
+pub struct PulledImage {
+    /// The digest of the image manifest.
+    pub manifest_digest: String,
+    /// The parsed metadata of each layer, in order.  All of the content is in the store.
+    pub streams: Vec<Stream>,
+    /// What happened.
+    pub report: PullReport,
+}
+
+/// Why a layer can't be pulled partially, as given by a [`Fallback`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[non_exhaustive]
+pub enum FallbackReason {
+    /// The layer isn't zstd-compressed.
+    NotZstd,
+    /// The layer has no zstd:chunked metadata, in the annotations or in a footer.
+    NoMetadata,
+    /// The metadata is malformed, fails its checksums, or doesn't fit the blob.
+    InvalidMetadata,
+    /// Parsing the metadata would exceed the memory budget.
+    BudgetExceeded,
+    /// The registry doesn't support range requests.
+    NoRangeSupport,
+    /// The layer has repeatedly failed verification, according to the [`NegativeCache`].
+    RepeatedFailures,
+    /// The layer wasn't ready within the timeout set by [`Puller::with_layer_timeout()`].
+    Timeout,
+}
+
+impl FallbackReason {
+    /// The reason for falling back, if the error (from [`Puller::pull()`], for example) was caused
+    /// by a layer that can't be pulled partially.
+    #[must_use]
+    pub fn of(err: &anyhow::Error) -> Option<Self> {
+        err.downcast_ref::<Fallback>()
+            .map(|fallback| fallback.reason)
+    }
+
+    /// A short name for the reason, like `no-range-support`, for logs and metrics.
+    #[must_use]
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::NotZstd => "not-zstd",
+            Self::NoMetadata => "no-metadata",
+            Self::InvalidMetadata => "invalid-metadata",
+            Self::BudgetExceeded => "budget-exceeded",
+            Self::NoRangeSupport => "no-range-support",
+            Self::RepeatedFailures => "repeated-failures",
+            Self::Timeout => "timeout",
+        }
+    }
+}
+
+impl fmt::Display for FallbackReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::NotZstd => "it isn't zstd-compressed",
+            Self::NoMetadata => "it has no zstd:chunked metadata",
+            Self::InvalidMetadata => "its metadata is invalid",
+            Self::BudgetExceeded => "its metadata exceeds the memory budget",
+            Self::NoRangeSupport => "the registry doesn't support range requests",
+            Self::RepeatedFailures => "it has repeatedly failed verification",
+            Self::Timeout => "it wasn't ready in time",
+        })
+    }
+}
+
+/// A layer couldn't be pulled partially, and should be fetched in full instead.  Errors from
+/// [`Puller::pull()`] can be downcast to this (or see [`FallbackReason::of()`]).
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("Layer {layer} can't be pulled partially: {reason}")]
+pub struct Fallback {
+    /// The digest of the layer.
+    pub layer: String,
+    /// Why it couldn't be pulled partially.
+    pub reason: FallbackReason,
+}
+
+impl Fallback {
+    fn new(layer: &OciDescriptor, reason: FallbackReason) -> Self {
+        Self {
+            layer: layer.digest.clone(),
+            reason,
+        }
+    }
+}
+
+// Limits on the disk space used by a pull
+#[derive(Debug, Default)]
+struct DiskLimits {
+    quota: Option<DiskQuota>,
+    // The store whose free space gets checked
+    #[cfg(unix)]
+    free_space: Option<ChunkStore>,
+    #[cfg(unix)]
+    reservation: Option<Mutex<SpaceReservation>>,
+}
+
+impl DiskLimits {
+    // Makes sure that there's room for the content of a layer, before fetching any of it.  The
+    // quota is reserved until the result is dropped.
+    fn reserve(
+        &self,
+        stream: &Stream,
+        cache: &dyn Backend,
+    ) -> Result<Option<QuotaReservation<'_>>> {
+        let Some(quota) = &self.quota else {
+            return Ok(None);
+        };
+        let reserved = quota.reserve(stream, cache)?;
+        #[cfg(unix)]
+        if let Some(reservation) = &self.reservation {
+            reservation
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .grow(reserved.usage().store_growth)?;
+        } else if let Some(store) = &self.free_space {
+            check_free_space(reserved.usage(), store, None)?;
+        }
+        Ok(Some(reserved))
+    }
+
+    // Some content is about to be written to the store, so it no longer needs to be reserved
+    fn release(&self, bytes: u64) -> Result<()> {
+        #[cfg(unix)]
+        if let Some(reservation) = &self.reservation {
+            reservation
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .release(bytes)?;
+        }
+        #[cfg(not(unix))]
+        let _ = bytes;
+        Ok(())
+    }
+}
+
+/// Pulls images from a registry into a [`Backend`].  The settings are kept, so one puller can be
+/// used for many pulls.
+pub struct Puller {
+    client: Client,
+    auth: RegistryAuth,
+    cache: Arc<dyn Backend>,
+    known: KnownContent,
+    connections: usize,
+    // Created on first use, so that the number of connections can still be changed until then
+    pool: OnceLock<ConnectionPool>,
+    bandwidth: Option<Bandwidth>,
+    // Content that's being fetched, shared by all pulls so that content appearing in several
+    // layers (or images) only gets downloaded once
+    inflight: Mutex<HashMap<Arc<str>, Arc<OnceCell<()>>>>,
+    // For telling pulls apart in the connection pool
+    pulls: AtomicU64,
+    hedge_after: Option<Duration>,
+    retry_policy: RetryPolicy,
+    checksum_policy: ChecksumPolicy,
+    priority: Box<dyn Fn(&str) -> u32 + Send + Sync>,
+    layer_timeout: Option<Duration>,
+    progress: Arc<dyn Progress>,
+    negative: Option<NegativeCache>,
+    limits: DiskLimits,
+    #[cfg(all(feature = "extract", unix))]
+    extraction: Option<(PathBuf, ExtractOptions)>,
+    recorder: Option<Recorder>,
+    replay: Option<Replay>,
+    span: SpanParent,
+}
+
+impl Puller {
+    /// Creates a puller which fetches with the given client (anonymously, unless changed with
+    /// [`Self::with_auth()`]) and stores content in `cache`.
+    #[must_use]
+    pub fn new(client: Client, cache: Arc<dyn Backend>) -> Self {
+        Self {
+            client,
+            auth: RegistryAuth::Anonymous,
+            cache,
+            known: KnownContent::with_defaults(),
+            connections: 100,
+            pool: OnceLock::new(),
+            bandwidth: None,
+            inflight: Mutex::default(),
+            pulls: AtomicU64::new(0),
+            hedge_after: None,
+            retry_policy: RetryPolicy::new(),
+            checksum_policy: ChecksumPolicy::default(),
+            priority: Box::new(|_| 1),
+            layer_timeout: None,
+            progress: Arc::new(NoProgress),
+            negative: None,
+            limits: DiskLimits::default(),
+            #[cfg(all(feature = "extract", unix))]
+            extraction: None,
+            recorder: None,
+            replay: None,
+            span: SpanParent::default(),
+        }
+    }
+
+    /// Sets the credentials for the registry.
+    #[must_use]
+    pub fn with_auth(mut self, auth: RegistryAuth) -> Self {
+        self.auth = auth;
+        self
+    }
+
+    /// Sets the table of content that's never fetched.  The default is
+    /// [`KnownContent::with_defaults()`].
+    #[must_use]
+    pub fn with_known_content(mut self, known: KnownContent) -> Self {
+        self.known = known;
+        self
+    }
+
+    /// Sets the maximum number of concurrent range requests, shared between all layers of all
+    /// pulls.  The default is 100.
+    #[must_use]
+    pub const fn with_connections(mut self, connections: usize) -> Self {
+        self.connections = connections;
+        self
+    }
+
+    /// Limits the download rate of all pulls together, in bytes per second.  There's no limit by
+    /// default.
+    #[must_use]
+    pub fn with_bandwidth_limit(mut self, bytes_per_second: u64) -> Self {
+        self.bandwidth = Some(Bandwidth::new(bytes_per_second));
+        self
+    }
+
+    /// Sets when network failures are retried, and for how long.  The default is
+    /// [`RetryPolicy::new()`], with one [`HealthTracker`] for each pull.
+    #[must_use]
+    pub const fn with_retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.retry_policy = policy;
+        self
+    }
+
+    /// Sends a second request for any range that hasn't completed after `delay`, and uses
+    /// whichever finishes first.  This helps with tail latency on lossy links.
+    #[must_use]
+    pub const fn with_hedge_after(mut self, delay: Duration) -> Self {
+        self.hedge_after = Some(delay);
+        self
+    }
+
+    /// Sets which interpretations of the metadata checksums are accepted.
+    #[must_use]
+    pub const fn with_checksum_policy(mut self, policy: ChecksumPolicy) -> Self {
+        self.checksum_policy = policy;
+        self
+    }
+
+    /// Sets the priority of each file (by its path in the layer).  Files with a lower value are
+    /// fetched first, across all layers, after the metadata (which has priority 0).  All files
+    /// have priority 1 by default.
+    #[must_use]
+    pub fn with_priority(mut self, priority: impl Fn(&str) -> u32 + Send + Sync + 'static) -> Self {
+        self.priority = Box::new(priority);
+        self
+    }
+
+    /// Gives up if any layer isn't ready within `timeout` of the start of the pull, so that the
+    /// caller can fall back to some other method (like a full pull) instead of waiting forever.
+    #[must_use]
+    pub const fn with_layer_timeout(mut self, timeout: Duration) -> Self {
+        self.layer_timeout = Some(timeout);
+        self
+    }
+
+    /// Sets where progress is reported.
+    #[must_use]
+    pub fn with_progress(mut self, progress: Arc<dyn Progress>) -> Self {
+        self.progress = progress;
+        self
+    }
+
+    /// Records layers that fail verification, and refuses to partially pull layers that have
+    /// failed repeatedly.
+    #[must_use]
+    pub fn with_negative_cache(mut self, negative: NegativeCache) -> Self {
+        self.negative = Some(negative);
+        self
+    }
+
+    /// Fails (before fetching the content of a layer) if the pull would add more to the store
+    /// than the quota allows.
+    #[must_use]
+    pub fn with_quota(mut self, quota: DiskQuota) -> Self {
+        self.limits.quota = Some(quota);
+        self
+    }
+
+    /// Fails (before fetching the content of a layer) if it wouldn't fit in the free space of
+    /// the filesystem holding `store`.
+    #[cfg(unix)]
+    #[must_use]
+    pub fn with_free_space_check(mut self, store: ChunkStore) -> Self {
+        self.limits.free_space = Some(store);
+        self.limits
+            .quota
+            .get_or_insert_with(|| DiskQuota::new(u64::MAX));
+        self
+    }
+
+    /// Grows the reservation by the space needed by each layer before fetching its content, so
+    /// that the pull can't run out of space part way through.
+    #[cfg(unix)]
+    #[must_use]
+    pub fn with_space_reservation(mut self, reservation: SpaceReservation) -> Self {
+        self.limits.reservation = Some(Mutex::new(reservation));
+        self.limits
+            .quota
+            .get_or_insert_with(|| DiskQuota::new(u64::MAX));
+        self
+    }
+
+    /// Unpacks the image into `dir`.  Each layer is applied (in order) as soon as its content is
+    /// in, while later layers are still downloading.
+    #[cfg(all(feature = "extract", unix))]
+    #[must_use]
+    pub fn with_extraction(mut self, dir: impl Into<PathBuf>, options: ExtractOptions) -> Self {
+        self.extraction = Some((dir.into(), options));
+        self
+    }
+
+    /// Records every response from the registry into a replay bundle.
+    #[must_use]
+    pub fn with_recorder(mut self, recorder: Recorder) -> Self {
+        self.recorder = Some(recorder);
+        self
+    }
+
+    /// Repeats a pull offline, with the responses from a replay bundle instead of the registry.
+    #[must_use]
+    pub fn with_replay(mut self, replay: Replay) -> Self {
+        self.replay = Some(replay);
+        self
+    }
+
+    /// Reports the pull as part of a span of the caller's.
+    #[must_use]
+    #[cfg_attr(not(feature = "otel"), allow(clippy::missing_const_for_fn))]
+    pub fn with_span_parent(mut self, span: SpanParent) -> Self {
+        self.span = span;
+        self
+    }
+
+    /// Pulls an image, which must be an image manifest (not an index) with zstd:chunked layers.
+    /// The content of all layers ends up in the store, and unpacked if that was requested.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the manifest or any layer can't be fetched (after retrying for as long as the
+    /// pull makes progress), if a layer isn't zstd:chunked, if any content fails verification,
+    /// or if a layer doesn't fit in the disk limits or doesn't finish in time.
+    pub async fn pull(&self, image: &Reference) -> Result<PulledImage> {
+        let span = self
+            .span
+            .child(Phase::Pull, &[(IMAGE_NAME, &image.whole())]);
+        let op = PullOp::new(self, image, span.parent());
+        span.finish(op.pull().await)
+    }
+
+    /// Pulls several images at once, as for [`Self::pull()`], sharing the connections, bandwidth
+    /// and disk limits between them.  Returns the result for each image, in order.
+    ///
+    /// Extraction can't be used with more than one image, since they would all be unpacked into
+    /// the same directory.
+    pub async fn pull_all(&self, images: &[Reference]) -> Vec<Result<PulledImage>> {
+        #[cfg(all(feature = "extract", unix))]
+        if self.extraction.is_some() && images.len() > 1 {
+            return images
+                .iter()
+                .map(|image| {
+                    Err(anyhow::anyhow!(
+                        "Not pulling {image}: can't extract several images into one directory"
+                    ))
+                })
+                .collect();
+        }
+        join_all(images.iter().map(|image| self.pull(image))).await
+    }
+
+    fn pool(&self) -> &ConnectionPool {
+        self.pool
+            .get_or_init(|| ConnectionPool::new(self.connections))
+    }
+
+    /// Finds the digest of the manifest that an image refers to now, without pulling it, to see
+    /// whether a tag has moved since the last pull.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the registry can't be reached or doesn't know the image.
+    pub async fn manifest_digest(&self, image: &Reference) -> Result<String> {
+        if let Some(replay) = &self.replay {
+            let body = replay
+                .get(&image.whole(), None)?
+                .with_context(|| format!("The manifest of {image} isn't in the replay bundle"))?;
+            return Ok(digest::sha256(&body));
+        }
+        Ok(self.client.fetch_manifest_digest(image, &self.auth).await?)
+    }
+}
+
+impl fmt::Debug for Puller {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Puller")
+            .field("connections", &self.connections)
+            .field("hedge_after", &self.hedge_after)
+            .field("checksum_policy", &self.checksum_policy)
+            .field("layer_timeout", &self.layer_timeout)
+            .finish_non_exhaustive()
+    }
+}
+
+// Fetches with a lower priority value go first.
+const METADATA_PRIORITY: u32 = 0;
+
+// Orders fetches by priority: a fetch waits until there are no outstanding fetches with a lower
+// priority value, across all layers.  Fetches need to be registered before they start waiting, so
+// that the ones which are registered early (like the metadata of all layers) hold back the rest.
+#[derive(Default)]
+struct Scheduler {
+    outstanding: Mutex<BTreeMap<u32, usize>>,
+    changed: Notify,
+}
+
+impl Scheduler {
+    fn register(&self, priority: u32, count: usize) {
+        let mut outstanding = self
+            .outstanding
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        *outstanding.entry(priority).or_default() += count;
+    }
+
+    fn done(&self, priority: u32) {
+        let mut outstanding = self
+            .outstanding
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if let Some(count) = outstanding.get_mut(&priority) {
+            *count -= 1;
+            if *count == 0 {
+                outstanding.remove(&priority);
+            }
+        }
+        drop(outstanding);
+        self.changed.notify_waiters();
+    }
+
+    async fn wait_turn(&self, priority: u32) {
+        loop {
+            let changed = self.changed.notified();
+            let first = self
+                .outstanding
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .keys()
+                .next()
+                .copied();
+            if first.is_none_or(|first| first >= priority) {
+                return;
+            }
+            changed.await;
+        }
+    }
+}
+
+// The connections shared by all of the pulls of a puller.  A free connection goes to a waiting
+// pull with the fewest connections, so that one big pull can't starve the others.
+struct ConnectionPool {
+    state: Mutex<PoolState>,
+    changed: Notify,
+}
+
+struct PoolState {
+    available: usize,
+    // For each pull: the number of connections it holds, and of requests waiting for one
+    pulls: HashMap<u64, (usize, usize)>,
+}
+
+impl PoolState {
+    fn take(&mut self, pull: u64) -> bool {
+        let held = self.pulls.get(&pull).map_or(0, |&(held, _)| held);
+        let fewest = (self.pulls.values())
+            .filter(|&&(_, waiting)| waiting > 0)
+            .map(|&(held, _)| held)
+            .min()
+            .unwrap_or(held);
+        if self.available == 0 || held > fewest {
+            return false;
+        }
+        self.available -= 1;
+        if let Some((held, waiting)) = self.pulls.get_mut(&pull) {
+            *held += 1;
+            *waiting -= 1;
+        }
+        true
+    }
+
+    fn update(&mut self, pull: u64, f: impl FnOnce(&mut (usize, usize))) {
+        if let Some(counts) = self.pulls.get_mut(&pull) {
+            f(counts);
+            if *counts == (0, 0) {
+                self.pulls.remove(&pull);
+            }
+        }
+    }
+}
+
+impl ConnectionPool {
+    fn new(connections: usize) -> Self {
+        Self {
+            state: Mutex::new(PoolState {
+                available: connections,
+                pulls: HashMap::new(),
+            }),
+            changed: Notify::new(),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, PoolState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    async fn acquire(&self, pull: u64) -> Connection<'_> {
+        self.lock().pulls.entry(pull).or_default().1 += 1;
+        let mut waiter = Waiter {
+            pool: self,
+            pull,
+            waiting: true,
+        };
+        loop {
+            let changed = self.changed.notified();
+            if self.lock().take(pull) {
+                waiter.waiting = false;
+                // Taking a connection changes which pull has the fewest
+                self.changed.notify_waiters();
+                return Connection { pool: self, pull };
+            }
+            changed.await;
+        }
+    }
+}
+
+// How try_download_range() is used
+#[derive(Debug, Clone, Copy)]
+enum RangeMode<'a> {
+    // On its own, retrying failures, and reporting progress if `report` is true
+    Standalone { report: bool },
+    // As one half of a hedged request, making a single attempt and leaving retries, health and
+    // progress to the caller.  `sent` is notified once the request has a connection.
+    Hedged { sent: Option<&'a Notify> },
+}
+
+// A request waiting for a connection, which stops waiting if it's dropped (on a timeout, or when
+// the other half of a hedged request wins)
+struct Waiter<'a> {
+    pool: &'a ConnectionPool,
+    pull: u64,
+    waiting: bool,
+}
+
+impl Drop for Waiter<'_> {
+    fn drop(&mut self) {
+        if self.waiting {
+            self.pool
+                .lock()
+                .update(self.pull, |(_, waiting)| *waiting -= 1);
+            self.pool.changed.notify_waiters();
+        }
+    }
+}
+
+// A connection from the pool, which is given back when dropped
+struct Connection<'a> {
+    pool: &'a ConnectionPool,
+    pull: u64,
+}
+
+impl Drop for Connection<'_> {
+    fn drop(&mut self) {
+        let mut state = self.pool.lock();
+        state.available += 1;
+        state.update(self.pull, |(held, _)| *held -= 1);
+        drop(state);
+        self.pool.changed.notify_waiters();
+    }
+}
+
+// A token bucket limiting the download rate of all pulls together, allowing bursts of up to a
+// second's worth.  Waiters take turns, so each connection gets its share.
+struct Bandwidth {
+    rate: u64,
+    bucket: tokio::sync::Mutex<(f64, Instant)>,
+}
+
+impl Bandwidth {
+    fn new(rate: u64) -> Self {
+        Self {
+            rate: rate.max(1),
+            bucket: tokio::sync::Mutex::new((0., Instant::now())),
+        }
+    }
+
+    // Accounts for bytes that were just received, waiting if they went over the limit
+    #[allow(clippy::cast_precision_loss)]
+    async fn consume(&self, bytes: u64) {
+        let mut bucket = self.bucket.lock().await;
+        let (tokens, updated) = &mut *bucket;
+        let rate = self.rate as f64;
+        let now = Instant::now();
+        *tokens = now
+            .duration_since(*updated)
+            .as_secs_f64()
+            .mul_add(rate, *tokens)
+            .min(rate);
+        *updated = now;
+        *tokens -= bytes as f64;
+        if *tokens < 0. {
+            tokio::time::sleep(Duration::from_secs_f64(-*tokens / rate)).await;
+        }
+        // Only now, so that the others wait their turn
+        drop(bucket);
+    }
+}
+
+// Counters which get updated while a layer is being pulled.
+struct LayerCounters<'a> {
+    layer: &'a str,
+    downloaded: AtomicU64,
+    cached: AtomicU64,
+    retries: AtomicU64,
+    hedges: AtomicU64,
+}
+
+impl<'a> LayerCounters<'a> {
+    const fn new(layer: &'a str) -> Self {
+        Self {
+            layer,
+            downloaded: AtomicU64::new(0),
+            cached: AtomicU64::new(0),
+            retries: AtomicU64::new(0),
+            hedges: AtomicU64::new(0),
+        }
+    }
+
+    fn report(&self, metadata_time: Duration, content_time: Duration) -> LayerReport {
+        LayerReport {
+            digest: self.layer.to_owned(),
+            downloaded: self.downloaded.load(Ordering::Relaxed),
+            cached: self.cached.load(Ordering::Relaxed),
+            retries: self.retries.load(Ordering::Relaxed),
+            hedges: self.hedges.load(Ordering::Relaxed),
+            metadata_time,
+            content_time,
+            extract_time: None,
+        }
+    }
+}
+
+// The state of a single pull
+struct PullOp<'a> {
+    puller: &'a Puller,
+    image: &'a Reference,
+    span: SpanParent,
+    // Identifies the pull in the connection pool
+    id: u64,
+    scheduler: Scheduler,
+    health: HealthTracker,
+    // The disk quota reserved by each layer, which is kept until the pull is over: by then the
+    // content is in the store, where the next pull will find it
+    reserved: Mutex<Vec<QuotaReservation<'a>>>,
+    // The number of layers that have been unpacked so far
+    #[cfg(all(feature = "extract", unix))]
+    unpacked: watch::Sender<usize>,
+}
+
+impl<'a> PullOp<'a> {
+    fn new(puller: &'a Puller, image: &'a Reference, span: SpanParent) -> Self {
+        Self {
+            puller,
+            image,
+            span,
+            id: puller.pulls.fetch_add(1, Ordering::Relaxed),
+            scheduler: Scheduler::default(),
+            health: HealthTracker::new(puller.retry_policy),
+            reserved: Mutex::default(),
+            #[cfg(all(feature = "extract", unix))]
+            unpacked: watch::Sender::new(0),
+        }
+    }
+
+    // Some bytes of the layer were downloaded.
+    fn advance(&self, counters: &LayerCounters, n_bytes: u64) {
+        self.puller.progress.downloaded(counters.layer, n_bytes);
+    }
+
+    // Some bytes of the layer don't need to be downloaded.
+    fn skip(&self, counters: &LayerCounters, n_bytes: u64) {
+        self.puller.progress.skipped(counters.layer, n_bytes);
+    }
+
+    // Some bytes of the layer were found in the cache.
+    fn cached(&self, counters: &LayerCounters, n_bytes: u64) {
+        counters.cached.fetch_add(n_bytes, Ordering::Relaxed);
+        self.puller.progress.cached(counters.layer, n_bytes);
+    }
+
+    // Reserves disk space for the content of a layer, until the pull is over
+    fn reserve(&self, stream: &Stream) -> Result<()> {
+        if let Some(reserved) = self.puller.limits.reserve(stream, &*self.puller.cache)? {
+            (self.reserved.lock())
+                .unwrap_or_else(PoisonError::into_inner)
+                .push(reserved);
+        }
+        Ok(())
+    }
+
+    fn emit(&self, event: &Event) {
+        self.puller.progress.event(event);
+    }
+
+    async fn softfail(
+        &self,
+        counters: &LayerCounters<'_>,
+        err: impl Into<anyhow::Error>,
+    ) -> Result<()> {
+        counters.retries.fetch_add(1, Ordering::Relaxed);
+        if self.health.failure() {
+            tokio::time::sleep(self.health.policy().retry_delay()).await;
+            Ok(())
+        } else {
+            // Out of patience: let the error bubble out.
+            Err(err.into())
+        }
+    }
+
+    async fn pull_manifest(&self) -> Result<(OciManifest, String)> {
+        let image = self.image;
+        let (body, digest) = if let Some(replay) = &self.puller.replay {
+            let body = replay
+                .get(&image.whole(), None)?
+                .with_context(|| format!("The manifest of {image} isn't in the replay bundle"))?;
+            let digest = digest::sha256(&body);
+            (body, digest)
+        } else {
+            let accepted = [
+                IMAGE_MANIFEST_MEDIA_TYPE,
+                IMAGE_MANIFEST_LIST_MEDIA_TYPE,
+                OCI_IMAGE_MEDIA_TYPE,
+                OCI_IMAGE_INDEX_MEDIA_TYPE,
+            ];
+            self.puller
+                .client
+                .pull_manifest_raw(image, &self.puller.auth, &accepted)
+                .await?
+        };
+        if let Some(recorder) = &self.puller.recorder {
+            recorder.record(&image.whole(), None, &body)?;
+        }
+        Ok((serde_json::from_slice(&body)?, digest))
+    }
+
+    // To simplify progress tracking, if this function fails, the entire operation needs to be
+    // aborted, so it tries really hard not to fail... it will also never download any byte that it
+    // has already successfully received (ie: it will make the range request smaller before trying
+    // again).
+    //
+    // If `report` is false then progress isn't updated, and it's up to the caller to do so.
+    async fn download_range(
+        &self,
+        desc: &OciDescriptor,
+        counters: &LayerCounters<'_>,
+        range: &Range<u64>,
+        report: bool,
+    ) -> Result<Vec<u8>> {
+        self.try_download_range(desc, counters, range, RangeMode::Standalone { report })
+            .await?
+    }
+
+    // The implementation of download_range() and of the halves of hedged requests.  The outer
+    // error aborts the operation, and the inner one (only for hedged requests) is a failed
+    // attempt, which the caller may retry.
+    async fn try_download_range(
+        &self,
+        desc: &OciDescriptor,
+        counters: &LayerCounters<'_>,
+        range: &Range<u64>,
+        mode: RangeMode<'_>,
+    ) -> Result<Result<Vec<u8>>> {
+        let (report, hedged) = match mode {
+            RangeMode::Standalone { report } => (report, false),
+            RangeMode::Hedged { .. } => (false, true),
+        };
+        if let Some(replay) = &self.puller.replay {
+            let data = replay
+                .get(&desc.digest, Some(range.clone()))?
+                .with_context(|| {
+                    format!(
+                        "Range {range:?} of {} isn't in the replay bundle",
+                        desc.digest
+                    )
+                })?;
+            if report {
+                self.advance(counters, data.len() as u64);
+            }
+            return Ok(Ok(data));
+        }
+
+        let mut buffer = RangeBuffer::new(range.clone());
+
+        // Layers (and images) are pulled in parallel, so this is what limits the total number of
+        // requests.
+        let _connection = self.puller.pool().acquire(self.id).await;
+        if let RangeMode::Hedged { sent: Some(sent) } = mode {
+            sent.notify_one();
+        }
+
+        'send_request: while !buffer.is_complete() {
+            let resp = match self
+                .puller
+                .client
+                .pull_blob_stream_partial(
+                    self.image,
+                    desc,
+                    buffer.position(),
+                    Some(buffer.remaining()),
+                )
+                .await
+            {
+                Ok(resp) => resp,
+                Err(err) if hedged => return Ok(Err(err.into())),
+                Err(err) => {
+                    self.softfail(counters, err).await?;
+                    continue 'send_request;
+                }
+            };
+
+            // Maybe some servers would respond with a full request if we give the complete range
+            // but let's wait until someone actually encounters that before we try to handle it...
+            let BlobResponse::Partial(mut stream) = resp else {
+                bail!(Fallback::new(desc, FallbackReason::NoRangeSupport));
+            };
+
+            // Some servers send more than we asked for (up to the end of the blob), so stop reading
+            // once we have what we need.  Only the useful bytes count for karma and progress.
+            while !buffer.is_complete()
+                && let Some(result) = stream.next().await
+            {
+                match result {
+                    Ok(bytes) => {
+                        counters
+                            .downloaded
+                            .fetch_add(bytes.len() as u64, Ordering::Relaxed);
+                        let n_bytes = buffer.push(&bytes);
+                        if let Some(bandwidth) = &self.puller.bandwidth {
+                            bandwidth.consume(bytes.len() as u64).await;
+                        }
+
+                        if !hedged {
+                            self.health.progress(n_bytes);
+                        }
+                        if report {
+                            self.advance(counters, n_bytes);
+                        }
+                    }
+                    Err(err) if hedged => return Ok(Err(err.into())),
+                    Err(err) => {
+                        self.softfail(counters, err).await?;
+                        continue 'send_request;
+                    }
+                }
+            }
+        }
+
+        let data = buffer.into_data();
+        if let Some(recorder) = &self.puller.recorder {
+            recorder.record(&desc.digest, Some(range.clone()), &data)?;
+        }
+        Ok(Ok(data))
+    }
+
+    // Like download_range() but, if hedging is enabled, sends a second request for the same range
+    // if the first one takes too long, and returns the result of whichever finishes first.  The
+    // delay only starts once the first request has a connection from the pool, and the second one
+    // takes another.  A failed attempt only counts once (when both requests failed) before the
+    // range is retried, and progress is only reported once the range is complete, so that
+    // neither failures nor bytes get counted twice.
+    async fn download_range_hedged(
+        &self,
+        desc: &OciDescriptor,
+        counters: &LayerCounters<'_>,
+        range: &Range<u64>,
+    ) -> Result<Vec<u8>> {
+        let Some(delay) = self.puller.hedge_after else {
+            return self.download_range(desc, counters, range, true).await;
+        };
+
+        let data = loop {
+            let sent = Notify::new();
+            let primary = Box::pin(self.try_download_range(
+                desc,
+                counters,
+                range,
+                RangeMode::Hedged { sent: Some(&sent) },
+            ));
+            let hedge = Box::pin(async {
+                sent.notified().await;
+                tokio::time::sleep(delay).await;
+                counters.hedges.fetch_add(1, Ordering::Relaxed);
+                self.try_download_range(desc, counters, range, RangeMode::Hedged { sent: None })
+                    .await
+            });
+
+            // If the first one to finish failed, give the other one a chance
+            let attempt = match select(primary, hedge).await {
+                Either::Left((Ok(Ok(data)), _)) | Either::Right((Ok(Ok(data)), _)) => Ok(data),
+                Either::Left((Err(err), _)) | Either::Right((Err(err), _)) => return Err(err),
+                Either::Left((Ok(Err(_)), hedge)) => hedge.await?,
+                Either::Right((Ok(Err(_)), primary)) => primary.await?,
+            };
+            match attempt {
+                Ok(data) => break data,
+                Err(err) => self.softfail(counters, err).await?,
+            }
+        };
+
+        self.health.progress(data.len() as u64);
+        self.advance(counters, data.len() as u64);
+        Ok(data)
+    }
+
+    async fn check_and_save(
+        &self,
+        digest: &str,
+        decompress: bool,
+        mut data: Vec<u8>,
+    ) -> Result<()> {
+        let cache = Arc::clone(&self.puller.cache);
+        let owned_digest = digest.to_owned();
+        tokio::task::spawn_blocking(move || {
+            if decompress {
+                data = zstd::decode_all(&data[..])?;
+            }
+
+            let actual = digest::sha256(&data);
+            ensure!(
+                actual == owned_digest,
+                "Digest mismatch: expected {owned_digest} but got {actual}"
+            );
+
+            cache.insert(&owned_digest, &data)
+        })
+        .await??;
+
+        self.emit(&Event::ChunkVerified { digest });
+        Ok(())
+    }
+
+    async fn download_metadata(
+        &self,
+        layer: &OciDescriptor,
+        counters: &LayerCounters<'_>,
+        reference: &MetadataReference,
+    ) -> Result<Vec<u8>> {
+        // The cache verifies what it reads, and drops anything that's corrupt: fetch it again.
+        if let Some(digest) = &reference.digest
+            && let Some(data) = self.puller.cache.get(digest).or_else(|err| {
+                if err.is::<CorruptObject>() {
+                    Ok(None)
+                } else {
+                    Err(err)
+                }
+            })?
+        {
+            self.cached(counters, reference.range.end - reference.range.start);
+            return Ok(data);
+        }
+
+        let result = self
+            .download_range_hedged(layer, counters, &reference.range)
+            .await?;
+
+        if let Some(digest) = &reference.digest {
+            self.emit(&Event::ChunkFetched {
+                digest,
+                bytes: result.len() as u64,
+            });
+            // The cache is keyed by the digest of what it holds, which only works if the digest
+            // covers the compressed frame.  Anything else is checked but not cached.
+            if reference
+                .verify(&result, self.puller.checksum_policy)
+                .context(Fallback::new(layer, FallbackReason::InvalidMetadata))?
+                == Some(ChecksumCoverage::Uncompressed)
+            {
+                self.emit(&Event::ChunkVerified { digest });
+                return Ok(result);
+            }
+            // Caching metadata might not make sense for the "incremental updates" case (since it's
+            // definitely going to be different next time) but it definitely makes sense from the
+            // "bad network connection and my download got interrupted" case.
+            self.check_and_save(digest, false, result.clone()).await?;
+        }
+
+        Ok(result)
+    }
+
+    // Makes sure that the content is in the cache.  If another pull or layer (or another file in
+    // this layer) is already fetching the same content then we wait for it instead of fetching it
+    // again.  If that fails, the next waiter gets to try.
+    async fn ensure_content(
+        &self,
+        layer: &OciDescriptor,
+        counters: &LayerCounters<'_>,
+        reference: &ContentReference,
+    ) -> Result<()> {
+        let inflight = &self.puller.inflight;
+        let cell = Arc::clone(
+            inflight
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .entry(Arc::clone(&reference.digest))
+                .or_default(),
+        );
+
+        let mut fetched = false;
+        cell.get_or_try_init(|| {
+            fetched = true;
+            self.fetch_content(layer, counters, reference)
+        })
+        .await?;
+        if fetched {
+            // It's in the store now, where later fetches will find it
+            let mut inflight = inflight.lock().unwrap_or_else(PoisonError::into_inner);
+            if inflight
+                .get(&reference.digest)
+                .is_some_and(|other| Arc::ptr_eq(other, &cell))
+            {
+                inflight.remove(&reference.digest);
+            }
+        } else {
+            self.cached(counters, reference.compressed_size());
+        }
+
+        Ok(())
+    }
+
+    // Checks for a chunk of zeros, hashing away from the runtime if it's worth checking at all
+    async fn is_zeros(&self, reference: &ContentReference) -> Result<bool> {
+        if !reference.could_be_zeros() {
+            return Ok(false);
+        }
+        let reference = reference.clone();
+        Ok(tokio::task::spawn_blocking(move || reference.is_zeros()).await?)
+    }
+
+    async fn fetch_content(
+        &self,
+        layer: &OciDescriptor,
+        counters: &LayerCounters<'_>,
+        reference: &ContentReference,
+    ) -> Result<()> {
+        let limits = &self.puller.limits;
+        if let Some(data) = self.puller.known.resolve(reference) {
+            self.skip(counters, reference.compressed_size());
+            limits.release(reference.size)?;
+            self.check_and_save(&reference.digest, false, data.to_vec())
+                .await?;
+        } else if self.puller.cache.contains(&reference.digest)? {
+            self.cached(counters, reference.compressed_size());
+        } else if self.is_zeros(reference).await? {
+            // No need to download zeros...
+            self.skip(counters, reference.compressed_size());
+            let data = vec![0; reference.size.try_into()?];
+            limits.release(reference.size)?;
+            self.check_and_save(&reference.digest, false, data).await?;
+        } else {
+            let result = self
+                .download_range_hedged(layer, counters, &reference.range)
+                .await?;
+            self.emit(&Event::ChunkFetched {
+                digest: &reference.digest,
+                bytes: result.len() as u64,
+            });
+            limits.release(reference.size)?;
+            if let Err(err) = self.check_and_save(&reference.digest, true, result).await {
+                if let Some(negative) = &self.puller.negative {
+                    negative.record(&layer.digest, Some(&reference.digest))?;
+                }
+                return Err(err);
+            }
+        }
+
+        Ok(())
+    }
+
+    // Finds the metadata of a layer, from the annotations or else from the footer
+    async fn metadata_references(
+        &self,
+        layer: &OciDescriptor,
+        counters: &LayerCounters<'_>,
+    ) -> Result<MetadataReferences> {
+        let artifact = is_zstd_artifact_media_type(&layer.media_type);
+        if !artifact && !is_zstd_media_type(&layer.media_type) {
+            return Err(anyhow::anyhow!(
+                "Layer has media type {}, which isn't zstd",
+                layer.media_type
+            )
+            .context(Fallback::new(layer, FallbackReason::NotZstd)));
+        }
+        let invalid = || Fallback::new(layer, FallbackReason::InvalidMetadata);
+        let annotation = |key: &str| layer.annotations.as_ref()?.get(key);
+        let size: u64 = layer.size.try_into()?;
+        let from_oci = if artifact {
+            MetadataReferences::from_oci_artifact(annotation)
+        } else {
+            MetadataReferences::try_from_oci(annotation, Some(size)).with_context(invalid)?
+        };
+        let metadata = if let Some(metadata) = from_oci {
+            metadata
+        } else {
+            // The annotations got lost somewhere (typically a proxy converting the manifest to
+            // Docker schema2), but the same information is in the footer.
+            let suffix = self
+                .download_range(
+                    layer,
+                    counters,
+                    &(size.saturating_sub(FOOTER_SIZE)..size),
+                    false,
+                )
+                .await?;
+            MetadataReferences::from_oci_or_footer(annotation, Some(&suffix))
+                .with_context(invalid)?
+                .context("Not a zstd:chunked image?")
+                .context(Fallback::new(layer, FallbackReason::NoMetadata))?
+        };
+        let report = metadata.consistency_report(size, None);
+        if !report.is_consistent() {
+            return Err(anyhow::anyhow!("Layer {}: {report}", layer.digest).context(invalid()));
+        }
+        Ok(metadata)
+    }
+
+    async fn download_zstd_chunked_layer(
+        &self,
+        layer: &OciDescriptor,
+    ) -> Result<(Stream, LayerReport)> {
+        let size: u64 = layer.size.try_into()?;
+        self.puller.progress.layer_started(&layer.digest, size);
+        let counters = LayerCounters::new(&layer.digest);
+        let start = Instant::now();
+        self.emit(&Event::LayerStarted {
+            layer: &layer.digest,
+        });
+
+        let metadata = self.metadata_references(layer, &counters).await?;
+        let (manifest, tarsplit) = try_join!(
+            self.download_metadata(layer, &counters, &metadata.manifest),
+            async {
+                if metadata.has_tarsplit() {
+                    self.download_metadata(layer, &counters, &metadata.tarsplit)
+                        .await
+                } else {
+                    Ok(vec![])
+                }
+            }
+        )?;
+        self.scheduler.done(METADATA_PRIORITY);
+        self.scheduler.done(METADATA_PRIORITY);
+
+        self.emit(&Event::MetadataFetched {
+            layer: &layer.digest,
+            manifest_size: manifest.len(),
+            tarsplit_size: tarsplit.len(),
+        });
+
+        let stream = if metadata.has_tarsplit() {
+            Stream::new_from_frames(&manifest[..], &tarsplit[..])
+        } else {
+            Stream::new_from_manifest(&manifest[..], &ParseOptions::default())
+        }
+        .and_then(|stream| {
+            stream.check_references(&metadata, Some(size))?;
+            Ok(stream)
+        })
+        .map_err(|err| {
+            let reason = if matches!(err, crate::Error::MemoryBudgetExceeded(_)) {
+                FallbackReason::BudgetExceeded
+            } else {
+                FallbackReason::InvalidMetadata
+            };
+            anyhow::Error::from(err).context(Fallback::new(layer, reason))
+        })?;
+        self.reserve(&stream)?;
+
+        // Remove the parts of the file that we know we won't need (tar headers, etc.)
+        // We get that by summing up the parts we do need and subtracting it from the total size.
+        // Files with the same content share a range, which is only downloaded once.
+        let already_accounted = (manifest.len() + tarsplit.len()) as u64;
+        let mut ranges = HashSet::new();
+        let needed: u64 = stream
+            .references()
+            .filter(|reference| ranges.insert(reference.range.clone()))
+            .map(ContentReference::compressed_size)
+            .sum();
+        let unneeded = size
+            .checked_sub(needed)
+            .and_then(|size| size.checked_sub(already_accounted))
+            .with_context(|| {
+                format!(
+                    "Layer {}: content and metadata add up to more than its {size} bytes",
+                    layer.digest
+                )
+            })
+            .context(Fallback::new(layer, FallbackReason::InvalidMetadata))?;
+        self.skip(&counters, unneeded);
+
+        let metadata_time = start.elapsed();
+        let start = Instant::now();
+
+        let files: Vec<_> = stream
+            .files
+            .iter()
+            .map(|file| ((self.puller.priority)(&file.name), file))
+            .collect();
+        for (priority, _) in &files {
+            self.scheduler.register(*priority, 1);
+        }
+
+        stream::iter(files)
+            .map(Result::<_, anyhow::Error>::Ok)
+            .try_for_each_concurrent(None, |(priority, file)| {
+                let (stream, counters) = (&stream, &counters);
+                async move {
+                    self.scheduler.wait_turn(priority).await;
+                    for reference in stream.file_references(file) {
+                        self.ensure_content(layer, counters, reference)
+                            .await
+                            .with_context(|| format!("Unable to fetch {}", file.name))?;
+                    }
+                    self.scheduler.done(priority);
+                    Ok(())
+                }
+            })
+            .await?;
+
+        if let Some(negative) = &self.puller.negative {
+            negative.forget(&layer.digest)?;
+        }
+        self.emit(&Event::LayerComplete {
+            layer: &layer.digest,
+        });
+
+        let report = counters.report(metadata_time, start.elapsed());
+        Ok((stream, report))
+    }
+
+    // Pulls a layer, giving up if it isn't done by the deadline.
+    async fn download_layer_by(
+        &self,
+        layer: &OciDescriptor,
+        deadline: Option<Instant>,
+    ) -> Result<(Stream, LayerReport)> {
+        ensure!(
+            !self
+                .puller
+                .negative
+                .as_ref()
+                .is_some_and(|negative| negative.is_bad(&layer.digest)),
+            Fallback::new(layer, FallbackReason::RepeatedFailures)
+        );
+        let Some(deadline) = deadline else {
+            return self.download_zstd_chunked_layer(layer).await;
+        };
+        tokio::time::timeout_at(deadline.into(), self.download_zstd_chunked_layer(layer))
+            .await
+            .context(Fallback::new(layer, FallbackReason::Timeout))?
+    }
+
+    // Unpacks a layer once the layers below it are done.  Content is checked against its digest
+    // again as it's read from the cache.
+    #[cfg(all(feature = "extract", unix))]
+    async fn unpack(&self, index: usize, stream: &Stream) -> Result<Option<Duration>> {
+        let Some((dir, options)) = &self.puller.extraction else {
+            return Ok(None);
+        };
+        self.unpacked.subscribe().wait_for(|&n| n == index).await?;
+        let start = Instant::now();
+        let (stream, cache, dir) = (stream.clone(), Arc::clone(&self.puller.cache), dir.clone());
+        let (options, span) = (options.clone(), self.span.clone());
+        tokio::task::spawn_blocking(move || {
+            span.in_scope(|| {
+                extract_to_dir(&stream, dir, &options, |reference| {
+                    cache
+                        .get(&reference.digest)?
+                        .with_context(|| format!("Content {} is missing", reference.digest))
+                })
+            })
+        })
+        .await??;
+        self.unpacked.send_modify(|n| *n += 1);
+        Ok(Some(start.elapsed()))
+    }
+
+    #[cfg(not(all(feature = "extract", unix)))]
+    #[allow(clippy::unused_async)]
+    async fn unpack(&self, _index: usize, _stream: &Stream) -> Result<Option<Duration>> {
+        Ok(None)
+    }
+
+    // Pulls a layer and, if requested, unpacks it
+    async fn pull_layer(
+        &self,
+        index: usize,
+        layer: &OciDescriptor,
+        deadline: Option<Instant>,
+    ) -> Result<(Stream, LayerReport)> {
+        let fetch = self
+            .span
+            .child(Phase::Fetch, &[(LAYER_DIGEST, &layer.digest)]);
+        let result = self.download_layer_by(layer, deadline).await;
+        if let Ok((_, report)) = &result {
+            fetch.add_bytes(report.downloaded);
+        }
+        let (stream, mut report) = fetch.finish(result)?;
+        report.extract_time = self
+            .unpack(index, &stream)
+            .await
+            .with_context(|| format!("Unable to extract layer {}", layer.digest))?;
+        if report.extract_time.is_some() {
+            self.emit(&Event::LayerExtracted {
+                layer: &layer.digest,
+            });
+        }
+        Ok((stream, report))
+    }
+
+    async fn pull(&self) -> Result<PulledImage> {
+        let start = Instant::now();
+        let deadline = self.puller.layer_timeout.map(|timeout| start + timeout);
+
+        let fetch = self
+            .span
+            .child(Phase::Fetch, &[(IMAGE_NAME, &self.image.whole())]);
+        let (manifest, manifest_digest) = fetch.finish(self.pull_manifest().await)?;
+        let OciManifest::Image(manifest) = manifest else {
+            bail!("This is not an image manifest");
+        };
+
+        let total: i64 = manifest.layers.iter().map(|l| l.size).sum();
+        self.puller
+            .progress
+            .pull_started(manifest.layers.len(), total.try_into()?);
+
+        // Normally the metadata of all layers goes first, so that priority files go before anything
+        // else.  When extracting, layers can be unpacked while later metadata is still on its way.
+        #[cfg(all(feature = "extract", unix))]
+        let extracting = self.puller.extraction.is_some();
+        #[cfg(not(all(feature = "extract", unix)))]
+        let extracting = false;
+        if extracting
+            && let Some(layer) = (manifest.layers.iter())
+                .find(|layer| is_zstd_artifact_media_type(&layer.media_type))
+        {
+            bail!(
+                "Layer {} is an artifact ({}), which can't be unpacked",
+                layer.digest,
+                layer.media_type
+            );
+        }
+        if !extracting {
+            self.scheduler
+                .register(METADATA_PRIORITY, 2 * manifest.layers.len());
+        }
+
+        let (streams, layers): (Vec<_>, Vec<_>) = try_join_all(
+            manifest
+                .layers
+                .iter()
+                .enumerate()
+                .map(|(index, layer)| self.pull_layer(index, layer, deadline)),
+        )
+        .await?
+        .into_iter()
+        .unzip();
+
+        Ok(PulledImage {
+            manifest_digest,
+            streams,
+            report: PullReport {
+                layers,
+                total_time: start.elapsed(),
+            },
+        })
+    }
+}
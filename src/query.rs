@@ -0,0 +1,250 @@
+//! Filtering the entries of a layer, to answer questions like "which binaries are setuid?" without
+//! walking the manifest by hand.
+//!
+//! ```
+//! # use zstd_chunked::{Stream, query::Filter};
+//! # fn example(stream: &Stream) {
+//! let setuid = Filter::new().with_kind("reg").with_mode(0o4000);
+//! for (entry, _references) in setuid.matches(stream) {
+//!     println!("{}", entry.name);
+//! }
+//! # }
+//! ```
+
+use std::ops::{Bound, RangeBounds};
+
+use crate::{ContentReference, ManifestEntry, Stream, normalize_path};
+
+/// A filter over the entries of a [`Stream`].  A new filter matches everything, and each `with_*`
+/// method adds a condition which entries must also satisfy.
+#[derive(Debug, Clone)]
+pub struct Filter {
+    glob: Option<String>,
+    kinds: Vec<String>,
+    size: (Bound<u64>, Bound<u64>),
+    mtime: Option<(Bound<i64>, Bound<i64>)>,
+    digest: Option<String>,
+    mode: u32,
+}
+
+impl Default for Filter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Filter {
+    /// Creates a filter which matches every entry.
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            glob: None,
+            kinds: vec![],
+            size: (Bound::Unbounded, Bound::Unbounded),
+            mtime: None,
+            digest: None,
+            mode: 0,
+        }
+    }
+
+    /// Only matches paths matching a glob, where `*` matches anything but `/`, `**` matches
+    /// anything (so `usr/**/bin/*` matches `usr/bin/ls` and `usr/local/bin/ls`), and `?` matches a
+    /// single character other than `/`.  Leading `./` or `/` and trailing `/` are ignored, as for
+    /// [`Stream::lookup()`].
+    #[must_use]
+    pub fn with_glob(mut self, glob: impl Into<String>) -> Self {
+        self.glob = Some(glob.into());
+        self
+    }
+
+    /// Only matches entries of the given type (`"reg"`, `"dir"`, `"symlink"`, ...).  Calling this
+    /// more than once matches entries of any of the types.
+    #[must_use]
+    pub fn with_kind(mut self, kind: impl Into<String>) -> Self {
+        self.kinds.push(kind.into());
+        self
+    }
+
+    /// Only matches entries whose size is in the range.  Entries without a size (like
+    /// directories) count as empty.
+    #[must_use]
+    pub fn with_size(mut self, size: impl RangeBounds<u64>) -> Self {
+        self.size = (size.start_bound().cloned(), size.end_bound().cloned());
+        self
+    }
+
+    /// Only matches entries whose modification time, in seconds since the Unix epoch, is in the
+    /// range.  Entries without a (valid) modification time don't match.
+    #[must_use]
+    pub fn with_mtime(mut self, mtime: impl RangeBounds<i64>) -> Self {
+        self.mtime = Some((mtime.start_bound().cloned(), mtime.end_bound().cloned()));
+        self
+    }
+
+    /// Only matches entries whose content has the given digest.
+    #[must_use]
+    pub fn with_digest(mut self, digest: impl Into<String>) -> Self {
+        self.digest = Some(digest.into());
+        self
+    }
+
+    /// Only matches entries whose mode has all of the given bits set: `0o4000` finds setuid
+    /// entries, and `0o111` entries which are executable by everyone.
+    #[must_use]
+    pub const fn with_mode(mut self, bits: u32) -> Self {
+        self.mode = bits;
+        self
+    }
+
+    /// Checks whether a single entry matches.
+    #[must_use]
+    pub fn is_match(&self, entry: &ManifestEntry) -> bool {
+        self.glob
+            .as_ref()
+            .is_none_or(|glob| glob_matches(normalize_path(glob), normalize_path(&entry.name)))
+            && (self.kinds.is_empty() || self.kinds.contains(&entry.kind))
+            && self.size.contains(&entry.size.unwrap_or_default())
+            && self.mtime.is_none_or(|range| {
+                entry
+                    .mtime
+                    .as_deref()
+                    .and_then(parse_time)
+                    .is_some_and(|mtime| range.contains(&mtime))
+            })
+            && self
+                .digest
+                .as_ref()
+                .is_none_or(|digest| entry.digest.as_ref() == Some(digest))
+            && entry.mode.unwrap_or_default() & self.mode == self.mode
+    }
+
+    /// Iterates over the matching entries of a stream, in order, along with the references needed
+    /// to reconstruct their content (as for [`Stream::lookup()`]).
+    pub fn matches<'a>(
+        &'a self,
+        stream: &'a Stream,
+    ) -> impl Iterator<
+        Item = (
+            &'a ManifestEntry,
+            impl Iterator<Item = &'a ContentReference>,
+        ),
+    > {
+        stream
+            .entries
+            .iter()
+            .enumerate()
+            .filter(|(_, entry)| self.is_match(entry))
+            .map(|(index, entry)| {
+                // Only the last entry for a path has its content in the index
+                let file = stream
+                    .lookup_indexes(&entry.name)
+                    .and_then(|(last, file)| file.filter(|_| last == index))
+                    .and_then(|file| stream.files.get(file));
+                let references = file
+                    .into_iter()
+                    .flat_map(|file| stream.file_references(file));
+                (entry, references)
+            })
+    }
+}
+
+// Matches a path against a glob, as described for `Filter::with_glob()`
+fn glob_matches(glob: &str, path: &str) -> bool {
+    // The offsets at which the rest of the path could start
+    let offsets = || {
+        path.char_indices()
+            .map(|(offset, _)| offset)
+            .chain([path.len()])
+    };
+
+    if let Some(rest) = glob.strip_prefix("**") {
+        // "**/" can also match no directories at all
+        return rest
+            .strip_prefix('/')
+            .is_some_and(|rest| glob_matches(rest, path))
+            || offsets().any(|offset| path.get(offset..).is_some_and(|p| glob_matches(rest, p)));
+    }
+    if let Some(rest) = glob.strip_prefix('*') {
+        let segment = path.find('/').unwrap_or(path.len());
+        return offsets()
+            .take_while(|&offset| offset <= segment)
+            .any(|offset| path.get(offset..).is_some_and(|p| glob_matches(rest, p)));
+    }
+
+    let mut glob_chars = glob.chars();
+    let mut path_chars = path.chars();
+    match (glob_chars.next(), path_chars.next()) {
+        (None, None) => true,
+        (Some('?'), Some(c)) if c != '/' => glob_matches(glob_chars.as_str(), path_chars.as_str()),
+        (Some(g), Some(c)) if g == c => glob_matches(glob_chars.as_str(), path_chars.as_str()),
+        _ => false,
+    }
+}
+
+// Parses an RFC 3339 timestamp (as written by Go) into seconds since the Unix epoch.  Returns
+// None for fields out of range, and for years so far away that the result would overflow.
+fn parse_time(time: &str) -> Option<i64> {
+    let (date, time) = time.split_once(['T', 't', ' '])?;
+    let mut date = date.splitn(3, '-').map(str::parse::<i64>);
+    let (year, month, day) = (date.next()?.ok()?, date.next()?.ok()?, date.next()?.ok()?);
+
+    let (time, offset) = if let Some(time) = time.strip_suffix(['Z', 'z']) {
+        (time, 0)
+    } else {
+        let split = time.rfind(['+', '-'])?;
+        let (time, zone) = time.split_at(split);
+        let sign = if zone.starts_with('-') { -1 } else { 1 };
+        let (hours, minutes) = zone.get(1..)?.split_once(':')?;
+        let (hours, minutes) = (hours.parse::<i64>().ok()?, minutes.parse::<i64>().ok()?);
+        if !(0..24).contains(&hours) || !(0..60).contains(&minutes) {
+            return None;
+        }
+        (time, sign * (hours * 3600 + minutes * 60))
+    };
+    // Fractional seconds are dropped
+    let time = time.split_once('.').map_or(time, |(time, _)| time);
+    let mut time = time.splitn(3, ':').map(str::parse::<i64>);
+    let (hour, minute, second) = (time.next()?.ok()?, time.next()?.ok()?, time.next()?.ok()?);
+
+    // Leap seconds are allowed, as in RFC 3339
+    if !(1..=12).contains(&month)
+        || !(1..=31).contains(&day)
+        || !(0..24).contains(&hour)
+        || !(0..60).contains(&minute)
+        || !(0..=60).contains(&second)
+    {
+        return None;
+    }
+    // Howard Hinnant's days_from_civil(), checked since the year can be anything
+    let year = if month <= 2 {
+        year.checked_sub(1)?
+    } else {
+        year
+    };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let doy = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era.checked_mul(146_097)?.checked_add(doe - 719_468)?;
+    days.checked_mul(86400)?
+        .checked_add(hour * 3600 + minute * 60 + second - offset)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn times_are_parsed_without_overflowing() {
+        assert_eq!(parse_time("1970-01-01T00:00:00Z"), Some(0));
+        assert_eq!(parse_time("2024-02-29T12:34:56.789Z"), Some(1_709_210_096));
+        assert_eq!(parse_time("2024-02-29T14:34:56+02:00"), Some(1_709_210_096));
+        assert_eq!(parse_time("1969-12-31T23:59:59Z"), Some(-1));
+
+        assert_eq!(parse_time("2024-02-29T24:00:00Z"), None);
+        assert_eq!(parse_time("2024-02-29T12:00:00+99999999999:00"), None);
+        for year in [i64::MAX, i64::MIN, i64::MAX / 365, i64::MIN / 365] {
+            assert_eq!(parse_time(&format!("{year}-01-01T00:00:00Z")), None);
+        }
+    }
+}
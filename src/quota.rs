@@ -0,0 +1,472 @@
+//! Disk space accounting for pulls, for devices with very little storage.
+//!
+//! A [`DiskQuota`] is given the parsed metadata of each layer before its content is fetched, and
+//! fails with [`QuotaExceeded`] as soon as the pull would need more space than allowed, instead of
+//! running the disk full half way through.
+//!
+//! On Unix, [`check_free_space()`] compares what's needed with the free space on the filesystems
+//! that will be written to, and a [`SpaceReservation`] sets the space aside (with `fallocate()`)
+//! so that other processes can't use it up while the content is being fetched.
+
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex, PoisonError},
+};
+#[cfg(unix)]
+use std::{
+    fs::{self, File, OpenOptions},
+    io::ErrorKind,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use anyhow::Result;
+
+use crate::{
+    Chunk, Stream,
+    store::{Backend, ChunkStore},
+};
+
+/// The disk space needed to pull one or more layers.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DiskUsage {
+    /// The number of objects that would be added to the store.
+    pub new_objects: u64,
+
+    /// The total (uncompressed) size of the objects that would be added to the store.
+    pub store_growth: u64,
+
+    /// The size of the reconstructed layers, if they're going to be written out (or extracted).
+    /// This is zero for quotas that only cover the store.
+    pub output_size: u64,
+}
+
+impl DiskUsage {
+    /// The total number of bytes needed.
+    #[must_use]
+    pub const fn total(&self) -> u64 {
+        self.store_growth.saturating_add(self.output_size)
+    }
+}
+
+/// The error returned by [`DiskQuota::reserve()`] when a layer doesn't fit.
+#[derive(Debug, Clone, Copy, thiserror::Error)]
+#[error(
+    "Disk quota exceeded: the layer needs {} bytes ({} new objects totalling {} bytes, plus {} \
+     bytes of output) but only {} of the {limit} byte quota remain",
+    .needed.total(),
+    .needed.new_objects,
+    .needed.store_growth,
+    .needed.output_size,
+    .limit.saturating_sub(*.reserved)
+)]
+pub struct QuotaExceeded {
+    /// The quota, in bytes.
+    pub limit: u64,
+
+    /// The number of bytes already reserved by earlier layers.
+    pub reserved: u64,
+
+    /// What the layer would have needed.
+    pub needed: DiskUsage,
+}
+
+/// The error returned when a filesystem doesn't have enough free space.
+#[cfg(unix)]
+#[derive(Debug, Clone, thiserror::Error)]
+#[error(
+    "Not enough free space on {}: {needed} bytes are needed but only {available} are available",
+    .path.display()
+)]
+pub struct InsufficientSpace {
+    /// A path on the filesystem.
+    pub path: PathBuf,
+
+    /// The number of bytes available to unprivileged users.
+    pub available: u64,
+
+    /// The number of bytes needed.
+    pub needed: u64,
+}
+
+#[derive(Debug, Default)]
+struct Reserved {
+    bytes: u64,
+    digests: HashSet<Arc<str>>,
+}
+
+/// A thread-safe limit on the disk space used by a pull, shared between its layers.
+///
+/// Space stays reserved until the [`QuotaReservation`] is dropped, which should be once the
+/// content is in the store (or the pull has failed), so the same quota can be used for one pull
+/// after another.  Objects are counted once, even if several layers use them, and objects which
+/// are already in the store aren't counted at all.  Sizes are the sizes of the content:
+/// filesystem overhead isn't included, so leave some headroom.
+#[derive(Debug)]
+pub struct DiskQuota {
+    limit: u64,
+    count_output: bool,
+    reflinks: bool,
+    reserved: Mutex<Reserved>,
+}
+
+impl DiskQuota {
+    /// Creates a quota of `limit` bytes, which covers the growth of the store only.
+    #[must_use]
+    pub fn new(limit: u64) -> Self {
+        Self {
+            limit,
+            count_output: false,
+            reflinks: false,
+            reserved: Mutex::default(),
+        }
+    }
+
+    /// Sets whether the reconstructed layers count against the quota, for pulls that write them
+    /// out (or extract them) as well as storing their content.
+    #[must_use]
+    pub const fn with_output(mut self, count_output: bool) -> Self {
+        self.count_output = count_output;
+        self
+    }
+
+    /// Sets whether the output will share storage with the store (by reflinking objects into
+    /// it), in which case files whose content is a single object don't count towards the output
+    /// size.  Files made up of several objects are still counted, since they need to be copied.
+    #[must_use]
+    pub const fn with_reflinks(mut self, reflinks: bool) -> Self {
+        self.reflinks = reflinks;
+        self
+    }
+
+    /// Calculates the disk space needed by a layer, without reserving it.  Objects that are in the
+    /// store, or that were reserved by earlier layers, aren't counted.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the store can't be checked.
+    pub fn usage(&self, stream: &Stream, store: &(impl Backend + ?Sized)) -> Result<DiskUsage> {
+        self.usage_locked(&self.lock(), stream, store)
+    }
+
+    fn usage_locked(
+        &self,
+        reserved: &Reserved,
+        stream: &Stream,
+        store: &(impl Backend + ?Sized),
+    ) -> Result<DiskUsage> {
+        let mut usage = DiskUsage::default();
+        for reference in stream.unique_references() {
+            if reserved.digests.contains(&reference.digest) {
+                continue;
+            }
+            if !store.contains(&reference.digest)? {
+                usage.new_objects += 1;
+                usage.store_growth = usage.store_growth.saturating_add(reference.size);
+            }
+        }
+
+        if self.count_output {
+            let reflinked: u64 = if self.reflinks {
+                stream
+                    .files
+                    .iter()
+                    .filter_map(|file| match stream.chunks.get(file.chunks.clone()) {
+                        Some([Chunk::External(reference)]) => Some(reference.size),
+                        _ => None,
+                    })
+                    .sum()
+            } else {
+                0
+            };
+            usage.output_size = stream.size() - reflinked;
+        }
+        Ok(usage)
+    }
+
+    /// Reserves the disk space needed by a layer, before fetching its content.  The space is
+    /// released when the returned reservation is dropped.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`QuotaExceeded`] (reserving nothing) if the layer doesn't fit in what's left of
+    /// the quota, or if the store can't be checked.
+    pub fn reserve(
+        &self,
+        stream: &Stream,
+        store: &(impl Backend + ?Sized),
+    ) -> Result<QuotaReservation<'_>> {
+        let mut reserved = self.lock();
+        let needed = self.usage_locked(&reserved, stream, store)?;
+        if reserved.bytes.saturating_add(needed.total()) > self.limit {
+            Err(QuotaExceeded {
+                limit: self.limit,
+                reserved: reserved.bytes,
+                needed,
+            })?;
+        }
+        reserved.bytes += needed.total();
+        let mut digests = vec![];
+        for reference in stream.references() {
+            if reserved.digests.insert(Arc::clone(&reference.digest)) {
+                digests.push(Arc::clone(&reference.digest));
+            }
+        }
+        drop(reserved);
+
+        Ok(QuotaReservation {
+            quota: self,
+            usage: needed,
+            digests,
+        })
+    }
+
+    /// The number of bytes reserved so far.
+    #[must_use]
+    pub fn reserved(&self) -> u64 {
+        self.lock().bytes
+    }
+
+    /// The quota, in bytes.
+    #[must_use]
+    pub const fn limit(&self) -> u64 {
+        self.limit
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Reserved> {
+        // The state is always consistent, so we can ignore poisoning
+        self.reserved.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Disk space reserved by [`DiskQuota::reserve()`], which goes back to the quota when this is
+/// dropped.
+#[derive(Debug)]
+#[must_use]
+pub struct QuotaReservation<'a> {
+    quota: &'a DiskQuota,
+    usage: DiskUsage,
+    // The objects that this reservation counted, which other layers skipped
+    digests: Vec<Arc<str>>,
+}
+
+impl QuotaReservation<'_> {
+    /// What was reserved.
+    #[must_use]
+    pub const fn usage(&self) -> &DiskUsage {
+        &self.usage
+    }
+}
+
+impl Drop for QuotaReservation<'_> {
+    fn drop(&mut self) {
+        let mut reserved = self.quota.lock();
+        reserved.bytes = reserved.bytes.saturating_sub(self.usage.total());
+        for digest in &self.digests {
+            reserved.digests.remove(digest);
+        }
+    }
+}
+
+/// Returns the free space available to unprivileged users on the filesystem containing `path`,
+/// or the nearest ancestor of it that exists.
+///
+/// # Errors
+///
+/// Fails if the filesystem can't be queried.
+#[cfg(unix)]
+pub fn available_space(path: impl AsRef<Path>) -> Result<u64> {
+    let stat = rustix::fs::statvfs(existing_ancestor(path.as_ref())?)?;
+    Ok(stat.f_bavail.saturating_mul(stat.f_frsize))
+}
+
+/// Checks that there's enough free space for `usage`, before anything is fetched.
+///
+/// The store's growth is checked against the filesystem of `store`, and the output against the
+/// filesystem of `output` (if there is one).  When both are on the same filesystem, the total is
+/// checked.
+///
+/// This is a snapshot: use a [`SpaceReservation`] to make sure the space is still there later.
+///
+/// # Errors
+///
+/// Fails with [`InsufficientSpace`] if a filesystem is too full, or if one can't be queried.
+#[cfg(unix)]
+pub fn check_free_space(
+    usage: &DiskUsage,
+    store: &ChunkStore,
+    output: Option<&Path>,
+) -> Result<()> {
+    let store_path = existing_ancestor(store.root())?;
+    let mut checks = vec![(store_path, usage.store_growth)];
+    if let Some(output) = output {
+        let output_path = existing_ancestor(output)?;
+        if fs::metadata(&output_path)?.dev() == fs::metadata(&checks[0].0)?.dev() {
+            checks[0].1 += usage.output_size;
+        } else {
+            checks.push((output_path, usage.output_size));
+        }
+    }
+
+    for (path, needed) in checks {
+        let available = available_space(&path)?;
+        if needed > available {
+            Err(InsufficientSpace {
+                path,
+                available,
+                needed,
+            })?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn existing_ancestor(path: &Path) -> Result<PathBuf> {
+    for ancestor in path.ancestors() {
+        match fs::symlink_metadata(ancestor) {
+            Ok(_) => return Ok(ancestor.to_path_buf()),
+            Err(err) if err.kind() == ErrorKind::NotFound => {}
+            Err(err) => Err(err)?,
+        }
+    }
+    // Relative paths run out of ancestors before reaching the current directory
+    Ok(PathBuf::from("."))
+}
+
+/// Disk space set aside for a pull, held in a preallocated file.
+///
+/// The reservation is grown (with `fallocate()`) before content is fetched, and released bit by
+/// bit as the content is written, so that the space needed for the rest of the pull can't be
+/// taken by anything else in the meantime.  The file is removed when the reservation is dropped.
+///
+/// On the BSDs other than FreeBSD, which don't have `fallocate()`, the file is only extended, so
+/// the space isn't actually set aside there.
+#[cfg(unix)]
+#[derive(Debug)]
+pub struct SpaceReservation {
+    file: File,
+    path: PathBuf,
+    size: u64,
+}
+
+#[cfg(unix)]
+impl SpaceReservation {
+    /// Creates an empty reservation on the filesystem containing `dir` (which is created if
+    /// needed).  Use the directory that the content will be written to, such as the root of the
+    /// store.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the reservation file can't be created.
+    pub fn new(dir: impl AsRef<Path>) -> Result<Self> {
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir)?;
+        let name = format!(
+            ".space-reservation.{}.{}",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        );
+        let path = dir.join(name);
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        Ok(Self {
+            file,
+            path,
+            size: 0,
+        })
+    }
+
+    /// Reserves `bytes` more.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`InsufficientSpace`] (reserving nothing) if the filesystem is too full, or if
+    /// the space can't be allocated for some other reason.
+    pub fn grow(&mut self, bytes: u64) -> Result<()> {
+        match allocate(&self.file, self.size, bytes) {
+            Ok(()) => {
+                self.size += bytes;
+                Ok(())
+            }
+            Err(err) => {
+                // Don't keep whatever was allocated before the failure
+                self.file.set_len(self.size)?;
+                if err == rustix::io::Errno::NOSPC {
+                    let dir = self.path.parent().unwrap_or(&self.path).to_path_buf();
+                    Err(InsufficientSpace {
+                        available: available_space(&dir)?,
+                        path: dir,
+                        needed: bytes,
+                    })?;
+                }
+                Err(err.into())
+            }
+        }
+    }
+
+    /// Gives back `bytes` of the reservation, which is normally done just before writing that
+    /// much content.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the reservation file can't be truncated.
+    pub fn release(&mut self, bytes: u64) -> Result<()> {
+        self.size = self.size.saturating_sub(bytes);
+        self.file.set_len(self.size)?;
+        Ok(())
+    }
+
+    /// The number of bytes currently reserved.
+    #[must_use]
+    pub const fn size(&self) -> u64 {
+        self.size
+    }
+}
+
+// Allocates space in a file.  libc doesn't have fallocate() (or posix_fallocate()) for the BSDs
+// other than FreeBSD, so there the file is only extended, without setting the space aside.
+#[cfg(unix)]
+fn allocate(file: &File, offset: u64, len: u64) -> rustix::io::Result<()> {
+    #[cfg(not(any(
+        target_os = "netbsd",
+        target_os = "openbsd",
+        target_os = "dragonfly",
+        target_os = "espidf",
+        target_os = "horizon",
+        target_os = "nto",
+        target_os = "redox",
+        target_os = "vita",
+    )))]
+    {
+        rustix::fs::fallocate(file, rustix::fs::FallocateFlags::empty(), offset, len)
+    }
+    #[cfg(any(
+        target_os = "netbsd",
+        target_os = "openbsd",
+        target_os = "dragonfly",
+        target_os = "espidf",
+        target_os = "horizon",
+        target_os = "nto",
+        target_os = "redox",
+        target_os = "vita",
+    ))]
+    {
+        let size = offset.checked_add(len).ok_or(rustix::io::Errno::FBIG)?;
+        file.set_len(size)
+            .map_err(|err| rustix::io::Errno::from_io_error(&err).unwrap_or(rustix::io::Errno::IO))
+    }
+}
+
+#[cfg(unix)]
+impl Drop for SpaceReservation {
+    fn drop(&mut self) {
+        // Nothing useful can be done if this fails
+        let _ = fs::remove_file(&self.path);
+    }
+}
@@ -0,0 +1,281 @@
+//! Collecting the responses to HTTP range requests.
+//!
+//! Not every server honours the end of a requested range: some CDNs send a few bytes too many and
+//! others stream all the way to the end of the blob.  [`RangeBuffer`] accumulates the data for a
+//! range across one or more responses (for example, when a request is resumed after an error),
+//! keeping exactly the bytes that were asked for and reporting how much was useful, so that
+//! progress accounting and the assembled data both stay correct.
+//!
+//! Fetching hundreds of tiny ranges one by one is slow, even over HTTP/2.  A [`RangePlanner`]
+//! merges the ranges of nearby references into fewer, larger requests, at the cost of also
+//! downloading the gaps between them, and says how to split each response back up.
+//!
+//! Servers that support it can also send several ranges in a single response:
+//! [`range_header()`] asks for them and [`split_multipart()`] takes the `multipart/byteranges`
+//! response apart again.
+
+use core::ops::Range;
+
+use anyhow::{Context, Result, ensure};
+
+use crate::ContentReference;
+
+/// The data received so far for a range of a blob.
+#[derive(Debug, Clone)]
+pub struct RangeBuffer {
+    range: Range<u64>,
+    data: Vec<u8>,
+    discarded: u64,
+}
+
+impl RangeBuffer {
+    /// Creates an empty buffer for the given range.
+    #[must_use]
+    pub const fn new(range: Range<u64>) -> Self {
+        Self {
+            range,
+            data: vec![],
+            discarded: 0,
+        }
+    }
+
+    /// The range that the buffer is collecting.
+    #[must_use]
+    pub fn range(&self) -> Range<u64> {
+        self.range.clone()
+    }
+
+    /// The offset (in the blob) of the next byte that's needed.  When a response is cut short,
+    /// this is where the next request should start.
+    #[must_use]
+    pub const fn position(&self) -> u64 {
+        self.range.start + self.data.len() as u64
+    }
+
+    /// The number of bytes still needed.
+    #[must_use]
+    pub const fn remaining(&self) -> u64 {
+        self.range.end.saturating_sub(self.position())
+    }
+
+    /// Checks if all of the data for the range has been received.  Callers should stop reading
+    /// the response at this point, in case the server is sending more than was asked for.
+    #[must_use]
+    pub const fn is_complete(&self) -> bool {
+        self.remaining() == 0
+    }
+
+    /// The number of bytes received beyond the end of the range, which were thrown away.
+    #[must_use]
+    pub const fn discarded(&self) -> u64 {
+        self.discarded
+    }
+
+    /// Adds the next piece of a response, which is assumed to continue from [`Self::position()`].
+    /// Anything past the end of the range is discarded.  Returns the number of bytes that were
+    /// kept, which is what should be counted as progress.
+    pub fn push(&mut self, bytes: &[u8]) -> u64 {
+        let remaining = usize::try_from(self.remaining()).unwrap_or(usize::MAX);
+        let (useful, extra) = bytes.split_at(bytes.len().min(remaining));
+        self.data.extend_from_slice(useful);
+        self.discarded += extra.len() as u64;
+        useful.len() as u64
+    }
+
+    /// Returns the data received so far.
+    #[must_use]
+    pub fn into_data(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+/// Merges the ranges of references into fewer requests.  Ranges separated by no more than the
+/// maximum gap are merged, so at most that many unneeded bytes are downloaded between them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RangePlanner {
+    max_gap: u64,
+    max_size: Option<u64>,
+}
+
+impl RangePlanner {
+    /// Creates a planner which merges ranges separated by at most `max_gap` bytes, with no limit
+    /// on the size of the merged requests.
+    #[must_use]
+    pub const fn new(max_gap: u64) -> Self {
+        Self {
+            max_gap,
+            max_size: None,
+        }
+    }
+
+    /// Stops merging ranges into a request once it would grow beyond `max_size` bytes.  A single
+    /// range bigger than that still gets a request of its own.
+    #[must_use]
+    pub const fn with_max_size(mut self, max_size: u64) -> Self {
+        self.max_size = Some(max_size);
+        self
+    }
+
+    /// Plans the requests for the given references, in the order of their offsets.  References
+    /// with the same or overlapping ranges share a request.
+    pub fn plan<'a>(
+        &self,
+        references: impl IntoIterator<Item = &'a ContentReference>,
+    ) -> Vec<CoalescedRange> {
+        let mut references: Vec<_> = references.into_iter().collect();
+        references.sort_by_key(|reference| (reference.range.start, reference.range.end));
+
+        let mut plan: Vec<CoalescedRange> = vec![];
+        for reference in references {
+            let range = &reference.range;
+            let mergeable = plan.last().filter(|last| {
+                range.start <= last.range.end.saturating_add(self.max_gap)
+                    && self.max_size.is_none_or(|max_size| {
+                        range.end.max(last.range.end) - last.range.start <= max_size
+                    })
+            });
+            if mergeable.is_none() {
+                plan.push(CoalescedRange {
+                    range: range.clone(),
+                    parts: vec![],
+                });
+            }
+            if let Some(last) = plan.last_mut() {
+                last.range.end = last.range.end.max(range.end);
+                last.parts.push(reference.clone());
+            }
+        }
+        plan
+    }
+}
+
+/// A single request planned by [`RangePlanner::plan()`], covering the ranges of one or more
+/// references.
+#[derive(Debug, Clone)]
+pub struct CoalescedRange {
+    /// The range to request.
+    pub range: Range<u64>,
+
+    /// The references whose data is in the range, in order.
+    pub parts: Vec<ContentReference>,
+}
+
+impl CoalescedRange {
+    /// The number of bytes in the range which don't belong to any of the references.
+    #[must_use]
+    pub fn wasted(&self) -> u64 {
+        let mut needed = 0;
+        let mut end = self.range.start;
+        for part in &self.parts {
+            let start = part.range.start.max(end);
+            needed += part.range.end.saturating_sub(start);
+            end = end.max(part.range.end);
+        }
+        (self.range.end - self.range.start) - needed
+    }
+
+    /// Splits the data for the range (as received) into the data for each reference.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the data doesn't have the size of the range.
+    pub fn split<'a>(
+        &'a self,
+        data: &'a [u8],
+    ) -> Result<impl Iterator<Item = (&'a ContentReference, &'a [u8])>> {
+        ensure!(
+            data.len() as u64 == self.range.end - self.range.start,
+            "Expected {} bytes for range {:?} but got {}",
+            self.range.end - self.range.start,
+            self.range,
+            data.len()
+        );
+        Ok(self.parts.iter().map(move |part| {
+            // Each part is within the range, which has the size of the data
+            #[allow(clippy::cast_possible_truncation)]
+            let slice = &data[(part.range.start - self.range.start) as usize
+                ..(part.range.end - self.range.start) as usize];
+            (part, slice)
+        }))
+    }
+}
+
+/// Builds the value of a `Range` header asking for all of the given ranges, none of which can be
+/// empty.
+#[must_use]
+pub fn range_header(ranges: &[Range<u64>]) -> String {
+    let specs: Vec<_> = ranges
+        .iter()
+        .map(|range| format!("{}-{}", range.start, range.end.saturating_sub(1)))
+        .collect();
+    format!("bytes={}", specs.join(","))
+}
+
+/// Parses the value of a `Content-Range` header, like `bytes 0-99/1234`, into the range that it
+/// describes.
+#[must_use]
+pub fn parse_content_range(value: &str) -> Option<Range<u64>> {
+    let (range, _total) = value.trim().strip_prefix("bytes ")?.split_once('/')?;
+    let (first, last) = range.split_once('-')?;
+    let first = first.trim().parse::<u64>().ok()?;
+    let last = last.trim().parse::<u64>().ok()?;
+    (first <= last).then_some(first..last.checked_add(1)?)
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+/// Splits the body of a `multipart/byteranges` response into its parts, given the value of the
+/// `Content-Type` header (which holds the boundary).
+///
+/// Returns the range and data of each part, in the order that the server sent them.  Servers may
+/// merge overlapping or nearby ranges, or send them in a different order, so callers should look
+/// for the part that contains each range they asked for.
+///
+/// # Errors
+///
+/// Fails if the content type isn't `multipart/byteranges` or if the body is malformed.
+pub fn split_multipart<'a>(
+    content_type: &str,
+    body: &'a [u8],
+) -> Result<Vec<(Range<u64>, &'a [u8])>> {
+    let mut params = content_type.split(';');
+    let media_type = params.next().unwrap_or_default().trim();
+    ensure!(
+        media_type.eq_ignore_ascii_case("multipart/byteranges"),
+        "Expected multipart/byteranges but got {media_type}"
+    );
+    let boundary = params
+        .filter_map(|param| param.trim().split_once('='))
+        .find(|(name, _)| name.eq_ignore_ascii_case("boundary"))
+        .map(|(_, value)| value.trim_matches('"'))
+        .with_context(|| format!("No boundary in {content_type}"))?;
+    let delimiter = format!("--{boundary}").into_bytes();
+
+    let mut parts = vec![];
+    let start = find(body, &delimiter).context("No parts in multipart response")?;
+    let mut rest = &body[start + delimiter.len()..];
+    while !rest.starts_with(b"--") {
+        let end = find(rest, b"\r\n\r\n").context("Unterminated headers in multipart response")?;
+        let headers = String::from_utf8_lossy(&rest[..end]);
+        let range = headers
+            .lines()
+            .filter_map(|line| line.split_once(':'))
+            .find(|(name, _)| name.trim().eq_ignore_ascii_case("content-range"))
+            .and_then(|(_, value)| parse_content_range(value))
+            .context("Part without a valid Content-Range in multipart response")?;
+        rest = &rest[end + 4..];
+
+        let size = usize::try_from(range.end - range.start)?;
+        ensure!(rest.len() >= size, "Part for {range:?} is cut short");
+        let (data, after) = rest.split_at(size);
+        parts.push((range, data));
+
+        let next = find(after, &delimiter).context("Multipart response is cut short")?;
+        rest = &after[next + delimiter.len()..];
+    }
+    Ok(parts)
+}
@@ -0,0 +1,296 @@
+//! Readers over the reconstructed content of a [`Stream`], which resolve chunks as they're needed.
+
+use core::fmt;
+#[cfg(feature = "tokio")]
+use core::{
+    pin::Pin,
+    task::{Context, Poll, ready},
+};
+use std::{
+    borrow::Cow,
+    io::{self, Read, Seek, SeekFrom},
+    sync::Arc,
+};
+
+use anyhow::Result;
+#[cfg(feature = "tokio")]
+use tokio::io::{AsyncRead, ReadBuf};
+
+use crate::{Chunk, ContentReference, Error, Stream, inline_data};
+
+/// A [`Read`] and [`Seek`] implementation over the reconstructed content of a [`Stream`], as
+/// returned by [`Stream::reader()`].
+///
+/// Only the chunks covering the offsets that are actually read get resolved, which allows random
+/// access into a layer (for example, to read a single file out of it) without reconstructing the
+/// whole thing.  The most recently used chunk is kept, so sequential reads resolve each chunk
+/// once.  Errors are reported as [`io::Error`]s wrapping an [`Error`].
+pub struct StreamReader<'a, R> {
+    stream: &'a Stream,
+    resolve_reference: R,
+    // The offset at which each chunk starts, plus the total size at the end
+    offsets: Vec<u64>,
+    position: u64,
+    current: Option<(usize, Cow<'a, [u8]>)>,
+}
+
+impl<R> fmt::Debug for StreamReader<'_, R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StreamReader")
+            .field("position", &self.position)
+            .field("size", &self.size())
+            .field("current", &self.current.as_ref().map(|(index, _)| index))
+            .finish_non_exhaustive()
+    }
+}
+
+impl<R> StreamReader<'_, R> {
+    /// The total size of the content.
+    #[must_use]
+    pub fn size(&self) -> u64 {
+        self.offsets.last().copied().unwrap_or_default()
+    }
+
+    /// The current position of the reader.
+    #[must_use]
+    pub const fn position(&self) -> u64 {
+        self.position
+    }
+}
+
+impl<'a, R> StreamReader<'a, R>
+where
+    R: Fn(&ContentReference) -> Result<Vec<u8>>,
+{
+    pub(crate) fn new(stream: &'a Stream, resolve_reference: R) -> Self {
+        let mut offsets = Vec::with_capacity(stream.chunks.len() + 1);
+        let mut offset = 0;
+        offsets.push(offset);
+        for chunk in stream.chunks.iter() {
+            offset += chunk_size(chunk);
+            offsets.push(offset);
+        }
+        Self {
+            stream,
+            resolve_reference,
+            offsets,
+            position: 0,
+            current: None,
+        }
+    }
+
+    // Makes sure that the given chunk is loaded, and returns its data.
+    fn load(&mut self, index: usize) -> io::Result<&[u8]> {
+        if self
+            .current
+            .as_ref()
+            .is_none_or(|(current, _)| *current != index)
+        {
+            let chunk = self
+                .stream
+                .chunks
+                .get(index)
+                .ok_or(io::ErrorKind::InvalidInput)?;
+            let data = match (chunk, inline_data(chunk).map_err(io::Error::other)?) {
+                (Chunk::External(reference), None) => {
+                    let data = (self.resolve_reference)(reference).map_err(|err| {
+                        io::Error::other(Error::Resolver {
+                            digest: Arc::clone(&reference.digest),
+                            source: err.into(),
+                        })
+                    })?;
+                    // The offsets depend on the sizes being right
+                    if data.len() as u64 != reference.size {
+                        Err(io::Error::other(Error::Verification {
+                            digest: Arc::clone(&reference.digest),
+                            source: format!(
+                                "Expected {} bytes but got {}",
+                                reference.size,
+                                data.len()
+                            )
+                            .into(),
+                        }))?;
+                    }
+                    Cow::Owned(data)
+                }
+                (_, data) => data.unwrap_or_default(),
+            };
+            self.current = Some((index, data));
+        }
+        Ok(self.current.as_ref().map_or(&[], |(_, data)| data))
+    }
+}
+
+impl<R> Read for StreamReader<'_, R>
+where
+    R: Fn(&ContentReference) -> Result<Vec<u8>>,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let position = self.position;
+        if buf.is_empty() || position >= self.size() {
+            return Ok(0);
+        }
+        // The last chunk starting at or before the position: empty chunks get skipped over
+        let index = self
+            .offsets
+            .partition_point(|&offset| offset <= position)
+            .saturating_sub(1);
+        let start = self.offsets.get(index).copied().unwrap_or_default();
+        let data = self.load(index)?;
+        let available = data
+            .get(usize::try_from(position - start).unwrap_or(usize::MAX)..)
+            .unwrap_or_default();
+        let n = available.len().min(buf.len());
+        buf.get_mut(..n)
+            .unwrap_or_default()
+            .copy_from_slice(available.get(..n).unwrap_or_default());
+        self.position += n as u64;
+        Ok(n)
+    }
+}
+
+impl<R> Seek for StreamReader<'_, R>
+where
+    R: Fn(&ContentReference) -> Result<Vec<u8>>,
+{
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(delta) => self.size().checked_add_signed(delta),
+            SeekFrom::Current(delta) => self.position.checked_add_signed(delta),
+        };
+        self.position = position.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Seek to a negative or overflowing position",
+            )
+        })?;
+        Ok(self.position)
+    }
+}
+
+pub(crate) const fn chunk_size(chunk: &Chunk) -> u64 {
+    match chunk {
+        Chunk::Inline(data) => data.len() as u64,
+        Chunk::InlineCompressed { size, .. } => *size as u64,
+        Chunk::External(reference) => reference.size,
+        Chunk::Unavailable { size, .. } => *size,
+    }
+}
+
+#[cfg(feature = "tokio")]
+/// An [`AsyncRead`] over the reconstructed content of a [`Stream`], as returned by
+/// [`Stream::into_async_read()`].
+///
+/// Chunks are resolved one at a time, when the reader gets to them, so only one chunk needs to be
+/// held in memory.  Errors are reported as [`io::Error`]s wrapping an [`Error`].
+pub struct AsyncStreamReader<R, F> {
+    stream: Stream,
+    resolve_reference: R,
+    next: usize,
+    // The digest and size of the reference being resolved
+    pending: Option<(Arc<str>, u64, Pin<Box<F>>)>,
+    buffer: Vec<u8>,
+    offset: usize,
+}
+
+#[cfg(feature = "tokio")]
+impl<R, F> fmt::Debug for AsyncStreamReader<R, F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AsyncStreamReader")
+            .field("next", &self.next)
+            .field("pending", &self.pending.as_ref().map(|(digest, ..)| digest))
+            .field("buffered", &(self.buffer.len() - self.offset))
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl<R, F> AsyncStreamReader<R, F>
+where
+    R: Fn(&ContentReference) -> F,
+    F: Future<Output = Result<Vec<u8>>>,
+{
+    pub(crate) const fn new(stream: Stream, resolve_reference: R) -> Self {
+        Self {
+            stream,
+            resolve_reference,
+            next: 0,
+            pending: None,
+            buffer: vec![],
+            offset: 0,
+        }
+    }
+
+    /// Returns the stream that's being read.
+    #[must_use]
+    pub const fn stream(&self) -> &Stream {
+        &self.stream
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl<R, F> AsyncRead for AsyncStreamReader<R, F>
+where
+    R: Fn(&ContentReference) -> F + Unpin,
+    F: Future<Output = Result<Vec<u8>>>,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if let Some(data) = this.buffer.get(this.offset..)
+                && !data.is_empty()
+            {
+                let n = data.len().min(buf.remaining());
+                buf.put_slice(data.get(..n).unwrap_or_default());
+                this.offset += n;
+                return Poll::Ready(Ok(()));
+            }
+
+            if let Some((digest, size, future)) = &mut this.pending {
+                let result = ready!(future.as_mut().poll(cx));
+                let (digest, size) = (Arc::clone(digest), *size);
+                this.pending = None;
+                let data = result.map_err(|err| {
+                    io::Error::other(Error::Resolver {
+                        digest: Arc::clone(&digest),
+                        source: err.into(),
+                    })
+                })?;
+                // As for StreamReader, the content would be the wrong size otherwise
+                if data.len() as u64 != size {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        Error::Verification {
+                            digest,
+                            source: format!("Expected {size} bytes but got {}", data.len()).into(),
+                        },
+                    )));
+                }
+                this.buffer = data;
+                this.offset = 0;
+                continue;
+            }
+
+            let Some(chunk) = this.stream.chunks.get(this.next) else {
+                // End of the stream
+                return Poll::Ready(Ok(()));
+            };
+            this.next += 1;
+            match (chunk, inline_data(chunk).map_err(io::Error::other)?) {
+                (Chunk::External(reference), None) => {
+                    let future = Box::pin((this.resolve_reference)(reference));
+                    this.pending = Some((Arc::clone(&reference.digest), reference.size, future));
+                }
+                (_, data) => {
+                    this.buffer = data.unwrap_or_default().into_owned();
+                    this.offset = 0;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,86 @@
+//! Producing anonymized copies of the metadata, for sharing in bug reports.
+
+use anyhow::{Context, Result};
+use base64::{Engine, engine::general_purpose::STANDARD as b64};
+use serde_json::{Map, Value};
+
+use crate::digest;
+
+// Names get hashed in the same way in both files, so the redacted copies are still consistent
+// with each other.
+fn hash_name(name: &str) -> Value {
+    Value::String(digest::sha256(name.as_bytes()))
+}
+
+fn redact_manifest_entry(entry: &mut Map<String, Value>) {
+    for key in ["name", "linkName"] {
+        if let Some(Value::String(name)) = entry.get(key) {
+            let hashed = hash_name(name);
+            entry.insert(key.to_owned(), hashed);
+        }
+    }
+    entry.remove("xattrs");
+    entry.remove("uname");
+    entry.remove("gname");
+}
+
+fn redact_tarsplit_entry(entry: &mut Map<String, Value>) -> Result<()> {
+    if let Some(Value::String(name)) = entry.get("name") {
+        let hashed = hash_name(name);
+        entry.insert("name".to_owned(), hashed);
+    }
+    if let Some(Value::String(raw)) = entry.remove("name_raw") {
+        let raw = b64.decode(raw)?;
+        entry.insert("name".to_owned(), hash_name(&String::from_utf8_lossy(&raw)));
+    }
+
+    // The payload of segments contains the tar headers (including names).  Replace it with zeros,
+    // keeping the length.  The payload of file entries is just a crc64 of the content: keep it.
+    if entry.get("type") == Some(&Value::from(crate::format::TARSPLIT_SEGMENT_TYPE))
+        && let Some(Value::String(payload)) = entry.get("payload")
+    {
+        let zeros = vec![0; b64.decode(payload)?.len()];
+        entry.insert("payload".to_owned(), Value::String(b64.encode(zeros)));
+    }
+    Ok(())
+}
+
+/// Produces anonymized copies of the (compressed) manifest and tarsplit, suitable for sharing.
+///
+/// Filenames and link targets are replaced with their digests, xattrs and user/group names are
+/// removed, and the inline tar data is replaced with zeros.  All offsets, sizes and digests are
+/// preserved, and the result is still valid zstd:chunked metadata which can be parsed with
+/// [`Stream::new_from_frames()`](crate::Stream::new_from_frames).
+///
+/// Note that hashing names is not a strong form of anonymization: it's easy to check if a given
+/// well-known file (like `etc/os-release`) is present, and the file contents can be checked
+/// against a known set of digests in the same way.
+///
+/// # Errors
+///
+/// Fails if the metadata can't be decompressed or isn't in the expected JSON format.
+pub fn redact(manifest: &[u8], tarsplit: &[u8]) -> Result<(Vec<u8>, Vec<u8>)> {
+    let mut manifest: Value = serde_json::from_slice(&zstd::decode_all(manifest)?)?;
+    let entries = manifest
+        .get_mut("entries")
+        .and_then(Value::as_array_mut)
+        .context("zstd:chunked manifest has no entries")?;
+    for entry in entries {
+        if let Some(entry) = entry.as_object_mut() {
+            redact_manifest_entry(entry);
+        }
+    }
+
+    let mut redacted_tarsplit = vec![];
+    for line in String::from_utf8(zstd::decode_all(tarsplit)?)?.lines() {
+        let mut entry: Map<String, Value> = serde_json::from_str(line)?;
+        redact_tarsplit_entry(&mut entry)?;
+        serde_json::to_writer(&mut redacted_tarsplit, &entry)?;
+        redacted_tarsplit.push(b'\n');
+    }
+
+    Ok((
+        zstd::encode_all(&serde_json::to_vec(&manifest)?[..], 0)?,
+        zstd::encode_all(&redacted_tarsplit[..], 0)?,
+    ))
+}
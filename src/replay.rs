@@ -0,0 +1,230 @@
+//! Recording the responses a pull depends on, so that it can be repeated offline.
+//!
+//! Reconstruction bugs seen in the field are often hard to reproduce: they may depend on exactly
+//! which ranges were requested, or on a registry briefly serving bad data.  A [`Recorder`] saves
+//! every response that a puller receives into a replay bundle, and [`Replay`] serves them back in
+//! the same order, so that the pull can be re-run exactly without access to the registry.
+//!
+//! A bundle is a directory containing `responses.jsonl`, which lists the requests (by resource and
+//! byte range) along with the digest of each response, and a `bodies` directory holding the
+//! responses themselves, named by digest.  Responses are appended as they arrive, so a bundle
+//! recorded by a pull that crashed is still usable.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fs::{self, File, OpenOptions},
+    io::Write,
+    ops::Range,
+    path::{Path, PathBuf},
+    process,
+    sync::{
+        Mutex, PoisonError,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use anyhow::{Context, Result, ensure};
+use serde::{Deserialize, Serialize};
+
+use crate::digest::{check_digest, sha256};
+
+const REPLAY_VERSION: u32 = 1;
+const RESPONSES: &str = "responses.jsonl";
+const BODIES: &str = "bodies";
+
+/// A recorded response, as listed in the bundle.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Response {
+    /// What was requested: the digest of a blob, or the reference of a manifest.
+    pub resource: String,
+
+    /// The byte range that was requested, or `None` for the whole resource.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub range: Option<Range<u64>>,
+
+    /// The digest of the response body.
+    pub digest: String,
+
+    /// The size of the response body.
+    pub size: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Header {
+    version: u32,
+}
+
+type Key = (String, Option<Range<u64>>);
+
+fn body_path(dir: &Path, digest: &str) -> Result<PathBuf> {
+    check_digest(digest)?;
+    Ok(dir.join(BODIES).join(digest.trim_start_matches("sha256:")))
+}
+
+/// Records responses into a new replay bundle.  It can be shared between threads.
+#[derive(Debug)]
+pub struct Recorder {
+    dir: PathBuf,
+    responses: Mutex<File>,
+}
+
+impl Recorder {
+    /// Creates a new bundle in the given directory, which may exist but mustn't already contain a
+    /// bundle.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the bundle couldn't be created.
+    pub fn create(dir: impl Into<PathBuf>) -> Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(dir.join(BODIES))?;
+        let path = dir.join(RESPONSES);
+        let mut responses = OpenOptions::new()
+            .append(true)
+            .create_new(true)
+            .open(&path)
+            .with_context(|| format!("Unable to create replay bundle {}", path.display()))?;
+        let mut header = serde_json::to_vec(&Header {
+            version: REPLAY_VERSION,
+        })?;
+        header.push(b'\n');
+        responses.write_all(&header)?;
+
+        Ok(Self {
+            dir,
+            responses: Mutex::new(responses),
+        })
+    }
+
+    /// Records the body received for a resource (or a range of it).
+    ///
+    /// # Errors
+    ///
+    /// Fails if the bundle couldn't be written.
+    pub fn record(
+        &self,
+        resource: &str,
+        range: Option<Range<u64>>,
+        body: &[u8],
+    ) -> Result<Response> {
+        let response = Response {
+            resource: resource.to_owned(),
+            range,
+            digest: sha256(body),
+            size: body.len() as u64,
+        };
+
+        // Bodies are named by their digest, so one that's already there doesn't need writing again
+        let path = body_path(&self.dir, &response.digest)?;
+        if !path.exists() {
+            // Threads recording the same body at once need different temporary files
+            static NEXT: AtomicU64 = AtomicU64::new(0);
+            let mut tmp = path.clone().into_os_string();
+            tmp.push(format!(
+                ".{}.{}.tmp",
+                process::id(),
+                NEXT.fetch_add(1, Ordering::Relaxed)
+            ));
+            let tmp = Path::new(&tmp);
+            fs::write(tmp, body)?;
+            if let Err(err) = fs::rename(tmp, &path) {
+                let _ = fs::remove_file(tmp);
+                Err(err)?;
+            }
+        }
+
+        // Each line goes out in a single write, so that lines from different threads don't mix
+        let mut line = serde_json::to_vec(&response)?;
+        line.push(b'\n');
+        self.responses
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .write_all(&line)?;
+
+        Ok(response)
+    }
+}
+
+/// Serves the responses of a replay bundle.  It can be shared between threads.
+///
+/// When the same request was recorded more than once, the responses are returned in the order
+/// they were recorded, and the last one is repeated if the request is made more often.
+#[derive(Debug)]
+pub struct Replay {
+    dir: PathBuf,
+    responses: Mutex<HashMap<Key, VecDeque<Response>>>,
+}
+
+impl Replay {
+    /// Opens the bundle in the given directory.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the bundle can't be read or parsed.
+    pub fn open(dir: impl Into<PathBuf>) -> Result<Self> {
+        let dir = dir.into();
+        let path = dir.join(RESPONSES);
+        let data = fs::read_to_string(&path)
+            .with_context(|| format!("Unable to read replay bundle {}", path.display()))?;
+        let mut lines = data.lines();
+
+        let header: Header = serde_json::from_str(lines.next().unwrap_or_default())
+            .with_context(|| format!("Unable to parse replay bundle {}", path.display()))?;
+        ensure!(
+            header.version == REPLAY_VERSION,
+            "Unsupported replay bundle version {}",
+            header.version
+        );
+
+        let mut responses: HashMap<_, VecDeque<_>> = HashMap::new();
+        for line in lines {
+            // A line cut short by a crash can only be the last one
+            let Ok(response) = serde_json::from_str::<Response>(line) else {
+                break;
+            };
+            check_digest(&response.digest)?;
+            responses
+                .entry((response.resource.clone(), response.range.clone()))
+                .or_default()
+                .push_back(response);
+        }
+
+        Ok(Self {
+            dir,
+            responses: Mutex::new(responses),
+        })
+    }
+
+    /// Returns the next recorded body for a resource (or a range of it), or `None` if the request
+    /// was never recorded.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the body is missing from the bundle or doesn't match its recorded digest.
+    pub fn get(&self, resource: &str, range: Option<Range<u64>>) -> Result<Option<Vec<u8>>> {
+        let response = self
+            .responses
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get_mut(&(resource.to_owned(), range))
+            .and_then(|queue| {
+                if queue.len() > 1 {
+                    queue.pop_front()
+                } else {
+                    queue.front().cloned()
+                }
+            });
+        let Some(response) = response else {
+            return Ok(None);
+        };
+
+        let body = fs::read(body_path(&self.dir, &response.digest)?)
+            .with_context(|| format!("Replay bundle is missing {}", response.digest))?;
+        ensure!(
+            body.len() as u64 == response.size && sha256(&body) == response.digest,
+            "Replay bundle has corrupt body {}",
+            response.digest
+        );
+        Ok(Some(body))
+    }
+}
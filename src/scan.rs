@@ -0,0 +1,131 @@
+//! Surveying a directory of blobs, like the storage of a registry.
+//!
+//! [`scan_blob_dir()`] looks at every file under a directory (in parallel) and works out which
+//! ones are zstd:chunked, reading only their footers and metadata, for maintenance tools that need
+//! to know what thousands of blobs contain without reading them all.
+//!
+//! ```no_run
+//! # use zstd_chunked::scan::{BlobKind, scan_blob_dir};
+//! # fn example() -> anyhow::Result<()> {
+//! for blob in scan_blob_dir("/var/lib/registry/docker/registry/v2/blobs")? {
+//!     if let BlobKind::ZstdChunked { stats, .. } = &blob.kind {
+//!         println!("{}: {} bytes of content", blob.path.display(), stats.content_size);
+//!     }
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{
+    fs::{self, File},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result, anyhow};
+
+use crate::{
+    MetadataReferences, Stream, blob::BlobReader, frame::ZSTD_MAGIC, parallel, stats::LayerStats,
+};
+
+/// What a blob turned out to be.
+#[derive(Debug)]
+pub enum BlobKind {
+    /// Not zstd at all.
+    Other,
+
+    /// A zstd stream without a zstd:chunked footer.
+    Zstd,
+
+    /// A zstd:chunked blob, whose metadata was parsed successfully.
+    ZstdChunked {
+        /// The metadata references from the footer.
+        references: MetadataReferences,
+        /// The statistics computed from the metadata.
+        stats: Box<LayerStats>,
+    },
+
+    /// The blob couldn't be read, or it has a zstd:chunked footer but its metadata is invalid
+    /// (or uses a manifest type that this crate doesn't understand).
+    Failed(anyhow::Error),
+}
+
+/// The summary of one blob found by [`scan_blob_dir()`].
+#[derive(Debug)]
+pub struct BlobSummary {
+    /// The path of the blob.
+    pub path: PathBuf,
+
+    /// The size of the blob, in bytes.
+    pub size: u64,
+
+    /// What the blob turned out to be.
+    pub kind: BlobKind,
+}
+
+// Lists the regular files under a directory, without following symlinks
+fn list_files(dir: &Path, files: &mut Vec<(PathBuf, u64)>) -> Result<()> {
+    let entries = fs::read_dir(dir).with_context(|| format!("Failed to list {}", dir.display()))?;
+    for entry in entries {
+        let entry = entry.with_context(|| format!("Failed to list {}", dir.display()))?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            list_files(&entry.path(), files)?;
+        } else if file_type.is_file() {
+            files.push((entry.path(), entry.metadata()?.len()));
+        }
+    }
+    Ok(())
+}
+
+fn inspect(path: &Path) -> Result<BlobKind> {
+    let file = File::open(path)?;
+    if file.size()? < ZSTD_MAGIC.len() as u64 || file.read_at(&(0..4))? != ZSTD_MAGIC {
+        return Ok(BlobKind::Other);
+    }
+    let Some(references) = MetadataReferences::from_blob(&file)? else {
+        return Ok(BlobKind::Zstd);
+    };
+    // Parsing the stream checks that the manifest and tarsplit agree
+    let stream = Stream::from_blob(&file, &references)?;
+    let metadata_size = [&references.manifest, &references.tarsplit]
+        .map(|reference| reference.range.end.saturating_sub(reference.range.start))
+        .iter()
+        .sum();
+    let stats = LayerStats::from_stream(&stream, metadata_size, Some(file.size()?));
+    Ok(BlobKind::ZstdChunked {
+        references,
+        stats: Box::new(stats),
+    })
+}
+
+/// Finds all of the files under a directory (recursively, without following symlinks) and
+/// summarizes each one, using one thread per available CPU.
+///
+/// Only the start, the footer and the metadata of each blob are read.  Returns the summaries
+/// sorted by path.  Blobs that can't be read or parsed are reported as [`BlobKind::Failed`].
+///
+/// # Errors
+///
+/// Fails if the directory (or one under it) can't be listed.
+pub fn scan_blob_dir(path: impl AsRef<Path>) -> Result<Vec<BlobSummary>> {
+    let mut files = vec![];
+    list_files(path.as_ref(), &mut files)?;
+    files.sort_unstable();
+
+    let kinds = parallel::map(&files, |(path, _)| {
+        inspect(path)
+            .with_context(|| format!("Failed to inspect {}", path.display()))
+            .unwrap_or_else(BlobKind::Failed)
+    });
+
+    // Anything left over belonged to a thread that panicked
+    Ok(files
+        .into_iter()
+        .zip(kinds)
+        .map(|((path, size), kind)| BlobSummary {
+            path,
+            size,
+            kind: kind.unwrap_or_else(|| BlobKind::Failed(anyhow!("Scanning thread panicked"))),
+        })
+        .collect())
+}
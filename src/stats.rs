@@ -0,0 +1,234 @@
+//! Aggregate statistics about a layer, for capacity planning.
+
+use core::ops::Range;
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, HashSet},
+};
+
+use anyhow::Result;
+
+use crate::{Manifest, ParseOptions, Stream, entry_content};
+
+/// The number of entries reported in [`LayerStats::largest_files`].
+pub const LARGEST_FILES: usize = 10;
+
+/// Statistics about a zstd:chunked layer, computed from its metadata.
+#[derive(Debug, Clone, Default)]
+pub struct LayerStats {
+    /// The number of entries of each type in the manifest (`"reg"`, `"dir"`, `"symlink"`, ...),
+    /// not counting `"chunk"` entries.
+    pub entries_by_type: BTreeMap<String, u64>,
+
+    /// A histogram of the number of chunks per regular file with content: maps a number of chunks
+    /// to the number of files that have that many chunks.  Unchunked files count as one chunk.
+    pub chunks_per_file: BTreeMap<u64, u64>,
+
+    /// The names and (uncompressed) sizes of the largest regular files, largest first.
+    pub largest_files: Vec<(String, u64)>,
+
+    /// The total uncompressed size of all regular files.
+    pub content_size: u64,
+
+    /// The total compressed size of the content ranges, counting content which appears more than
+    /// once only once.  This is the amount of data a pull with an empty cache would download.
+    pub unique_compressed_size: u64,
+
+    /// The compressed size of the manifest and tarsplit.
+    pub metadata_size: u64,
+
+    /// The size of the complete layer, if it was provided.
+    pub layer_size: Option<u64>,
+
+    /// An estimate of the number of range requests required to fetch all of the content with an
+    /// empty cache: the number of unique ranges, after merging ranges that are adjacent.
+    pub range_requests: u64,
+}
+
+impl LayerStats {
+    /// Computes the statistics from the compressed manifest and tarsplit frames.  The size of the
+    /// layer is optional, and only used to compute the metadata overhead.
+    ///
+    /// # Errors
+    ///
+    /// Fails as for [`Manifest::from_frame()`].
+    pub fn new(manifest: &[u8], tarsplit: &[u8], layer_size: Option<u64>) -> Result<Self> {
+        Self::new_with_options(manifest, tarsplit, layer_size, &ParseOptions::default())
+    }
+
+    /// Like [`Self::new()`], but with a memory budget (and only that) taken from the options.
+    ///
+    /// # Errors
+    ///
+    /// Fails as for [`Manifest::from_frame()`].
+    pub fn new_with_options(
+        manifest: &[u8],
+        tarsplit: &[u8],
+        layer_size: Option<u64>,
+        options: &ParseOptions,
+    ) -> Result<Self> {
+        let metadata_size = (manifest.len() + tarsplit.len()) as u64;
+        let manifest = Manifest::from_frame(manifest, options)?;
+
+        let mut stats = Self {
+            metadata_size,
+            layer_size,
+            ..Self::default()
+        };
+
+        let mut files = vec![];
+        let mut chunk_counts = vec![];
+        let mut seen = HashSet::new();
+        let mut ranges = vec![];
+
+        for entry in &manifest.entries {
+            // Every chunk of a file has a range of its own
+            if let Some((digest, _, range)) = entry_content(entry)
+                && seen.insert(digest)
+            {
+                ranges.push(range);
+            }
+
+            if entry.kind == "chunk" {
+                if let Some(count) = chunk_counts.last_mut() {
+                    *count += 1;
+                }
+                continue;
+            }
+
+            *stats.entries_by_type.entry(entry.kind.clone()).or_default() += 1;
+
+            if let (Some(size), Some(_), Some(_), Some(_)) =
+                (entry.size, &entry.digest, entry.offset, entry.end_offset)
+            {
+                stats.content_size += size;
+                files.push((entry.name.clone(), size));
+                chunk_counts.push(1);
+            }
+        }
+
+        stats.summarize(files, chunk_counts, ranges);
+        Ok(stats)
+    }
+
+    /// Computes the statistics from an already parsed stream, given the compressed size of its
+    /// manifest and tarsplit, without parsing the manifest a second time.
+    ///
+    /// This gives the same result as [`Self::new()`], except that the chunks of files are counted
+    /// from the stream: manifest entries that the tarsplit doesn't use aren't included.
+    #[must_use]
+    pub fn from_stream(stream: &Stream, metadata_size: u64, layer_size: Option<u64>) -> Self {
+        let mut stats = Self {
+            metadata_size,
+            layer_size,
+            ..Self::default()
+        };
+
+        let mut files = vec![];
+        // The stream doesn't keep the "chunk" entries, and has already checked the ranges
+        for entry in stream.entries() {
+            *stats.entries_by_type.entry(entry.kind.clone()).or_default() += 1;
+
+            if let (Some(size), Some(_), Some(_), Some(_)) =
+                (entry.size, &entry.digest, entry.offset, entry.end_offset)
+            {
+                stats.content_size += size;
+                files.push((entry.name.clone(), size));
+            }
+        }
+
+        let ranges = (stream.unique_references())
+            .map(|reference| reference.range.clone())
+            .collect();
+        let chunk_counts = stream.files.iter().map(|file| file.chunks.len() as u64);
+        stats.summarize(files, chunk_counts, ranges);
+        stats
+    }
+
+    // Fills in the histogram, the largest files and the ranges from what was collected
+    fn summarize(
+        &mut self,
+        mut files: Vec<(String, u64)>,
+        chunk_counts: impl IntoIterator<Item = u64>,
+        mut ranges: Vec<Range<u64>>,
+    ) {
+        for count in chunk_counts {
+            *self.chunks_per_file.entry(count).or_default() += 1;
+        }
+
+        files.sort_by_key(|(_, size)| Reverse(*size));
+        files.truncate(LARGEST_FILES);
+        self.largest_files = files;
+
+        ranges.sort_by_key(|range| range.start);
+        let mut last_end = None;
+        for range in ranges {
+            self.unique_compressed_size += range.end - range.start;
+            if last_end != Some(range.start) {
+                self.range_requests += 1;
+            }
+            last_end = Some(range.end);
+        }
+    }
+
+    /// The size of the metadata as a percentage of the size of the layer, if the size of the layer
+    /// is known.
+    #[must_use]
+    #[allow(clippy::cast_precision_loss)]
+    pub fn metadata_overhead(&self) -> Option<f64> {
+        let layer_size = self.layer_size.filter(|&size| size > 0)?;
+        Some(100. * self.metadata_size as f64 / layer_size as f64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::digest;
+
+    #[test]
+    fn stats_from_stream_agree_with_manifest() -> Result<()> {
+        let (first, second, small) = (
+            digest::sha256(b"first"),
+            digest::sha256(b"second"),
+            digest::sha256(b"small"),
+        );
+        let whole = digest::sha256(b"whole");
+        // A directory, a file in two chunks and a small file
+        let manifest = format!(
+            r#"{{"version":1,"entries":[
+                {{"type":"dir","name":"dir"}},
+                {{"type":"reg","name":"big","size":20,"digest":"{whole}","offset":0,
+                  "endOffset":5,"chunkSize":10,"chunkDigest":"{first}"}},
+                {{"type":"chunk","name":"big","offset":5,"endOffset":9,"chunkSize":10,
+                  "chunkOffset":10,"chunkDigest":"{second}"}},
+                {{"type":"reg","name":"small","size":10,"digest":"{small}","offset":20,
+                  "endOffset":25}}
+            ]}}"#
+        );
+        let tarsplit = concat!(
+            r#"{"type":1,"name":"dir"}"#,
+            "\n",
+            r#"{"type":1,"name":"big","size":20}"#,
+            "\n",
+            r#"{"type":1,"name":"small","size":10}"#,
+            "\n",
+        );
+        let manifest = zstd::encode_all(manifest.as_bytes(), 0)?;
+        let tarsplit = zstd::encode_all(tarsplit.as_bytes(), 0)?;
+
+        let from_frames = LayerStats::new(&manifest, &tarsplit, Some(100))?;
+        let stream = Stream::new_from_frames(&manifest, &tarsplit)?;
+        let metadata_size = (manifest.len() + tarsplit.len()) as u64;
+        let from_stream = LayerStats::from_stream(&stream, metadata_size, Some(100));
+
+        assert_eq!(from_frames.unique_compressed_size, 14);
+        assert_eq!(from_frames.range_requests, 2);
+        assert_eq!(
+            from_frames.chunks_per_file,
+            BTreeMap::from([(1, 1), (2, 1)])
+        );
+        assert_eq!(format!("{from_frames:?}"), format!("{from_stream:?}"));
+        Ok(())
+    }
+}
@@ -0,0 +1,808 @@
+//! Stores for chunk data, addressed by digest.
+//!
+//! [`ChunkStore`] is a simple on-disk store, and [`MemoryStore`] keeps everything in memory.  Code
+//! that only needs to read and write objects works with any [`Backend`].  With the `encryption`
+//! feature, `EncryptedStore` encrypts the objects in any other backend.
+
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{self, ErrorKind, Write},
+    path::{Path, PathBuf},
+    process,
+    sync::{
+        Arc, PoisonError, RwLock, RwLockReadGuard,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use anyhow::{Result, ensure};
+
+use crate::digest::{self, check_digest};
+#[cfg(feature = "encryption")]
+use core::fmt;
+#[cfg(all(feature = "fs-verity", target_os = "linux"))]
+use {
+    anyhow::Context,
+    composefs_ioctls::fsverity::{
+        EnableVerityError, MeasureVerityError, fs_ioc_enable_verity, fs_ioc_measure_verity,
+    },
+};
+
+#[cfg(unix)]
+use crate::{digest::to_hex, fsverity};
+
+/// How objects are laid out inside of a [`ChunkStore`] directory.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Layout {
+    /// Each object is stored directly in the store directory, named by its digest (for example
+    /// `sha256:0123...`).
+    #[default]
+    Flat,
+
+    /// Objects are stored in `sha256/xx/yyyy...`, where `xx` is the first two hex digits of the
+    /// digest.  This keeps directories at a reasonable size for stores with millions of objects.
+    Sharded,
+
+    /// Objects are stored in the same `objects/xx/yyyy...` fan-out that composefs uses, named by
+    /// their fs-verity digest.  A `chunks/sha256:0123...` symlink points at the object for each
+    /// content digest.  This allows the same directory to be used as a composefs repository.
+    #[cfg(unix)]
+    Composefs,
+}
+
+/// How hard a [`ChunkStore`] tries to make sure that writes survive a crash or power loss.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Durability {
+    /// Never sync.  Objects are still renamed into place once complete, so concurrent readers
+    /// never see partial data, but after a power loss an object may exist with truncated content.
+    /// This is only suitable if reads are verified (see [`ChunkStore::with_verify_reads()`]).
+    None,
+
+    /// Sync the content of each object before renaming it into place, so that after a crash it
+    /// either exists with the correct content or doesn't exist at all.
+    #[default]
+    Data,
+
+    /// Like [`Durability::Data`], and also sync the directory after renaming, so that objects
+    /// which were successfully inserted are still there after a crash.
+    Full,
+}
+
+/// The result of [`ChunkStore::ingest_tree()`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct IngestReport {
+    /// The number of regular files that were found.
+    pub files: u64,
+
+    /// The number of files whose content wasn't already in the store.
+    pub added: u64,
+
+    /// The total size of the added files.
+    pub added_bytes: u64,
+}
+
+/// A directory of objects, each named by the digest of its (uncompressed) content.
+#[derive(Debug, Clone)]
+pub struct ChunkStore {
+    root: PathBuf,
+    layout: Layout,
+    verify_reads: bool,
+    durability: Durability,
+    #[cfg(all(feature = "fs-verity", target_os = "linux"))]
+    fsverity: bool,
+}
+
+/// The error returned by [`ChunkStore::get()`] (and other [`Backend`]s) when an object doesn't
+/// match its digest.
+///
+/// This means that it was damaged on disk (or written incorrectly).  The object is removed from
+/// the store, so it can be fetched again.
+#[derive(Debug, Clone, thiserror::Error)]
+#[error("Stored object {digest} is corrupt (its content has digest {actual})")]
+pub struct CorruptObject {
+    /// The digest that the object was stored under.
+    pub digest: String,
+    /// The digest of the data that was actually found.
+    pub actual: String,
+}
+
+// Makes the names of temporary files unique within the process.
+static TMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+// Refs are stored as files in a single directory, so only allow simple names.
+fn check_ref_name(name: &str) -> Result<()> {
+    ensure!(
+        !name.is_empty() && !name.starts_with('.') && !name.contains(['/', '\\', '\0']),
+        "Invalid ref name {name:?}"
+    );
+    Ok(())
+}
+
+// SHA-256 with 4096 byte blocks, matching crate::fsverity::digest().
+#[cfg(all(feature = "fs-verity", target_os = "linux"))]
+const FSVERITY_SHA256: u8 = 1;
+#[cfg(all(feature = "fs-verity", target_os = "linux"))]
+const FSVERITY_BLOCK_SIZE: u32 = 4096;
+
+// Enables fs-verity on the file and returns the digest that the kernel measured.
+#[cfg(all(feature = "fs-verity", target_os = "linux"))]
+fn enable_fsverity(path: &Path) -> Result<String> {
+    let file = File::open(path)?;
+    match fs_ioc_enable_verity(&file, FSVERITY_SHA256, FSVERITY_BLOCK_SIZE) {
+        Ok(()) | Err(EnableVerityError::AlreadyEnabled) => {}
+        Err(err) => {
+            return Err(err)
+                .with_context(|| format!("Unable to enable fs-verity on {}", path.display()));
+        }
+    }
+    let measured = fs_ioc_measure_verity::<32>(&file, FSVERITY_SHA256)?;
+    Ok(to_hex(&measured))
+}
+
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(err) if err.kind() != ErrorKind::NotFound => Err(err),
+        _ => Ok(()),
+    }
+}
+
+impl ChunkStore {
+    /// Creates a store with the default ([`Layout::Flat`]) layout.  The directory will be created
+    /// when the first object is inserted.
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self::with_layout(root, Layout::default())
+    }
+
+    /// Creates a store with the given layout.
+    pub fn with_layout(root: impl Into<PathBuf>, layout: Layout) -> Self {
+        Self {
+            root: root.into(),
+            layout,
+            verify_reads: true,
+            durability: Durability::default(),
+            #[cfg(all(feature = "fs-verity", target_os = "linux"))]
+            fsverity: false,
+        }
+    }
+
+    /// Sets whether [`Self::get()`] checks each object against its digest, which is the default.
+    /// Turning this off saves hashing the data on every read, for callers that verify it
+    /// themselves (or that trust the storage, for example because of fs-verity).
+    #[must_use]
+    pub const fn with_verify_reads(mut self, verify: bool) -> Self {
+        self.verify_reads = verify;
+        self
+    }
+
+    /// Sets the durability policy for writes.  The default is [`Durability::Data`].
+    #[must_use]
+    pub const fn with_durability(mut self, durability: Durability) -> Self {
+        self.durability = durability;
+        self
+    }
+
+    /// Enables fs-verity on each object after it's written, making the stored data immutable, as
+    /// enforced by the kernel.  Inserting will fail if the filesystem doesn't support fs-verity.
+    #[cfg(all(feature = "fs-verity", target_os = "linux"))]
+    #[must_use]
+    pub const fn with_fsverity(mut self, enable: bool) -> Self {
+        self.fsverity = enable;
+        self
+    }
+
+    /// Returns the fs-verity digest of the object with the given digest (as a hex string), as
+    /// measured by the kernel.  Returns None if fs-verity isn't enabled on the object.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the digest is malformed, if the object doesn't exist, or if there was an I/O
+    /// error.
+    #[cfg(all(feature = "fs-verity", target_os = "linux"))]
+    pub fn fsverity_digest(&self, digest: &str) -> Result<Option<String>> {
+        let file = File::open(self.path(digest)?)?;
+        match fs_ioc_measure_verity::<32>(&file, FSVERITY_SHA256) {
+            Ok(measured) => Ok(Some(to_hex(&measured))),
+            Err(MeasureVerityError::VerityMissing | MeasureVerityError::FilesystemNotSupported) => {
+                Ok(None)
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// The directory that the store lives in.
+    #[must_use]
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// The layout of the store.
+    #[must_use]
+    pub const fn layout(&self) -> Layout {
+        self.layout
+    }
+
+    /// Returns the path at which the object with the given digest can be read, if it exists.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the digest isn't a well-formed `sha256:` digest.
+    pub fn path(&self, digest: &str) -> Result<PathBuf> {
+        check_digest(digest)?;
+        Ok(match self.layout {
+            Layout::Flat => self.root.join(digest),
+            Layout::Sharded => {
+                let hex = &digest["sha256:".len()..];
+                let (fanout, rest) = hex.split_at(2);
+                self.root.join("sha256").join(fanout).join(rest)
+            }
+            #[cfg(unix)]
+            Layout::Composefs => self.root.join("chunks").join(digest),
+        })
+    }
+
+    /// Checks if the object with the given digest is present in the store.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the digest is malformed or if there was an I/O error.
+    pub fn contains(&self, digest: &str) -> Result<bool> {
+        Ok(fs::exists(self.path(digest)?)?)
+    }
+
+    /// Reads the object with the given digest, or returns None if it isn't present.  Unless
+    /// disabled with [`Self::with_verify_reads()`], the data is checked against the digest.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the digest is malformed or if there was an I/O error, or with [`CorruptObject`]
+    /// (after removing the object, so that it can be fetched and inserted again) if the data
+    /// doesn't match the digest.
+    pub fn get(&self, digest: &str) -> Result<Option<Vec<u8>>> {
+        let path = self.path(digest)?;
+        let data = match fs::read(&path) {
+            Ok(data) => data,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => Err(err)?,
+        };
+        if self.verify_reads {
+            let actual = digest::sha256(&data);
+            if actual != digest {
+                // The symlink is only a name: the object itself has to go, or inserting the
+                // chunk again would just link back to the corrupt data
+                #[cfg(unix)]
+                if self.layout == Layout::Composefs
+                    && let Ok(target) = fs::read_link(&path)
+                    && let Some(chunks) = path.parent()
+                {
+                    remove_if_exists(&chunks.join(target))?;
+                }
+                remove_if_exists(&path)?;
+                Err(CorruptObject {
+                    digest: digest.to_owned(),
+                    actual,
+                })?;
+            }
+        }
+        Ok(Some(data))
+    }
+
+    // Writes the data to a new file in the `tmp` directory, to be renamed into place once it's
+    // complete.  This way, readers never see partially-written objects.
+    fn write_tmp(&self, data: &[u8]) -> Result<PathBuf> {
+        let dir = self.root.join("tmp");
+        fs::create_dir_all(&dir)?;
+        let n = TMP_COUNTER.fetch_add(1, Ordering::Relaxed);
+        let path = dir.join(format!("{}-{n}", process::id()));
+        let mut file = File::create(&path)?;
+        file.write_all(data)?;
+        if self.durability != Durability::None {
+            file.sync_all()?;
+        }
+        Ok(path)
+    }
+
+    // Syncs the directory containing the given path, so that a rename (or a new symlink) in it
+    // survives a crash, if the durability policy asks for that.
+    fn sync_parent(&self, path: &Path) -> Result<()> {
+        #[cfg(unix)]
+        if self.durability == Durability::Full
+            && let Some(parent) = path.parent()
+        {
+            File::open(parent)?.sync_all()?;
+        }
+        #[cfg(not(unix))]
+        let _ = path;
+        Ok(())
+    }
+
+    // Atomically moves a temporary file into place, removing it on failure.
+    fn commit_tmp(&self, tmp: &Path, path: &Path) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        if let Err(err) = fs::rename(tmp, path) {
+            let _ = fs::remove_file(tmp);
+            Err(err)?;
+        }
+        self.sync_parent(path)
+    }
+
+    /// Stores the given data under the given digest.  The data is not verified against the digest.
+    /// If fs-verity was requested, it's enabled on the object after it's written.
+    ///
+    /// The object is written to a temporary file and renamed into place, so concurrent readers
+    /// (and concurrent inserts of the same object) never see partial data.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the digest is malformed or if there was an I/O error.
+    pub fn insert(&self, digest: &str, data: &[u8]) -> Result<()> {
+        let path = self.path(digest)?;
+        match self.layout {
+            Layout::Flat | Layout::Sharded => {
+                let tmp = self.write_tmp(data)?;
+
+                #[cfg(all(feature = "fs-verity", target_os = "linux"))]
+                if self.fsverity {
+                    enable_fsverity(&tmp)?;
+                }
+
+                self.commit_tmp(&tmp, &path)?;
+            }
+            #[cfg(unix)]
+            Layout::Composefs => {
+                let verity = to_hex(&fsverity::digest(data));
+                let (fanout, rest) = verity.split_at(2);
+
+                let object = self.root.join("objects").join(fanout).join(rest);
+                if !fs::exists(&object)? {
+                    let tmp = self.write_tmp(data)?;
+
+                    #[cfg(all(feature = "fs-verity", target_os = "linux"))]
+                    if self.fsverity {
+                        let measured = enable_fsverity(&tmp)?;
+                        ensure!(measured == verity, "fs-verity digest mismatch on {verity}");
+                    }
+
+                    self.commit_tmp(&tmp, &object)?;
+                }
+
+                fs::create_dir_all(self.root.join("chunks"))?;
+                let target = Path::new("../objects").join(fanout).join(rest);
+                match std::os::unix::fs::symlink(target, &path) {
+                    Err(err) if err.kind() != ErrorKind::AlreadyExists => Err(err)?,
+                    _ => self.sync_parent(&path)?,
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Walks a directory tree (such as an already-extracted rootfs) and adds the content of each
+    /// regular file to the store, by digest.  Later pulls can then reuse any files that the
+    /// machine already has, even if they came from a different image.
+    ///
+    /// Symlinks are not followed, and special files are ignored.  File content is copied into the
+    /// store, so later changes to the tree don't affect it.
+    ///
+    /// # Errors
+    ///
+    /// Fails if there was an I/O error.
+    pub fn ingest_tree(&self, dir: impl AsRef<Path>) -> Result<IngestReport> {
+        let mut report = IngestReport::default();
+        let mut dirs = vec![dir.as_ref().to_path_buf()];
+        while let Some(dir) = dirs.pop() {
+            for entry in fs::read_dir(&dir)? {
+                let entry = entry?;
+                let file_type = entry.file_type()?;
+                if file_type.is_dir() {
+                    dirs.push(entry.path());
+                } else if file_type.is_file() {
+                    let data = fs::read(entry.path())?;
+                    let digest = digest::sha256(&data);
+                    report.files += 1;
+                    if !self.contains(&digest)? {
+                        self.insert(&digest, &data)?;
+                        report.added += 1;
+                        report.added_bytes += data.len() as u64;
+                    }
+                }
+            }
+        }
+        Ok(report)
+    }
+
+    /// Records the list of objects used by an image (or any other named collection of objects),
+    /// replacing any previous list with the same name.  Names can't contain `/` or start with `.`;
+    /// a manifest digest is a good choice.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the name or any of the digests are malformed or if there was an I/O error.
+    pub fn set_ref<'a>(
+        &self,
+        name: &str,
+        digests: impl IntoIterator<Item = &'a str>,
+    ) -> Result<()> {
+        check_ref_name(name)?;
+        let mut contents = String::new();
+        for digest in digests {
+            check_digest(digest)?;
+            contents.push_str(digest);
+            contents.push('\n');
+        }
+        let tmp = self.write_tmp(contents.as_bytes())?;
+        self.commit_tmp(&tmp, &self.root.join("refs").join(name))
+    }
+
+    /// Returns the list of objects recorded by [`ChunkStore::set_ref()`], or None if there's no
+    /// ref with the given name.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the name is malformed or if there was an I/O error.
+    pub fn get_ref(&self, name: &str) -> Result<Option<Vec<String>>> {
+        check_ref_name(name)?;
+        match fs::read_to_string(self.root.join("refs").join(name)) {
+            Ok(contents) => Ok(Some(contents.lines().map(str::to_owned).collect())),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Removes a ref.  The objects themselves are left in place.  Removing a ref that doesn't
+    /// exist is not an error.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the name is malformed or if there was an I/O error.
+    pub fn remove_ref(&self, name: &str) -> Result<()> {
+        check_ref_name(name)?;
+        match fs::remove_file(self.root.join("refs").join(name)) {
+            Err(err) if err.kind() != ErrorKind::NotFound => Err(err.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns the names of all refs in the store, in no particular order.
+    ///
+    /// # Errors
+    ///
+    /// Fails if there was an I/O error.
+    pub fn refs(&self) -> Result<Vec<String>> {
+        let entries = match fs::read_dir(self.root.join("refs")) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(vec![]),
+            Err(err) => Err(err)?,
+        };
+        let mut names = vec![];
+        for entry in entries {
+            if let Some(name) = entry?.file_name().to_str() {
+                names.push(name.to_owned());
+            }
+        }
+        Ok(names)
+    }
+}
+
+/// Storage for objects addressed by digest, as used by [`crate::plan`], [`crate::quota`] and
+/// pullers.
+///
+/// [`ChunkStore`] keeps objects in a directory and [`MemoryStore`] keeps them in memory.  Embedders
+/// with other storage (a database, an encrypted volume, flash that needs writes batched, ...) can
+/// implement it themselves.
+///
+/// Implementations can be shared between threads, and must be safe for concurrent inserts of the
+/// same object.  Objects are inserted under the digest of their (uncompressed) content.
+pub trait Backend: Send + Sync {
+    /// Checks if the object with the given digest is present.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the digest is malformed or if the storage fails.
+    fn contains(&self, digest: &str) -> Result<bool>;
+
+    /// Reads the object with the given digest, or returns None if it isn't present.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the digest is malformed or if the storage fails.  Backends that check what they
+    /// read should fail with [`CorruptObject`] (and forget the object) on a mismatch, so that
+    /// callers know to fetch it again.
+    fn get(&self, digest: &str) -> Result<Option<Vec<u8>>>;
+
+    /// Stores the given data under the given digest.  The data is not verified against the
+    /// digest.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the digest is malformed or if the storage fails.
+    fn insert(&self, digest: &str, data: &[u8]) -> Result<()>;
+
+    /// Whether [`Self::get()`] checks objects against their digests, so that it can't hold data
+    /// stored under another digest (like the encrypted objects of an `EncryptedStore`).  The
+    /// default is `false`.
+    fn verifies_reads(&self) -> bool {
+        false
+    }
+}
+
+impl Backend for ChunkStore {
+    fn contains(&self, digest: &str) -> Result<bool> {
+        Self::contains(self, digest)
+    }
+
+    fn get(&self, digest: &str) -> Result<Option<Vec<u8>>> {
+        Self::get(self, digest)
+    }
+
+    fn insert(&self, digest: &str, data: &[u8]) -> Result<()> {
+        Self::insert(self, digest, data)
+    }
+
+    fn verifies_reads(&self) -> bool {
+        self.verify_reads
+    }
+}
+
+impl<T: Backend + ?Sized> Backend for &T {
+    fn contains(&self, digest: &str) -> Result<bool> {
+        (**self).contains(digest)
+    }
+
+    fn get(&self, digest: &str) -> Result<Option<Vec<u8>>> {
+        (**self).get(digest)
+    }
+
+    fn insert(&self, digest: &str, data: &[u8]) -> Result<()> {
+        (**self).insert(digest, data)
+    }
+
+    fn verifies_reads(&self) -> bool {
+        (**self).verifies_reads()
+    }
+}
+
+impl<T: Backend + ?Sized> Backend for Arc<T> {
+    fn contains(&self, digest: &str) -> Result<bool> {
+        (**self).contains(digest)
+    }
+
+    fn get(&self, digest: &str) -> Result<Option<Vec<u8>>> {
+        (**self).get(digest)
+    }
+
+    fn insert(&self, digest: &str, data: &[u8]) -> Result<()> {
+        (**self).insert(digest, data)
+    }
+
+    fn verifies_reads(&self) -> bool {
+        (**self).verifies_reads()
+    }
+}
+
+/// A [`Backend`] that keeps objects in memory, for short-lived pulls (like extracting an image
+/// once) and for testing.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    objects: RwLock<HashMap<String, Arc<[u8]>>>,
+}
+
+impl MemoryStore {
+    /// Creates an empty store.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The number of objects in the store.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.read().len()
+    }
+
+    /// Checks if the store is empty.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.read().is_empty()
+    }
+
+    /// The total size of the objects in the store, in bytes.
+    #[must_use]
+    pub fn size(&self) -> u64 {
+        self.read().values().map(|data| data.len() as u64).sum()
+    }
+
+    fn read(&self) -> RwLockReadGuard<'_, HashMap<String, Arc<[u8]>>> {
+        // The map is always consistent, so we can ignore poisoning
+        self.objects.read().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl Backend for MemoryStore {
+    fn contains(&self, digest: &str) -> Result<bool> {
+        check_digest(digest)?;
+        Ok(self.read().contains_key(digest))
+    }
+
+    fn get(&self, digest: &str) -> Result<Option<Vec<u8>>> {
+        check_digest(digest)?;
+        Ok(self.read().get(digest).map(|data| data.to_vec()))
+    }
+
+    fn insert(&self, digest: &str, data: &[u8]) -> Result<()> {
+        check_digest(digest)?;
+        self.objects
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entry(digest.to_owned())
+            .or_insert_with(|| data.into());
+        Ok(())
+    }
+}
+
+/// The error returned by [`EncryptedStore`] when an object can't be decrypted: it was modified,
+/// stored under a different digest, or encrypted with a different key.
+///
+/// Unlike [`CorruptObject`], the object is left in place, since the key might be the problem.
+#[cfg(feature = "encryption")]
+#[derive(Debug, Clone, thiserror::Error)]
+#[error("Unable to decrypt stored object {digest}")]
+pub struct DecryptionFailed {
+    /// The digest that the object was stored under.
+    pub digest: String,
+}
+
+// Encrypted objects are stored as the version, then the nonce, then the ciphertext and its tag
+#[cfg(feature = "encryption")]
+const ENCRYPTED_VERSION: u8 = 1;
+#[cfg(feature = "encryption")]
+const NONCE_SIZE: usize = 24;
+#[cfg(feature = "encryption")]
+const TAG_SIZE: usize = 16;
+
+/// A [`Backend`] that encrypts objects (with XChaCha20-Poly1305 and a random nonce for each)
+/// before storing them in another backend, for caches on disks that are shared or untrusted.
+///
+/// Objects are still stored under their digests, which are authenticated along with the content,
+/// so an object can't be swapped for another one.  The digests themselves aren't secret: anyone
+/// who can list the store can tell if it contains a file whose content they know.
+///
+/// The inner store sees the encrypted data, which doesn't match the digest, so it mustn't verify
+/// reads (a [`ChunkStore`] needs [`ChunkStore::with_verify_reads()`] turned off).  Decryption
+/// checks the data instead.
+#[cfg(feature = "encryption")]
+pub struct EncryptedStore<B> {
+    inner: B,
+    cipher: chacha20poly1305::XChaCha20Poly1305,
+}
+
+#[cfg(feature = "encryption")]
+impl<B> EncryptedStore<B> {
+    /// Wraps a store, encrypting with the given 256-bit key.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the store [verifies reads](Backend::verifies_reads()), since it would reject
+    /// every encrypted object as corrupt (and remove it).
+    pub fn new(inner: B, key: &[u8; 32]) -> Result<Self>
+    where
+        B: Backend,
+    {
+        use chacha20poly1305::KeyInit;
+
+        ensure!(
+            !inner.verifies_reads(),
+            "The store under an EncryptedStore mustn't verify reads"
+        );
+        Ok(Self {
+            inner,
+            cipher: chacha20poly1305::XChaCha20Poly1305::new(key.into()),
+        })
+    }
+
+    /// Generates a random key, for callers that don't derive theirs from something else.
+    #[must_use]
+    pub fn generate_key() -> [u8; 32] {
+        use chacha20poly1305::{KeyInit, aead::OsRng};
+
+        chacha20poly1305::XChaCha20Poly1305::generate_key(&mut OsRng).into()
+    }
+
+    /// The store holding the encrypted objects.
+    pub const fn inner(&self) -> &B {
+        &self.inner
+    }
+}
+
+#[cfg(feature = "encryption")]
+impl<B: Backend> Backend for EncryptedStore<B> {
+    fn contains(&self, digest: &str) -> Result<bool> {
+        self.inner.contains(digest)
+    }
+
+    fn get(&self, digest: &str) -> Result<Option<Vec<u8>>> {
+        use chacha20poly1305::{
+            XNonce,
+            aead::{Aead, Payload},
+        };
+
+        let Some(data) = self.inner.get(digest)? else {
+            return Ok(None);
+        };
+        let failed = || DecryptionFailed {
+            digest: digest.to_owned(),
+        };
+        let Some((&ENCRYPTED_VERSION, rest)) = data.split_first() else {
+            Err(failed())?
+        };
+        if rest.len() < NONCE_SIZE + TAG_SIZE {
+            Err(failed())?;
+        }
+        let (nonce, ciphertext) = rest.split_at(NONCE_SIZE);
+        let payload = Payload {
+            msg: ciphertext,
+            aad: digest.as_bytes(),
+        };
+        let content = self
+            .cipher
+            .decrypt(XNonce::from_slice(nonce), payload)
+            .map_err(|_| failed())?;
+        Ok(Some(content))
+    }
+
+    fn insert(&self, digest: &str, data: &[u8]) -> Result<()> {
+        use chacha20poly1305::{
+            AeadCore, XChaCha20Poly1305,
+            aead::{Aead, OsRng, Payload},
+        };
+
+        check_digest(digest)?;
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let payload = Payload {
+            msg: data,
+            aad: digest.as_bytes(),
+        };
+        let ciphertext = self
+            .cipher
+            .encrypt(&nonce, payload)
+            .map_err(|_| anyhow::anyhow!("Unable to encrypt object {digest}"))?;
+
+        let mut object = Vec::with_capacity(1 + NONCE_SIZE + ciphertext.len());
+        object.push(ENCRYPTED_VERSION);
+        object.extend_from_slice(&nonce);
+        object.extend_from_slice(&ciphertext);
+        self.inner.insert(digest, &object)
+    }
+}
+
+#[cfg(feature = "encryption")]
+impl<B: fmt::Debug> fmt::Debug for EncryptedStore<B> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EncryptedStore")
+            .field("inner", &self.inner)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(all(test, feature = "encryption"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encrypted_store_rejects_verifying_backends() -> Result<()> {
+        let key = EncryptedStore::<MemoryStore>::generate_key();
+        assert!(EncryptedStore::new(ChunkStore::new("unused"), &key).is_err());
+        assert!(EncryptedStore::new(Arc::new(ChunkStore::new("unused")), &key).is_err());
+        EncryptedStore::new(ChunkStore::new("unused").with_verify_reads(false), &key)?;
+
+        let data = b"hello";
+        let digest = digest::sha256(data);
+        let store = EncryptedStore::new(MemoryStore::new(), &key)?;
+        store.insert(&digest, data)?;
+        assert_ne!(store.inner().get(&digest)?.as_deref(), Some(&data[..]));
+        assert_eq!(store.get(&digest)?.as_deref(), Some(&data[..]));
+        Ok(())
+    }
+}
@@ -0,0 +1,251 @@
+//! Telemetry for the phases of pulling an image, so that slow pulls can be traced.
+//!
+//! With the `otel` feature, each [`PhaseSpan`] is reported to the global OpenTelemetry tracer and
+//! meter providers, under the `zstd-chunked` instrumentation scope: as a span named after its
+//! phase, and in the `zstd_chunked.phase.duration` (seconds) and `zstd_chunked.phase.bytes`
+//! metrics, with the phase in the [`PHASE`] attribute.  Without the feature, spans do nothing, so
+//! callers can instrument their code unconditionally.
+//!
+//! This crate reports [`Phase::Verify`] for [`Stream::write_to_verified()`] and
+//! [`Stream::write_to_strict()`], and [`Phase::Extract`] for `extract::extract_to_dir()`.  Pulls
+//! and fetches happen outside of it, so pullers report those themselves.
+//!
+//! [`Stream::write_to_verified()`]: crate::Stream::write_to_verified()
+//! [`Stream::write_to_strict()`]: crate::Stream::write_to_strict()
+
+// Without the feature, much of this does nothing, but the signatures shouldn't depend on it
+#![cfg_attr(not(feature = "otel"), allow(clippy::missing_const_for_fn))]
+
+use core::fmt;
+#[cfg(feature = "otel")]
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Instant,
+};
+
+#[cfg(feature = "otel")]
+use opentelemetry::{
+    Context, InstrumentationScope, KeyValue, global,
+    trace::{Status, TraceContextExt, Tracer},
+};
+
+/// The attribute holding the name of the image, as in the OpenTelemetry semantic conventions.
+pub const IMAGE_NAME: &str = "container.image.name";
+
+/// The attribute holding the digest of the image manifest, as in the OpenTelemetry semantic
+/// conventions.
+pub const MANIFEST_DIGEST: &str = "oci.manifest.digest";
+
+/// The attribute holding the digest of the layer blob.
+pub const LAYER_DIGEST: &str = "zstd_chunked.layer.digest";
+
+/// The attribute holding the [`Phase::name()`] on metrics.
+pub const PHASE: &str = "zstd_chunked.phase";
+
+/// A phase of pulling an image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    /// Pulling a whole image (or layer), including the other phases.
+    Pull,
+    /// Fetching content (or metadata) from a registry.
+    Fetch,
+    /// Checking content against its digests.
+    Verify,
+    /// Unpacking a layer to a directory.
+    Extract,
+}
+
+impl Phase {
+    /// The name of the phase, which is also the name of its spans.
+    #[must_use]
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Pull => "zstd_chunked.pull",
+            Self::Fetch => "zstd_chunked.fetch",
+            Self::Verify => "zstd_chunked.verify",
+            Self::Extract => "zstd_chunked.extract",
+        }
+    }
+}
+
+/// A phase in progress.  It ends with [`Self::finish()`] or, with no status, when it's dropped.
+#[must_use]
+pub struct PhaseSpan {
+    #[cfg(feature = "otel")]
+    active: Option<Active>,
+}
+
+#[cfg(feature = "otel")]
+struct Active {
+    context: Context,
+    attributes: Vec<KeyValue>,
+    start: Instant,
+    bytes: AtomicU64,
+}
+
+#[cfg(feature = "otel")]
+fn scope() -> InstrumentationScope {
+    InstrumentationScope::builder("zstd-chunked")
+        .with_version(env!("CARGO_PKG_VERSION"))
+        .build()
+}
+
+impl PhaseSpan {
+    /// Starts a phase, within whatever span is current on this thread.
+    pub fn start(phase: Phase, attributes: &[(&'static str, &str)]) -> Self {
+        #[cfg(feature = "otel")]
+        return Self::start_with_parent(phase, attributes, &Context::current());
+        #[cfg(not(feature = "otel"))]
+        {
+            let _ = (phase, attributes);
+            Self {}
+        }
+    }
+
+    #[cfg(feature = "otel")]
+    fn start_with_parent(
+        phase: Phase,
+        attributes: &[(&'static str, &str)],
+        parent: &Context,
+    ) -> Self {
+        let attributes: Vec<_> = attributes
+            .iter()
+            .map(|&(key, value)| KeyValue::new(key, value.to_owned()))
+            .collect();
+        let tracer = global::tracer_with_scope(scope());
+        let span = tracer
+            .span_builder(phase.name())
+            .with_attributes(attributes.clone())
+            .start_with_context(&tracer, parent);
+
+        let mut attributes = attributes;
+        attributes.push(KeyValue::new(PHASE, phase.name()));
+        Self {
+            active: Some(Active {
+                context: parent.with_span(span),
+                attributes,
+                start: Instant::now(),
+                bytes: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    /// Returns a handle for starting phases within this one, which can be sent to other threads.
+    #[must_use]
+    pub fn parent(&self) -> SpanParent {
+        SpanParent {
+            #[cfg(feature = "otel")]
+            context: self.active.as_ref().map(|active| active.context.clone()),
+        }
+    }
+
+    /// Counts bytes towards the `zstd_chunked.phase.bytes` metric, which is recorded when the
+    /// phase ends.
+    pub fn add_bytes(&self, bytes: u64) {
+        #[cfg(feature = "otel")]
+        if let Some(active) = &self.active {
+            active.bytes.fetch_add(bytes, Ordering::Relaxed);
+        }
+        #[cfg(not(feature = "otel"))]
+        let _ = bytes;
+    }
+
+    /// Ends the phase with the outcome of an operation, which is passed through.  A failure is
+    /// recorded as the status of the span, and in the `error.type` attribute.
+    ///
+    /// # Errors
+    ///
+    /// Returns `result` as it is.
+    pub fn finish<T, E: fmt::Display>(self, result: Result<T, E>) -> Result<T, E> {
+        #[cfg(feature = "otel")]
+        {
+            let mut this = self;
+            if let Some(active) = this.active.take() {
+                match &result {
+                    Ok(_) => active.end(Status::Ok, None),
+                    Err(err) => active.end(
+                        Status::error(err.to_string()),
+                        Some(std::any::type_name::<E>()),
+                    ),
+                }
+            }
+        }
+        result
+    }
+}
+
+#[cfg(feature = "otel")]
+impl Active {
+    // Ends the span and records the metrics
+    fn end(self, status: Status, error_type: Option<&'static str>) {
+        let mut attributes = self.attributes;
+        if let Some(error_type) = error_type {
+            attributes.push(KeyValue::new("error.type", error_type));
+        }
+        let span = self.context.span();
+        span.set_status(status);
+        span.end();
+
+        let meter = global::meter_with_scope(scope());
+        meter
+            .f64_histogram("zstd_chunked.phase.duration")
+            .with_unit("s")
+            .with_description("The duration of the phases of pulling images")
+            .build()
+            .record(self.start.elapsed().as_secs_f64(), &attributes);
+        meter
+            .u64_counter("zstd_chunked.phase.bytes")
+            .with_unit("By")
+            .with_description("The bytes processed by the phases of pulling images")
+            .build()
+            .add(self.bytes.into_inner(), &attributes);
+    }
+}
+
+/// A handle on a [`PhaseSpan`], as returned by [`PhaseSpan::parent()`].  The default handle has no
+/// span, so phases started within it are as for [`PhaseSpan::start()`].
+#[derive(Clone, Default)]
+pub struct SpanParent {
+    #[cfg(feature = "otel")]
+    context: Option<Context>,
+}
+
+impl SpanParent {
+    /// Starts a phase within the span.
+    pub fn child(&self, phase: Phase, attributes: &[(&'static str, &str)]) -> PhaseSpan {
+        #[cfg(feature = "otel")]
+        if let Some(context) = &self.context {
+            return PhaseSpan::start_with_parent(phase, attributes, context);
+        }
+        PhaseSpan::start(phase, attributes)
+    }
+
+    /// Runs a function with the span as the current span on this thread, so that the phases that
+    /// it starts (including those reported by this crate) are part of it.
+    pub fn in_scope<R>(&self, f: impl FnOnce() -> R) -> R {
+        #[cfg(feature = "otel")]
+        let _guard = self.context.clone().map(Context::attach);
+        f()
+    }
+}
+
+impl fmt::Debug for SpanParent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SpanParent").finish_non_exhaustive()
+    }
+}
+
+impl Drop for PhaseSpan {
+    fn drop(&mut self) {
+        #[cfg(feature = "otel")]
+        if let Some(active) = self.active.take() {
+            active.end(Status::Unset, None);
+        }
+    }
+}
+
+impl fmt::Debug for PhaseSpan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PhaseSpan").finish_non_exhaustive()
+    }
+}
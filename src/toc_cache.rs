@@ -0,0 +1,285 @@
+//! A cache of parsed metadata, for long-running services.
+//!
+//! Parsing the metadata of a large layer can take a noticeable amount of time, and services like
+//! snapshotters tend to need the same layers over and over.  [`TocCache`] keeps the most recently
+//! used [`Stream`]s in memory, keyed by the digest of their (compressed) manifest, which is
+//! referred to as the "TOC digest" by containers/storage.  Optionally, the compressed metadata can
+//! also be persisted to a directory so that it survives restarts.
+//!
+//! Persisted files are written atomically, and checked against their digests when they're read
+//! back.  Layers whose files are incomplete or don't match are removed and treated as cache
+//! misses.
+
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, ErrorKind},
+    path::{Path, PathBuf},
+    process,
+    sync::{
+        Arc, Mutex, PoisonError,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use anyhow::{Result, ensure};
+
+use crate::{
+    ParseOptions, Stream,
+    digest::{self, check_digest},
+};
+
+#[derive(Debug)]
+struct Entry {
+    stream: Arc<Stream>,
+    last_used: u64,
+}
+
+#[derive(Debug, Default)]
+struct Entries {
+    map: HashMap<String, Entry>,
+    clock: u64,
+}
+
+/// A thread-safe, size-limited cache of parsed metadata, keyed by TOC digest.
+#[derive(Debug)]
+pub struct TocCache {
+    entries: Mutex<Entries>,
+    capacity: usize,
+    persist: Option<PathBuf>,
+    options: ParseOptions,
+}
+
+impl TocCache {
+    /// Creates an in-memory cache which holds at most `capacity` parsed layers, evicting the least
+    /// recently used ones when it's full.
+    #[must_use]
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            entries: Mutex::default(),
+            capacity,
+            persist: None,
+            options: ParseOptions::default(),
+        }
+    }
+
+    /// Creates a cache which additionally stores the compressed metadata of each layer in the given
+    /// directory.  Layers that were evicted from memory (or that were added before a restart) get
+    /// reparsed from there, without needing to be fetched again.
+    pub fn with_persistence(capacity: usize, dir: impl Into<PathBuf>) -> Self {
+        Self {
+            persist: Some(dir.into()),
+            ..Self::new(capacity)
+        }
+    }
+
+    /// Sets the options used when parsing metadata.
+    #[must_use]
+    pub const fn with_parse_options(mut self, options: ParseOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Entries> {
+        // The map is always in a consistent state, so we can ignore poisoning
+        self.entries.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    // The persisted files of a layer: the tarsplit, its digest and the manifest, in the order
+    // that they're written
+    fn paths(&self, toc_digest: &str) -> Result<Option<[PathBuf; 3]>> {
+        check_digest(toc_digest)?;
+        Ok(self.persist.as_ref().map(|dir| {
+            ["tarsplit", "tarsplit-digest", "manifest"]
+                .map(|extension| dir.join(format!("{toc_digest}.{extension}")))
+        }))
+    }
+
+    fn load(&self, toc_digest: &str) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        let Some(paths) = self.paths(toc_digest)? else {
+            return Ok(None);
+        };
+        let read = |path| match fs::read(path) {
+            Ok(data) => Ok(Some(data)),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        };
+        let [tarsplit_path, digest_path, manifest_path] = &paths;
+        // Without the manifest, there's nothing there (or a save is still in progress)
+        let Some(manifest) = read(manifest_path)? else {
+            return Ok(None);
+        };
+        if let (Some(tarsplit), Some(tarsplit_digest)) = (read(tarsplit_path)?, read(digest_path)?)
+            && *digest::sha256(&manifest) == *toc_digest
+            && digest::sha256(&tarsplit).as_bytes() == tarsplit_digest
+        {
+            return Ok(Some((manifest, tarsplit)));
+        }
+        // Corrupt, or left over from before files were written atomically, so it needs to be
+        // fetched again
+        remove_files(&paths)?;
+        Ok(None)
+    }
+
+    fn save(&self, toc_digest: &str, manifest: &[u8], tarsplit: &[u8]) -> Result<()> {
+        if let Some([tarsplit_path, digest_path, manifest_path]) = self.paths(toc_digest)?
+            && let Some(dir) = &self.persist
+        {
+            fs::create_dir_all(dir)?;
+            // The manifest is written last, since it's the one that get() looks for first
+            write_atomic(&tarsplit_path, tarsplit)?;
+            write_atomic(&digest_path, digest::sha256(tarsplit).as_bytes())?;
+            write_atomic(&manifest_path, manifest)?;
+        }
+        Ok(())
+    }
+
+    fn insert(&self, toc_digest: &str, stream: Arc<Stream>) {
+        let mut entries = self.lock();
+        entries.clock += 1;
+        let last_used = entries.clock;
+        entries
+            .map
+            .insert(toc_digest.to_owned(), Entry { stream, last_used });
+
+        // Linear, but the cache is expected to hold tens of layers, not millions
+        while entries.map.len() > self.capacity {
+            let Some(oldest) = entries
+                .map
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| key.clone())
+            else {
+                break;
+            };
+            entries.map.remove(&oldest);
+        }
+        drop(entries);
+    }
+
+    fn get_in_memory(&self, toc_digest: &str) -> Option<Arc<Stream>> {
+        let mut entries = self.lock();
+        entries.clock += 1;
+        let now = entries.clock;
+        let stream = entries.map.get_mut(toc_digest).map(|entry| {
+            entry.last_used = now;
+            Arc::clone(&entry.stream)
+        });
+        drop(entries);
+        stream
+    }
+
+    fn parse(&self, toc_digest: &str, manifest: &[u8], tarsplit: &[u8]) -> Result<Arc<Stream>> {
+        ensure!(
+            *digest::sha256(manifest) == *toc_digest,
+            "zstd:chunked manifest doesn't match TOC digest {toc_digest}"
+        );
+        let stream = Arc::new(Stream::new_from_frames_with_options(
+            manifest,
+            tarsplit,
+            &self.options,
+        )?);
+        self.insert(toc_digest, Arc::clone(&stream));
+        Ok(stream)
+    }
+
+    /// Returns the parsed metadata for the given TOC digest, if it's in memory or in the
+    /// persistence directory.
+    /// Persisted metadata that doesn't match its digests is removed, and reported as missing.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the TOC digest is malformed, or if persisted metadata couldn't be read or parsed.
+    pub fn get(&self, toc_digest: &str) -> Result<Option<Arc<Stream>>> {
+        if let Some(stream) = self.get_in_memory(toc_digest) {
+            return Ok(Some(stream));
+        }
+        self.load(toc_digest)?
+            .map(|(manifest, tarsplit)| self.parse(toc_digest, &manifest, &tarsplit))
+            .transpose()
+    }
+
+    /// Returns the parsed metadata for the given TOC digest, calling `fetch` to get the compressed
+    /// manifest and tarsplit if it's not already cached.  The manifest returned by `fetch` is
+    /// checked against the TOC digest before it gets parsed, cached and (optionally) persisted.
+    ///
+    /// Note that the lock isn't held while fetching, so concurrent calls for the same layer may
+    /// both end up fetching it.
+    ///
+    /// # Errors
+    ///
+    /// Fails if `fetch` fails, if the manifest doesn't match the TOC digest, or if it can't be
+    /// parsed.
+    pub fn get_or_fetch(
+        &self,
+        toc_digest: &str,
+        fetch: impl FnOnce() -> Result<(Vec<u8>, Vec<u8>)>,
+    ) -> Result<Arc<Stream>> {
+        if let Some(stream) = self.get(toc_digest)? {
+            return Ok(stream);
+        }
+        let (manifest, tarsplit) = fetch()?;
+        let stream = self.parse(toc_digest, &manifest, &tarsplit)?;
+        self.save(toc_digest, &manifest, &tarsplit)?;
+        Ok(stream)
+    }
+
+    /// Removes the given layer from the cache, including the persistence directory.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the TOC digest is malformed or if the persisted files couldn't be removed.
+    pub fn invalidate(&self, toc_digest: &str) -> Result<()> {
+        self.lock().map.remove(toc_digest);
+        if let Some(paths) = self.paths(toc_digest)? {
+            remove_files(&paths)?;
+        }
+        Ok(())
+    }
+
+    /// Drops all of the parsed layers from memory.  The persistence directory is left alone.
+    pub fn clear(&self) {
+        self.lock().map.clear();
+    }
+
+    /// The number of parsed layers currently held in memory.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.lock().map.len()
+    }
+
+    /// Checks if there are no parsed layers held in memory.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+// Writes to a temporary file first, so that readers never see a partial file
+fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+    // Concurrent saves of the same layer need different temporary files
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    let mut tmp = path.to_owned().into_os_string();
+    tmp.push(format!(
+        ".{}.{}.tmp",
+        process::id(),
+        NEXT.fetch_add(1, Ordering::Relaxed)
+    ));
+    let tmp = Path::new(&tmp);
+    fs::write(tmp, data)?;
+    if let Err(err) = fs::rename(tmp, path) {
+        let _ = fs::remove_file(tmp);
+        return Err(err);
+    }
+    Ok(())
+}
+
+fn remove_files(paths: &[PathBuf]) -> io::Result<()> {
+    for path in paths {
+        match fs::remove_file(path) {
+            Err(err) if err.kind() != ErrorKind::NotFound => return Err(err),
+            _ => {}
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,384 @@
+//! Verification of content against the references in a [`Stream`].
+
+use core::{fmt, ops::Range};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    hash::{BuildHasher, RandomState},
+    sync::{Arc, Mutex, PoisonError},
+};
+
+use anyhow::{Context, Result, anyhow, bail, ensure};
+
+use crate::{ContentReference, MetadataReferences, Stream, digest, parallel};
+
+/// Loads and verifies a batch of references, using one thread per available CPU.
+///
+/// The `source` function should return the *decompressed* data corresponding to the reference,
+/// exactly like the `resolve_reference()` function passed to
+/// [`Stream::write_to()`](crate::Stream::write_to).
+///
+/// Returns one result for each reference, in the same order as the references were given.  A
+/// failure of the `source` function is reported as a failure for that reference.
+pub fn verify_many<'a>(
+    references: impl IntoIterator<Item = &'a ContentReference>,
+    source: impl Fn(&ContentReference) -> Result<Vec<u8>> + Sync,
+) -> Vec<Result<()>> {
+    let references: Vec<_> = references.into_iter().collect();
+    let results = parallel::map(&references, |reference| {
+        source(reference).and_then(|data| Ok(reference.verify(&data)?))
+    });
+
+    // Anything left over belonged to a thread that panicked
+    results
+        .into_iter()
+        .map(|result| result.unwrap_or_else(|| Err(anyhow!("Verification thread panicked"))))
+        .collect()
+}
+
+/// Selects a random sample of roughly `percent` percent of the references, for use with
+/// [`verify_many()`] when verifying everything would take too long.
+///
+/// This is meant for latency-sensitive paths where the content will be verified lazily anyway
+/// (for example, by fs-verity on first read).  The selection is randomized per process, so a
+/// malicious layer can't arrange for its bad chunks to avoid being sampled.  The metadata should
+/// still be verified in full.
+pub fn sample<'a>(
+    references: impl IntoIterator<Item = &'a ContentReference>,
+    percent: u8,
+) -> Vec<&'a ContentReference> {
+    let state = RandomState::new();
+    references
+        .into_iter()
+        .filter(|reference| state.hash_one(&reference.range) % 100 < u64::from(percent))
+        .collect()
+}
+
+/// Cheaply checks that each reference points at a plausible zstd frame, without fetching or
+/// decompressing the content.
+///
+/// For each reference, the `read_range` function is called to read the (at most 18 byte) range
+/// containing the frame header, which is then checked with
+/// [`ContentReference::check_frame_header()`].  This can be used as a pre-flight check before
+/// committing to a partial pull, but it doesn't replace verifying the digests.
+///
+/// # Errors
+///
+/// Fails on the first reference that doesn't pass the check, or if `read_range` fails.
+pub fn check_frames<'a>(
+    references: impl IntoIterator<Item = &'a ContentReference>,
+    mut read_range: impl FnMut(&Range<u64>) -> Result<Vec<u8>>,
+) -> Result<()> {
+    for reference in references {
+        let prefix = read_range(&reference.frame_header_range())?;
+        reference.check_frame_header(&prefix).with_context(|| {
+            format!(
+                "Invalid frame for {} at {:?}",
+                reference.digest, reference.range
+            )
+        })?;
+    }
+    Ok(())
+}
+
+/// The error returned by [`LazyVerifier::read()`] for content that failed verification.
+#[derive(Debug, Clone, thiserror::Error)]
+#[error("Content {digest} failed verification")]
+pub struct VerificationFailed {
+    /// The digest of the content that failed verification.
+    pub digest: Arc<str>,
+}
+
+/// Wraps a source of content, verifying each reference the first time it's read.
+///
+/// This is meant for lazily-mounted layers, where content is only fetched when it's accessed.  The
+/// verdict for each reference (its digest and range) is remembered: content that was verified
+/// once isn't hashed again, and content that failed once keeps failing (with
+/// [`VerificationFailed`], which a filesystem would report as `EIO`) without being fetched again.
+/// References that share a digest but point at different ranges are verified separately, so a
+/// corrupt copy doesn't poison the good ones.  This assumes that the source returns the same data
+/// for the same range each time, as a blob does.
+pub struct LazyVerifier<F> {
+    source: F,
+    verdicts: Mutex<HashMap<VerdictKey, bool>>,
+}
+
+// A reference, as far as its verdict is concerned: the digest and where the data came from
+type VerdictKey = (Arc<str>, Range<u64>);
+
+impl<F> fmt::Debug for LazyVerifier<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LazyVerifier")
+            .field("verdicts", &self.verdicts)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<F: Fn(&ContentReference) -> Result<Vec<u8>>> LazyVerifier<F> {
+    /// Creates a verifier for content returned by the given `source` function, which should
+    /// return the *decompressed* data corresponding to the reference.
+    pub fn new(source: F) -> Self {
+        Self {
+            source,
+            verdicts: Mutex::default(),
+        }
+    }
+
+    /// Returns the verdict for the given reference: `Some(true)` if it was verified,
+    /// `Some(false)` if it failed, or None if it hasn't been read yet.
+    pub fn verdict(&self, reference: &ContentReference) -> Option<bool> {
+        self.verdicts
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(&(Arc::clone(&reference.digest), reference.range.clone()))
+            .copied()
+    }
+
+    /// Reads the content for the given reference, verifying it if it hasn't been verified yet.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`VerificationFailed`] if the content doesn't match the reference (now or
+    /// previously), or with the error from the `source` function.
+    pub fn read(&self, reference: &ContentReference) -> Result<Vec<u8>> {
+        let failed = || VerificationFailed {
+            digest: Arc::clone(&reference.digest),
+        };
+
+        match self.verdict(reference) {
+            Some(false) => Err(failed())?,
+            Some(true) => {
+                let data = (self.source)(reference)?;
+                // Cheap, and catches sources that return the wrong thing
+                if data.len() as u64 != reference.size {
+                    Err(failed())?;
+                }
+                Ok(data)
+            }
+            None => {
+                let data = (self.source)(reference)?;
+                let ok = reference.verify(&data).is_ok();
+                self.verdicts
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .insert((Arc::clone(&reference.digest), reference.range.clone()), ok);
+                if ok { Ok(data) } else { Err(failed())? }
+            }
+        }
+    }
+}
+
+/// Incrementally verifies a compressed blob that's being reassembled from ranges, for example
+/// before re-pushing it to a registry.
+///
+/// Ranges can be added in any order.  They're hashed as soon as everything before them has
+/// arrived, so only out-of-order ranges need to be held in memory.
+#[derive(Debug)]
+pub struct BlobVerifier {
+    hasher: digest::Sha256,
+    expected: String,
+    size: u64,
+    position: u64,
+    pending: BTreeMap<u64, Vec<u8>>,
+}
+
+impl BlobVerifier {
+    /// Creates a verifier for a blob with the given digest (`sha256:0123...`) and size, as found
+    /// in the OCI descriptor.
+    pub fn new(expected: impl Into<String>, size: u64) -> Self {
+        Self {
+            hasher: digest::Sha256::new(),
+            expected: expected.into(),
+            size,
+            position: 0,
+            pending: BTreeMap::new(),
+        }
+    }
+
+    /// The number of bytes at the start of the blob that have been hashed so far.
+    #[must_use]
+    pub const fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// Adds the data for the range starting at `offset`.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the range extends past the end of the blob or overlaps data that was already
+    /// added.
+    pub fn add(&mut self, offset: u64, data: Vec<u8>) -> Result<()> {
+        let end = offset + data.len() as u64;
+        ensure!(
+            end <= self.size,
+            "Range {offset}..{end} is past the end of the blob"
+        );
+        ensure!(
+            offset >= self.position
+                && self
+                    .pending
+                    .range(..end)
+                    .next_back()
+                    .is_none_or(|(start, data)| { start + data.len() as u64 <= offset }),
+            "Range {offset}..{end} overlaps data that was already added"
+        );
+        if !data.is_empty() {
+            self.pending.insert(offset, data);
+        }
+
+        while let Some(entry) = self.pending.first_entry()
+            && *entry.key() == self.position
+        {
+            let data = entry.remove();
+            self.hasher.update(&data);
+            self.position += data.len() as u64;
+        }
+        Ok(())
+    }
+
+    /// Checks that the whole blob was added and that it has the expected digest.  If the digest
+    /// doesn't match, [`find_bad_regions()`] can be used to find out where the problem is.
+    ///
+    /// # Errors
+    ///
+    /// Fails if part of the blob is missing or if the digest doesn't match.
+    pub fn finish(self) -> Result<()> {
+        if self.position < self.size {
+            bail!(
+                "Blob is incomplete: missing data at {}..{}",
+                self.position,
+                self.pending.keys().next().copied().unwrap_or(self.size)
+            );
+        }
+        let actual = self.hasher.finalize_string();
+        ensure!(
+            actual == self.expected,
+            "Blob digest mismatch: expected {} but got {actual}",
+            self.expected
+        );
+        Ok(())
+    }
+}
+
+/// Finds the regions of a blob which don't match their own checksums: the manifest and tarsplit
+/// (if their digests are known) and each of the content ranges.
+///
+/// The `read_range` function is called to read the (compressed) data of each region.  If no bad
+/// regions are found but the blob digest still doesn't match, the problem is in a part of the blob
+/// that has no checksum of its own: the tar headers, or the footer.
+///
+/// # Errors
+///
+/// Fails if `read_range` fails.
+pub fn find_bad_regions(
+    stream: &Stream,
+    metadata: &MetadataReferences,
+    mut read_range: impl FnMut(&Range<u64>) -> Result<Vec<u8>>,
+) -> Result<Vec<Range<u64>>> {
+    let mut bad = vec![];
+
+    for reference in [&metadata.manifest, &metadata.tarsplit] {
+        if let Some(expected) = &reference.digest
+            && digest::sha256(&read_range(&reference.range)?) != *expected
+        {
+            bad.push(reference.range.clone());
+        }
+    }
+
+    let mut seen = HashSet::new();
+    for reference in stream.references() {
+        if seen.insert(reference.range.clone()) {
+            let data = read_range(&reference.range)?;
+            if reference
+                .decompress(&data)
+                .and_then(|data| reference.verify(&data))
+                .is_err()
+            {
+                bad.push(reference.range.clone());
+            }
+        }
+    }
+
+    bad.sort_by_key(|range| range.start);
+    Ok(bad)
+}
+
+/// What the digest in a [`MetadataReference`](crate::MetadataReference) was computed over.
+///
+/// The spec (and this crate, and containers/storage) digest the compressed frame, but some
+/// producers have digested the decompressed metadata instead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChecksumCoverage {
+    /// The compressed frame, as stored in the blob.  This is the documented interpretation.
+    Compressed,
+    /// The metadata after decompression.
+    Uncompressed,
+}
+
+/// Which interpretations of a metadata digest [`MetadataReference::verify()`] accepts.
+///
+/// [`MetadataReference::verify()`]: crate::MetadataReference::verify()
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ChecksumPolicy {
+    /// Only accept the documented interpretation, [`ChecksumCoverage::Compressed`].
+    #[default]
+    Strict,
+    /// Also accept [`ChecksumCoverage::Uncompressed`], for layers from producers that got it
+    /// wrong.  The compressed interpretation is tried first.
+    Lenient,
+}
+
+/// The error returned by [`MetadataReference::verify()`] when the data doesn't match the digest
+/// under any interpretation allowed by the policy.
+///
+/// [`MetadataReference::verify()`]: crate::MetadataReference::verify()
+#[derive(Debug, Clone, thiserror::Error)]
+#[error(
+    "Metadata digest mismatch: expected {expected} but got {compressed} (compressed){}",
+    .uncompressed.as_ref().map(|digest| format!(" or {digest} (uncompressed)")).unwrap_or_default()
+)]
+pub struct ChecksumMismatch {
+    /// The digest from the reference.
+    pub expected: String,
+    /// The digest of the compressed frame.
+    pub compressed: String,
+    /// The digest of the decompressed metadata, if the policy allowed checking it and the frame
+    /// could be decompressed.
+    pub uncompressed: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reference(range: Range<u64>, data: &[u8]) -> ContentReference {
+        ContentReference {
+            range,
+            digest: digest::sha256(data).into(),
+            size: data.len() as u64,
+        }
+    }
+
+    #[test]
+    fn lazy_verifier_keeps_shared_digests_apart() -> Result<()> {
+        let good = reference(0..10, b"hello");
+        let corrupt = reference(10..20, b"hello");
+        let verifier = LazyVerifier::new(|reference: &ContentReference| {
+            Ok(if reference.range.start == 0 {
+                b"hello".to_vec()
+            } else {
+                b"jello".to_vec()
+            })
+        });
+
+        assert_eq!(verifier.read(&good)?, b"hello");
+        let err = verifier
+            .read(&corrupt)
+            .err()
+            .context("corrupt read passed")?;
+        assert!(err.downcast_ref::<VerificationFailed>().is_some());
+        assert_eq!(verifier.read(&good)?, b"hello");
+        assert_eq!(verifier.verdict(&good), Some(true));
+        assert_eq!(verifier.verdict(&corrupt), Some(false));
+        Ok(())
+    }
+}
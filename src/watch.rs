@@ -0,0 +1,200 @@
+//! Keeping the latest versions of a set of images pre-fetched.
+//!
+//! Edge devices often have to apply updates within short maintenance windows, over slow links.  A
+//! [`Watcher`] checks a list of images (typically tags) on a schedule and pulls each one into the
+//! store as soon as it moves to a new manifest.  Only the content that the store doesn't have yet
+//! is fetched, so each pull downloads the delta from the versions before it, and by the time the
+//! window opens the update is already local.
+//!
+//! ```no_run
+//! # use zstd_chunked::{pull::Puller, watch::Watcher};
+//! # async fn example(puller: Puller, images: Vec<oci_client::Reference>) {
+//! let mut watcher = Watcher::new(puller, images);
+//! loop {
+//!     for update in watcher.next_round().await {
+//!         println!("{update}");
+//!     }
+//! }
+//! # }
+//! ```
+
+use core::{fmt, time::Duration};
+use std::collections::HashMap;
+
+use oci_client::Reference;
+use tokio::time::{Interval, MissedTickBehavior, interval};
+
+use crate::pull::{PullReport, Puller};
+
+/// What happened to one image in a round of checks.
+#[derive(Debug)]
+pub enum Update {
+    /// The image still refers to the manifest that was pre-fetched before.
+    Unchanged {
+        /// The image, as given to the watcher.
+        image: String,
+        /// The digest of its manifest.
+        digest: String,
+    },
+
+    /// The image moved to a new manifest (or was checked for the first time), which was pulled.
+    Prefetched {
+        /// The image, as given to the watcher.
+        image: String,
+        /// The digest of the manifest that was pre-fetched before, if any.
+        previous: Option<String>,
+        /// The digest of the new manifest.
+        digest: String,
+        /// What the pull did, including how much of the content was already in the store.
+        report: PullReport,
+    },
+
+    /// Checking or pulling the image failed.  It's tried again in the next round.
+    Failed {
+        /// The image, as given to the watcher.
+        image: String,
+        /// What went wrong.
+        error: anyhow::Error,
+    },
+}
+
+impl fmt::Display for Update {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unchanged { image, digest } => write!(f, "{image}: unchanged at {digest}"),
+            Self::Prefetched {
+                image,
+                previous,
+                digest,
+                report,
+            } => {
+                let downloaded: u64 = report.layers.iter().map(|l| l.downloaded).sum();
+                let cached: u64 = report.layers.iter().map(|l| l.cached).sum();
+                match previous {
+                    Some(previous) => write!(f, "{image}: {previous} -> {digest}")?,
+                    None => write!(f, "{image}: {digest}")?,
+                }
+                write!(
+                    f,
+                    ", {downloaded} bytes downloaded, {cached} bytes from cache"
+                )
+            }
+            Self::Failed { image, error } => write!(f, "{image}: failed: {error:#}"),
+        }
+    }
+}
+
+/// Watches a list of images, pulling each one whenever it changes.  See the
+/// [module documentation](self).
+pub struct Watcher {
+    puller: Puller,
+    images: Vec<Reference>,
+    interval: Duration,
+    window: Box<dyn Fn() -> bool + Send + Sync>,
+    ticker: Option<Interval>,
+    // The digest that was last pre-fetched for each image
+    current: HashMap<String, String>,
+}
+
+impl Watcher {
+    /// Creates a watcher for the given images, which pulls them with `puller`.  Images are
+    /// checked every 15 minutes, unless changed with [`Self::with_interval()`].
+    #[must_use]
+    pub fn new(puller: Puller, images: impl IntoIterator<Item = Reference>) -> Self {
+        Self {
+            puller,
+            images: images.into_iter().collect(),
+            interval: Duration::from_mins(15),
+            window: Box::new(|| true),
+            ticker: None,
+            current: HashMap::new(),
+        }
+    }
+
+    /// Sets the time between rounds of checks.
+    #[must_use]
+    pub const fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Only lets rounds run while `window` returns true, for keeping downloads to off-peak hours
+    /// (for example).  Rounds that fall outside of the window are skipped.
+    #[must_use]
+    pub fn with_window(mut self, window: impl Fn() -> bool + Send + Sync + 'static) -> Self {
+        self.window = Box::new(window);
+        self
+    }
+
+    /// The digest of the manifest that was last pre-fetched for an image, if any.
+    #[must_use]
+    pub fn current(&self, image: &Reference) -> Option<&str> {
+        self.current.get(&image.whole()).map(String::as_str)
+    }
+
+    /// Waits for the next round that falls inside the window and runs it, as for
+    /// [`Self::check()`].  The first round starts straight away.
+    pub async fn next_round(&mut self) -> Vec<Update> {
+        loop {
+            let period = self.interval.max(Duration::from_millis(1));
+            let ticker = self.ticker.get_or_insert_with(|| {
+                let mut ticker = interval(period);
+                ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+                ticker
+            });
+            ticker.tick().await;
+            if (self.window)() {
+                return self.check().await;
+            }
+        }
+    }
+
+    /// Checks all of the images now, one at a time, and pulls those that have moved to a new
+    /// manifest since they were last pre-fetched.
+    pub async fn check(&mut self) -> Vec<Update> {
+        let mut updates = vec![];
+        for image in &self.images {
+            let name = image.whole();
+            let previous = self.current.get(&name).cloned();
+            let digest = match self.puller.manifest_digest(image).await {
+                Ok(digest) => digest,
+                Err(error) => {
+                    updates.push(Update::Failed { image: name, error });
+                    continue;
+                }
+            };
+            if previous.as_ref() == Some(&digest) {
+                updates.push(Update::Unchanged {
+                    image: name,
+                    digest,
+                });
+                continue;
+            }
+            match self.puller.pull(image).await {
+                Ok(pulled) => {
+                    self.current
+                        .insert(name.clone(), pulled.manifest_digest.clone());
+                    updates.push(Update::Prefetched {
+                        image: name,
+                        previous,
+                        digest: pulled.manifest_digest,
+                        report: pulled.report,
+                    });
+                }
+                Err(error) => updates.push(Update::Failed { image: name, error }),
+            }
+        }
+        updates
+    }
+}
+
+impl fmt::Debug for Watcher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Watcher")
+            .field("puller", &self.puller)
+            .field("images", &self.images)
+            .field("interval", &self.interval)
+            .field("current", &self.current)
+            .finish_non_exhaustive()
+    }
+}
@@ -0,0 +1,1256 @@
+//! Writing zstd:chunked layers from directory trees.
+//!
+//! The layer is written in one pass: each tar header (and the padding around the file content) is
+//! compressed into small frames, and the content of each regular file goes into a zstd frame of
+//! its own, which can later be fetched with a single range request.  The manifest, tarsplit and
+//! footer are appended at the end, as skippable frames, so the result is still an ordinary zstd
+//! compressed tar stream for clients that don't know about zstd:chunked.
+//!
+//! [`ArtifactWriter`] is a profile for huge single files (like AI models or VM images) instead:
+//! there's no tar stream and no tarsplit, and each file is split into [`Chunking`] chunks with a
+//! manifest entry each, so that a new version of the file only needs its changed chunks fetched.
+
+use core::{cell::RefCell, fmt, mem};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs::{self, File},
+    io::{self, Read, Write},
+    os::unix::fs::{FileTypeExt, MetadataExt},
+    path::Path,
+};
+
+use anyhow::{Context, Result, bail, ensure};
+use base64::{Engine, engine::general_purpose::STANDARD as b64};
+use serde::Serialize;
+use tar::{Builder, EntryType, Header, HeaderMode};
+
+use crate::{
+    ContentReference, MetadataReference, MetadataReferences, Stream, crc64,
+    dedup::{DedupReport, RECHUNK_THRESHOLD},
+    digest::{self, Sha256},
+    format::{Footer, TARSPLIT_FILE_TYPE, TARSPLIT_SEGMENT_TYPE, ZSTD_SKIPPABLE_MAGIC},
+    resolver,
+};
+
+/// The compression level used by [`Writer::new()`].
+pub const DEFAULT_LEVEL: i32 = 3;
+
+// Formats a timestamp the way Go's encoding/json does (RFC 3339), without pulling in a date crate.
+fn format_time(secs: i64) -> String {
+    // Howard Hinnant's civil_from_days()
+    let (days, secs) = (secs.div_euclid(86400), secs.rem_euclid(86400));
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+// A PAX extended header record: "<length> <key>=<value>\n", where the length includes itself.
+fn pax_record(key: &str, value: &[u8]) -> Vec<u8> {
+    let rest = key.len() + value.len() + 3;
+    let mut length = rest + 1;
+    while length != rest + length.to_string().len() {
+        length = rest + length.to_string().len();
+    }
+    let mut record = format!("{length} {key}=").into_bytes();
+    record.extend_from_slice(value);
+    record.push(b'\n');
+    record
+}
+
+// The device number encoding used by Linux (and glibc).  Both halves fit in 32 bits.
+#[allow(clippy::cast_possible_truncation)]
+const fn device_numbers(rdev: u64) -> (u32, u32) {
+    let major = ((rdev >> 8) & 0xfff) | ((rdev >> 32) & !0xfff);
+    let minor = (rdev & 0xff) | ((rdev >> 12) & !0xff);
+    (major as u32, minor as u32)
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ManifestEntry {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    link_name: Option<String>,
+    mode: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    size: Option<u64>,
+    uid: u32,
+    gid: u32,
+    modtime: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dev_major: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dev_minor: Option<u32>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    xattrs: BTreeMap<String, String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    digest: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    offset: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    end_offset: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    chunk_size: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    chunk_offset: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    chunk_digest: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct Manifest<'a> {
+    version: u32,
+    entries: &'a [ManifestEntry],
+}
+
+#[derive(Debug, Serialize)]
+struct TarSplitEntry<'a> {
+    #[serde(rename = "type")]
+    kind: u8,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    size: Option<u64>,
+    payload: Option<String>,
+    position: u64,
+}
+
+// Counts and hashes everything that gets written to the output.
+#[derive(Debug)]
+struct Output<W> {
+    inner: W,
+    position: u64,
+    hasher: Sha256,
+}
+
+impl<W: Write> Write for Output<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.position += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Output<W> {
+    // Writes some metadata as a skippable frame containing a zstd frame, returning the zstd frame
+    // along with its reference.
+    fn write_metadata(&mut self, data: &[u8], level: i32) -> Result<(MetadataReference, Vec<u8>)> {
+        let compressed = zstd::bulk::compress(data, level)?;
+        self.write_all(&ZSTD_SKIPPABLE_MAGIC)?;
+        self.write_all(&u32::try_from(compressed.len())?.to_le_bytes())?;
+        let start = self.position;
+        self.write_all(&compressed)?;
+        let reference = MetadataReference {
+            range: start..self.position,
+            digest: Some(digest::sha256(&compressed)),
+            uncompressed_size: data.len() as u64,
+        };
+        Ok((reference, compressed))
+    }
+}
+
+/// The result of writing a layer, with everything needed to describe it in an OCI image.
+#[derive(Debug)]
+pub struct LayerInfo {
+    /// The positions and digests of the manifest and tarsplit, as found in the footer and the
+    /// layer descriptor annotations (see [`MetadataReferences::to_oci()`]).
+    pub metadata: MetadataReferences,
+
+    /// The digest of the compressed blob.
+    pub digest: String,
+
+    /// The size of the compressed blob.
+    pub size: u64,
+
+    /// The digest of the uncompressed tar stream, for the `rootfs.diff_ids` of the image config.
+    pub diff_id: String,
+}
+
+/// Writes a zstd:chunked layer.
+///
+/// The tar stream uses GNU headers, with PAX extended headers for extended attributes.  Files are
+/// read from disk while the layer is written, so they shouldn't be modified in the meantime.
+pub struct Writer<W> {
+    output: Output<W>,
+    level: i32,
+    // Only used for formatting headers: its output gets moved to `inline` after each entry
+    tar: Builder<Vec<u8>>,
+    inline: Vec<u8>,
+    diff_id: Sha256,
+    entries: Vec<ManifestEntry>,
+    tarsplit: Vec<u8>,
+    tarsplit_position: u64,
+    hardlinks: HashMap<(u64, u64), String>,
+    // Set by rechunk(), which copies the padding along with the tar headers
+    copy_padding: bool,
+}
+
+impl<W> fmt::Debug for Writer<W> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Writer")
+            .field("position", &self.output.position)
+            .field("level", &self.level)
+            .field("entries", &self.entries.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl<W: Write> Writer<W> {
+    /// Creates a writer which writes a layer to `output`, using the default compression level.
+    pub fn new(output: W) -> Self {
+        Self {
+            output: Output {
+                inner: output,
+                position: 0,
+                hasher: Sha256::new(),
+            },
+            level: DEFAULT_LEVEL,
+            tar: Builder::new(vec![]),
+            inline: vec![],
+            diff_id: Sha256::new(),
+            entries: vec![],
+            tarsplit: vec![],
+            tarsplit_position: 0,
+            hardlinks: HashMap::new(),
+            copy_padding: false,
+        }
+    }
+
+    /// Sets the zstd compression level.
+    #[must_use]
+    pub const fn with_level(mut self, level: i32) -> Self {
+        self.level = level;
+        self
+    }
+
+    /// Writes a layer containing the contents of `dir` (but not `dir` itself) to `output`.
+    ///
+    /// # Errors
+    ///
+    /// Fails if there was an I/O error, or if the tree contains something that can't be
+    /// represented (like a filename that isn't valid UTF-8).
+    pub fn from_dir(dir: impl AsRef<Path>, output: W) -> Result<(W, LayerInfo)> {
+        let mut writer = Self::new(output);
+        writer.append_dir(dir)?;
+        writer.finish()
+    }
+
+    fn add_tarsplit_entry(&mut self, entry: &TarSplitEntry<'_>) -> Result<()> {
+        serde_json::to_writer(&mut self.tarsplit, entry)?;
+        self.tarsplit.push(b'\n');
+        self.tarsplit_position += 1;
+        Ok(())
+    }
+
+    // Compresses the pending inline data (tar headers and padding) into a frame of its own.
+    fn flush_inline(&mut self) -> Result<()> {
+        if self.inline.is_empty() {
+            return Ok(());
+        }
+        let data = mem::take(&mut self.inline);
+        self.diff_id.update(&data);
+        self.output
+            .write_all(&zstd::bulk::compress(&data, self.level)?)?;
+        self.add_tarsplit_entry(&TarSplitEntry {
+            kind: TARSPLIT_SEGMENT_TYPE,
+            name: None,
+            size: None,
+            payload: Some(b64.encode(&data)),
+            position: self.tarsplit_position,
+        })
+    }
+
+    // Writes the content of a regular file, and its manifest entry.  Unless the file is split
+    // into chunks, the content goes into a frame of its own.  Returns the size of the content.
+    fn write_content(
+        &mut self,
+        mut reader: impl Read,
+        mut entry: ManifestEntry,
+        split: &Split,
+    ) -> Result<u64> {
+        self.flush_inline()?;
+
+        let mut hasher = Sha256::new();
+        let mut crc = 0;
+        let mut total = 0;
+        let mut chunks = vec![];
+        if matches!(split, Split::Whole) {
+            let start = self.output.position;
+            let mut buffer = vec![0; 1 << 20];
+            let mut encoder = zstd::Encoder::new(&mut self.output, self.level)?;
+            loop {
+                let n = reader.read(&mut buffer)?;
+                if n == 0 {
+                    break;
+                }
+                let data = &buffer[..n];
+                hasher.update(data);
+                self.diff_id.update(data);
+                crc = crc64::update(crc, data);
+                encoder.write_all(data)?;
+                total += n as u64;
+            }
+            encoder.finish()?;
+            entry.offset = Some(start);
+            entry.end_offset = Some(self.output.position);
+        } else {
+            // As for ArtifactWriter, with the first chunk in the "reg" entry
+            let mut buffer = Vec::with_capacity(split.max());
+            let mut sizes = split.sizes();
+            loop {
+                let wanted = split.max() - buffer.len();
+                (&mut reader).take(wanted as u64).read_to_end(&mut buffer)?;
+                if buffer.is_empty() {
+                    break;
+                }
+                let end = match split {
+                    Split::Chunking(chunking) => chunking.cut(&buffer),
+                    _ => sizes
+                        .next()
+                        .map_or(buffer.len(), |size| size.min(buffer.len())),
+                };
+                let chunk = &buffer[..end];
+                hasher.update(chunk);
+                self.diff_id.update(chunk);
+                crc = crc64::update(crc, chunk);
+
+                let start = self.output.position;
+                self.output
+                    .write_all(&zstd::bulk::compress(chunk, self.level)?)?;
+                let mut chunk_entry = if total == 0 {
+                    None
+                } else {
+                    Some(ManifestEntry {
+                        kind: "chunk",
+                        ..artifact_entry(&entry.name, entry.mode, 0)
+                    })
+                };
+                let target = chunk_entry.as_mut().unwrap_or(&mut entry);
+                target.offset = Some(start);
+                target.end_offset = Some(self.output.position);
+                target.chunk_size = Some(end as u64);
+                target.chunk_offset = Some(total);
+                target.chunk_digest = Some(digest::sha256(chunk));
+                chunks.extend(chunk_entry);
+                total += end as u64;
+                buffer.drain(..end);
+            }
+        }
+
+        entry.size = Some(total);
+        entry.digest = Some(hasher.finalize_string());
+        self.add_tarsplit_entry(&TarSplitEntry {
+            kind: TARSPLIT_FILE_TYPE,
+            name: Some(&entry.name),
+            size: Some(total),
+            payload: Some(b64.encode(crc.to_be_bytes())),
+            position: self.tarsplit_position,
+        })?;
+        for chunk in &mut chunks {
+            chunk.modtime.clone_from(&entry.modtime);
+            (chunk.uid, chunk.gid) = (entry.uid, entry.gid);
+        }
+        self.entries.push(entry);
+        self.entries.append(&mut chunks);
+
+        // Pad the content to a whole number of blocks, unless the padding is copied from elsewhere
+        if !self.copy_padding {
+            let padding = total.next_multiple_of(512) - total;
+            self.inline
+                .resize(self.inline.len() + usize::try_from(padding)?, 0);
+        }
+        Ok(total)
+    }
+
+    fn append_xattrs(&mut self, path: &Path, entry: &mut ManifestEntry) -> Result<()> {
+        let mut records = vec![];
+        for name in xattr::list(path)? {
+            let name = name
+                .to_str()
+                .with_context(|| format!("Non-UTF-8 xattr name on {}", path.display()))?;
+            if let Some(value) = xattr::get(path, name)? {
+                records.extend(pax_record(&format!("SCHILY.xattr.{name}"), &value));
+                entry.xattrs.insert(name.to_owned(), b64.encode(&value));
+            }
+        }
+        if !records.is_empty() {
+            let mut header = Header::new_ustar();
+            header.set_entry_type(EntryType::XHeader);
+            header.set_path("././@PaxHeader")?;
+            header.set_size(records.len() as u64);
+            header.set_cksum();
+            self.tar.append(&header, &records[..])?;
+        }
+        Ok(())
+    }
+
+    /// Adds a single filesystem object to the layer under the given name, without recursing into
+    /// directories.  Symlinks aren't followed.  Regular files with more than one link are written
+    /// as hardlinks to the first name they were added under.
+    ///
+    /// # Errors
+    ///
+    /// Fails if there was an I/O error, or if the object is a socket (which tar can't represent).
+    pub fn append_path(&mut self, path: impl AsRef<Path>, name: &str) -> Result<()> {
+        let path = path.as_ref();
+        let metadata = fs::symlink_metadata(path)?;
+        let file_type = metadata.file_type();
+        if file_type.is_socket() {
+            bail!("Can't add socket {} to a layer", path.display());
+        }
+
+        let mut header = Header::new_gnu();
+        header.set_metadata_in_mode(&metadata, HeaderMode::Complete);
+        let mut entry = ManifestEntry {
+            kind: "reg",
+            name: name.to_owned(),
+            link_name: None,
+            mode: metadata.mode() & 0o7777,
+            size: None,
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+            modtime: format_time(metadata.mtime()),
+            dev_major: None,
+            dev_minor: None,
+            xattrs: BTreeMap::new(),
+            digest: None,
+            offset: None,
+            end_offset: None,
+            chunk_size: None,
+            chunk_offset: None,
+            chunk_digest: None,
+        };
+        self.append_xattrs(path, &mut entry)?;
+
+        let key = (metadata.dev(), metadata.ino());
+        let mut size = None;
+        if file_type.is_file()
+            && let Some(target) = self.hardlinks.get(&key)
+        {
+            entry.kind = "hardlink";
+            entry.link_name = Some(target.clone());
+            header.set_entry_type(EntryType::Link);
+            header.set_size(0);
+            self.tar.append_link(&mut header, name, target)?;
+        } else if file_type.is_symlink() {
+            let target = fs::read_link(path)?;
+            let target = target
+                .to_str()
+                .with_context(|| format!("Non-UTF-8 symlink target for {}", path.display()))?;
+            entry.kind = "symlink";
+            entry.link_name = Some(target.to_owned());
+            self.tar.append_link(&mut header, name, target)?;
+        } else {
+            if file_type.is_dir() {
+                entry.kind = "dir";
+            } else if file_type.is_file() {
+                if metadata.nlink() > 1 {
+                    self.hardlinks.insert(key, name.to_owned());
+                }
+                size = Some(metadata.len()).filter(|&size| size > 0);
+            } else {
+                let (major, minor) = device_numbers(metadata.rdev());
+                entry.kind = if file_type.is_char_device() {
+                    "char"
+                } else if file_type.is_block_device() {
+                    "block"
+                } else {
+                    "fifo"
+                };
+                if !file_type.is_fifo() {
+                    header.set_device_major(major)?;
+                    header.set_device_minor(minor)?;
+                    entry.dev_major = Some(major);
+                    entry.dev_minor = Some(minor);
+                }
+            }
+            self.tar.append_data(&mut header, name, io::empty())?;
+        }
+
+        self.inline.append(self.tar.get_mut());
+        if let Some(size) = size {
+            let written = self.write_content(File::open(path)?, entry, &Split::Whole)?;
+            ensure!(
+                written == size,
+                "{} changed size while it was being written",
+                path.display()
+            );
+        } else {
+            self.append_empty(entry)?;
+        }
+        Ok(())
+    }
+
+    // Records an entry without content, which tar-split does too
+    fn append_empty(&mut self, entry: ManifestEntry) -> Result<()> {
+        self.add_tarsplit_entry(&TarSplitEntry {
+            kind: TARSPLIT_FILE_TYPE,
+            name: Some(&entry.name),
+            size: None,
+            payload: None,
+            position: self.tarsplit_position,
+        })?;
+        self.entries.push(entry);
+        Ok(())
+    }
+
+    /// Adds the contents of `dir` (but not `dir` itself) to the layer, in sorted order, with names
+    /// relative to `dir`.
+    ///
+    /// # Errors
+    ///
+    /// Fails if there was an I/O error, or if the tree contains something that can't be
+    /// represented (like a filename that isn't valid UTF-8).
+    pub fn append_dir(&mut self, dir: impl AsRef<Path>) -> Result<()> {
+        let dir = dir.as_ref();
+        let mut todo = vec![String::new()];
+        while let Some(prefix) = todo.pop() {
+            let mut children = vec![];
+            for child in fs::read_dir(dir.join(&prefix))? {
+                let child = child?;
+                let Ok(name) = child.file_name().into_string() else {
+                    bail!("Non-UTF-8 filename in {}", child.path().display());
+                };
+                children.push((format!("{prefix}{name}"), child.file_type()?.is_dir()));
+            }
+            children.sort();
+
+            // Each directory's entries come before the contents of its subdirectories, which are
+            // then visited in order
+            let mut subdirs = vec![];
+            for (name, is_dir) in children {
+                self.append_path(dir.join(&name), &name)?;
+                if is_dir {
+                    subdirs.push(format!("{name}/"));
+                }
+            }
+            todo.extend(subdirs.into_iter().rev());
+        }
+        Ok(())
+    }
+
+    /// Writes the end of the tar stream, the manifest, the tarsplit and the footer.
+    ///
+    /// # Errors
+    ///
+    /// Fails if there was an I/O error.
+    pub fn finish(mut self) -> Result<(W, LayerInfo)> {
+        self.tar.finish()?;
+        self.inline.append(self.tar.get_mut());
+        let (output, info, _) = self.write_metadata()?;
+        Ok((output, info))
+    }
+
+    // Writes the manifest, the tarsplit and the footer, returning the compressed metadata too
+    fn write_metadata(mut self) -> Result<(W, LayerInfo, [Vec<u8>; 2])> {
+        self.flush_inline()?;
+
+        let manifest = serde_json::to_vec(&Manifest {
+            version: 1,
+            entries: &self.entries,
+        })?;
+        let (manifest, compressed_manifest) = self.output.write_metadata(&manifest, self.level)?;
+        let tarsplit = mem::take(&mut self.tarsplit);
+        let (tarsplit, compressed_tarsplit) = self.output.write_metadata(&tarsplit, self.level)?;
+
+        Footer::new(&manifest, &tarsplit).write_to(&mut self.output)?;
+        self.output.flush()?;
+
+        let Output {
+            inner,
+            position,
+            hasher,
+        } = self.output;
+        Ok((
+            inner,
+            LayerInfo {
+                metadata: MetadataReferences { manifest, tarsplit },
+                digest: hasher.finalize_string(),
+                size: position,
+                diff_id: self.diff_id.finalize_string(),
+            },
+            [compressed_manifest, compressed_tarsplit],
+        ))
+    }
+}
+
+// How the content of a file is split into frames
+#[derive(Debug)]
+enum Split {
+    // A single frame
+    Whole,
+    // Chunks of these sizes, to keep the frames that the file had before
+    Sizes(Vec<usize>),
+    Chunking(Chunking),
+}
+
+impl Split {
+    // The largest chunk
+    fn max(&self) -> usize {
+        match self {
+            Self::Whole => 0,
+            Self::Sizes(sizes) => sizes.iter().copied().max().unwrap_or_default(),
+            Self::Chunking(chunking) => chunking.max(),
+        }
+    }
+
+    fn sizes(&self) -> impl Iterator<Item = usize> + '_ {
+        match self {
+            Self::Sizes(sizes) => sizes.as_slice(),
+            _ => &[],
+        }
+        .iter()
+        .copied()
+    }
+}
+
+/// How [`ArtifactWriter`] and [`rechunk()`] split files into chunks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Chunking {
+    /// Chunks of exactly this many bytes, except for the last one.  This suits files which are
+    /// updated in place, like VM images.
+    Fixed(usize),
+
+    /// Content-defined chunks, cut where a rolling hash of the content says so.  Data inserted
+    /// or removed only changes the chunks around it, instead of shifting all the ones after it.
+    ContentDefined {
+        /// The minimum size of a chunk (except for the last one).
+        min: usize,
+        /// The size that chunks should have on average.  This is rounded to a power of two.
+        average: usize,
+        /// The maximum size of a chunk.
+        max: usize,
+    },
+}
+
+impl Chunking {
+    /// Content-defined chunks of the given average size, between a quarter and four times that.
+    #[must_use]
+    pub const fn content_defined(average: usize) -> Self {
+        Self::ContentDefined {
+            min: average / 4,
+            average,
+            max: average.saturating_mul(4),
+        }
+    }
+
+    // The largest chunk, which is how much needs to be buffered to find the next cut
+    const fn max(&self) -> usize {
+        match *self {
+            Self::Fixed(size) => size,
+            Self::ContentDefined { max, .. } => max,
+        }
+    }
+
+    fn check(&self) -> Result<()> {
+        match *self {
+            Self::Fixed(size) => ensure!(size > 0, "Chunks can't be empty"),
+            Self::ContentDefined { min, average, max } => ensure!(
+                0 < average && min <= average && average <= max,
+                "Chunk sizes must satisfy 0 < average and min <= average <= max"
+            ),
+        }
+        Ok(())
+    }
+
+    // Finds the end of the next chunk in `data`, which holds at least the largest possible chunk
+    // (unless it's the end of the file)
+    fn cut(&self, data: &[u8]) -> usize {
+        match *self {
+            Self::Fixed(size) => size.min(data.len()),
+            Self::ContentDefined { min, average, max } => {
+                let end = data.len().min(max);
+                // Check the top bits of the hash, which depend on the most bytes
+                let bits = average.next_power_of_two().trailing_zeros();
+                let mask = u64::MAX.checked_shl(64 - bits).unwrap_or(0);
+                let mut hash = 0u64;
+                for (i, &byte) in data.iter().enumerate().take(end).skip(min) {
+                    hash = (hash << 1).wrapping_add(GEAR[usize::from(byte)]);
+                    if hash & mask == 0 {
+                        return i + 1;
+                    }
+                }
+                end
+            }
+        }
+    }
+}
+
+impl Default for Chunking {
+    /// Content-defined chunks of 4MiB on average.
+    fn default() -> Self {
+        Self::content_defined(4 << 20)
+    }
+}
+
+// The table for the gear hash: one pseudo-random value per byte value, from splitmix64
+const GEAR: [u64; 256] = {
+    let mut table = [0; 256];
+    let mut state = 0u64;
+    let mut i = 0;
+    while i < table.len() {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+};
+
+// The entry for a file in an artifact, before its content is known
+fn artifact_entry(name: &str, mode: u32, mtime: i64) -> ManifestEntry {
+    ManifestEntry {
+        kind: "reg",
+        name: name.to_owned(),
+        link_name: None,
+        mode,
+        size: None,
+        uid: 0,
+        gid: 0,
+        modtime: format_time(mtime),
+        dev_major: None,
+        dev_minor: None,
+        xattrs: BTreeMap::new(),
+        digest: None,
+        offset: None,
+        end_offset: None,
+        chunk_size: None,
+        chunk_offset: None,
+        chunk_digest: None,
+    }
+}
+
+/// Writes a zstd:chunked artifact: files stored back to back, split into chunks, with no tar
+/// stream and no tarsplit.
+///
+/// The result is read with [`Stream::new_from_manifest()`](crate::Stream::new_from_manifest()),
+/// and should be pushed with a `+zstd` media type (see
+/// [`is_zstd_artifact_media_type()`](crate::is_zstd_artifact_media_type())) and the manifest
+/// annotations from [`MetadataReferences::to_oci()`].  As for layers, decompressing the whole
+/// blob gives the content, here the files one after the other, and
+/// [`LayerInfo::diff_id`] is its digest.
+pub struct ArtifactWriter<W> {
+    output: Output<W>,
+    level: i32,
+    chunking: Chunking,
+    content: Sha256,
+    entries: Vec<ManifestEntry>,
+}
+
+impl<W> fmt::Debug for ArtifactWriter<W> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ArtifactWriter")
+            .field("position", &self.output.position)
+            .field("level", &self.level)
+            .field("chunking", &self.chunking)
+            .field("entries", &self.entries.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl<W: Write> ArtifactWriter<W> {
+    /// Creates a writer which writes an artifact to `output`, using the default compression level
+    /// and chunking.
+    pub fn new(output: W) -> Self {
+        Self {
+            output: Output {
+                inner: output,
+                position: 0,
+                hasher: Sha256::new(),
+            },
+            level: DEFAULT_LEVEL,
+            chunking: Chunking::default(),
+            content: Sha256::new(),
+            entries: vec![],
+        }
+    }
+
+    /// Sets the zstd compression level.
+    #[must_use]
+    pub const fn with_level(mut self, level: i32) -> Self {
+        self.level = level;
+        self
+    }
+
+    /// Sets how files are split into chunks.
+    #[must_use]
+    pub const fn with_chunking(mut self, chunking: Chunking) -> Self {
+        self.chunking = chunking;
+        self
+    }
+
+    /// Writes an artifact containing the single file at `path`, under the given name.
+    ///
+    /// # Errors
+    ///
+    /// As for [`Self::append_file()`].
+    pub fn from_file(path: impl AsRef<Path>, name: &str, output: W) -> Result<(W, LayerInfo)> {
+        let mut writer = Self::new(output);
+        writer.append_file(path, name)?;
+        writer.finish()
+    }
+
+    /// Adds the regular file at `path` under the given name, with its permission bits and
+    /// modification time.
+    ///
+    /// # Errors
+    ///
+    /// Fails if there was an I/O error, if `path` isn't a regular file, or if the chunking is
+    /// invalid.
+    pub fn append_file(&mut self, path: impl AsRef<Path>, name: &str) -> Result<()> {
+        let path = path.as_ref();
+        let metadata = fs::metadata(path)?;
+        ensure!(
+            metadata.is_file(),
+            "{} isn't a regular file",
+            path.display()
+        );
+        let size = self.append(
+            File::open(path)?,
+            name,
+            metadata.mode() & 0o7777,
+            metadata.mtime(),
+        )?;
+        ensure!(
+            size == metadata.len(),
+            "{} changed size while it was being written",
+            path.display()
+        );
+        Ok(())
+    }
+
+    /// Adds a file with the content from `reader`, under the given name, with mode 0644.
+    ///
+    /// # Errors
+    ///
+    /// Fails if reading or writing fails, or if the chunking is invalid.
+    pub fn append_reader(&mut self, reader: impl Read, name: &str) -> Result<()> {
+        self.append(reader, name, 0o644, 0)?;
+        Ok(())
+    }
+
+    // Writes each chunk into a frame of its own, with a manifest entry each: "reg" for the first
+    // and "chunk" for the rest.  Returns the size of the file.
+    fn append(&mut self, mut reader: impl Read, name: &str, mode: u32, mtime: i64) -> Result<u64> {
+        self.chunking.check()?;
+        let first = self.entries.len();
+        let mut file = Sha256::new();
+        let mut buffer = Vec::with_capacity(self.chunking.max());
+        let mut size = 0;
+        loop {
+            // Fill the buffer, so that the cut doesn't depend on how the reads were split
+            let wanted = self.chunking.max() - buffer.len();
+            (&mut reader).take(wanted as u64).read_to_end(&mut buffer)?;
+            if buffer.is_empty() {
+                break;
+            }
+            let end = self.chunking.cut(&buffer);
+            let chunk = &buffer[..end];
+            file.update(chunk);
+            self.content.update(chunk);
+
+            let start = self.output.position;
+            self.output
+                .write_all(&zstd::bulk::compress(chunk, self.level)?)?;
+            let mut entry = artifact_entry(name, mode, mtime);
+            if self.entries.len() > first {
+                entry.kind = "chunk";
+            }
+            entry.offset = Some(start);
+            entry.end_offset = Some(self.output.position);
+            entry.chunk_size = Some(end as u64);
+            entry.chunk_offset = Some(size);
+            entry.chunk_digest = Some(digest::sha256(chunk));
+            self.entries.push(entry);
+            size += end as u64;
+            buffer.drain(..end);
+        }
+
+        if let Some(entry) = self.entries.get_mut(first) {
+            entry.size = Some(size);
+            entry.digest = Some(file.finalize_string());
+        } else {
+            // Empty files have no chunks, but still get an entry
+            self.entries.push(artifact_entry(name, mode, mtime));
+        }
+        Ok(size)
+    }
+
+    /// Writes the manifest and the footer.
+    ///
+    /// # Errors
+    ///
+    /// Fails if there was an I/O error.
+    pub fn finish(mut self) -> Result<(W, LayerInfo)> {
+        let manifest = serde_json::to_vec(&Manifest {
+            version: 1,
+            entries: &self.entries,
+        })?;
+        let (manifest, _) = self.output.write_metadata(&manifest, self.level)?;
+        let tarsplit = MetadataReference::absent();
+
+        Footer::new(&manifest, &tarsplit).write_to(&mut self.output)?;
+        self.output.flush()?;
+
+        let Output {
+            inner,
+            position,
+            hasher,
+        } = self.output;
+        Ok((
+            inner,
+            LayerInfo {
+                metadata: MetadataReferences { manifest, tarsplit },
+                digest: hasher.finalize_string(),
+                size: position,
+                diff_id: self.content.finalize_string(),
+            },
+        ))
+    }
+}
+
+/// The predicted savings of a layer rewritten by [`rechunk()`], for a client which already has the
+/// related layers.
+#[derive(Debug, Clone)]
+pub struct RechunkReport {
+    /// The analysis of the original layer.
+    pub before: DedupReport,
+
+    /// The analysis of the rewritten layer.
+    pub after: DedupReport,
+}
+
+impl RechunkReport {
+    /// How much less the client would need to download with the rewritten layer, in compressed
+    /// bytes.  This is zero if the rewritten layer is no better.
+    #[must_use]
+    pub const fn savings(&self) -> u64 {
+        self.before
+            .download_size()
+            .saturating_sub(self.after.download_size())
+    }
+}
+
+// What has been read from the tar stream since it was last taken, unless paused
+#[derive(Debug, Default)]
+struct Recording {
+    data: Vec<u8>,
+    paused: bool,
+}
+
+// Records what the tar crate reads, so that headers (including extension headers) and padding can
+// be copied as they are
+struct Tee<'a, R> {
+    inner: R,
+    recording: &'a RefCell<Recording>,
+}
+
+impl<R: Read> Read for Tee<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        let mut recording = self.recording.borrow_mut();
+        if !recording.paused {
+            recording.data.extend_from_slice(&buf[..n]);
+        }
+        Ok(n)
+    }
+}
+
+// The manifest entry for an entry of a tar stream, or None for the kinds that the manifest doesn't
+// describe (like PAX global headers), which are left in the inline data
+fn tar_manifest_entry(entry: &mut tar::Entry<impl Read>) -> Result<Option<ManifestEntry>> {
+    let header = entry.header();
+    let kind = match header.entry_type() {
+        EntryType::Regular | EntryType::Continuous => "reg",
+        EntryType::Directory => "dir",
+        EntryType::Symlink => "symlink",
+        EntryType::Link => "hardlink",
+        EntryType::Char => "char",
+        EntryType::Block => "block",
+        EntryType::Fifo => "fifo",
+        _ => return Ok(None),
+    };
+    let utf8 = |bytes: &[u8], what| {
+        String::from_utf8(bytes.to_vec()).with_context(|| format!("Non-UTF-8 {what} in layer"))
+    };
+    let (mode, uid, gid) = (header.mode()?, header.uid()?, header.gid()?);
+    let mtime = i64::try_from(header.mtime()?)?;
+    let device = (kind == "char" || kind == "block")
+        .then(|| Ok::<_, io::Error>((header.device_major()?, header.device_minor()?)))
+        .transpose()?;
+    let mut result = ManifestEntry {
+        kind,
+        name: utf8(&entry.path_bytes(), "filename")?,
+        link_name: entry
+            .link_name_bytes()
+            .map(|link| utf8(&link, "link target"))
+            .transpose()?,
+        uid: u32::try_from(uid)?,
+        gid: u32::try_from(gid)?,
+        ..artifact_entry("", mode & 0o7777, mtime)
+    };
+    if let Some((major, minor)) = device {
+        result.dev_major = major;
+        result.dev_minor = minor;
+    }
+    if let Some(extensions) = entry.pax_extensions()? {
+        for extension in extensions {
+            let extension = extension?;
+            if let Some(name) = extension.key_bytes().strip_prefix(b"SCHILY.xattr.") {
+                let value = b64.encode(extension.value_bytes());
+                result.xattrs.insert(utf8(name, "xattr name")?, value);
+            }
+        }
+    }
+    Ok(Some(result))
+}
+
+/// Rewrites a layer so that more of its content can be reused by clients which already have a
+/// set of related layers (like previous nightly builds of the same image), and predicts the
+/// savings.
+///
+/// The content of each file is written:
+///
+/// - in the same frames as before, if the client already has all of them;
+/// - split into `chunking` chunks if it's at least [`RECHUNK_THRESHOLD`] bytes, so that the
+///   parts which stay the same in the next build can be reused (as long as that gets rewritten
+///   with the same chunking);
+/// - in a frame of its own otherwise.
+///
+/// The tar stream is copied exactly, headers, padding and order included, so the rewritten layer
+/// has the same `diff_id` and can replace the original without changing the image config.  Files
+/// aren't reordered: the content of each one is in frames of its own, so the order makes no
+/// difference to what can be reused.  Content is resolved as for [`Stream::write_to_verified()`].
+///
+/// # Errors
+///
+/// Fails if content can't be resolved or doesn't match its digest, if the tar stream can't be
+/// parsed (or has names that aren't UTF-8), if the chunking is invalid, or if writing fails.
+pub fn rechunk<W: Write>(
+    layer: &Stream,
+    have: &[&Stream],
+    chunking: Chunking,
+    output: W,
+    resolve_reference: impl Fn(&ContentReference) -> Result<Vec<u8>>,
+) -> Result<(W, LayerInfo, RechunkReport)> {
+    chunking.check()?;
+    let have_digests: HashSet<&str> = have
+        .iter()
+        .flat_map(|stream| stream.references())
+        .map(|reference| &*reference.digest)
+        .collect();
+
+    let resolve = resolver(resolve_reference, true);
+    let recording = RefCell::default();
+    let mut archive = tar::Archive::new(Tee {
+        inner: layer.reader(|reference| Ok(resolve(reference)?)),
+        recording: &recording,
+    });
+    let mut writer = Writer::new(output);
+    writer.copy_padding = true;
+
+    // The files with content are in the same order in the stream
+    let mut files = layer.files.iter().peekable();
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        writer.inline.append(&mut recording.borrow_mut().data);
+        let Some(manifest_entry) = tar_manifest_entry(&mut entry)? else {
+            continue;
+        };
+        let size = entry.header().entry_size()?;
+        if manifest_entry.kind != "reg" || size == 0 {
+            writer.append_empty(manifest_entry)?;
+            continue;
+        }
+
+        let references: Vec<_> = files
+            .next_if(|file| file.name == manifest_entry.name)
+            .map(|file| layer.file_references(file).collect())
+            .unwrap_or_default();
+        let split = if !references.is_empty()
+            && references
+                .iter()
+                .all(|reference| have_digests.contains(&*reference.digest))
+        {
+            match &references[..] {
+                [_] => Split::Whole,
+                _ => Split::Sizes(
+                    references
+                        .iter()
+                        .map(|reference| usize::try_from(reference.size))
+                        .collect::<Result<_, _>>()?,
+                ),
+            }
+        } else if size >= RECHUNK_THRESHOLD {
+            Split::Chunking(chunking)
+        } else {
+            Split::Whole
+        };
+
+        recording.borrow_mut().paused = true;
+        let written = writer.write_content(&mut entry, manifest_entry, &split)?;
+        recording.borrow_mut().paused = false;
+        ensure!(written == size, "Short tar entry in layer");
+    }
+
+    // The end of the archive, and whatever padding follows it
+    let mut tee = archive.into_inner();
+    io::copy(&mut tee, &mut io::sink())?;
+    writer.inline.append(&mut recording.borrow_mut().data);
+
+    let (output, info, [manifest, tarsplit]) = writer.write_metadata()?;
+    let rewritten = Stream::new_from_frames(&manifest, &tarsplit)?;
+    let report = RechunkReport {
+        before: DedupReport::new(layer, have),
+        after: DedupReport::new(&rewritten, have),
+    };
+    Ok((output, info, report))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::symlink;
+
+    use super::*;
+    use crate::{blob::BlobReader, testing::TempDir};
+
+    // Incompressible data, the same for the same seed
+    fn random(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state = state
+                    .wrapping_mul(6_364_136_223_846_793_005)
+                    .wrapping_add(1_442_695_040_888_963_407);
+                state.to_be_bytes()[0]
+            })
+            .collect()
+    }
+
+    fn reconstruct(stream: &Stream, blob: &[u8]) -> Result<Vec<u8>> {
+        let mut output = vec![];
+        stream.write_to_verified(&mut output, |reference| blob.content(reference))?;
+        Ok(output)
+    }
+
+    fn tree() -> Result<TempDir> {
+        let dir = TempDir::new()?;
+        fs::create_dir(dir.path().join("sub"))?;
+        fs::write(dir.path().join("sub/big"), random(1_500_000, 1))?;
+        fs::write(dir.path().join("small"), "small")?;
+        fs::write(dir.path().join("empty"), "")?;
+        fs::hard_link(dir.path().join("small"), dir.path().join("sub/hardlink"))?;
+        symlink("sub/big", dir.path().join("symlink"))?;
+        Ok(dir)
+    }
+
+    #[test]
+    fn layers_round_trip() -> Result<()> {
+        let dir = tree()?;
+        let (blob, info) = Writer::from_dir(dir.path(), vec![])?;
+        assert_eq!(info.size, blob.len() as u64);
+        assert_eq!(info.digest, digest::sha256(&blob));
+
+        let manifest = blob.read_at(&info.metadata.manifest.range)?;
+        let tarsplit = blob.read_at(&info.metadata.tarsplit.range)?;
+        info.metadata.verify(&manifest, &tarsplit)?;
+        let stream = Stream::new_from_frames(&manifest, &tarsplit)?;
+        let tar = reconstruct(&stream, &blob)?;
+        assert_eq!(digest::sha256(&tar), info.diff_id);
+
+        let mut names = vec![];
+        for entry in tar::Archive::new(&tar[..]).entries()? {
+            let mut entry = entry?;
+            let mut content = vec![];
+            entry.read_to_end(&mut content)?;
+            let name = String::from_utf8(entry.path_bytes().into_owned())?;
+            if let Ok(expected) = fs::read(dir.path().join(&name))
+                && entry.header().entry_type() == EntryType::Regular
+            {
+                assert_eq!(content, expected, "{name}");
+            }
+            names.push((name, entry.header().entry_type()));
+        }
+        assert_eq!(
+            names,
+            [
+                ("empty".to_owned(), EntryType::Regular),
+                ("small".to_owned(), EntryType::Regular),
+                ("sub".to_owned(), EntryType::Directory),
+                ("symlink".to_owned(), EntryType::Symlink),
+                ("sub/big".to_owned(), EntryType::Regular),
+                ("sub/hardlink".to_owned(), EntryType::Link),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn rechunking_keeps_the_diff_id() -> Result<()> {
+        let dir = tree()?;
+        let (blob, info) = Writer::from_dir(dir.path(), vec![])?;
+        let stream = Stream::from_blob(&blob, &info.metadata)?;
+        assert_eq!(stream.references().count(), 2);
+
+        let chunking = Chunking::Fixed(256 << 10);
+        let (rewritten, rewritten_info, _) =
+            rechunk(&stream, &[], chunking, vec![], |reference| {
+                blob.content(reference)
+            })?;
+        assert_eq!(rewritten_info.diff_id, info.diff_id);
+
+        // The big file is split, and the small one isn't
+        let rechunked = Stream::from_blob(&rewritten, &rewritten_info.metadata)?;
+        let sizes: Vec<_> = rechunked.references().map(|r| r.size).collect();
+        assert_eq!(
+            sizes,
+            [5, 262_144, 262_144, 262_144, 262_144, 262_144, 189_280]
+        );
+        let tar = reconstruct(&rechunked, &rewritten)?;
+        assert_eq!(digest::sha256(&tar), info.diff_id);
+        Ok(())
+    }
+
+    #[test]
+    fn artifact_chunks_parse_back() -> Result<()> {
+        let data = random(2500, 2);
+        let mut writer = ArtifactWriter::new(vec![]).with_chunking(Chunking::Fixed(1000));
+        writer.append_reader(&data[..], "model")?;
+        let (blob, info) = writer.finish()?;
+        assert_eq!(info.diff_id, digest::sha256(&data));
+        assert!(info.metadata.tarsplit.range.is_empty());
+
+        let stream = Stream::from_blob(&blob, &info.metadata)?;
+        let names: Vec<_> = stream.files.iter().map(|file| &*file.name).collect();
+        assert_eq!(names, ["model"]);
+        let references: Vec<_> = stream.file_references(&stream.files[0]).collect();
+        assert_eq!(references.len(), 3);
+        for (reference, chunk) in references.into_iter().zip(data.chunks(1000)) {
+            assert_eq!(reference.size, chunk.len() as u64);
+            assert_eq!(*reference.digest, digest::sha256(chunk));
+            assert_eq!(blob.content(reference)?, chunk);
+        }
+        Ok(())
+    }
+}